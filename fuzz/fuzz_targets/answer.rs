@@ -0,0 +1,15 @@
+//! Requests to the reflector, which each protocol must answer or decline
+//! without panicking
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate ping_rs;
+
+use ping_rs::protocol;
+
+fuzz_target!(|data: &[u8]| {
+    for name in protocol::names() {
+        protocol::create(&name).unwrap().answer(data);
+    }
+});
@@ -0,0 +1,18 @@
+//! Responses to a valid request of each protocol, which must classify
+//! without panicking
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate ping_rs;
+
+use ping_rs::probe::Probes;
+use ping_rs::protocol;
+
+fuzz_target!(|data: &[u8]| {
+    for name in protocol::names() {
+        let mut probe = protocol::create(&name).unwrap();
+        let request = probe.request(&mut Probes::new(1, 0, true, None));
+        probe.classify(&request, data);
+    }
+});
@@ -0,0 +1,18 @@
+//! The parsers of the ASCII ping protocol, which see both requests at the
+//! reflector and replies at the client
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate ping_rs;
+
+use ping_rs::probe;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(id) = probe::parse_id(data) {
+        probe::parse_seq(id);
+    }
+    probe::parse_reply_size(data);
+    probe::padded_reply(data, probe::MAX_REPLY);
+    probe::stamped_reply(data, 0, data.len());
+});
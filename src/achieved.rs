@@ -0,0 +1,138 @@
+//! The configuration a run actually got, read back from the kernel at
+//! startup rather than taken from the flags, so that results show which
+//! requested features were silently downgraded, e.g. an affinity narrowed
+//! to the CPUs which are online, and what the kernel granted where nothing
+//! was asked for, such as socket buffer sizes
+
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+
+use sched::{self, Priority};
+use sockopt;
+
+/// One setting, as asked for and as achieved
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Achieved {
+    pub name: &'static str,
+    /// None when the run left the setting at the kernel's default
+    pub requested: Option<String>,
+    pub achieved: String,
+}
+
+impl Achieved {
+    fn new(name: &'static str, requested: Option<String>, achieved: String) -> Achieved {
+        Achieved {
+            name: name,
+            requested: requested,
+            achieved: achieved,
+        }
+    }
+
+    /// Whether the setting was asked for but something else was granted
+    pub fn downgraded(&self) -> bool {
+        self.requested.as_ref().map_or(false, |r| *r != self.achieved)
+    }
+}
+
+fn cpu_list(cpus: &[usize]) -> String {
+    let mut cpus = cpus.to_vec();
+    cpus.sort();
+    let cpus: Vec<String> = cpus.iter().map(|c| format!("{}", c)).collect();
+    cpus.join(",")
+}
+
+/// Sets `priority` as `SO_PRIORITY` on a kernel socket bound to `src`, and
+/// reads back it and the options the kernel chose. Options which can't be
+/// read, e.g. off Linux, are left out
+pub fn sockets(src: Ipv4Addr, priority: Option<u32>) -> Result<Vec<Achieved>, String> {
+    let socket = match UdpSocket::bind(SocketAddrV4::new(src, 0)) {
+        Ok(socket) => socket,
+        Err(e) => return Err(format!("Unable to bind a socket to {}. {}", src, e)),
+    };
+    if let Some(priority) = priority {
+        if let Err(e) = sockopt::set_priority(&socket, priority) {
+            return Err(format!("Unable to set SO_PRIORITY {}. {}", priority, e));
+        }
+    }
+    let mut achieved = Vec::new();
+    if let Ok(value) = sockopt::priority(&socket) {
+        let requested = priority.map(|p| format!("{}", p));
+        achieved.push(Achieved::new("so_priority", requested, format!("{}", value)));
+    }
+    if let Ok(bytes) = sockopt::recv_buffer(&socket) {
+        achieved.push(Achieved::new("recv_buffer", None, format!("{}", bytes)));
+    }
+    if let Ok(bytes) = sockopt::send_buffer(&socket) {
+        achieved.push(Achieved::new("send_buffer", None, format!("{}", bytes)));
+    }
+    if let Ok(us) = sockopt::busy_poll(&socket) {
+        achieved.push(Achieved::new("busy_poll_us", None, format!("{}", us)));
+    }
+    Ok(achieved)
+}
+
+// sets `cpus` and `priority` on the calling thread and reads them back
+fn apply(cpus: Option<Vec<usize>>, priority: Option<Priority>) -> Result<Vec<Achieved>, String> {
+    let mut achieved = Vec::new();
+    if let Some(ref cpus) = cpus {
+        if let Err(e) = sched::set_affinity(cpus) {
+            return Err(format!("Unable to set the receiver's CPU affinity. {}", e));
+        }
+        let granted = match sched::affinity() {
+            Ok(granted) => granted,
+            Err(e) => return Err(format!("Unable to read CPU affinity. {}", e)),
+        };
+        achieved.push(Achieved::new("receiver_cpus", Some(cpu_list(cpus)), cpu_list(&granted)));
+    }
+    if let Some(priority) = priority {
+        if let Err(e) = sched::set_priority(priority) {
+            return Err(format!("Unable to set the receiver's priority {}. {}", priority, e));
+        }
+        let granted = match sched::priority() {
+            Ok(granted) => granted,
+            Err(e) => return Err(format!("Unable to read thread priority. {}", e)),
+        };
+        achieved.push(Achieved::new("receiver_priority",
+                                    Some(format!("{}", priority)),
+                                    format!("{}", granted)));
+    }
+    Ok(achieved)
+}
+
+/// Applies `cpus` and `priority` to a short-lived thread, as they will be to
+/// the stats receiver, and reads back what it was given
+pub fn receiver(cpus: Option<Vec<usize>>,
+                priority: Option<Priority>)
+                -> Result<Vec<Achieved>, String> {
+    let (tx, rx) = mpsc::channel();
+    let _ = thread::spawn(move || {
+        let _ = tx.send(apply(cpus, priority));
+    });
+    match rx.recv() {
+        Ok(result) => result,
+        Err(_) => Err("Unable to check the receiver's scheduling".to_owned()),
+    }
+}
+
+/// How probes are timestamped: by the TSC with the `asm` feature, otherwise
+/// by CLOCK_MONOTONIC, and by the reflector too with `--direction-jitter`
+pub fn timestamps(reflector: bool) -> Achieved {
+    let clock = if cfg!(feature = "asm") {
+        "tsc"
+    } else {
+        "clock_monotonic"
+    };
+    let achieved = if reflector {
+        format!("{}+reflector", clock)
+    } else {
+        clock.to_owned()
+    };
+    Achieved::new("timestamps", None, achieved)
+}
+
+/// Whether replies are padded to `--reply-size`, as the reflector told the
+/// handshake
+pub fn reply_padding(supported: bool) -> Achieved {
+    Achieved::new("reply_padding", Some("true".to_owned()), format!("{}", supported))
+}
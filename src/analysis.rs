@@ -0,0 +1,117 @@
+/// Minimum autocorrelation for a lag to be reported as a period
+const MIN_CORRELATION: f64 = 0.3;
+
+/// Maximum number of periods reported
+const MAX_PERIODS: usize = 3;
+
+/// Seconds of per-second maxima kept for finding periods, the most recent
+/// ones, so that long runs don't grow without bound. Periods up to a third
+/// of this are found
+pub const MAX_SECONDS: usize = 3600;
+
+/// Finds periodic spikes in a series of per-second maximum latencies using
+/// autocorrelation, returning `(period in seconds, correlation)` for the
+/// strongest periods, excluding multiples of a stronger period
+pub fn periods(series: &[u64]) -> Vec<(usize, f64)> {
+    let n = series.len();
+    // a period must repeat at least a few times to be meaningful
+    let max_lag = n / 3;
+    if max_lag < 2 {
+        return Vec::new();
+    }
+
+    let mean = series.iter().fold(0.0, |acc, v| acc + *v as f64) / n as f64;
+    let centered: Vec<f64> = series.iter().map(|v| *v as f64 - mean).collect();
+    let variance = centered.iter().fold(0.0, |acc, v| acc + v * v);
+    if variance == 0.0 {
+        return Vec::new();
+    }
+
+    let mut acf = vec![0.0; max_lag + 2];
+    for lag in 1..(max_lag + 2) {
+        let mut sum = 0.0;
+        for i in 0..(n - lag) {
+            sum += centered[i] * centered[i + lag];
+        }
+        acf[lag] = sum / variance;
+    }
+
+    // local maxima of the autocorrelation are candidate periods
+    let mut peaks: Vec<(usize, f64)> = (2..(max_lag + 1))
+        .filter(|&lag| acf[lag] >= MIN_CORRELATION && acf[lag] > acf[lag - 1] &&
+                       acf[lag] >= acf[lag + 1])
+        .map(|lag| (lag, acf[lag]))
+        .collect();
+    peaks.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+    let mut periods: Vec<(usize, f64)> = Vec::new();
+    for (lag, r) in peaks {
+        if periods.len() >= MAX_PERIODS {
+            break;
+        }
+        // harmonics of a period show up within a second of its multiples
+        let harmonic = periods.iter().any(|&(p, _)| {
+            let rem = lag % p;
+            rem <= 1 || p - rem <= 1
+        });
+        if !harmonic {
+            periods.push((lag, r));
+        }
+    }
+    periods
+}
+
+/// Merges per-second series from several workers, each given with the
+/// second of the run it starts at, taking the maximum of each second over the
+/// last `MAX_SECONDS` of them
+pub fn merge_maxima(series: &[(usize, Vec<u64>)]) -> Vec<u64> {
+    let end = series.iter().map(|&(from, ref s)| from + s.len()).max().unwrap_or(0);
+    let start = end.saturating_sub(MAX_SECONDS);
+    let mut merged = vec![0; end - start];
+    for &(from, ref s) in series {
+        for (second, v) in (from..).zip(s.iter()) {
+            if second >= start && *v > merged[second - start] {
+                merged[second - start] = *v;
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periodic_spikes_are_found_without_their_harmonics() {
+        let series: Vec<u64> = (0..60).map(|i| if i % 10 == 0 { 1000 } else { 100 }).collect();
+        let found = periods(&series);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, 10);
+        assert!(found[0].1 > MIN_CORRELATION);
+    }
+
+    #[test]
+    fn flat_or_short_series_have_no_periods() {
+        assert!(periods(&[500; 60]).is_empty());
+        assert!(periods(&[1, 1000, 1, 1000, 1]).is_empty());
+        assert!(periods(&[]).is_empty());
+    }
+
+    #[test]
+    fn maxima_are_aligned_by_their_start() {
+        let merged = merge_maxima(&[(0, vec![1, 5, 2]), (1, vec![4, 1, 7])]);
+        assert_eq!(merged, vec![1, 5, 2, 7]);
+        assert!(merge_maxima(&[]).is_empty());
+    }
+
+    #[test]
+    fn only_the_last_seconds_of_maxima_are_kept() {
+        let merged = merge_maxima(&[(0, vec![1; MAX_SECONDS]), (MAX_SECONDS + 5, vec![9])]);
+        assert_eq!(merged.len(), MAX_SECONDS);
+        assert_eq!(merged[merged.len() - 1], 9);
+        // the seconds between the two series were never observed
+        assert_eq!(&merged[merged.len() - 6..merged.len() - 1], &[0; 5]);
+        assert_eq!(merged[0], 1);
+    }
+}
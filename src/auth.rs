@@ -0,0 +1,247 @@
+//! Signing of probe payloads with a shared secret, so that a reflector exposed
+//! to untrusted networks only answers clients which hold it, and clients can
+//! tell its replies from forged ones
+//!
+//! A signed payload carries ` mac=<time><hex>` after the first word of its
+//! header line: the unix time it was signed at, in seconds as 8 hex digits,
+//! and the first 128 bits of the HMAC-SHA256 of `req` or `rep`, as the payload
+//! is a request or a reply, then that time and the payload without the token.
+//! Signing the direction keeps a request reflected back to its client from
+//! passing for a reply. Payloads signed more than `MAX_AGE_SECS` from
+//! the verifier's clock are refused, so that a captured one can't be replayed
+//! for longer. To keep padded payloads at the size asked for, signing takes
+//! the token's room from their filler where there is enough of it
+
+extern crate crypto;
+extern crate time;
+
+use std::fs::File;
+use std::io::Read;
+
+use self::crypto::mac::Mac;
+
+use probe::Probes;
+use protocol::{Answer, Outcome, Probe};
+
+/// Bytes of the HMAC carried in a payload
+const MAC_LEN: usize = 16;
+
+/// Hex digits of the signing time
+const TIME_LEN: usize = 8;
+
+/// Length of the ` mac=<time><hex>` token
+pub const TOKEN_LEN: usize = 5 + TIME_LEN + 2 * MAC_LEN;
+
+/// How far from the verifier's clock a signing time may be, in either
+/// direction, allowing for clocks which are a little apart
+pub const MAX_AGE_SECS: u64 = 30;
+
+fn now() -> u64 {
+    time::get_time().sec as u64
+}
+
+/// Which way a signed payload travels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Request,
+    Reply,
+}
+
+impl Direction {
+    fn label(&self) -> &'static [u8] {
+        match *self {
+            Direction::Request => b"req",
+            Direction::Reply => b"rep",
+        }
+    }
+}
+
+/// A shared secret
+#[derive(Clone)]
+pub struct Key {
+    secret: Vec<u8>,
+}
+
+impl Key {
+    pub fn new(secret: &[u8]) -> Result<Key, String> {
+        if secret.is_empty() {
+            return Err("the key is empty".to_owned());
+        }
+        Ok(Key { secret: secret.to_vec() })
+    }
+
+    /// Reads a key from `path`, ignoring trailing whitespace so that it can
+    /// be written with `echo`
+    pub fn load(path: &str) -> Result<Key, String> {
+        let mut secret = Vec::new();
+        if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut secret)) {
+            return Err(format!("Unable to read key {}. {}", path, e));
+        }
+        while secret.last().map_or(false, |&b| b == b'\n' || b == b'\r' || b == b' ') {
+            secret.pop();
+        }
+        Key::new(&secret).map_err(|e| format!("Unable to use key {}. {}", path, e))
+    }
+
+    fn mac(&self, direction: Direction, signed_at: &str, payload: &[u8]) -> Vec<u8> {
+        let mut hmac = crypto::hmac::Hmac::new(crypto::sha2::Sha256::new(), &self.secret);
+        hmac.input(direction.label());
+        hmac.input(signed_at.as_bytes());
+        hmac.input(payload);
+        hmac.result().code()[..MAC_LEN].to_vec()
+    }
+
+    /// Signs `payload`, which must not be signed already, as travelling in
+    /// `direction`
+    pub fn sign(&self, payload: &[u8], direction: Direction) -> Vec<u8> {
+        self.sign_at(payload, direction, now())
+    }
+
+    /// Signs `payload` as if at `secs` since the unix epoch
+    pub fn sign_at(&self, payload: &[u8], direction: Direction, secs: u64) -> Vec<u8> {
+        let payload = shrink_filler(payload, TOKEN_LEN);
+        let signed_at = format!("{:08x}", secs as u32);
+        let mac = self.mac(direction, &signed_at, &payload);
+        let at = first_word(&payload);
+        let mut signed = Vec::with_capacity(payload.len() + TOKEN_LEN);
+        signed.extend_from_slice(&payload[..at]);
+        signed.extend_from_slice(b" mac=");
+        signed.extend_from_slice(signed_at.as_bytes());
+        for b in &mac {
+            signed.extend_from_slice(format!("{:02x}", b).as_bytes());
+        }
+        signed.extend_from_slice(&payload[at..]);
+        signed
+    }
+
+    /// The payload without its signature, if it is signed with this key as
+    /// travelling in `direction`, no more than `MAX_AGE_SECS` from now
+    pub fn verify(&self, signed: &[u8], direction: Direction) -> Option<Vec<u8>> {
+        let at = first_word(signed);
+        if signed.len() < at + TOKEN_LEN || &signed[at..at + 5] != b" mac=" {
+            return None;
+        }
+        let token = match ::std::str::from_utf8(&signed[at + 5..at + TOKEN_LEN]) {
+            Ok(token) if token.chars().all(|c| c.is_digit(16)) => token,
+            _ => return None,
+        };
+        let (signed_at, hex) = token.split_at(TIME_LEN);
+        let secs = match u32::from_str_radix(signed_at, 16) {
+            Ok(secs) => secs as u64,
+            Err(_) => return None,
+        };
+        let now = now();
+        let age = if now > secs { now - secs } else { secs - now };
+        if age > MAX_AGE_SECS {
+            return None;
+        }
+        let mut mac = Vec::with_capacity(MAC_LEN);
+        for i in 0..MAC_LEN {
+            match u8::from_str_radix(&hex[2 * i..2 * i + 2], 16) {
+                Ok(b) => mac.push(b),
+                Err(_) => return None,
+            }
+        }
+        let mut payload = signed[..at].to_vec();
+        payload.extend_from_slice(&signed[at + TOKEN_LEN..]);
+        if crypto::util::fixed_time_eq(&mac, &self.mac(direction, signed_at, &payload)) {
+            Some(payload)
+        } else {
+            None
+        }
+    }
+}
+
+/// A protocol whose requests are signed, accepting only responses signed
+/// with the same key
+pub struct Signed {
+    probe: Box<Probe>,
+    key: Key,
+}
+
+impl Signed {
+    pub fn new(probe: Box<Probe>, key: Key) -> Signed {
+        Signed {
+            probe: probe,
+            key: key,
+        }
+    }
+}
+
+impl Probe for Signed {
+    fn request(&mut self, probes: &mut Probes) -> Vec<u8> {
+        self.key.sign(&self.probe.request(probes), Direction::Request)
+    }
+
+    fn classify(&self, request: &[u8], response: &[u8]) -> Outcome {
+        let response = match self.key.verify(response, Direction::Reply) {
+            Some(response) => response,
+            None => return Outcome::Unauthenticated,
+        };
+        let request = self.key
+            .verify(request, Direction::Request)
+            .unwrap_or_else(|| request.to_vec());
+        self.probe.classify(&request, &response)
+    }
+
+    fn answer(&self, received: &[u8]) -> Answer {
+        let request = self.key.verify(received, Direction::Request);
+        match request.map(|request| self.probe.answer(&request)) {
+            Some(Answer::Reply(reply)) => Answer::Reply(self.key.sign(&reply, Direction::Reply)),
+            Some(Answer::Incomplete) => Answer::Incomplete,
+            Some(Answer::Unknown) | None => Answer::Unknown,
+        }
+    }
+
+    fn udp(&self) -> bool {
+        self.probe.udp()
+    }
+
+    fn tcp(&self) -> bool {
+        self.probe.tcp()
+    }
+
+    fn use_tcp(&mut self) {
+        self.probe.use_tcp()
+    }
+
+    fn framed(&self, received: &[u8]) -> bool {
+        self.probe.framed(received)
+    }
+}
+
+// the offset of the end of the first word of `payload`
+fn first_word(payload: &[u8]) -> usize {
+    payload.iter().position(|&b| b == b' ' || b == b'\r' || b == b'\n').unwrap_or(payload.len())
+}
+
+// removes up to `len` bytes of filler: a run of `.` ending the header line,
+// or whatever follows the header line
+fn shrink_filler(payload: &[u8], len: usize) -> Vec<u8> {
+    let end = payload.iter().position(|&b| b == b'\r' || b == b'\n').unwrap_or(payload.len());
+    let header = &payload[..end];
+    let rest = &payload[end..];
+    let newline = if rest.starts_with(b"\r\n") {
+        2
+    } else if rest.is_empty() {
+        0
+    } else {
+        1
+    };
+    let trailer = &rest[newline..];
+    if !trailer.is_empty() {
+        if trailer.len() < len {
+            return payload.to_vec();
+        }
+        return payload[..payload.len() - len].to_vec();
+    }
+    let dots = header.iter().rev().take_while(|&&b| b == b'.').count();
+    // a filler is the last word of the header, and at least one dot of it
+    // stays so that it remains one
+    if dots <= len || header.len() == dots || header[header.len() - dots - 1] != b' ' {
+        return payload.to_vec();
+    }
+    let mut shrunk = header[..header.len() - len].to_vec();
+    shrunk.extend_from_slice(rest);
+    shrunk
+}
@@ -0,0 +1,143 @@
+//! A handshake through which a client learns which optional features a
+//! ping-rs reflector supports, so that they can be used without matching
+//! flags on both ends by hand
+//!
+//! The client sends `HELLO ping-rs`, padded with a filler, and a reflector
+//! answers `WELCOME ping-rs <version> <feature>...`. Other echo services
+//! return the request unchanged or not at all, which tells the client that
+//! none of the features are available. A hello is only answered if it is at
+//! least as long as the welcome, so that the handshake can't amplify traffic
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// Probe IDs are kept in replies, so replies can be matched to requests
+pub const IDS: &'static str = "ids";
+/// Replies are padded to the size asked for by `reply=<bytes>`
+pub const PADDING: &'static str = "padding";
+/// A filler following the header line is repeated in padded replies
+pub const PATTERN: &'static str = "pattern";
+/// Only signed requests are answered, and replies are signed
+pub const AUTH: &'static str = "auth";
+/// Probes received are tallied by client thread, see `ledger`
+pub const LEDGER: &'static str = "ledger";
+/// Replies to UDP probes asking for them carry the reflector's receive and
+/// transmit times
+pub const STAMPS: &'static str = "stamps";
+
+/// Bytes a hello is padded to, leaving room for features to be added
+pub const HELLO_LEN: usize = 128;
+
+const HELLO: &'static str = "HELLO ping-rs";
+const WELCOME: &'static str = "WELCOME ping-rs";
+
+/// The features a reflector advertised
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: String,
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    /// The features of this build's reflector, with `AUTH` when it holds a
+    /// key
+    pub fn local(auth: bool) -> Capabilities {
+        let mut features = vec![IDS.to_owned(),
+                                PADDING.to_owned(),
+                                PATTERN.to_owned(),
+                                LEDGER.to_owned(),
+                                STAMPS.to_owned()];
+        if auth {
+            features.push(AUTH.to_owned());
+        }
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            features: features,
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// The request which starts the handshake
+pub fn hello() -> Vec<u8> {
+    let mut hello = format!("{} ", HELLO);
+    while hello.len() < HELLO_LEN - 2 {
+        hello.push('.');
+    }
+    hello.push_str("\r\n");
+    hello.into_bytes()
+}
+
+/// Whether `received` starts a handshake
+pub fn is_hello(received: &[u8]) -> bool {
+    received.starts_with(HELLO.as_bytes())
+}
+
+/// The reflector's answer to a hello, or None if the hello is too short for
+/// it
+pub fn welcome(received: &[u8], capabilities: &Capabilities) -> Option<Vec<u8>> {
+    let welcome = format!("{} {} {}\r\n",
+                          WELCOME,
+                          capabilities.version,
+                          capabilities.features.join(" "));
+    if welcome.len() > received.len() {
+        None
+    } else {
+        Some(welcome.into_bytes())
+    }
+}
+
+/// The capabilities advertised by `reply`, if it is a welcome
+pub fn parse(reply: &[u8]) -> Option<Capabilities> {
+    let line = match ::std::str::from_utf8(reply) {
+        Ok(line) => line.trim(),
+        Err(_) => return None,
+    };
+    if !line.starts_with(WELCOME) {
+        return None;
+    }
+    let mut words = line[WELCOME.len()..].split_whitespace();
+    let version = match words.next() {
+        Some(version) => version.to_owned(),
+        None => return None,
+    };
+    Some(Capabilities {
+        version: version,
+        features: words.map(|w| w.to_owned()).collect(),
+    })
+}
+
+/// Asks the reflector at `target` for its capabilities over UDP, sending up
+/// to `attempts` hellos each waiting `timeout` for the welcome. None means it
+/// is not a ping-rs reflector, or didn't answer
+pub fn query(target: SocketAddr,
+             timeout: Duration,
+             attempts: usize)
+             -> io::Result<Option<Capabilities>> {
+    let local = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = try!(UdpSocket::bind(local));
+    try!(socket.set_read_timeout(Some(timeout)));
+    let hello = hello();
+    let mut buffer = [0; 1024];
+    for _ in 0..attempts {
+        try!(socket.send_to(&hello, target));
+        loop {
+            match socket.recv_from(&mut buffer) {
+                Ok((len, peer)) if peer == target => {
+                    // anything else answering a hello, e.g. an echo service
+                    // returning it, is not a reflector
+                    return Ok(parse(&buffer[..len]));
+                }
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,83 @@
+//! Checkpoints of a run's state, so that a long run survives a crash or a
+//! reboot of the host and can be resumed as the same run
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use sketch::{self, Sketch};
+
+const MAGIC: &'static [u8] = b"PRCK";
+const VERSION: u64 = 1;
+
+/// What carries over into a resumed run
+pub struct Checkpoint {
+    pub run_id: u64,
+    /// Start of the run, as recorded in its metadata
+    pub start: String,
+    /// Windows completed
+    pub window: usize,
+    /// Probes recorded as 'ok'
+    pub recorded: u64,
+    /// RTT of every probe completed
+    pub latency: Sketch,
+}
+
+impl Checkpoint {
+    /// Writes the checkpoint to `path`, replacing it only once complete so
+    /// that a crash while writing leaves the previous checkpoint intact
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let partial = format!("{}.partial", path);
+        {
+            let mut w = BufWriter::new(try!(File::create(&partial)));
+            try!(w.write_all(MAGIC));
+            try!(sketch::write_u64(&mut w, VERSION));
+            try!(sketch::write_u64(&mut w, self.run_id));
+            try!(sketch::write_u64(&mut w, self.start.len() as u64));
+            try!(w.write_all(self.start.as_bytes()));
+            try!(sketch::write_u64(&mut w, self.window as u64));
+            try!(sketch::write_u64(&mut w, self.recorded));
+            try!(self.latency.write_to(&mut w));
+            try!(w.flush());
+            try!(w.get_ref().sync_all());
+        }
+        fs::rename(&partial, path)
+    }
+
+    pub fn load(path: &str) -> Result<Checkpoint, String> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("Unable to open checkpoint {}. {}", path, e)),
+        };
+        read(&mut BufReader::new(file)).map_err(|e| format!("Invalid checkpoint {}. {}", path, e))
+    }
+}
+
+fn read<R: Read>(r: &mut R) -> io::Result<Checkpoint> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+    let mut magic = [0; 4];
+    try!(r.read_exact(&mut magic));
+    if &magic[..] != MAGIC {
+        return Err(invalid("not a ping-rs checkpoint"));
+    }
+    if try!(sketch::read_u64(r)) != VERSION {
+        return Err(invalid("unsupported checkpoint version"));
+    }
+    let run_id = try!(sketch::read_u64(r));
+    let len = try!(sketch::read_u64(r));
+    if len > 64 {
+        return Err(invalid("start time too long"));
+    }
+    let mut start = vec![0; len as usize];
+    try!(r.read_exact(&mut start));
+    let start = match String::from_utf8(start) {
+        Ok(start) => start,
+        Err(_) => return Err(invalid("start time is not UTF-8")),
+    };
+    Ok(Checkpoint {
+        run_id: run_id,
+        start: start,
+        window: try!(sketch::read_u64(r)) as usize,
+        recorded: try!(sketch::read_u64(r)),
+        latency: try!(Sketch::read_from(r)),
+    })
+}
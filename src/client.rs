@@ -0,0 +1,1293 @@
+extern crate time;
+
+use std;
+use std::any::Any;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::str::FromStr;
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "userspace")]
+use rips::udp::UdpSocket;
+use tic::{Clocksource, Sample, Sender};
+
+use Metric;
+use analysis;
+use control::{Control, Flow};
+use errqueue::{self, IcmpError};
+use outlier::Outliers;
+use probe::{self, Probes};
+use protocol::{self, Echo, Outcome, Probe};
+use simulate::{Fate, Impairment, Simulator};
+use sketch::{Distributions, Sketch, Sliding, Slow};
+use sockopt;
+use timer::Timer;
+
+/// How long a std socket blocks in `recv_from` before re-checking whether the
+/// run is shutting down
+const RECV_TIMEOUT_MS: u64 = 1000;
+
+/// Large enough for any datagram, so padded replies aren't truncated
+const RECV_BUFFER: usize = 1024*64;
+
+/// Pause before restarting a failed worker, so a persistent error doesn't spin
+const RESTART_DELAY_MS: u64 = 100;
+
+/// Consecutive send failures after which sends are backed off
+const BACKOFF_THRESHOLD: u32 = 3;
+
+/// Initial and maximum delay between sends while backing off
+const BACKOFF_BASE_MS: u64 = 1;
+const BACKOFF_MAX_MS: u64 = 1000;
+
+/// How often a paused worker checks whether probing has resumed
+const PAUSE_POLL_MS: u64 = 10;
+
+/// How long before a send time hybrid pacing stops sleeping and spins, which
+/// covers the usual wakeup latency of a sleep
+const SPIN_NS: u64 = 100_000;
+
+/// What to do when a worker returns an error or panics
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnError {
+    /// Log and count the failure, then run the worker again
+    Restart,
+    /// Log and count the failure, then let the worker exit
+    Stop,
+    /// Log the failure and terminate the process
+    Abort,
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OnError, String> {
+        match s {
+            "restart" => Ok(OnError::Restart),
+            "stop" => Ok(OnError::Stop),
+            "abort" => Ok(OnError::Abort),
+            _ => Err(format!("unknown policy: {}", s)),
+        }
+    }
+}
+
+/// How a worker waits for the next send time when probes are paced
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pacing {
+    /// Sleep until the send time. Wakeups may be tens of microseconds late
+    Sleep,
+    /// Busy-wait on the clock, occupying a core but sending on time
+    Spin,
+    /// Sleep until shortly before the send time, then busy-wait
+    Hybrid,
+    /// Block on a timerfd armed with the absolute send time
+    Timerfd,
+}
+
+impl Pacing {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Pacing::Sleep => "sleep",
+            Pacing::Spin => "spin",
+            Pacing::Hybrid => "hybrid",
+            Pacing::Timerfd => "timerfd",
+        }
+    }
+}
+
+impl FromStr for Pacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pacing, String> {
+        match s {
+            "sleep" => Ok(Pacing::Sleep),
+            "spin" => Ok(Pacing::Spin),
+            "hybrid" => Ok(Pacing::Hybrid),
+            "timerfd" => Ok(Pacing::Timerfd),
+            _ => Err(format!("unknown pacing: {}", s)),
+        }
+    }
+}
+
+/// How a worker with several source addresses chooses between them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceMode {
+    /// Send from the first source until it keeps failing, then the next
+    Failover,
+    /// Send from each source in turn
+    RoundRobin,
+}
+
+impl SourceMode {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SourceMode::Failover => "failover",
+            SourceMode::RoundRobin => "round-robin",
+        }
+    }
+}
+
+impl FromStr for SourceMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SourceMode, String> {
+        match s {
+            "failover" => Ok(SourceMode::Failover),
+            "round-robin" => Ok(SourceMode::RoundRobin),
+            _ => Err(format!("unknown source mode: {}", s)),
+        }
+    }
+}
+
+/// What latency is broken down by on the receiving side
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RxKey {
+    /// The CPU which processed the reply, from `SO_INCOMING_CPU`
+    Cpu,
+    /// The receive queue which handled the reply, by NAPI ID
+    Queue,
+}
+
+impl RxKey {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RxKey::Cpu => "cpu",
+            RxKey::Queue => "queue",
+        }
+    }
+}
+
+impl FromStr for RxKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RxKey, String> {
+        match s {
+            "cpu" => Ok(RxKey::Cpu),
+            "queue" => Ok(RxKey::Queue),
+            _ => Err(format!("unknown breakdown: {}", s)),
+        }
+    }
+}
+
+/// Request/response exchange used on persistent TCP connections
+#[derive(Clone)]
+pub struct TcpConfig {
+    /// Sent instead of the usual `PING` request when given
+    pub request: Option<Vec<u8>>,
+    /// Responses must match this exactly when given, otherwise a response is
+    /// a whole message of the protocol, up to the first newline for most
+    pub expect: Option<Vec<u8>>,
+    /// Disables Nagle's algorithm with `TCP_NODELAY`
+    pub nodelay: bool,
+    /// Sets `TCP_QUICKACK` before each read, so responses are acked at once
+    pub quickack: bool,
+    /// Congestion control algorithm, e.g. `cubic` or `bbr`
+    pub congestion: Option<String>,
+}
+
+/// Measures the harness's own overhead: the interval between back-to-back
+/// timestamps as taken around each probe, which is what noop mode reports
+pub fn baseline(clocksource: &Clocksource, samples: usize) -> Sketch {
+    let mut sketch = Sketch::new();
+    for _ in 0..samples {
+        let t0 = clocksource.counter();
+        let t1 = clocksource.counter();
+        sketch.insert((clocksource.convert(t1) - clocksource.convert(t0)) as u64);
+    }
+    sketch
+}
+
+/// What a worker hands back to the main thread when it exits
+pub struct Report {
+    pub completed: u64,
+    /// Probes sent without an error
+    pub sent: u64,
+    /// Maximum RTT observed in each of the last seconds of the run, in
+    /// nanoseconds, from the second `maxima_from`
+    pub maxima: Vec<u64>,
+    pub maxima_from: usize,
+    /// RTTs by position within a burst, when sending bursts
+    pub positions: Vec<Sketch>,
+}
+
+/// State owned by a single client thread
+pub struct Worker {
+    id: usize,
+    probes: Probes,
+    protocol: Box<Probe>,
+    clocksource: Clocksource,
+    stats: Sender<Metric>,
+    control: Arc<Control>,
+    next_send: u64,
+    adaptive: Option<u64>,
+    pacing: Pacing,
+    timer: Option<Timer>,
+    srtt: u64,
+    outliers: Option<Outliers>,
+    sliding: Option<Arc<Mutex<Sliding>>>,
+    flows: Vec<Option<Flow>>,
+    distributions: Arc<Mutex<Distributions>>,
+    slowest: bool,
+    expect_size: Option<usize>,
+    truncated_error: bool,
+    ramp_delay: u64,
+    priority: Option<u32>,
+    router_alert: bool,
+    rx_queues: bool,
+    by_rx: Option<RxKey>,
+    rx: Option<u32>,
+    // the width of reply size buckets, and the bucket of the last reply
+    by_size: Option<u32>,
+    reply_bucket: Option<u32>,
+    time_of_day: Option<u32>,
+    subsecond: Option<u32>,
+    calibration: usize,
+    sources: Vec<Ipv4Addr>,
+    source_mode: SourceMode,
+    failover_after: u32,
+    // the source in use when failing over, and its consecutive failures
+    source: usize,
+    source_failures: u32,
+    // the source of the probe being completed, with several sources
+    probe_source: Option<u32>,
+    // the second and time-of-day bin of the last completion, as converting to
+    // local time for every reply would be wasteful
+    bin: (i64, u32),
+    send_failures: u32,
+    // whether replies are timestamped, and the one-way delays and RTT of the
+    // last one
+    stamped: bool,
+    delays: Option<(i64, i64, i64)>,
+    jitter: u64,
+    start: u64,
+    maxima: Vec<u64>,
+    maxima_from: usize,
+    positions: Vec<Sketch>,
+    completed: u64,
+    sent: u64,
+}
+
+impl Worker {
+    pub fn new(id: usize,
+               probes: Probes,
+               clocksource: Clocksource,
+               stats: Sender<Metric>,
+               control: Arc<Control>,
+               distributions: Arc<Mutex<Distributions>>)
+               -> Worker {
+        let start = clocksource.time();
+        let slowest = distributions.lock().unwrap().slowest.capacity() > 0;
+        Worker {
+            id: id,
+            probes: probes,
+            protocol: Box::new(Echo),
+            clocksource: clocksource,
+            stats: stats,
+            control: control,
+            next_send: 0,
+            adaptive: None,
+            pacing: Pacing::Sleep,
+            timer: None,
+            srtt: 0,
+            outliers: None,
+            sliding: None,
+            flows: Vec::new(),
+            distributions: distributions,
+            slowest: slowest,
+            expect_size: None,
+            truncated_error: false,
+            ramp_delay: 0,
+            priority: None,
+            router_alert: false,
+            rx_queues: false,
+            by_rx: None,
+            rx: None,
+            by_size: None,
+            reply_bucket: None,
+            time_of_day: None,
+            subsecond: None,
+            calibration: 0,
+            sources: Vec::new(),
+            source_mode: SourceMode::Failover,
+            failover_after: 0,
+            source: 0,
+            source_failures: 0,
+            probe_source: None,
+            bin: (-1, 0),
+            send_failures: 0,
+            stamped: false,
+            delays: None,
+            jitter: (start ^ ((id as u64 + 1) << 32)) | 1,
+            start: start,
+            maxima: Vec::new(),
+            maxima_from: 0,
+            positions: Vec::new(),
+            completed: 0,
+            sent: 0,
+        }
+    }
+
+    /// Selects the protocol probes speak, the ASCII echo protocol by default
+    pub fn set_protocol(&mut self, protocol: Box<Probe>) {
+        self.protocol = protocol;
+    }
+
+    /// Paces probes at the smoothed RTT, but never faster than one per
+    /// `floor` nanoseconds, like `ping -A`
+    pub fn set_adaptive(&mut self, floor: Option<u64>) {
+        self.adaptive = floor;
+    }
+
+    /// Selects how the worker waits between paced probes
+    pub fn set_pacing(&mut self, pacing: Pacing) {
+        self.pacing = pacing;
+    }
+
+    /// Captures context for probes which are slow relative to the rolling
+    /// median
+    pub fn set_outliers(&mut self, outliers: Option<Outliers>) {
+        self.outliers = outliers;
+    }
+
+    /// Counts responses whose size differs from `size` as mismatched
+    pub fn set_expect_size(&mut self, size: Option<usize>) {
+        self.expect_size = size;
+    }
+
+    /// Fails the worker on a truncated response, rather than only counting it
+    pub fn set_truncated_error(&mut self, truncated_error: bool) {
+        self.truncated_error = truncated_error;
+    }
+
+    /// Delays the first probe by `delay` ns, so that workers can be started
+    /// gradually
+    pub fn set_ramp_delay(&mut self, delay: u64) {
+        self.ramp_delay = delay;
+    }
+
+    /// Sets `SO_PRIORITY` on the kernel sockets the worker probes over
+    pub fn set_priority(&mut self, priority: Option<u32>) {
+        self.priority = priority;
+    }
+
+    /// Adds the IPv4 Router Alert option to probes on kernel sockets
+    pub fn set_router_alert(&mut self, router_alert: bool) {
+        self.router_alert = router_alert;
+    }
+
+    /// Counts the receive queue which handled each reply on kernel sockets
+    pub fn set_rx_queues(&mut self, rx_queues: bool) {
+        self.rx_queues = rx_queues;
+    }
+
+    /// Breaks latency down by the CPU or receive queue which handled each
+    /// reply on kernel sockets
+    pub fn set_by_rx(&mut self, by_rx: Option<RxKey>) {
+        self.by_rx = by_rx;
+    }
+
+    /// Breaks latency down by the size of the reply, in buckets of `bytes`
+    pub fn set_by_size(&mut self, bytes: Option<u32>) {
+        self.by_size = bytes;
+    }
+
+    /// Breaks latency down by the local time of day at which probes were
+    /// sent, in bins of `minutes`
+    pub fn set_time_of_day(&mut self, minutes: Option<u32>) {
+        self.time_of_day = minutes;
+    }
+
+    /// Breaks latency down by when within the second probes were sent, in
+    /// buckets of `ms`
+    pub fn set_subsecond(&mut self, ms: Option<u32>) {
+        self.subsecond = ms;
+    }
+
+    /// Breaks jitter down by direction, from the reflector's timestamps in
+    /// replies to probes asking for them
+    pub fn set_stamped(&mut self, stamped: bool) {
+        self.stamped = stamped;
+    }
+
+    /// Sends from several source addresses, whose sockets `run_stdnet` is
+    /// given one set of slots after another. Failing over moves to the next
+    /// source after `failover_after` consecutive failed probes
+    pub fn set_sources(&mut self, sources: Vec<Ipv4Addr>, mode: SourceMode, failover_after: u32) {
+        self.sources = sources;
+        self.source_mode = mode;
+        self.failover_after = failover_after;
+    }
+
+    /// Feeds RTTs into a sliding window of per-second sketches
+    pub fn set_sliding(&mut self, sliding: Option<Arc<Mutex<Sliding>>>) {
+        self.sliding = sliding;
+    }
+
+    /// Consumes the worker, flushing its logs
+    pub fn into_report(self) -> Report {
+        Report {
+            completed: self.completed,
+            sent: self.sent,
+            maxima: self.maxima,
+            maxima_from: self.maxima_from,
+            positions: self.positions,
+        }
+    }
+
+    /// Calls `run` until shutdown, handling errors and panics it produces
+    /// according to `policy`
+    pub fn supervise<F>(&mut self, policy: OnError, mut run: F)
+        where F: FnMut(&mut Worker) -> Result<(), String>
+    {
+        if self.ramp_delay > 0 {
+            let deadline = time::precise_time_ns() + self.ramp_delay;
+            while self.control.running() && time::precise_time_ns() < deadline {
+                thread::sleep(Duration::from_millis(PAUSE_POLL_MS));
+            }
+            debug!("worker {} starting after {} ms", self.id, self.ramp_delay / 1_000_000);
+        }
+        while self.control.running() {
+            let error = match panic::catch_unwind(AssertUnwindSafe(|| run(self))) {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => e,
+                Err(e) => panic_message(e),
+            };
+            let t = self.clocksource.counter();
+            let _ = self.stats.send(Sample::new(t, t, Metric::WorkerError));
+            match policy {
+                OnError::Restart => {
+                    error!("worker {} failed, restarting: {}", self.id, error);
+                    thread::sleep(Duration::from_millis(RESTART_DELAY_MS));
+                }
+                OnError::Stop => {
+                    error!("worker {} failed, stopping: {}", self.id, error);
+                    return;
+                }
+                OnError::Abort => {
+                    error!("worker {} failed, aborting run: {}", self.id, error);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+
+    /// Runs closed-loop probes over the userspace stack until shutdown,
+    /// taking turns between the sockets so each carries its own flow
+    #[cfg(feature = "userspace")]
+    pub fn run_rips(&mut self, sockets: &mut [UdpSocket]) -> Result<(), String> {
+        let mut srcs = Vec::with_capacity(sockets.len());
+        for socket in sockets.iter_mut() {
+            // like a kernel socket's, so that a lost reply is counted rather
+            // than blocking the worker until shutdown
+            if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(RECV_TIMEOUT_MS))) {
+                return Err(format!("Unable to set receive timeout: {}", e));
+            }
+            match socket.local_addr() {
+                Ok(src) => srcs.push(src),
+                Err(e) => return Err(format!("Unable to get local address: {}", e)),
+            }
+        }
+        let mut buffer = vec![0; RECV_BUFFER];
+        while self.wait() {
+            let slot = self.probes.seq() as usize % sockets.len();
+            let socket = &mut sockets[slot];
+            let dst = self.control.target();
+            self.pin_flow(slot, "udp", srcs[slot], dst);
+            let request = self.protocol.request(&mut self.probes);
+            let sent = self.wall_time();
+            let t0 = self.clocksource.counter();
+            // send_to blocks on the shared stack lock, so this captures lock
+            // contention as well as the stack's own packet processing
+            let result = socket.send_to(&request, dst);
+            let t1 = self.clocksource.counter();
+            // recorded whatever becomes of the probe, as leaving out those
+            // which time out would favour the fast path through the stack
+            let _ = self.stats.send(Sample::new(t0, t1, Metric::Stack));
+            if !self.sent(result, t0, t1) {
+                continue;
+            }
+            let len = match socket.recv_from(&mut buffer) {
+                Ok((len, _)) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => {
+                    let t2 = self.clocksource.counter();
+                    let _ = self.stats.send(Sample::new(t0, t2, Metric::Timeout));
+                    continue;
+                }
+                Err(e) => return Err(format!("Unable to read from socket: {}", e)),
+            };
+            let t2 = self.clocksource.counter();
+            if !try!(self.received(len, Some(request.len()), t0, t2)) {
+                continue;
+            }
+            if !self.classified(&request, &buffer[..len], t0, t2) {
+                continue;
+            }
+            let _ = self.stats.send(Sample::new(t0, t2, Metric::Ok));
+            if self.stamped {
+                self.stamps(&buffer[..len], sent, t0, t2);
+            }
+            let seq = self.probes.seq();
+            self.complete(seq, sent, t0, t2);
+        }
+        Ok(())
+    }
+
+    /// Runs closed-loop probes over kernel sockets until shutdown, taking
+    /// turns between the sockets so each carries its own flow
+    pub fn run_stdnet(&mut self, sockets: &[std::net::UdpSocket]) -> Result<(), String> {
+        let mut srcs = Vec::with_capacity(sockets.len());
+        for socket in sockets {
+            let _ = socket.set_read_timeout(Some(Duration::from_millis(RECV_TIMEOUT_MS)));
+            try!(self.apply_options(socket));
+            if let Err(e) = errqueue::enable(socket) {
+                debug!("worker {} unable to enable IP_RECVERR: {}", self.id, e);
+            }
+            match socket.local_addr() {
+                Ok(src) => srcs.push(src),
+                Err(e) => return Err(format!("Unable to get local address: {}", e)),
+            }
+        }
+        let mut buffer = vec![0; RECV_BUFFER];
+        let sources = cmp::max(self.sources.len(), 1);
+        let slots = sockets.len() / sources;
+        while self.wait() {
+            let source = match self.source_mode {
+                SourceMode::Failover => self.source,
+                SourceMode::RoundRobin => self.probes.seq() as usize % sources,
+            };
+            let slot = self.probes.seq() as usize % slots;
+            // each source's sockets are flows of their own
+            let flow = source * slots + slot;
+            let socket = &sockets[flow];
+            let dst = self.control.target();
+            self.pin_flow(flow, "udp", srcs[flow], dst);
+            let request = self.protocol.request(&mut self.probes);
+            let sent = self.wall_time();
+            let t0 = self.clocksource.counter();
+            let result = socket.send_to(&request, dst);
+            if !self.sent(result, t0, self.clocksource.counter()) {
+                self.source_result(false);
+                continue;
+            }
+            if sources > 1 {
+                self.probe_source = Some(source as u32);
+                let mut distributions = self.distributions.lock().unwrap();
+                *distributions.source_probes.entry(source as u32).or_insert(0) += 1;
+            }
+            let len = match socket.recv_from(&mut buffer) {
+                Ok((len, _)) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => {
+                    let t1 = self.clocksource.counter();
+                    let _ = self.stats.send(Sample::new(t0, t1, Metric::Timeout));
+                    self.source_result(false);
+                    continue;
+                }
+                Err(e) => {
+                    // with IP_RECVERR, ICMP errors for our probes surface here
+                    // and the details wait in the error queue
+                    match errqueue::read(socket) {
+                        Some(icmp) => {
+                            let t1 = self.clocksource.counter();
+                            let _ = self.stats.send(Sample::new(t0, t1, icmp_metric(icmp)));
+                            self.source_result(false);
+                            continue;
+                        }
+                        None => return Err(format!("Unable to read from socket: {}", e)),
+                    }
+                }
+            };
+            let t1 = self.clocksource.counter();
+            if !try!(self.received(len, Some(request.len()), t0, t1)) {
+                continue;
+            }
+            if !self.classified(&request, &buffer[..len], t0, t1) {
+                continue;
+            }
+            self.observe_rx(socket);
+            let _ = self.stats.send(Sample::new(t0, t1, Metric::Ok));
+            if self.stamped {
+                self.stamps(&buffer[..len], sent, t0, t1);
+            }
+            let seq = self.probes.seq();
+            self.complete(seq, sent, t0, t1);
+            self.source_result(true);
+        }
+        Ok(())
+    }
+
+    // tracks consecutive failures of the source in use, failing over to the
+    // next once there are too many
+    fn source_result(&mut self, ok: bool) {
+        if ok || self.sources.len() < 2 {
+            self.source_failures = 0;
+            return;
+        }
+        self.source_failures += 1;
+        if self.source_mode != SourceMode::Failover || self.source_failures < self.failover_after {
+            return;
+        }
+        let next = (self.source + 1) % self.sources.len();
+        warn!("worker {} failing over from source {} to {} after {} failed probes",
+              self.id,
+              self.sources[self.source],
+              self.sources[next],
+              self.source_failures);
+        self.source = next;
+        self.source_failures = 0;
+    }
+
+    /// Sends bursts of `size` probes back-to-back over a kernel socket and
+    /// then collects the replies, recording the RTT of each position within
+    /// the burst so queue build-up and drain at the bottleneck can be seen.
+    /// Probes must be tagged, as replies are matched to positions by ID
+    pub fn run_burst(&mut self,
+                     socket: &std::net::UdpSocket,
+                     size: usize)
+                     -> Result<(), String> {
+        let _ = socket.set_read_timeout(Some(Duration::from_millis(RECV_TIMEOUT_MS)));
+        try!(self.apply_options(socket));
+        if let Err(e) = errqueue::enable(socket) {
+            debug!("worker {} unable to enable IP_RECVERR: {}", self.id, e);
+        }
+        let src = match socket.local_addr() {
+            Ok(src) => src,
+            Err(e) => return Err(format!("Unable to get local address: {}", e)),
+        };
+        if self.positions.len() < size {
+            self.positions.resize(size, Sketch::new());
+        }
+        let mut buffer = vec![0; RECV_BUFFER];
+        let mut pending = Vec::with_capacity(size);
+        while self.wait() {
+            let dst = self.control.target();
+            self.pin_flow(0, "udp", src, dst);
+
+            // send the whole burst before reading any replies
+            let first = self.probes.seq() + 1;
+            pending.clear();
+            for _ in 0..size {
+                let request = self.protocol.request(&mut self.probes);
+                let sent = self.wall_time();
+                let t0 = self.clocksource.counter();
+                let result = socket.send_to(&request, dst);
+                if self.sent(result, t0, self.clocksource.counter()) {
+                    pending.push(Some((sent, t0, request)));
+                } else {
+                    pending.push(None);
+                }
+            }
+
+            // the position within the burst of the probe `payload` answers
+            let position_of = |payload: &[u8]| {
+                probe::parse_id(payload)
+                    .and_then(probe::parse_seq)
+                    .and_then(|seq| seq.checked_sub(first))
+                    .and_then(|p| if p < size as u64 { Some(p as usize) } else { None })
+            };
+            let mut outstanding = pending.iter().filter(|p| p.is_some()).count();
+            while outstanding > 0 {
+                let len = match socket.recv_from(&mut buffer) {
+                    Ok((len, _)) => len,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                                  e.kind() == io::ErrorKind::TimedOut => {
+                        // the rest of the burst was lost
+                        let t1 = self.clocksource.counter();
+                        for &(_, t0, _) in pending.iter().filter_map(|p| p.as_ref()) {
+                            let _ = self.stats.send(Sample::new(t0, t1, Metric::Timeout));
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        match errqueue::read_probe(socket) {
+                            Some((icmp, payload)) => {
+                                // an error for a probe of an earlier burst was
+                                // already counted as a timeout
+                                if let Some((_, t0, _)) =
+                                       position_of(&payload).and_then(|p| pending[p].take()) {
+                                    let t1 = self.clocksource.counter();
+                                    let _ = self.stats
+                                        .send(Sample::new(t0, t1, icmp_metric(icmp)));
+                                    outstanding -= 1;
+                                }
+                                continue;
+                            }
+                            None => return Err(format!("Unable to read from socket: {}", e)),
+                        }
+                    }
+                };
+                let t1 = self.clocksource.counter();
+                let position = position_of(&buffer[..len]);
+                let (sent, t0, request) = match position.and_then(|p| pending[p].take()) {
+                    Some(p) => p,
+                    None => {
+                        // a late reply from an earlier burst, or a duplicate
+                        let _ = self.stats.send(Sample::new(t1, t1, Metric::Mismatch));
+                        continue;
+                    }
+                };
+                outstanding -= 1;
+                let position = position.unwrap();
+                if !try!(self.received(len, Some(request.len()), t0, t1)) {
+                    continue;
+                }
+                // e.g. an unsigned reply when running with --key
+                if !self.classified(&request, &buffer[..len], t0, t1) {
+                    continue;
+                }
+                let _ = self.stats.send(Sample::new(t0, t1, Metric::Ok));
+                let rtt = (self.clocksource.convert(t1) - self.clocksource.convert(t0)) as u64;
+                self.positions[position].insert(rtt);
+                self.complete(first + position as u64, sent, t0, t1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs request/response exchanges over a persistent TCP connection until
+    /// shutdown, reconnecting after timeouts or a change of target
+    pub fn run_tcp(&mut self, config: &TcpConfig) -> Result<(), String> {
+        while self.control.running() {
+            let dst = self.control.target();
+            let mut stream = match TcpStream::connect(dst) {
+                Ok(s) => s,
+                Err(e) => return Err(format!("Unable to connect to {}: {}", dst, e)),
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_millis(RECV_TIMEOUT_MS)));
+            try!(self.apply_options(&stream));
+            if let Err(e) = stream.set_nodelay(config.nodelay) {
+                return Err(format!("Unable to set TCP_NODELAY: {}", e));
+            }
+            if let Some(ref algorithm) = config.congestion {
+                if let Err(e) = sockopt::set_congestion(&stream, algorithm) {
+                    return Err(format!("Unable to set TCP_CONGESTION {}: {}", algorithm, e));
+                }
+            }
+            match stream.local_addr() {
+                Ok(src) => self.pin_flow(0, "tcp", src, dst),
+                Err(e) => return Err(format!("Unable to get local address: {}", e)),
+            }
+            try!(self.exchange(&mut stream, dst, config));
+        }
+        Ok(())
+    }
+
+    // returns Ok when the connection should be replaced
+    fn exchange(&mut self,
+                stream: &mut TcpStream,
+                dst: SocketAddr,
+                config: &TcpConfig)
+                -> Result<(), String> {
+        let mut buffer = vec![0; 1024*2];
+        while self.wait() {
+            if self.control.target() != dst {
+                return Ok(());
+            }
+            let request = match config.request {
+                Some(ref r) => {
+                    // still numbered, so that each completion has its own seq
+                    self.probes.next_request();
+                    r.clone()
+                }
+                None => self.protocol.request(&mut self.probes),
+            };
+            let sent = self.wall_time();
+            let t0 = self.clocksource.counter();
+            if let Err(e) = stream.write_all(&request) {
+                return Err(format!("Unable to write to socket: {}", e));
+            }
+            if config.quickack {
+                let _ = sockopt::set_quickack(&*stream);
+            }
+            let expect = config.expect.as_ref();
+            let len = match read_response(stream, &mut buffer, expect, &*self.protocol) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => {
+                    let t1 = self.clocksource.counter();
+                    let _ = self.stats.send(Sample::new(t0, t1, Metric::Timeout));
+                    // a late response would desynchronize the stream
+                    return Ok(());
+                }
+                Err(e) => return Err(format!("Unable to read from socket: {}", e)),
+            };
+            let t1 = self.clocksource.counter();
+            // responses are framed on the stream, so can't be truncated
+            try!(self.received(len, None, t0, t1));
+            self.observe_rx(&*stream);
+            let ok = match config.expect {
+                Some(ref expect) if &buffer[..len] == &expect[..] => true,
+                Some(_) => {
+                    let _ = self.stats.send(Sample::new(t0, t1, Metric::Mismatch));
+                    false
+                }
+                None => self.classified(&request, &buffer[..len], t0, t1),
+            };
+            if !ok {
+                continue;
+            }
+            let _ = self.stats.send(Sample::new(t0, t1, Metric::Ok));
+            let seq = self.probes.seq();
+            self.complete(seq, sent, t0, t1);
+        }
+        Ok(())
+    }
+
+    /// Records empty samples to measure the overhead of the stats pipeline
+    pub fn run_noop(&mut self) -> Result<(), String> {
+        while self.wait() {
+            let t0 = self.clocksource.counter();
+            let t1 = self.clocksource.counter();
+            let _ = self.stats.send(Sample::new(t0, t1, Metric::Ok));
+            self.completed += 1;
+        }
+        Ok(())
+    }
+
+    /// Generates samples for a simulated path with `impairment` instead of
+    /// probing. Lost probes are counted at once rather than after a timeout,
+    /// so loss doesn't slow the run down
+    pub fn run_simulate(&mut self, impairment: &Impairment) -> Result<(), String> {
+        let seed = impairment.seed.unwrap_or_else(time::precise_time_ns);
+        let mut simulator = Simulator::new(impairment.clone(), seed ^ ((self.id as u64) << 32));
+        while self.wait() {
+            self.probes.next_request();
+            let sent = self.wall_time();
+            let t0 = self.clocksource.counter();
+            let (rtt, metric) = match simulator.next() {
+                Fate::Lost => {
+                    let _ = self.stats.send(Sample::new(t0, t0, Metric::Timeout));
+                    continue;
+                }
+                Fate::Delivered(rtt) => (rtt, Metric::Ok),
+                Fate::Reordered(rtt) => (rtt, Metric::Mismatch),
+            };
+            let t1 = self.hold(t0, rtt);
+            let _ = self.stats.send(Sample::new(t0, t1, metric.clone()));
+            if metric != Metric::Ok {
+                continue;
+            }
+            let seq = self.probes.seq();
+            self.complete(seq, sent, t0, t1);
+        }
+        Ok(())
+    }
+
+    // waits until `rtt` ns after the counter read `t0`, returning the counter
+    // then, sleeping most of the way and spinning the rest for precision
+    fn hold(&self, t0: u64, rtt: u64) -> u64 {
+        if rtt > SPIN_NS {
+            sleep_ns(rtt - SPIN_NS);
+        }
+        let start = self.clocksource.convert(t0);
+        loop {
+            let t1 = self.clocksource.counter();
+            if self.clocksource.convert(t1) - start >= rtt as f64 {
+                return t1;
+            }
+        }
+    }
+
+    // applies the options configured for kernel sockets
+    fn apply_options<S: AsRawFd>(&self, socket: &S) -> Result<(), String> {
+        if let Some(priority) = self.priority {
+            if let Err(e) = sockopt::set_priority(socket, priority) {
+                return Err(format!("Unable to set SO_PRIORITY {}: {}", priority, e));
+            }
+        }
+        if self.router_alert {
+            if let Err(e) = sockopt::set_router_alert(socket) {
+                return Err(format!("Unable to set router alert: {}", e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the five-tuple this worker's probes use, logging and publishing
+    /// it whenever it changes
+    fn pin_flow(&mut self,
+                slot: usize,
+                protocol: &'static str,
+                src: SocketAddr,
+                dst: SocketAddr) {
+        let flow = Flow {
+            protocol: protocol,
+            src: src,
+            dst: dst,
+        };
+        if slot >= self.flows.len() {
+            self.flows.resize(slot + 1, None);
+        }
+        if self.flows[slot].as_ref() != Some(&flow) {
+            info!("worker {} flow {}: {}", self.id, slot, flow);
+            self.control.set_flow(self.id, slot, flow.clone());
+            self.flows[slot] = Some(flow);
+        }
+    }
+
+    /// Notes which queue and CPU handled the reply just received on `socket`,
+    /// as enabled. The key for the latency breakdown is kept for `complete`
+    fn observe_rx<S: AsRawFd>(&mut self, socket: &S) {
+        if self.rx_queues {
+            if let Ok(napi) = sockopt::incoming_napi_id(socket) {
+                let mut distributions = self.distributions.lock().unwrap();
+                *distributions.rx_queues.entry((self.id, napi)).or_insert(0) += 1;
+            }
+        }
+        self.rx = match self.by_rx {
+            Some(RxKey::Cpu) => sockopt::incoming_cpu(socket).ok(),
+            Some(RxKey::Queue) => sockopt::incoming_napi_id(socket).ok(),
+            None => None,
+        };
+    }
+
+    /// Blocks while probing is paused and until the next send time permitted
+    /// by the configured rate, returning false once the run is over. The
+    /// difference between the intended and actual send times is recorded in
+    /// the schedule distribution. While the link is down, the probes which
+    /// would have been sent are counted as 'link_down'
+    fn wait(&mut self) -> bool {
+        let mut accounted = None;
+        while self.control.running() && !(self.control.active() && self.control.link_up()) {
+            thread::sleep(Duration::from_millis(PAUSE_POLL_MS));
+            self.next_send = 0;
+            if !self.control.link_up() {
+                // unthrottled, the closed loop would have sent one probe per
+                // RTT, as best estimated by the SRTT
+                let rate = self.control.rate() as u64;
+                let interval = if rate > 0 { 1_000_000_000 / rate } else { self.srtt };
+                if interval > 0 {
+                    let now = time::precise_time_ns();
+                    let since = *accounted.get_or_insert(now);
+                    let missed = (now - since) / interval;
+                    let t = self.clocksource.counter();
+                    for _ in 0..missed {
+                        let _ = self.stats.send(Sample::new(t, t, Metric::LinkDown));
+                    }
+                    accounted = Some(since + missed * interval);
+                }
+            }
+        }
+        let rate = self.control.rate() as u64;
+        let mut interval = if rate > 0 { 1_000_000_000 / rate } else { 0 };
+        if let Some(floor) = self.adaptive {
+            interval = cmp::max(interval, cmp::max(self.srtt, floor));
+        }
+        if interval > 0 {
+            let now = time::precise_time_ns();
+            if self.next_send > now {
+                let deadline = self.next_send;
+                self.pause_until(deadline, now);
+            }
+            if self.next_send > 0 {
+                // how late this probe is relative to its intended send time
+                let error = time::precise_time_ns().saturating_sub(self.next_send);
+                self.distributions.lock().unwrap().schedule.insert(error);
+            }
+            self.next_send = cmp::max(self.next_send, now) + interval;
+        }
+        self.control.running()
+    }
+
+    // waits until `deadline` using the configured pacing strategy
+    fn pause_until(&mut self, deadline: u64, now: u64) {
+        match self.pacing {
+            Pacing::Sleep => sleep_ns(deadline - now),
+            Pacing::Spin => spin_until(deadline),
+            Pacing::Hybrid => {
+                if deadline - now > SPIN_NS {
+                    sleep_ns(deadline - now - SPIN_NS);
+                }
+                spin_until(deadline);
+            }
+            Pacing::Timerfd => {
+                if self.timer.is_none() {
+                    match Timer::new() {
+                        Ok(timer) => self.timer = Some(timer),
+                        Err(e) => {
+                            warn!("worker {} unable to create timerfd, sleeping instead: {}",
+                                  self.id,
+                                  e);
+                            self.pacing = Pacing::Sleep;
+                            sleep_ns(deadline - now);
+                            return;
+                        }
+                    }
+                }
+                if let Some(ref timer) = self.timer {
+                    if let Err(e) = timer.sleep_until(deadline) {
+                        debug!("worker {} timerfd wait failed: {}", self.id, e);
+                    }
+                }
+            }
+        }
+    }
+
+    // wall-clock time is only needed when probes are being logged
+    fn wall_time(&self) -> time::Timespec {
+        if self.probes.logging() || self.outliers.is_some() || self.slowest ||
+           self.time_of_day.is_some() || self.subsecond.is_some() || self.stamped {
+            time::get_time()
+        } else {
+            time::Timespec::new(0, 0)
+        }
+    }
+
+    /// Accounts for the result of a send, returning whether a reply should be
+    /// awaited. Persistent failures back off exponentially with jitter, so a
+    /// dead route doesn't spin the worker
+    fn sent(&mut self, result: io::Result<usize>, t0: u64, t1: u64) -> bool {
+        let error = match result {
+            Ok(_) => {
+                if self.send_failures >= BACKOFF_THRESHOLD {
+                    info!("worker {} sends recovered after {} failures",
+                          self.id,
+                          self.send_failures);
+                }
+                self.send_failures = 0;
+                self.sent += 1;
+                return true;
+            }
+            Err(e) => e,
+        };
+        let _ = self.stats.send(Sample::new(t0, t1, Metric::SendError));
+        self.send_failures += 1;
+        if self.send_failures < BACKOFF_THRESHOLD {
+            return false;
+        }
+        if self.send_failures == BACKOFF_THRESHOLD {
+            warn!("worker {} backing off after repeated send failures: {}", self.id, error);
+            let _ = self.stats.send(Sample::new(t0, t1, Metric::Backoff));
+        }
+        let exponent = cmp::min(self.send_failures - BACKOFF_THRESHOLD, 16);
+        let delay = cmp::min(BACKOFF_BASE_MS << exponent, BACKOFF_MAX_MS) * 1_000_000;
+        // xorshift, scaled to between half and all of the delay
+        self.jitter ^= self.jitter << 13;
+        self.jitter ^= self.jitter >> 7;
+        self.jitter ^= self.jitter << 17;
+        let delay = delay / 2 + self.jitter % (delay / 2 + 1);
+        thread::sleep(Duration::new(delay / 1_000_000_000, (delay % 1_000_000_000) as u32));
+        false
+    }
+
+    /// Records the size of a response, and whether it was the expected size.
+    /// Returns false if a datagram answering a request of `request` bytes is
+    /// shorter than expected: such responses are counted as truncated and
+    /// must not be timed as completed probes
+    fn received(&mut self,
+                len: usize,
+                request: Option<usize>,
+                t0: u64,
+                t1: u64)
+                -> Result<bool, String> {
+        self.distributions.lock().unwrap().sizes.insert(len as u64);
+        if let Some(width) = self.by_size {
+            self.reply_bucket = Some(len as u32 - len as u32 % width);
+        }
+        if let Some(expected) = self.expect_size {
+            if len != expected {
+                let _ = self.stats.send(Sample::new(t0, t1, Metric::SizeMismatch));
+            }
+        }
+        // an echo returns the request, and the reflector pads to reply_size
+        let expected = match request {
+            Some(request) => self.expect_size.or(self.probes.reply_size()).unwrap_or(request),
+            None => return Ok(true),
+        };
+        if len >= expected {
+            return Ok(true);
+        }
+        let _ = self.stats.send(Sample::new(t0, t1, Metric::Truncated));
+        if self.truncated_error {
+            return Err(format!("Truncated response: {} of {} bytes", len, expected));
+        }
+        Ok(false)
+    }
+
+    /// Classifies a response with the protocol, counting it as 'mismatch',
+    /// 'malformed' or 'auth_failure' unless it is a valid answer to `request`
+    fn classified(&mut self, request: &[u8], response: &[u8], t0: u64, t1: u64) -> bool {
+        let metric = match protocol::classify(&*self.protocol, request, response) {
+            Outcome::Ok => return true,
+            Outcome::Mismatch => Metric::Mismatch,
+            Outcome::Malformed => Metric::Malformed,
+            Outcome::Unauthenticated => Metric::AuthFailure,
+        };
+        let _ = self.stats.send(Sample::new(t0, t1, metric));
+        false
+    }
+
+    // records how much the one-way delays to and from the reflector, and the
+    // RTT, changed since the last timestamped reply. The delays are offset by
+    // the difference between the hosts' clocks, which cancels out
+    fn stamps(&mut self, response: &[u8], sent: time::Timespec, t0: u64, t1: u64) {
+        let (rx, tx) = match probe::parse_stamps(response) {
+            Some(stamps) => stamps,
+            None => {
+                self.delays = None;
+                return;
+            }
+        };
+        let sent = sent.sec * 1_000_000_000 + sent.nsec as i64;
+        let rtt = (self.clocksource.convert(t1) - self.clocksource.convert(t0)) as i64;
+        let delays = (rx as i64 - sent, sent + rtt - tx as i64, rtt);
+        if let Some(last) = self.delays {
+            let mut distributions = self.distributions.lock().unwrap();
+            distributions.forward_jitter.insert((delays.0 - last.0).abs() as u64);
+            distributions.return_jitter.insert((delays.1 - last.1).abs() as u64);
+            distributions.rtt_jitter.insert((delays.2 - last.2).abs() as u64);
+        }
+        self.delays = Some(delays);
+    }
+
+    /// Accounts for a probe which received its reply
+    fn complete(&mut self, seq: u64, sent: time::Timespec, t0: u64, t1: u64) {
+        self.completed += 1;
+        let calibration = self.control.calibration();
+        if calibration != self.calibration {
+            if let Some(clocksource) = self.control.clocksource() {
+                self.clocksource = clocksource;
+            }
+            self.calibration = calibration;
+        }
+        let end = self.clocksource.convert(t1);
+        // the send time on the clock tic's traces use, alongside wall-clock
+        // time so probes can be lined up with both traces and external logs
+        let start = self.clocksource.convert(t0) as u64;
+        let rtt = end as u64 - start;
+
+        let second = ((end as u64).saturating_sub(self.start) / 1_000_000_000) as usize;
+        if second >= self.maxima_from + analysis::MAX_SECONDS {
+            let from = second + 1 - analysis::MAX_SECONDS;
+            let expired = cmp::min(from - self.maxima_from, self.maxima.len());
+            self.maxima.drain(..expired);
+            self.maxima_from = from;
+        }
+        if second >= self.maxima_from {
+            let i = second - self.maxima_from;
+            if i >= self.maxima.len() {
+                self.maxima.resize(i + 1, 0);
+            }
+            self.maxima[i] = cmp::max(self.maxima[i], rtt);
+        }
+        if let Some(ref sliding) = self.sliding {
+            sliding.lock().unwrap().insert(end as u64 / 1_000_000_000, rtt);
+        }
+
+        // the same smoothing as TCP's SRTT, with a gain of 1/8
+        self.srtt = if self.srtt == 0 {
+            rtt
+        } else {
+            (self.srtt * 7 + rtt) / 8
+        };
+        self.probes.record(seq, sent, start, rtt);
+        if let Some(minutes) = self.time_of_day {
+            if self.bin.0 != sent.sec {
+                let tm = time::at(sent);
+                let minute = tm.tm_hour as u32 * 60 + tm.tm_min as u32;
+                self.bin = (sent.sec, minute - minute % minutes);
+            }
+        }
+        {
+            let mut distributions = self.distributions.lock().unwrap();
+            distributions.latency.insert(rtt);
+            if let Some(rx) = self.rx.take() {
+                distributions.by_rx.entry(rx).or_insert_with(Sketch::new).insert(rtt);
+            }
+            if let Some(source) = self.probe_source.take() {
+                distributions.by_source.entry(source).or_insert_with(Sketch::new).insert(rtt);
+            }
+            if let Some(bucket) = self.reply_bucket.take() {
+                distributions.by_size.entry(bucket).or_insert_with(Sketch::new).insert(rtt);
+            }
+            if self.time_of_day.is_some() {
+                let bin = distributions.time_of_day.entry(self.bin.1);
+                bin.or_insert_with(Sketch::new).insert(rtt);
+            }
+            if let Some(ms) = self.subsecond {
+                let offset = sent.nsec as u32 / 1_000_000;
+                let bucket = distributions.subsecond.entry(offset - offset % ms);
+                bucket.or_insert_with(Sketch::new).insert(rtt);
+            }
+            if self.slowest && distributions.slowest.accepts(rtt) {
+                distributions.slowest.insert(Slow {
+                    worker: self.id,
+                    seq: seq,
+                    sent: sent.sec as u64 * 1_000_000_000 + sent.nsec as u64,
+                    monotonic: start,
+                    rtt: rtt,
+                });
+            }
+        }
+        if let Some(ref mut outliers) = self.outliers {
+            if outliers.observe(seq, &self.probes.id_of(seq), sent, start, rtt) {
+                let _ = self.stats.send(Sample::new(t0, t1, Metric::Outlier));
+            }
+        }
+    }
+}
+
+// reads a single response: exactly as long as the expected response if there
+// is one, otherwise up to the first newline
+fn read_response(stream: &mut TcpStream,
+                 buffer: &mut Vec<u8>,
+                 expect: Option<&Vec<u8>>,
+                 protocol: &Probe)
+                 -> io::Result<usize> {
+    let mut len = 0;
+    loop {
+        if let Some(expect) = expect {
+            if len >= expect.len() {
+                return Ok(len);
+            }
+        } else if protocol.framed(&buffer[..len]) {
+            return Ok(len);
+        }
+        if len == buffer.len() {
+            let grow = buffer.len();
+            buffer.resize(len + grow, 0);
+        }
+        let want = match expect {
+            Some(expect) => cmp::min(expect.len(), buffer.len()),
+            None => buffer.len(),
+        };
+        match try!(stream.read(&mut buffer[len..want])) {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+            n => len += n,
+        }
+    }
+}
+
+fn icmp_metric(error: IcmpError) -> Metric {
+    match error {
+        IcmpError::PortUnreachable => Metric::PortUnreachable,
+        IcmpError::HostUnreachable => Metric::HostUnreachable,
+        IcmpError::TtlExceeded => Metric::TtlExceeded,
+        IcmpError::Other => Metric::IcmpOther,
+    }
+}
+
+fn panic_message(payload: Box<Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        format!("panicked: {}", s)
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        format!("panicked: {}", s)
+    } else {
+        "panicked".to_owned()
+    }
+}
+
+fn sleep_ns(delay: u64) {
+    thread::sleep(Duration::new(delay / 1_000_000_000, (delay % 1_000_000_000) as u32));
+}
+
+fn spin_until(deadline: u64) {
+    while time::precise_time_ns() < deadline {}
+}
@@ -0,0 +1,308 @@
+//! Comparison of the latency distributions of two saved runs
+
+use std::f64;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use output;
+use units;
+
+/// Loads the RTTs of a run from a probe log. If `path` itself doesn't exist
+/// the per-thread logs `<path>.<thread>` written by `--probe-log` are read
+pub fn load(path: &str) -> Result<Vec<u64>, String> {
+    load_timed(path).map(|probes| probes.into_iter().map(|(_, rtt)| rtt).collect())
+}
+
+/// Loads the unix time in ns each probe of a run was sent at and its RTT,
+/// in the order of the logs, as `load` does
+pub fn load_timed(path: &str) -> Result<Vec<(u64, u64)>, String> {
+    let files = if Path::new(path).exists() {
+        vec![path.to_owned()]
+    } else {
+        (0..)
+            .map(|i| output::suffixed(path, &format!("{}", i)))
+            .take_while(|p| Path::new(p).exists())
+            .collect()
+    };
+    if files.is_empty() {
+        return Err(format!("No probe log found at {}", path));
+    }
+
+    let mut probes = Vec::new();
+    for file in &files {
+        let input = match output::open(file) {
+            Ok(input) => input,
+            Err(e) => return Err(format!("Unable to open {}. {}", file, e)),
+        };
+        for (number, line) in BufReader::new(input).lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Err(format!("Unable to read {}. {}", file, e)),
+            };
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // <id> <unix time ns> [<monotonic ns>] <rtt ns>, as older logs
+            // have no monotonic time
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let probe = if fields.len() >= 3 {
+                match (fields[1].parse(), fields[fields.len() - 1].parse()) {
+                    (Ok(sent), Ok(rtt)) => Some((sent, rtt)),
+                    _ => None,
+                }
+            } else {
+                None
+            };
+            match probe {
+                Some(probe) => probes.push(probe),
+                None => return Err(format!("Malformed line {} in {}", number + 1, file)),
+            }
+        }
+    }
+    if probes.is_empty() {
+        return Err(format!("No probes recorded in {}", path));
+    }
+    Ok(probes)
+}
+
+/// Result of a Mann-Whitney U test of whether values from `a` tend to be
+/// larger or smaller than values from `b`
+pub struct MannWhitney {
+    pub u: f64,
+    pub z: f64,
+    pub p: f64,
+    /// Probability that a value from `a` exceeds one from `b`, counting ties
+    /// as half
+    pub superiority: f64,
+}
+
+/// Mann-Whitney U test with the normal approximation and tie correction.
+/// Both inputs must be sorted
+pub fn mann_whitney(a: &[u64], b: &[u64]) -> MannWhitney {
+    let (n1, n2) = (a.len() as f64, b.len() as f64);
+    let n = n1 + n2;
+
+    // walk both samples in order, giving tied values their average rank
+    let mut rank_sum = 0.0;
+    let mut ties = 0.0;
+    let (mut i, mut j) = (0, 0);
+    let mut rank = 1.0;
+    while i < a.len() || j < b.len() {
+        let value = match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) => if x < y { *x } else { *y },
+            (Some(x), None) => *x,
+            (None, Some(y)) => *y,
+            (None, None) => unreachable!(),
+        };
+        let mut in_a = 0.0;
+        while i < a.len() && a[i] == value {
+            in_a += 1.0;
+            i += 1;
+        }
+        let mut in_b = 0.0;
+        while j < b.len() && b[j] == value {
+            in_b += 1.0;
+            j += 1;
+        }
+        let t = in_a + in_b;
+        rank_sum += in_a * (rank + (t - 1.0) / 2.0);
+        ties += t * t * t - t;
+        rank += t;
+    }
+
+    let u = rank_sum - n1 * (n1 + 1.0) / 2.0;
+    let mean = n1 * n2 / 2.0;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    let z = if variance > 0.0 {
+        (u - mean) / variance.sqrt()
+    } else {
+        0.0
+    };
+    MannWhitney {
+        u: u,
+        z: z,
+        p: erfc(z.abs() / f64::consts::SQRT_2),
+        superiority: u / (n1 * n2),
+    }
+}
+
+/// Two-sample Kolmogorov-Smirnov test, returning the largest distance
+/// between the empirical CDFs and its asymptotic p-value. Both inputs must be
+/// sorted
+pub fn kolmogorov_smirnov(a: &[u64], b: &[u64]) -> (f64, f64) {
+    let (n1, n2) = (a.len() as f64, b.len() as f64);
+    let mut d: f64 = 0.0;
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let value = if a[i] < b[j] { a[i] } else { b[j] };
+        while i < a.len() && a[i] == value {
+            i += 1;
+        }
+        while j < b.len() && b[j] == value {
+            j += 1;
+        }
+        d = d.max((i as f64 / n1 - j as f64 / n2).abs());
+    }
+
+    let ne = (n1 * n2 / (n1 + n2)).sqrt();
+    let lambda = (ne + 0.12 + 0.11 / ne) * d;
+    (d, kolmogorov(lambda))
+}
+
+/// Survival function of the Kolmogorov distribution
+fn kolmogorov(lambda: f64) -> f64 {
+    if lambda < 0.2 {
+        return 1.0;
+    }
+    let mut sum = 0.0;
+    let mut sign = 1.0;
+    for k in 1..101 {
+        let term = sign * (-2.0 * (k * k) as f64 * lambda * lambda).exp();
+        sum += term;
+        if term.abs() < 1e-12 {
+            break;
+        }
+        sign = -sign;
+    }
+    (2.0 * sum).max(0.0).min(1.0)
+}
+
+/// Complementary error function, accurate to about 1e-7
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t *
+            (-z * z - 1.26551223 +
+             t * (1.00002368 +
+                  t * (0.37409196 +
+                       t * (0.09678418 +
+                            t * (-0.18628806 +
+                                 t * (0.27886807 +
+                                      t * (-1.13520398 +
+                                           t * (1.48851587 +
+                                                t * (-0.82215223 + t * 0.17087277)))))))))
+                .exp();
+    if x >= 0.0 { r } else { 2.0 - r }
+}
+
+/// Value at percentile `p` of a sorted sample
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let rank = ((p / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank]
+}
+
+/// Loads two runs and logs whether their latency distributions differ
+/// significantly at level `alpha`, along with effect sizes
+pub fn run(path_a: &str, path_b: &str, alpha: f64) -> Result<(), String> {
+    let mut a = try!(load(path_a));
+    let mut b = try!(load(path_b));
+    a.sort();
+    b.sort();
+
+    info!("A: {} probes from {}", a.len(), path_a);
+    info!("B: {} probes from {}", b.len(), path_b);
+    for &(name, p) in &[("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p999", 99.9)] {
+        let (x, y) = (percentile(&a, p), percentile(&b, p));
+        info!("{}: A: {} B: {} difference: {} ({:+.1}%)",
+              name,
+              units::latency(x),
+              units::latency(y),
+              units::difference(y as i64 - x as i64),
+              100.0 * (y as f64 - x as f64) / x.max(1) as f64);
+    }
+
+    let mw = mann_whitney(&a, &b);
+    // Cliff's delta, positive when B tends to be slower than A
+    let delta = 1.0 - 2.0 * mw.superiority;
+    info!("mann-whitney: U: {:.0} z: {:.3} p: {:.3e} P(A > B): {:.3} cliff's delta: {:+.3}",
+          mw.u,
+          mw.z,
+          mw.p,
+          mw.superiority,
+          delta);
+
+    let (d, p) = kolmogorov_smirnov(&a, &b);
+    info!("kolmogorov-smirnov: D: {:.4} p: {:.3e}", d, p);
+
+    if mw.p < alpha || p < alpha {
+        info!("verdict: distributions differ significantly (alpha: {}); B is {} ({} effect)",
+              alpha,
+              if delta > 0.0 { "slower" } else { "faster" },
+              magnitude(delta));
+    } else {
+        info!("verdict: no significant difference (alpha: {})", alpha);
+    }
+    Ok(())
+}
+
+/// Conventional labels for the magnitude of Cliff's delta
+fn magnitude(delta: f64) -> &'static str {
+    let delta = delta.abs();
+    if delta < 0.147 {
+        "negligible"
+    } else if delta < 0.33 {
+        "small"
+    } else if delta < 0.474 {
+        "medium"
+    } else {
+        "large"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_samples_do_not_differ() {
+        let a: Vec<u64> = (1..51).collect();
+        let mw = mann_whitney(&a, &a);
+        assert_eq!(mw.u, 1250.0);
+        assert!(mw.z.abs() < 1e-9);
+        assert!((mw.p - 1.0).abs() < 1e-6);
+        assert_eq!(mw.superiority, 0.5);
+
+        let (d, p) = kolmogorov_smirnov(&a, &a);
+        assert_eq!(d, 0.0);
+        assert_eq!(p, 1.0);
+    }
+
+    #[test]
+    fn disjoint_samples_differ() {
+        let a: Vec<u64> = (1..51).collect();
+        let b: Vec<u64> = (101..151).collect();
+        let mw = mann_whitney(&a, &b);
+        assert_eq!(mw.u, 0.0);
+        assert_eq!(mw.superiority, 0.0);
+        assert!(mw.z < 0.0);
+        assert!(mw.p < 1e-6);
+        assert_eq!(magnitude(1.0 - 2.0 * mw.superiority), "large");
+
+        let (d, p) = kolmogorov_smirnov(&a, &b);
+        assert_eq!(d, 1.0);
+        assert!(p < 1e-6);
+    }
+
+    #[test]
+    fn erfc_matches_known_values() {
+        assert!((erfc(0.0) - 1.0).abs() < 1e-6);
+        assert!((erfc(1.0) - 0.157299207).abs() < 1e-6);
+        assert!((erfc(-1.0) - 1.842700793).abs() < 1e-6);
+    }
+
+    #[test]
+    fn percentiles_round_to_the_nearest_rank() {
+        let sorted: Vec<u64> = (0..11).collect();
+        assert_eq!(percentile(&sorted, 0.0), 0);
+        assert_eq!(percentile(&sorted, 50.0), 5);
+        assert_eq!(percentile(&sorted, 99.0), 10);
+    }
+
+    #[test]
+    fn effect_sizes_have_conventional_labels() {
+        assert_eq!(magnitude(0.1), "negligible");
+        assert_eq!(magnitude(-0.2), "small");
+        assert_eq!(magnitude(0.4), "medium");
+        assert_eq!(magnitude(-0.9), "large");
+    }
+}
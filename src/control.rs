@@ -0,0 +1,640 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use tic::{Clocksource, Meters, Percentile};
+
+use Metric;
+use metadata::Metadata;
+use sketch::{Sketch, Slow};
+
+/// The five-tuple used by one of a worker's flows
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Flow {
+    pub protocol: &'static str,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+impl fmt::Display for Flow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} -> {}", self.protocol, self.src, self.dst)
+    }
+}
+
+/// A target probed in turn with the others, one per window, with latency
+/// series of its own from when it was added
+struct Target {
+    addr: SocketAddr,
+    // false once removed, its series being kept
+    probing: bool,
+    windows: usize,
+    window: Sketch,
+    cumulative: Sketch,
+}
+
+impl Target {
+    fn new(addr: SocketAddr) -> Target {
+        Target {
+            addr: addr,
+            probing: true,
+            windows: 0,
+            window: Sketch::new(),
+            cumulative: Sketch::new(),
+        }
+    }
+}
+
+/// Run state shared between the main loop, the workers and the control
+/// endpoint
+pub struct Control {
+    running: AtomicBool,
+    active: AtomicBool,
+    link_up: AtomicBool,
+    shutdown: AtomicBool,
+    target: RwLock<SocketAddr>,
+    targets: Mutex<Vec<Target>>,
+    rate: AtomicUsize,
+    summary: Mutex<String>,
+    sliding: Mutex<String>,
+    slowest: Mutex<String>,
+    percentiles: Mutex<String>,
+    by_size: Mutex<String>,
+    flows: Mutex<BTreeMap<(usize, usize), Flow>>,
+    calibration: AtomicUsize,
+    clocksource: Mutex<Option<Clocksource>>,
+    metadata: Metadata,
+}
+
+impl Control {
+    pub fn new(target: SocketAddr, rate: usize, metadata: Metadata) -> Control {
+        Control {
+            running: AtomicBool::new(true),
+            active: AtomicBool::new(true),
+            link_up: AtomicBool::new(true),
+            shutdown: AtomicBool::new(false),
+            target: RwLock::new(target),
+            targets: Mutex::new(vec![Target::new(target)]),
+            rate: AtomicUsize::new(rate),
+            summary: Mutex::new("{}".to_owned()),
+            sliding: Mutex::new("{}".to_owned()),
+            slowest: Mutex::new("[]".to_owned()),
+            percentiles: Mutex::new("{}".to_owned()),
+            by_size: Mutex::new("{}".to_owned()),
+            flows: Mutex::new(BTreeMap::new()),
+            calibration: AtomicUsize::new(0),
+            clocksource: Mutex::new(None),
+            metadata: metadata,
+        }
+    }
+
+    /// False once the run is over and workers should exit
+    pub fn running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether workers should currently be sending probes
+    pub fn active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn set_active(&self, active: bool) {
+        self.active.store(active, Ordering::Relaxed);
+    }
+
+    /// Whether the probing interface is up, as far as is known
+    pub fn link_up(&self) -> bool {
+        self.link_up.load(Ordering::Relaxed)
+    }
+
+    pub fn set_link_up(&self, up: bool) {
+        self.link_up.store(up, Ordering::Relaxed);
+    }
+
+    /// Whether the run should end after the current window
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        *self.target.read().unwrap()
+    }
+
+    pub fn set_target(&self, target: SocketAddr) {
+        *self.target.write().unwrap() = target;
+    }
+
+    /// Adds `addr` to the targets probed in turn, returning false if it is
+    /// already one of them. A target removed before keeps its series
+    pub fn add_target(&self, addr: SocketAddr) -> bool {
+        let mut targets = self.targets.lock().unwrap();
+        if let Some(target) = targets.iter_mut().find(|t| t.addr == addr) {
+            let added = !target.probing;
+            target.probing = true;
+            return added;
+        }
+        targets.push(Target::new(addr));
+        true
+    }
+
+    /// Stops probing `addr` from the next window, unless it is the only
+    /// target left
+    pub fn remove_target(&self, addr: SocketAddr) -> Result<(), &'static str> {
+        let mut targets = self.targets.lock().unwrap();
+        if targets.iter().filter(|t| t.probing).count() < 2 {
+            return Err("the last target can't be removed");
+        }
+        match targets.iter_mut().find(|t| t.addr == addr && t.probing) {
+            Some(target) => {
+                target.probing = false;
+                Ok(())
+            }
+            None => Err("not a target"),
+        }
+    }
+
+    /// Probes `addr` in place of the current target, from now on
+    pub fn replace_target(&self, addr: SocketAddr) {
+        let current = self.target();
+        {
+            let mut targets = self.targets.lock().unwrap();
+            for target in targets.iter_mut().filter(|t| t.addr == current) {
+                target.probing = false;
+            }
+        }
+        self.add_target(addr);
+        self.set_target(addr);
+    }
+
+    /// Switches to the target of window `window`, counting from 0, among
+    /// those probed in turn. Returns it when there are several or it changed
+    pub fn rotate(&self, window: usize) -> Option<SocketAddr> {
+        let probing: Vec<SocketAddr> = self.targets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| t.probing)
+            .map(|t| t.addr)
+            .collect();
+        let target = probing[window % probing.len()];
+        if probing.len() < 2 && target == self.target() {
+            return None;
+        }
+        self.set_target(target);
+        Some(target)
+    }
+
+    /// Adds the latency of a window which probed `addr` to its series
+    pub fn record_target(&self, addr: SocketAddr, latency: &Sketch) {
+        let mut targets = self.targets.lock().unwrap();
+        if let Some(target) = targets.iter_mut().find(|t| t.addr == addr) {
+            target.windows += 1;
+            target.window = latency.clone();
+            target.cumulative.merge(latency);
+        }
+    }
+
+    /// Every target probed during the run, with the number of windows it was
+    /// probed in and its latency over them
+    pub fn target_series(&self) -> Vec<(SocketAddr, usize, Sketch)> {
+        self.targets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|t| (t.addr, t.windows, t.cumulative.clone()))
+            .collect()
+    }
+
+    fn targets_json(&self) -> String {
+        let targets: Vec<String> = self.targets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|t| {
+                format!("{{\"addr\":\"{}\",\"probing\":{},\"windows\":{},\"window\":{},\
+                         \"cumulative\":{}}}",
+                        t.addr,
+                        t.probing,
+                        t.windows,
+                        percentiles_json(&t.window),
+                        percentiles_json(&t.cumulative))
+            })
+            .collect();
+        format!("{{\"targets\":[{}]}}", targets.join(","))
+    }
+
+    /// Per-thread probe rate, zero meaning unthrottled
+    pub fn rate(&self) -> usize {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: usize) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Publishes the summary of the most recent window, which ran from
+    /// `start` to `end`, each given as (unix time, monotonic time) in ns
+    pub fn set_summary(&self,
+                       window: usize,
+                       start: (u64, u64),
+                       end: (u64, u64),
+                       rate: f64,
+                       meters: &Meters<Metric>) {
+        let p = |name: &str, value: f64| {
+            *meters.get_percentile(&Metric::Ok, Percentile(name.to_owned(), value)).unwrap_or(&0)
+        };
+        *self.summary.lock().unwrap() =
+            format!("{{\"window\":{},\"start\":{},\"start_monotonic\":{},\"end\":{},\
+                     \"end_monotonic\":{},\"rate\":{:.3},\"count\":{},\"p50\":{},\"p90\":{},\
+                     \"p99\":{},\"p999\":{},\"p9999\":{}}}",
+                    window,
+                    start.0,
+                    start.1,
+                    end.0,
+                    end.1,
+                    rate,
+                    meters.get_count(&Metric::Ok).unwrap_or(&0),
+                    p("p50", 50.0),
+                    p("p90", 90.0),
+                    p("p99", 99.0),
+                    p("p999", 99.9),
+                    p("p9999", 99.99));
+    }
+
+    /// Publishes percentiles over the sliding window
+    pub fn set_sliding(&self, window: usize, sketch: &Sketch) {
+        *self.sliding.lock().unwrap() =
+            format!("{{\"seconds\":{},\"count\":{},\"p50\":{},\"p90\":{},\"p99\":{},\
+                     \"p999\":{}}}",
+                    window,
+                    sketch.count(),
+                    sketch.percentile(50.0),
+                    sketch.percentile(90.0),
+                    sketch.percentile(99.0),
+                    sketch.percentile(99.9));
+    }
+
+    /// Publishes latency percentiles of the most recent window and of the
+    /// run so far as separate series, as alerting on cumulative values alone
+    /// hides regressions late in a long run
+    pub fn set_percentiles(&self, window: &Sketch, cumulative: &Sketch) {
+        *self.percentiles.lock().unwrap() = format!("{{\"window\":{},\"cumulative\":{}}}",
+                                                    percentiles_json(window),
+                                                    percentiles_json(cumulative));
+    }
+
+    /// Publishes latency by reply size of the most recent window and of the
+    /// run so far, each bucket keyed by the size it starts at
+    pub fn set_by_size(&self, window: &BTreeMap<u32, Sketch>, run: &BTreeMap<u32, Sketch>) {
+        let buckets = |sketches: &BTreeMap<u32, Sketch>| {
+            let buckets: Vec<String> = sketches.iter()
+                .map(|(bytes, sketch)| format!("\"{}\":{}", bytes, percentiles_json(sketch)))
+                .collect();
+            buckets.join(",")
+        };
+        *self.by_size.lock().unwrap() = format!("{{\"window\":{{{}}},\"cumulative\":{{{}}}}}",
+                                                buckets(window),
+                                                buckets(run));
+    }
+
+    /// Publishes the slowest probes of the most recent window
+    pub fn set_slowest(&self, probes: &[Slow]) {
+        let probes: Vec<String> = probes.iter()
+            .map(|p| {
+                format!("{{\"worker\":{},\"seq\":{},\"sent\":{},\"monotonic\":{},\
+                         \"rtt\":{}}}",
+                        p.worker,
+                        p.seq,
+                        p.sent,
+                        p.monotonic,
+                        p.rtt)
+            })
+            .collect();
+        *self.slowest.lock().unwrap() = format!("[{}]", probes.join(","));
+    }
+
+    /// Publishes a recalibrated clocksource for workers to switch to
+    pub fn set_clocksource(&self, clocksource: Clocksource) {
+        *self.clocksource.lock().unwrap() = Some(clocksource);
+        self.calibration.fetch_add(1, Ordering::Release);
+    }
+
+    /// Counts recalibrations, so workers can cheaply tell when to switch
+    pub fn calibration(&self) -> usize {
+        self.calibration.load(Ordering::Acquire)
+    }
+
+    /// The most recently recalibrated clocksource, if any
+    pub fn clocksource(&self) -> Option<Clocksource> {
+        self.clocksource.lock().unwrap().clone()
+    }
+
+    /// Records the five-tuple of flow `slot` of a worker
+    pub fn set_flow(&self, worker: usize, slot: usize, flow: Flow) {
+        self.flows.lock().unwrap().insert((worker, slot), flow);
+    }
+
+    /// Current five-tuple of each flow as `(worker, slot, flow)`, in worker
+    /// order
+    pub fn flows(&self) -> Vec<(usize, usize, Flow)> {
+        self.flows.lock().unwrap().iter().map(|(&(w, s), f)| (w, s, f.clone())).collect()
+    }
+
+    fn flows_json(&self) -> String {
+        let flows: Vec<String> = self.flows()
+            .iter()
+            .map(|&(worker, slot, ref flow)| {
+                format!("{{\"worker\":{},\"slot\":{},\"protocol\":\"{}\",\"src\":\"{}\",\
+                         \"dst\":\"{}\"}}",
+                        worker,
+                        slot,
+                        flow.protocol,
+                        flow.src,
+                        flow.dst)
+            })
+            .collect();
+        format!("[{}]", flows.join(","))
+    }
+
+    fn status(&self) -> String {
+        format!("{{\"active\":{},\"link_up\":{},\"target\":\"{}\",\"rate\":{}}}",
+                self.active(),
+                self.link_up(),
+                self.target(),
+                self.rate())
+    }
+}
+
+/// How long a stalled control client may hold a connection, in ms
+const TIMEOUT_MS: u64 = 1000;
+
+/// Serves the JSON-over-HTTP control API on `listen`
+///
+/// * `GET /meta` - version, command line, config hash, start time and host
+/// * `GET /status` - current target, rate and whether probing is active
+/// * `GET /summary` - rate and latency percentiles of the last window
+/// * `GET /sliding` - latency percentiles over the sliding window, if enabled
+/// * `GET /percentiles` - latency percentiles of the last window and the
+///   whole run
+/// * `GET /slowest` - the slowest probes of the last window, if enabled
+/// * `GET /flows` - the five-tuple of each flow
+/// * `GET /sizes` - latency percentiles by reply size of the last window and
+///   the whole run, if `--by-size` is given
+/// * `GET /targets` - the targets probed in turn, and those removed, with
+///   latency percentiles of each one's last window and of all its windows
+/// * `POST /start`, `POST /stop` - resume or pause probing
+/// * `POST /target?addr=<ip>:<port>` - change the probe target
+/// * `POST /targets/add?addr=<ip>:<port>` - add a target to probe in turn
+/// * `POST /targets/remove?addr=<ip>:<port>` - stop probing a target
+/// * `POST /rate?value=<n>` - change the per-thread rate, 0 for unthrottled
+/// * `POST /shutdown` - end the run after the current window
+pub fn listen(listen: SocketAddr, control: Arc<Control>) -> Result<(), String> {
+    let listener = match TcpListener::bind(listen) {
+        Ok(l) => l,
+        Err(e) => return Err(format!("Unable to bind control endpoint {}. {}", listen, e)),
+    };
+    info!("control endpoint listening on {}", listen);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => handle(stream, &control),
+                Err(e) => debug!("control connection failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The controls of the jobs run in one process, served on one endpoint
+#[derive(Default)]
+pub struct Registry {
+    jobs: Mutex<BTreeMap<String, Arc<Control>>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Serves `control` under `/jobs/<name>`
+    pub fn add(&self, name: &str, control: Arc<Control>) {
+        self.jobs.lock().unwrap().insert(name.to_owned(), control);
+    }
+
+    fn get(&self, name: &str) -> Option<Arc<Control>> {
+        self.jobs.lock().unwrap().get(name).cloned()
+    }
+
+    fn names_json(&self) -> String {
+        let names: Vec<String> =
+            self.jobs.lock().unwrap().keys().map(|name| format!("\"{}\"", name)).collect();
+        format!("{{\"jobs\":[{}]}}", names.join(","))
+    }
+}
+
+/// Serves the control API of every job in `registry` on `listen`: `GET
+/// /jobs` lists their names, and each job's API is under `/jobs/<name>`, e.g.
+/// `GET /jobs/east/summary`. Jobs are served from when they start
+pub fn listen_jobs(listen: SocketAddr, registry: Arc<Registry>) -> Result<(), String> {
+    let listener = match TcpListener::bind(listen) {
+        Ok(l) => l,
+        Err(e) => return Err(format!("Unable to bind control endpoint {}. {}", listen, e)),
+    };
+    info!("control endpoint for all jobs listening on {}", listen);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    if let Some((method, path, query)) = read_request(&mut stream) {
+                        let (status, body) = route_job(&registry, &method, &path, &query);
+                        respond(&mut stream, status, &body);
+                    }
+                }
+                Err(e) => debug!("control connection failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn route_job(registry: &Registry, method: &str, path: &str, query: &str) -> (u16, String) {
+    if path == "/jobs" || path == "/jobs/" {
+        return (200, registry.names_json());
+    }
+    if !path.starts_with("/jobs/") {
+        return (404, error("not found"));
+    }
+    let rest = &path["/jobs/".len()..];
+    let (name, endpoint) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    match registry.get(name) {
+        Some(control) => route(&control, method, endpoint, query),
+        None => (404, error("no such job")),
+    }
+}
+
+// the method, path and query string of a request, once its request line has
+// arrived, which may take more than one read
+fn read_request(stream: &mut TcpStream) -> Option<(String, String, String)> {
+    let timeout = Some(Duration::from_millis(TIMEOUT_MS));
+    if stream.set_read_timeout(timeout).is_err() || stream.set_write_timeout(timeout).is_err() {
+        return None;
+    }
+    let mut buffer = [0; 4096];
+    let mut len = 0;
+    while !buffer[..len].contains(&b'\n') {
+        if len == buffer.len() {
+            return None;
+        }
+        match stream.read(&mut buffer[len..]) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => len += n,
+        }
+    }
+    let request = String::from_utf8_lossy(&buffer[..len]).into_owned();
+    let mut line = request.lines().next().unwrap_or("").split(' ');
+    let method = line.next().unwrap_or("").to_owned();
+    let mut uri = line.next().unwrap_or("").splitn(2, '?');
+    let path = uri.next().unwrap_or("").to_owned();
+    let query = uri.next().unwrap_or("").to_owned();
+    Some((method, path, query))
+}
+
+fn handle(mut stream: TcpStream, control: &Control) {
+    if let Some((method, path, query)) = read_request(&mut stream) {
+        let (status, body) = route(control, &method, &path, &query);
+        respond(&mut stream, status, &body);
+    }
+}
+
+fn route(control: &Control, method: &str, path: &str, query: &str) -> (u16, String) {
+    match (method, path) {
+        ("GET", "/meta") => (200, control.metadata.to_json()),
+        ("GET", "/status") => (200, control.status()),
+        ("GET", "/summary") => (200, control.summary.lock().unwrap().clone()),
+        ("GET", "/sliding") => (200, control.sliding.lock().unwrap().clone()),
+        ("GET", "/percentiles") => (200, control.percentiles.lock().unwrap().clone()),
+        ("GET", "/sizes") => (200, control.by_size.lock().unwrap().clone()),
+        ("GET", "/slowest") => (200, control.slowest.lock().unwrap().clone()),
+        ("GET", "/flows") => (200, control.flows_json()),
+        ("GET", "/targets") => (200, control.targets_json()),
+        ("POST", "/start") => {
+            control.set_active(true);
+            (200, control.status())
+        }
+        ("POST", "/stop") => {
+            control.set_active(false);
+            (200, control.status())
+        }
+        ("POST", "/target") => {
+            match target(query) {
+                Some(target) => {
+                    info!("control: target changed to {}", target);
+                    control.replace_target(target);
+                    (200, control.status())
+                }
+                None => (400, error("expected addr=<ipv4>:<port>")),
+            }
+        }
+        ("POST", "/targets/add") => {
+            match target(query) {
+                Some(target) => {
+                    if control.add_target(target) {
+                        info!("control: target {} added", target);
+                    }
+                    (200, control.targets_json())
+                }
+                None => (400, error("expected addr=<ipv4>:<port>")),
+            }
+        }
+        ("POST", "/targets/remove") => {
+            match target(query) {
+                Some(target) => {
+                    match control.remove_target(target) {
+                        Ok(()) => {
+                            info!("control: target {} removed", target);
+                            (200, control.targets_json())
+                        }
+                        Err(e) => (400, error(e)),
+                    }
+                }
+                None => (400, error("expected addr=<ipv4>:<port>")),
+            }
+        }
+        ("POST", "/rate") => {
+            match param(query, "value").and_then(|v| usize::from_str(v).ok()) {
+                Some(rate) => {
+                    info!("control: rate changed to {}", rate);
+                    control.set_rate(rate);
+                    (200, control.status())
+                }
+                None => (400, error("expected value=<probes per second>")),
+            }
+        }
+        ("POST", "/shutdown") => {
+            control.shutdown.store(true, Ordering::Relaxed);
+            (200, control.status())
+        }
+        _ => (404, error("not found")),
+    }
+}
+
+fn percentiles_json(sketch: &Sketch) -> String {
+    format!("{{\"count\":{},\"p50\":{},\"p90\":{},\"p99\":{},\"p999\":{},\"p9999\":{}}}",
+            sketch.count(),
+            sketch.percentile(50.0),
+            sketch.percentile(90.0),
+            sketch.percentile(99.0),
+            sketch.percentile(99.9),
+            sketch.percentile(99.99))
+}
+
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    for pair in query.split('&') {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next() == Some(name) {
+            return kv.next();
+        }
+    }
+    None
+}
+
+// the `addr` of a request changing targets. Targets are IPv4 only, as on the
+// command line, since probes go out of IPv4 sockets and options like
+// `--router-alert` have no IPv6 equivalent here
+fn target(query: &str) -> Option<SocketAddr> {
+    match param(query, "addr").and_then(|v| SocketAddr::from_str(v).ok()) {
+        Some(addr) if addr.is_ipv4() => Some(addr),
+        _ => None,
+    }
+}
+
+fn error(message: &str) -> String {
+    format!("{{\"error\":\"{}\"}}", message)
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &str) {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        _ => "Not Found",
+    };
+    let _ = write!(stream,
+                   "HTTP/1.0 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                   status,
+                   reason,
+                   body.len(),
+                   body);
+}
@@ -0,0 +1,200 @@
+//! Recurrence of scheduled jobs in the five fields of a crontab, `<minute>
+//! <hour> <day of month> <month> <day of week>`, in local time. Each field
+//! is `*` or a list of values and ranges, e.g. `1-5`, with an optional step,
+//! e.g. `*/15`. As in cron, a day matches if either day field does when both
+//! are restricted
+
+extern crate time;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// When a scheduled job runs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    spec: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    // whether each day field is `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Minutes searched for the next run, a leap year and a day
+const HORIZON_MINUTES: i64 = 367 * 24 * 60;
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    /// Parses the five fields, or one of `@hourly`, `@daily` and `@weekly`
+    fn from_str(s: &str) -> Result<Schedule, String> {
+        let spec = match s {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            _ => s,
+        };
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("invalid schedule: {}. Expected <minute> <hour> <day of month> \
+                                <month> <day of week>",
+                               s));
+        }
+        let mut weekdays = try!(field(fields[4], 0, 7));
+        // Sunday is both 0 and 7
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+        Ok(Schedule {
+            spec: s.to_owned(),
+            minutes: try!(field(fields[0], 0, 59)),
+            hours: try!(field(fields[1], 0, 23)),
+            days: try!(field(fields[2], 1, 31)),
+            months: try!(field(fields[3], 1, 12)),
+            weekdays: weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.spec)
+    }
+}
+
+impl Schedule {
+    fn matches(&self, tm: &time::Tm) -> bool {
+        let day = self.days[tm.tm_mday as usize];
+        let weekday = self.weekdays[tm.tm_wday as usize];
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        self.minutes[tm.tm_min as usize] && self.hours[tm.tm_hour as usize] &&
+        self.months[tm.tm_mon as usize + 1] && day
+    }
+
+    /// The start of the first minute after `after` at which the job runs, or
+    /// None if no date within a year matches, e.g. for February 30th
+    pub fn next(&self, after: time::Timespec) -> Option<time::Timespec> {
+        // the start of the next minute
+        let mut minute = time::Timespec::new(after.sec - after.sec % 60 + 60, 0);
+        for _ in 0..HORIZON_MINUTES {
+            if self.matches(&time::at(minute)) {
+                return Some(minute);
+            }
+            minute.sec += 60;
+        }
+        None
+    }
+}
+
+// which of `min..=max` a field selects, indexed by value
+fn field(spec: &str, min: usize, max: usize) -> Result<Vec<bool>, String> {
+    let mut selected = vec![false; max + 1];
+    for part in spec.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => {
+                match part[i + 1..].parse::<usize>() {
+                    Ok(step) if step > 0 => (&part[..i], Some(step)),
+                    _ => return Err(format!("invalid step in {}", part)),
+                }
+            }
+            None => (part, None),
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else {
+            let mut bounds = range.splitn(2, '-');
+            let first = try!(value(bounds.next().unwrap_or(""), min, max));
+            let last = match bounds.next() {
+                Some(last) => try!(value(last, min, max)),
+                // a single value with a step runs from it to the end
+                None if step.is_some() => max,
+                None => first,
+            };
+            if last < first {
+                return Err(format!("invalid range {}", range));
+            }
+            (first, last)
+        };
+        let mut v = first;
+        while v <= last {
+            selected[v] = true;
+            v += step.unwrap_or(1);
+        }
+    }
+    Ok(selected)
+}
+
+fn value(s: &str, min: usize, max: usize) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(v) if v >= min && v <= max => Ok(v),
+        _ => Err(format!("invalid value {}. Must be {} to {}", s, min, max)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a time on `day` of `month` (from 1) which falls on `weekday` (Sunday
+    // being 0), as `time::at` would give it
+    fn tm(min: i32, hour: i32, day: i32, month: i32, weekday: i32) -> time::Tm {
+        let mut tm = time::empty_tm();
+        tm.tm_min = min;
+        tm.tm_hour = hour;
+        tm.tm_mday = day;
+        tm.tm_mon = month - 1;
+        tm.tm_wday = weekday;
+        tm
+    }
+
+    #[test]
+    fn a_step_on_a_single_value_runs_to_the_end_of_the_field() {
+        let schedule: Schedule = "5/20 * * * *".parse().unwrap();
+        let minutes: Vec<i32> = (0..60).filter(|&m| schedule.matches(&tm(m, 0, 1, 1, 0))).collect();
+        assert_eq!(minutes, vec![5, 25, 45]);
+        let schedule: Schedule = "58/1 * * * *".parse().unwrap();
+        let minutes: Vec<i32> = (0..60).filter(|&m| schedule.matches(&tm(m, 0, 1, 1, 0))).collect();
+        assert_eq!(minutes, vec![58, 59]);
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn sunday_is_both_0_and_7() {
+        let schedule: Schedule = "0 0 * * 7".parse().unwrap();
+        assert!(schedule.matches(&tm(0, 0, 7, 6, 0)));
+        assert!(!schedule.matches(&tm(0, 0, 8, 6, 1)));
+        assert_eq!(schedule.weekdays, "0 0 * * 0".parse::<Schedule>().unwrap().weekdays);
+    }
+
+    #[test]
+    fn either_day_field_matches_when_both_are_restricted() {
+        // the 1st of the month, and every Monday
+        let schedule: Schedule = "0 0 1 * 1".parse().unwrap();
+        assert!(schedule.matches(&tm(0, 0, 1, 3, 4)));
+        assert!(schedule.matches(&tm(0, 0, 9, 3, 1)));
+        assert!(!schedule.matches(&tm(0, 0, 10, 3, 2)));
+        // with one of them `*`, only the other restricts the day
+        let schedule: Schedule = "0 0 * * 1".parse().unwrap();
+        assert!(!schedule.matches(&tm(0, 0, 1, 3, 4)));
+        assert!(schedule.matches(&tm(0, 0, 9, 3, 1)));
+    }
+
+    #[test]
+    fn a_date_which_never_comes_has_no_next_run() {
+        let schedule: Schedule = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(schedule.next(time::get_time()), None);
+        let schedule: Schedule = "@hourly".parse().unwrap();
+        let now = time::get_time();
+        let next = schedule.next(now).unwrap();
+        assert!(next.sec > now.sec && next.sec - now.sec <= 60 * 60);
+    }
+}
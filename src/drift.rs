@@ -0,0 +1,80 @@
+//! Cross-checks of the clocksource against CLOCK_MONOTONIC during a run. The
+//! clocksource scales the TSC by a frequency estimated at startup, and on
+//! some hardware the error in the estimate adds up to visible skew over hours
+
+extern crate time;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tic::Clocksource;
+
+use control::Control;
+
+/// How often the clocksource is compared to CLOCK_MONOTONIC
+const CHECK_INTERVAL_S: u64 = 10;
+
+/// A recalibration, made once the clocksource had drifted too far
+#[derive(Clone, Copy, Debug)]
+pub struct Recalibration {
+    /// Wall-clock time, in ns since the unix epoch
+    pub unix: u64,
+    /// CLOCK_MONOTONIC, in ns
+    pub monotonic: u64,
+    /// How far ahead of CLOCK_MONOTONIC the clocksource was, in ns
+    pub offset: i64,
+    /// The rate at which it drifted since it was last calibrated
+    pub ppm: f64,
+}
+
+/// Compares `clocksource` to CLOCK_MONOTONIC every few seconds. Once they
+/// differ by more than `threshold` ns, it is recalibrated and workers pick up
+/// the new calibration through `control`. Recalibrations are returned as
+/// they happen, for the main loop to annotate the window they fell in
+pub fn monitor(clocksource: Clocksource,
+               threshold: u64,
+               control: Arc<Control>)
+               -> Arc<Mutex<Vec<Recalibration>>> {
+    let recalibrations = Arc::new(Mutex::new(Vec::new()));
+    let events = recalibrations.clone();
+    thread::spawn(move || {
+        let mut clocksource = clocksource;
+        let mut calibrated = time::precise_time_ns();
+        while control.running() {
+            thread::sleep(Duration::from_secs(CHECK_INTERVAL_S));
+            let (offset, monotonic) = measure(&clocksource);
+            let ppm = offset as f64 * 1_000_000.0 / (monotonic - calibrated) as f64;
+            debug!("clock drift: offset: {} ns ({:.3} ppm)", offset, ppm);
+            if offset.abs() as u64 <= threshold {
+                continue;
+            }
+            warn!("clock drift: clocksource is {} us {} CLOCK_MONOTONIC ({:.3} ppm), \
+                   recalibrating",
+                  offset.abs() / 1000,
+                  if offset > 0 { "ahead of" } else { "behind" },
+                  ppm);
+            clocksource = Clocksource::new();
+            calibrated = time::precise_time_ns();
+            control.set_clocksource(clocksource.clone());
+            let now = time::get_time();
+            events.lock().unwrap().push(Recalibration {
+                unix: now.sec as u64 * 1_000_000_000 + now.nsec as u64,
+                monotonic: monotonic,
+                offset: offset,
+                ppm: ppm,
+            });
+        }
+    });
+    recalibrations
+}
+
+// how far the clocksource is ahead of CLOCK_MONOTONIC, read between two
+// readings of the latter to halve the error of reading both
+fn measure(clocksource: &Clocksource) -> (i64, u64) {
+    let before = time::precise_time_ns();
+    let source = clocksource.convert(clocksource.counter()) as u64;
+    let after = time::precise_time_ns();
+    let monotonic = before + (after - before) / 2;
+    (source as i64 - monotonic as i64, monotonic)
+}
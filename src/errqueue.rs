@@ -0,0 +1,156 @@
+//! Classification of ICMP errors reported for probes sent on kernel sockets,
+//! using the socket error queue enabled by `IP_RECVERR`
+
+use std::io;
+use std::net::UdpSocket;
+
+/// An ICMP error associated with a probe
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpError {
+    PortUnreachable,
+    HostUnreachable,
+    TtlExceeded,
+    Other,
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    extern crate libc;
+
+    use std::io;
+    use std::mem;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    use super::IcmpError;
+
+    const SOL_IP: libc::c_int = 0;
+    const IP_RECVERR: libc::c_int = 11;
+    const MSG_ERRQUEUE: libc::c_int = 0x2000;
+    const SO_EE_ORIGIN_ICMP: u8 = 2;
+
+    const ICMP_DEST_UNREACH: u8 = 3;
+    const ICMP_PORT_UNREACH: u8 = 3;
+    const ICMP_TIME_EXCEEDED: u8 = 11;
+
+    // struct sock_extended_err from linux/errqueue.h
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct SockExtendedErr {
+        ee_errno: u32,
+        ee_origin: u8,
+        ee_type: u8,
+        ee_code: u8,
+        ee_pad: u8,
+        ee_info: u32,
+        ee_data: u32,
+    }
+
+    pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(socket.as_raw_fd(),
+                             SOL_IP,
+                             IP_RECVERR,
+                             &on as *const _ as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn align(len: usize) -> usize {
+        let word = mem::size_of::<usize>();
+        (len + word - 1) & !(word - 1)
+    }
+
+    pub fn read(socket: &UdpSocket) -> Option<(IcmpError, Vec<u8>)> {
+        let mut data = [0u8; 512];
+        // u64 storage keeps the control messages suitably aligned
+        let mut control = [0u64; 64];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let ret = unsafe {
+            libc::recvmsg(socket.as_raw_fd(), &mut msg, MSG_ERRQUEUE | libc::MSG_DONTWAIT)
+        };
+        if ret < 0 {
+            return None;
+        }
+        // the start of the datagram the error was reported for
+        let payload = data[..ret as usize].to_vec();
+
+        // walk the control messages for the IP_RECVERR payload
+        let header = mem::size_of::<libc::cmsghdr>();
+        let end = msg.msg_controllen as usize;
+        let base = control.as_ptr() as *const u8;
+        let mut offset = 0;
+        while offset + header <= end {
+            let cmsg = unsafe { &*(base.offset(offset as isize) as *const libc::cmsghdr) };
+            let len = cmsg.cmsg_len as usize;
+            if len < header || offset + len > end {
+                break;
+            }
+            if cmsg.cmsg_level == SOL_IP && cmsg.cmsg_type == IP_RECVERR &&
+               len >= align(header) + mem::size_of::<SockExtendedErr>() {
+                let err = unsafe {
+                    &*(base.offset((offset + align(header)) as isize) as *const SockExtendedErr)
+                };
+                if err.ee_origin != SO_EE_ORIGIN_ICMP {
+                    return Some((IcmpError::Other, payload));
+                }
+                let error = match (err.ee_type, err.ee_code) {
+                    (ICMP_DEST_UNREACH, ICMP_PORT_UNREACH) => IcmpError::PortUnreachable,
+                    (ICMP_DEST_UNREACH, _) => IcmpError::HostUnreachable,
+                    (ICMP_TIME_EXCEEDED, _) => IcmpError::TtlExceeded,
+                    _ => IcmpError::Other,
+                };
+                return Some((error, payload));
+            }
+            offset += align(len);
+        }
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+    use std::net::UdpSocket;
+
+    use super::IcmpError;
+
+    pub fn enable(_: &UdpSocket) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn read(_: &UdpSocket) -> Option<(IcmpError, Vec<u8>)> {
+        None
+    }
+}
+
+/// Asks the kernel to queue ICMP errors for this socket. A no-op on
+/// platforms without `IP_RECVERR`
+pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+    imp::enable(socket)
+}
+
+/// Reads and classifies the oldest queued ICMP error, if any
+pub fn read(socket: &UdpSocket) -> Option<IcmpError> {
+    imp::read(socket).map(|(error, _)| error)
+}
+
+/// Like `read`, with the start of the probe the error was reported for
+pub fn read_probe(socket: &UdpSocket) -> Option<(IcmpError, Vec<u8>)> {
+    imp::read(socket)
+}
@@ -0,0 +1,121 @@
+//! Addresses and routes of local interfaces, used to configure the userspace
+//! stack when `--ip` or `--gateway` aren't given
+
+extern crate libc;
+
+use std::ffi::CStr;
+use std::io;
+use std::net::Ipv4Addr;
+use std::ptr;
+
+use ipnetwork::Ipv4Network;
+
+/// The IPv4 networks configured on interface `name`, with their prefixes
+pub fn ipv4_networks(name: &str) -> io::Result<Vec<Ipv4Network>> {
+    let mut addrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut networks = Vec::new();
+    let mut cursor = addrs;
+    while !cursor.is_null() {
+        let ifa = unsafe { &*cursor };
+        cursor = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() || ifa.ifa_netmask.is_null() {
+            continue;
+        }
+        if unsafe { CStr::from_ptr(ifa.ifa_name) }.to_bytes() != name.as_bytes() {
+            continue;
+        }
+        if unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int != libc::AF_INET {
+            continue;
+        }
+        let (ip, mask) = unsafe {
+            (ipv4(ifa.ifa_addr as *const libc::sockaddr_in),
+             ipv4(ifa.ifa_netmask as *const libc::sockaddr_in))
+        };
+        let prefix = u32::from(mask).count_ones() as u8;
+        if let Ok(network) = Ipv4Network::new(ip, prefix) {
+            networks.push(network);
+        }
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    Ok(networks)
+}
+
+unsafe fn ipv4(addr: *const libc::sockaddr_in) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from_be((*addr).sin_addr.s_addr))
+}
+
+/// The gateway of the default route, if it goes out of interface `name`
+pub fn default_gateway(name: &str) -> Option<Ipv4Addr> {
+    imp::default_gateway(name)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::fs::File;
+    use std::io::Read;
+    use std::net::Ipv4Addr;
+
+    // /proc/net/route lists one route per line as `Iface Destination Gateway
+    // Flags ...`, with addresses in hex as the kernel holds them in memory
+    pub fn default_gateway(name: &str) -> Option<Ipv4Addr> {
+        let mut routes = String::new();
+        if File::open("/proc/net/route").and_then(|mut f| f.read_to_string(&mut routes)).is_err() {
+            return None;
+        }
+        for line in routes.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 || fields[0] != name || fields[1] != "00000000" {
+                continue;
+            }
+            if let Ok(gateway) = u32::from_str_radix(fields[2], 16) {
+                if gateway != 0 {
+                    return Some(Ipv4Addr::from(u32::from_be(gateway)));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+mod imp {
+    use std::net::Ipv4Addr;
+    use std::process::Command;
+    use std::str::FromStr;
+
+    // `route -n get default` prints the route as `key: value` lines,
+    // including `gateway` and `interface`
+    pub fn default_gateway(name: &str) -> Option<Ipv4Addr> {
+        let output = match Command::new("route").args(&["-n", "get", "default"]).output() {
+            Ok(output) => output,
+            Err(_) => return None,
+        };
+        let output = String::from_utf8_lossy(&output.stdout);
+        let mut gateway = None;
+        let mut interface = None;
+        for line in output.lines() {
+            let mut kv = line.splitn(2, ':');
+            match (kv.next().map(|k| k.trim()), kv.next().map(|v| v.trim())) {
+                (Some("gateway"), Some(v)) => gateway = Ipv4Addr::from_str(v).ok(),
+                (Some("interface"), Some(v)) => interface = Some(v.to_owned()),
+                _ => {}
+            }
+        }
+        match interface {
+            Some(ref interface) if interface == name => gateway,
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+mod imp {
+    use std::net::Ipv4Addr;
+
+    pub fn default_gateway(_: &str) -> Option<Ipv4Addr> {
+        None
+    }
+}
@@ -0,0 +1,104 @@
+//! Several independent measurement jobs in one process, read from a file
+//! with one job per line: a name followed by the arguments a run of its own
+//! would take, e.g.
+//!
+//! ```text
+//! # name interface target flags
+//! east eth0 10.0.1.1:12221 --stdnet --rate 100
+//! west eth0 10.0.2.1:12221 --stdnet --protocol dns:example.com
+//! ```
+//!
+//! Arguments are split on whitespace, without quoting. Blank lines and those
+//! starting with `#` are ignored
+//!
+//! A job runs once, unless a schedule follows its name: `@hourly`, `@daily`,
+//! `@weekly` or `@cron` and the five fields of a crontab. It then runs at
+//! every time the schedule matches, e.g. a 5 minute sweep every hour and an
+//! MTU probe nightly:
+//!
+//! ```text
+//! sweep @cron 0 * * * * eth0 10.0.1.1:12221 --stdnet --windows 5 --duration 60
+//! mtu @daily eth0 10.0.1.1:12221 --stdnet --mtu-probe 9000 --windows 1
+//! ```
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use cron::Schedule;
+
+/// One line of a jobs file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Job {
+    /// Names the directory the job's files are written to
+    pub name: String,
+    /// Position in the file, from 0
+    pub index: usize,
+    pub args: Vec<String>,
+    /// When the job recurs, if it does
+    pub schedule: Option<Schedule>,
+    /// Where the job's files are written: its name, or for a run of a
+    /// scheduled job a directory within it
+    pub dir: String,
+}
+
+/// Reads the jobs in `path`, whose names must be unique and usable as
+/// directory names
+pub fn load(path: &str) -> Result<Vec<Job>, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(format!("Unable to open jobs file {}. {}", path, e)),
+    };
+    let mut jobs = Vec::new();
+    let mut names = BTreeSet::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Err(format!("Unable to read jobs file {}. {}", path, e)),
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace().map(|w| w.to_owned()).peekable();
+        let name = words.next().unwrap();
+        if name.starts_with('.') ||
+           !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.') {
+            return Err(format!("{}:{}: invalid job name {}. Use letters, digits, '-', '_' and \
+                                '.'",
+                               path,
+                               number + 1,
+                               name));
+        }
+        if !names.insert(name.clone()) {
+            return Err(format!("{}:{}: job {} is defined twice", path, number + 1, name));
+        }
+        let schedule = match words.peek().map(|w| w.clone()) {
+            Some(ref word) if word.starts_with('@') => {
+                words.next();
+                let spec = if word == "@cron" {
+                    let fields: Vec<String> = words.by_ref().take(5).collect();
+                    fields.join(" ")
+                } else {
+                    word.clone()
+                };
+                match spec.parse() {
+                    Ok(schedule) => Some(schedule),
+                    Err(e) => return Err(format!("{}:{}: {}", path, number + 1, e)),
+                }
+            }
+            _ => None,
+        };
+        jobs.push(Job {
+            dir: name.clone(),
+            name: name,
+            index: jobs.len(),
+            args: words.collect(),
+            schedule: schedule,
+        });
+    }
+    if jobs.is_empty() {
+        return Err(format!("No jobs in {}", path));
+    }
+    Ok(jobs)
+}
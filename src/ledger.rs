@@ -0,0 +1,255 @@
+//! Sequence numbers a reflector received from each client thread, so that at
+//! the end of a run the client can tell probes lost on the way to the
+//! reflector from replies lost on the way back
+//!
+//! Probe IDs are `<run>-<thread>-<seq>`, and the reflector keeps a tally of
+//! each `<run>-<thread>` stream. The client asks for one with `LEDGER ping-rs
+//! <stream>`, padded like a hello so that the answer, `TALLY ping-rs <stream>
+//! <received> <highest> <duplicates>`, can't amplify traffic
+
+extern crate time;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// Bytes a query is padded to, as long as a hello
+pub const QUERY_LEN: usize = 128;
+
+/// Sequence numbers below the highest seen within which duplicates are told
+/// apart from late arrivals. Older ones are counted as received
+const WINDOW: u64 = 1024;
+
+/// Streams tallied at once, bounding what spoofed probe IDs can cost
+const MAX_STREAMS: usize = 4096;
+
+/// How long a stream may be silent before its tally is forgotten, long enough
+/// for a client to ask for it after stopping
+const IDLE_NS: u64 = 600_000_000_000;
+
+const QUERY: &'static str = "LEDGER ping-rs";
+const TALLY: &'static str = "TALLY ping-rs";
+
+/// What the reflector received of one stream
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tally {
+    /// Distinct probes received
+    pub received: u64,
+    /// The highest sequence number received
+    pub highest: u64,
+    /// Probes received more than once
+    pub duplicates: u64,
+}
+
+// the tally of a stream, with which of the last WINDOW sequence numbers were
+// received, by sequence number modulo WINDOW
+struct Stream {
+    tally: Tally,
+    window: [u64; WINDOW as usize / 64],
+    last: u64,
+}
+
+impl Stream {
+    fn new() -> Stream {
+        Stream {
+            tally: Tally::default(),
+            window: [0; WINDOW as usize / 64],
+            last: 0,
+        }
+    }
+
+    fn bit(seq: u64) -> (usize, u64) {
+        let i = seq % WINDOW;
+        ((i / 64) as usize, 1 << (i % 64))
+    }
+
+    fn observe(&mut self, seq: u64, now: u64) {
+        self.last = now;
+        let highest = self.tally.highest;
+        if seq > highest {
+            // forget the sequence numbers the window moves past
+            if seq - highest >= WINDOW {
+                self.window = [0; WINDOW as usize / 64];
+            } else {
+                for s in highest + 1..seq + 1 {
+                    let (word, bit) = Stream::bit(s);
+                    self.window[word] &= !bit;
+                }
+            }
+            self.tally.highest = seq;
+        } else if highest - seq >= WINDOW {
+            self.tally.received += 1;
+            return;
+        }
+        let (word, bit) = Stream::bit(seq);
+        if self.window[word] & bit != 0 {
+            self.tally.duplicates += 1;
+        } else {
+            self.window[word] |= bit;
+            self.tally.received += 1;
+        }
+    }
+}
+
+/// The reflector's tallies of the streams it received probes of
+pub struct Ledger {
+    streams: HashMap<String, Stream>,
+    pruned: u64,
+    full: bool,
+}
+
+impl Default for Ledger {
+    fn default() -> Ledger {
+        Ledger::new()
+    }
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger {
+            streams: HashMap::new(),
+            pruned: time::precise_time_ns(),
+            full: false,
+        }
+    }
+
+    /// Counts the probe with ID `id`. IDs which don't end in a sequence
+    /// number are ignored
+    pub fn observe(&mut self, id: &str) {
+        let (stream, seq) = match id.rfind('-') {
+            Some(i) => {
+                match id[i + 1..].parse::<u64>() {
+                    Ok(seq) if seq > 0 => (&id[..i], seq),
+                    _ => return,
+                }
+            }
+            None => return,
+        };
+        let now = time::precise_time_ns();
+        if now - self.pruned > IDLE_NS {
+            self.streams.retain(|_, stream| now - stream.last < IDLE_NS);
+            self.pruned = now;
+            self.full = false;
+        }
+        if !self.streams.contains_key(stream) {
+            if self.streams.len() >= MAX_STREAMS {
+                if !self.full {
+                    warn!("reflector: tallying {} streams, ignoring new ones", MAX_STREAMS);
+                    self.full = true;
+                }
+                return;
+            }
+            self.streams.insert(stream.to_owned(), Stream::new());
+        }
+        self.streams.get_mut(stream).unwrap().observe(seq, now);
+    }
+
+    /// The answer to a query, or None if it is too short for it. A stream
+    /// the reflector has no tally of received nothing
+    pub fn answer(&self, received: &[u8]) -> Option<Vec<u8>> {
+        let stream = match parse(received, QUERY) {
+            Some(words) => words.first().map_or(String::new(), |s| s.clone()),
+            None => return None,
+        };
+        let tally = self.streams.get(&stream).map_or(Tally::default(), |s| s.tally);
+        let answer = format!("{} {} {} {} {}\r\n",
+                             TALLY,
+                             stream,
+                             tally.received,
+                             tally.highest,
+                             tally.duplicates);
+        if answer.len() > received.len() {
+            None
+        } else {
+            Some(answer.into_bytes())
+        }
+    }
+}
+
+/// Whether `received` asks for a tally
+pub fn is_query(received: &[u8]) -> bool {
+    received.starts_with(QUERY.as_bytes())
+}
+
+/// The request for the tally of `stream`
+pub fn query_for(stream: &str) -> Vec<u8> {
+    let mut query = format!("{} {} ", QUERY, stream);
+    while query.len() < QUERY_LEN - 2 {
+        query.push('.');
+    }
+    query.push_str("\r\n");
+    query.into_bytes()
+}
+
+// the words after `prefix`, less the filler, if `received` starts with it
+fn parse(received: &[u8], prefix: &str) -> Option<Vec<String>> {
+    let line = match ::std::str::from_utf8(received) {
+        Ok(line) => line.trim(),
+        Err(_) => return None,
+    };
+    if !line.starts_with(prefix) {
+        return None;
+    }
+    Some(line[prefix.len()..]
+        .split_whitespace()
+        .filter(|w| !w.bytes().all(|b| b == b'.'))
+        .map(|w| w.to_owned())
+        .collect())
+}
+
+/// The tally of `stream` in `reply`, if it is one
+pub fn parse_tally(reply: &[u8], stream: &str) -> Option<Tally> {
+    let words = match parse(reply, TALLY) {
+        Some(words) => words,
+        None => return None,
+    };
+    if words.len() != 4 || words[0] != stream {
+        return None;
+    }
+    match (words[1].parse(), words[2].parse(), words[3].parse()) {
+        (Ok(received), Ok(highest), Ok(duplicates)) => {
+            Some(Tally {
+                received: received,
+                highest: highest,
+                duplicates: duplicates,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Asks the reflector at `target` for its tally of `stream` over UDP, sending
+/// up to `attempts` queries each waiting `timeout` for the answer. None means
+/// the reflector doesn't keep a ledger, or didn't answer
+pub fn query(target: SocketAddr,
+             stream: &str,
+             timeout: Duration,
+             attempts: usize)
+             -> io::Result<Option<Tally>> {
+    let local = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = try!(UdpSocket::bind(local));
+    try!(socket.set_read_timeout(Some(timeout)));
+    let query = query_for(stream);
+    let mut buffer = [0; 1024];
+    for _ in 0..attempts {
+        try!(socket.send_to(&query, target));
+        loop {
+            match socket.recv_from(&mut buffer) {
+                Ok((len, peer)) if peer == target => {
+                    match parse_tally(&buffer[..len], stream) {
+                        Some(tally) => return Ok(Some(tally)),
+                        // an echo of the query, as from a plain echo service
+                        None if is_query(&buffer[..len]) => return Ok(None),
+                        None => continue,
+                    }
+                }
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(None)
+}
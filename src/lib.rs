@@ -0,0 +1,20 @@
+//! The parts of ping-rs which are useful on their own: the probe format and
+//! its signing, the protocols probes speak, output files, and the reflector
+//! which answers them, the handshake through which it advertises its
+//! features and the ledger of probes it received. `EchoServer` runs the
+//! reflector in-process, e.g. as the target of integration tests
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate lazy_static;
+
+pub mod auth;
+pub mod capability;
+pub mod ledger;
+pub mod output;
+pub mod probe;
+pub mod protocol;
+pub mod server;
+
+pub use server::EchoServer;
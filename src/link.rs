@@ -0,0 +1,266 @@
+//! Link state changes of the probing interface, e.g. a flap or a VPN
+//! reconnecting, during which workers pause rather than dying or silently
+//! receiving nothing
+
+extern crate time;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use control::Control;
+
+/// The interface going down or coming back up
+#[derive(Clone, Copy, Debug)]
+pub struct LinkEvent {
+    /// Wall-clock time, in ns since the unix epoch
+    pub unix: u64,
+    /// CLOCK_MONOTONIC, in ns
+    pub monotonic: u64,
+    pub up: bool,
+}
+
+/// Follows the link state of `iface` through netlink, pausing workers through
+/// `control` while it is down. Changes are returned as they happen, for the
+/// main loop to report against the window they fell in
+pub fn watch(iface: &str, control: Arc<Control>) -> Result<Arc<Mutex<Vec<LinkEvent>>>, String> {
+    let mut socket = try!(imp::Socket::open());
+    let mut up = imp::is_up(iface);
+    control.set_link_up(up);
+    if !up {
+        warn!("link: {} is down, probing is paused until it comes up", iface);
+    }
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let iface = iface.to_owned();
+    thread::spawn(move || {
+        while control.running() {
+            let state = match socket.next(&iface) {
+                Ok(Some(state)) => state,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("link: unable to read netlink: {}", e);
+                    return;
+                }
+            };
+            if state == up {
+                continue;
+            }
+            up = state;
+            control.set_link_up(up);
+            if up {
+                info!("link: {} is up, resuming probing", iface);
+            } else {
+                warn!("link: {} is down, pausing probing", iface);
+            }
+            let now = time::get_time();
+            recorded.lock().unwrap().push(LinkEvent {
+                unix: now.sec as u64 * 1_000_000_000 + now.nsec as u64,
+                monotonic: time::precise_time_ns(),
+                up: up,
+            });
+        }
+    });
+    Ok(events)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    extern crate libc;
+
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::mem;
+
+    const NETLINK_ROUTE: libc::c_int = 0;
+    const RTMGRP_LINK: u32 = 1;
+    const RTM_NEWLINK: u16 = 16;
+    const RTM_DELLINK: u16 = 17;
+    const IFLA_IFNAME: u16 = 3;
+    const IFF_UP: u32 = 0x1;
+    const IFF_RUNNING: u32 = 0x40;
+
+    // struct nlmsghdr, then struct ifinfomsg, from linux/netlink.h and
+    // linux/rtnetlink.h
+    const NLMSG_HDRLEN: usize = 16;
+    const IFINFOMSG_LEN: usize = 16;
+
+    // struct sockaddr_nl from linux/netlink.h
+    #[repr(C)]
+    struct SockaddrNl {
+        nl_family: libc::sa_family_t,
+        nl_pad: u16,
+        nl_pid: u32,
+        nl_groups: u32,
+    }
+
+    /// How long a read blocks before the watcher re-checks whether the run is
+    /// over
+    const RECV_TIMEOUT_S: libc::time_t = 1;
+
+    pub struct Socket {
+        fd: libc::c_int,
+        buffer: Vec<u8>,
+    }
+
+    impl Socket {
+        /// A netlink socket subscribed to link changes
+        pub fn open() -> Result<Socket, String> {
+            let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, NETLINK_ROUTE) };
+            if fd < 0 {
+                return Err(format!("Unable to open netlink socket. {}",
+                                   io::Error::last_os_error()));
+            }
+            let socket = Socket {
+                fd: fd,
+                buffer: vec![0; 1024*16],
+            };
+            let addr = SockaddrNl {
+                nl_family: libc::AF_NETLINK as libc::sa_family_t,
+                nl_pad: 0,
+                nl_pid: 0,
+                nl_groups: RTMGRP_LINK,
+            };
+            let timeout = libc::timeval {
+                tv_sec: RECV_TIMEOUT_S,
+                tv_usec: 0,
+            };
+            let ret = unsafe {
+                libc::bind(fd,
+                           &addr as *const _ as *const libc::sockaddr,
+                           mem::size_of::<SockaddrNl>() as libc::socklen_t)
+            };
+            if ret != 0 {
+                return Err(format!("Unable to subscribe to link changes. {}",
+                                   io::Error::last_os_error()));
+            }
+            unsafe {
+                libc::setsockopt(fd,
+                                 libc::SOL_SOCKET,
+                                 libc::SO_RCVTIMEO,
+                                 &timeout as *const _ as *const libc::c_void,
+                                 mem::size_of::<libc::timeval>() as libc::socklen_t);
+            }
+            Ok(socket)
+        }
+
+        /// Whether `iface` is up after the next batch of messages, if any of
+        /// them concern it
+        pub fn next(&mut self, iface: &str) -> io::Result<Option<bool>> {
+            let len = unsafe {
+                libc::recv(self.fd,
+                           self.buffer.as_mut_ptr() as *mut libc::c_void,
+                           self.buffer.len(),
+                           0)
+            };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut |
+                    io::ErrorKind::Interrupted => Ok(None),
+                    _ => Err(e),
+                };
+            }
+            Ok(parse(&self.buffer[..len as usize], iface))
+        }
+    }
+
+    impl Drop for Socket {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+
+    fn u16_at(b: &[u8], i: usize) -> u16 {
+        let mut a = [0u8; 2];
+        a.copy_from_slice(&b[i..i + 2]);
+        unsafe { mem::transmute(a) }
+    }
+
+    fn u32_at(b: &[u8], i: usize) -> u32 {
+        let mut a = [0u8; 4];
+        a.copy_from_slice(&b[i..i + 4]);
+        unsafe { mem::transmute(a) }
+    }
+
+    fn align(len: usize) -> usize {
+        (len + 3) & !3
+    }
+
+    // the state of `iface` after the link messages in `messages`, matched by
+    // name as an interface which is recreated, e.g. a tunnel, gets a new index
+    fn parse(messages: &[u8], iface: &str) -> Option<bool> {
+        let mut state = None;
+        let mut offset = 0;
+        while offset + NLMSG_HDRLEN <= messages.len() {
+            let len = u32_at(messages, offset) as usize;
+            if len < NLMSG_HDRLEN || offset + len > messages.len() {
+                break;
+            }
+            let kind = u16_at(messages, offset + 4);
+            let body = &messages[offset + NLMSG_HDRLEN..offset + len];
+            offset += align(len);
+            if (kind != RTM_NEWLINK && kind != RTM_DELLINK) || body.len() < IFINFOMSG_LEN {
+                continue;
+            }
+            if name(&body[IFINFOMSG_LEN..]).map_or(true, |name| name != iface.as_bytes()) {
+                continue;
+            }
+            let flags = u32_at(body, 8);
+            state = Some(kind == RTM_NEWLINK && flags & IFF_UP != 0 && flags & IFF_RUNNING != 0);
+        }
+        state
+    }
+
+    // the IFLA_IFNAME attribute, without its terminating NUL
+    fn name(attributes: &[u8]) -> Option<&[u8]> {
+        let mut offset = 0;
+        while offset + 4 <= attributes.len() {
+            let len = u16_at(attributes, offset) as usize;
+            if len < 4 || offset + len > attributes.len() {
+                return None;
+            }
+            if u16_at(attributes, offset + 2) == IFLA_IFNAME {
+                let value = &attributes[offset + 4..offset + len];
+                let end = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+                return Some(&value[..end]);
+            }
+            offset += align(len);
+        }
+        None
+    }
+
+    /// Whether `iface` is up now, by its operational state in sysfs. Loopback
+    /// and many tunnels report `unknown`, which counts as up
+    pub fn is_up(iface: &str) -> bool {
+        let mut state = String::new();
+        let path = format!("/sys/class/net/{}/operstate", iface);
+        if File::open(path).and_then(|mut f| f.read_to_string(&mut state)).is_err() {
+            return false;
+        }
+        match state.trim() {
+            "down" | "lowerlayerdown" | "notpresent" => false,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+
+    pub struct Socket;
+
+    impl Socket {
+        pub fn open() -> Result<Socket, String> {
+            Err("link monitoring uses netlink, and is only supported on Linux".to_owned())
+        }
+
+        pub fn next(&mut self, _: &str) -> io::Result<Option<bool>> {
+            Ok(None)
+        }
+    }
+
+    pub fn is_up(_: &str) -> bool {
+        true
+    }
+}
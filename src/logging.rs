@@ -1,6 +1,9 @@
+extern crate libc;
 extern crate time;
 extern crate log;
 
+use std::thread;
+
 pub struct SimpleLogger;
 
 pub use log::{LogLevel, LogLevelFilter, LogMetadata, LogRecord};
@@ -12,15 +15,26 @@ impl log::Log for SimpleLogger {
 
     fn log(&self, record: &LogRecord) {
         if record.target() == "ping_rs" && self.enabled(record.metadata()) {
+            // with several jobs in one process, each job's thread is named
+            // after it
+            let source = match thread::current().name() {
+                Some(name) if name != "main" => format!("{}:{}", record.target(), name),
+                _ => record.target().to_string(),
+            };
             println!("{} {:<5} [{}] {}",
                      time::strftime("%Y-%m-%d %H:%M:%S", &time::now()).unwrap(),
                      record.level().to_string(),
-                     record.target().to_string(),
+                     source,
                      record.args());
         }
     }
 }
 
+/// Whether log lines go to a terminal, which can show colors
+pub fn is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
 pub fn set_log_level(level: usize) {
     let log_filter;
     match level {
@@ -5,31 +5,204 @@ extern crate clap;
 #[macro_use]
 extern crate lazy_static;
 extern crate ipnetwork;
+extern crate ping_rs;
+#[cfg(feature = "userspace")]
 extern crate pnet;
+#[cfg(feature = "userspace")]
 extern crate rips;
 extern crate tic;
 extern crate time;
 
+#[cfg(all(feature = "static", feature = "userspace"))]
+compile_error!("the static build excludes the userspace stack, build it with \
+                --no-default-features --features static");
+#[cfg(all(feature = "netmap", target_env = "musl"))]
+compile_error!("the netmap backend needs the system's netmap headers and can't be built for musl");
+
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::fs::{self, OpenOptions};
 use std::io::Write;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
 use std::process;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use ipnetwork::Ipv4Network;
+#[cfg(feature = "userspace")]
 use pnet::datalink::{self, NetworkInterface};
+#[cfg(feature = "userspace")]
+use pnet::util::MacAddr;
+#[cfg(feature = "userspace")]
 use rips::udp::UdpSocket;
-use tic::{Clocksource, Interest, Receiver, Sample, Sender};
+use ping_rs::{auth, capability, ledger, output, probe, protocol, server};
+use tic::{Clocksource, Interest, Meters, Percentile, Receiver, Sample, Sender};
 
+mod achieved;
+mod analysis;
+mod checkpoint;
+mod client;
+mod compare;
+mod control;
+mod cron;
+mod drift;
+mod errqueue;
+#[cfg(feature = "userspace")]
+mod iface;
+mod jobs;
+mod link;
 mod logging;
+mod metadata;
+mod mtu;
+mod outlier;
+mod route;
+#[cfg(feature = "userspace")]
+mod routes;
+mod sched;
+mod simulate;
+mod sketch;
+mod sockopt;
+mod starvation;
+mod steer;
+mod target;
+mod timer;
+mod units;
+mod upload;
+mod verdict;
+use auth::{Key, Signed};
+use checkpoint::Checkpoint;
+use client::{OnError, Pacing, Report, RxKey, SourceMode, TcpConfig, Worker};
+use control::{Control, Registry};
 use logging::set_log_level;
+use metadata::Metadata;
+use outlier::Outliers;
+use output::{Compression, Output};
+use probe::{Pattern, Probes};
+use sched::Priority;
+use server::Policy;
+use simulate::Impairment;
+use sketch::{Distributions, Sketch, Sliding};
+use units::{Count, Factor, Size, Span, Unit};
+use upload::Destination;
+
+/// How long to wait for workers to finish their in-flight probe at shutdown
+const SHUTDOWN_GRACE_MS: u64 = 2000;
+
+/// Failed probe outcomes which are counted on the kernel socket paths
+const ERROR_METRICS: [Metric; 6] = [Metric::Timeout,
+                                    Metric::PortUnreachable,
+                                    Metric::HostUnreachable,
+                                    Metric::TtlExceeded,
+                                    Metric::IcmpOther,
+                                    Metric::Mismatch];
+
+/// Run metadata is written here, alongside the traces saved by tic
+const METADATA_FILE: &'static str = "run_metadata.txt";
+
+/// Latency by position within a burst is written here
+const BURST_FILE: &'static str = "burst_positions.txt";
+/// Flags which act on the path probes take, so are refused with --noop and
+/// --simulate rather than silently ignored
+const PATH_FLAGS: &'static [&'static str] = &["rotate",
+                                              "race",
+                                              "protocol",
+                                              "key",
+                                              "probe-ids",
+                                              "request-size",
+                                              "reply-size",
+                                              "pattern",
+                                              "expect-size",
+                                              "by-size",
+                                              "localize-loss",
+                                              "direction-jitter",
+                                              "sources",
+                                              "steer",
+                                              "tcp-request",
+                                              "tcp-expect"];
+
+/// Flags which configure the whole process rather than one run, so are
+/// refused on the line of a job rather than silently ignored
+const PROCESS_FLAGS: &'static [&'static str] = &["units",
+                                                 "precision",
+                                                 "histogram-digits",
+                                                 "histogram-max"];
+
+/// With --localize-loss, where each thread's probes were lost
+const LOSS_FILE: &'static str = "loss_direction.txt";
+
+/// Latency by time of day is rewritten here after every window
+const TIME_OF_DAY_FILE: &'static str = "time_of_day.txt";
+
+/// Latency by reply size is rewritten here after every window
+const BY_SIZE_FILE: &'static str = "latency_by_size.txt";
+
+/// Latency by position within the second is rewritten here after every window
+const SUBSECOND_FILE: &'static str = "subsecond.txt";
+
+/// Recalibrations of the clocksource after it drifted are written here
+const DRIFT_FILE: &'static str = "clock_drift.txt";
+
+/// Link state changes of the probing interface are written here
+const LINK_FILE: &'static str = "link_events.txt";
+
+/// Windows in which the measuring host withheld CPU time are written here
+const STARVATION_FILE: &'static str = "host_starvation.txt";
+
+/// Route changes detected from shifts in the minimum RTT are written here
+const ROUTE_FILE: &'static str = "route_changes.txt";
+
+/// The wall-clock and monotonic start and end times of each window are
+/// written here, so tic's traces can be lined up with external logs
+const WINDOW_FILE: &'static str = "windows.txt";
+
+/// Back-to-back timestamp pairs taken to measure the noop baseline
+const BASELINE_SAMPLES: usize = 1_000_000;
 
+/// Latency percentiles reported each window
+const PERCENTILES: [(&'static str, f64); 5] = [("p50", 50.0),
+                                              ("p90", 90.0),
+                                              ("p99", 99.0),
+                                              ("p999", 99.9),
+                                              ("p9999", 99.99)];
+
+/// Port tic serves a run's stats on, or the first job's
+const STATS_PORT: u16 = 42024;
+
+/// How long each racing probe waits for its reply
+const RACE_TIMEOUT_MS: u64 = 1000;
+
+/// How long each hello of the handshake waits for the reflector's answer
+const HANDSHAKE_TIMEOUT_MS: u64 = 500;
+
+#[cfg(feature = "userspace")]
 lazy_static! {
     static ref DEFAULT_ROUTE: Ipv4Network = Ipv4Network::from_cidr("0.0.0.0/0").unwrap();
 }
 
+/// What opening the raw channel needs, on failure
+#[cfg(all(feature = "userspace", target_os = "freebsd", not(feature = "netmap")))]
+const CHANNEL_HINT: &'static str = ". The channel is a BPF device, which needs read and write \
+                                    access to /dev/bpf*";
+#[cfg(all(feature = "userspace", target_os = "freebsd", feature = "netmap"))]
+const CHANNEL_HINT: &'static str = ". The channel is a netmap port, which needs the netmap \
+                                    device and a driver with native support";
+#[cfg(all(feature = "userspace", not(target_os = "freebsd")))]
+const CHANNEL_HINT: &'static str = ". Raw channels need root or CAP_NET_RAW";
+
+/// The userspace network stack shared by the workers
+#[cfg(feature = "userspace")]
+type Stack = Arc<Mutex<rips::NetworkStack>>;
+
+/// Without the userspace feature there is no stack, and workers always use
+/// kernel sockets
+#[cfg(not(feature = "userspace"))]
+type Stack = ();
+
 macro_rules! eprintln {
     ($($arg:tt)*) => (
         match writeln!(&mut ::std::io::stderr(), $($arg)* ) {
@@ -42,12 +215,46 @@ macro_rules! eprintln {
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub enum Metric {
     Ok,
+    Stack,
+    WorkerError,
+    Outlier,
+    Timeout,
+    PortUnreachable,
+    HostUnreachable,
+    TtlExceeded,
+    IcmpOther,
+    Mismatch,
+    SizeMismatch,
+    Truncated,
+    Malformed,
+    AuthFailure,
+    LinkDown,
+    SendError,
+    Backoff,
+    MtuBlackhole,
 }
 
 impl fmt::Display for Metric {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Metric::Ok => write!(f, "ok"),
+            Metric::Stack => write!(f, "stack"),
+            Metric::WorkerError => write!(f, "worker_error"),
+            Metric::Outlier => write!(f, "outlier"),
+            Metric::Timeout => write!(f, "timeout"),
+            Metric::PortUnreachable => write!(f, "port_unreachable"),
+            Metric::HostUnreachable => write!(f, "host_unreachable"),
+            Metric::TtlExceeded => write!(f, "ttl_exceeded"),
+            Metric::IcmpOther => write!(f, "icmp_other"),
+            Metric::Mismatch => write!(f, "mismatch"),
+            Metric::SizeMismatch => write!(f, "size_mismatch"),
+            Metric::Truncated => write!(f, "truncated"),
+            Metric::Malformed => write!(f, "malformed"),
+            Metric::AuthFailure => write!(f, "auth_failure"),
+            Metric::LinkDown => write!(f, "link_down"),
+            Metric::SendError => write!(f, "send_error"),
+            Metric::Backoff => write!(f, "backoff"),
+            Metric::MtuBlackhole => write!(f, "mtu_blackhole"),
         }
     }
 }
@@ -55,250 +262,3050 @@ impl fmt::Display for Metric {
 fn main() {
     set_log_level(0);
     let args = ArgumentParser::new();
+    units::configure(args.get_units(), args.get_precision());
+    let (digits, max_value) = args.get_histogram();
+    sketch::configure(digits, max_value);
+
+    if let Some(listen) = args.get_server_listen() {
+        let mut log = args.get_server_log();
+        if let Some(ref mut log) = log {
+            let _ = Metadata::new(probe::run_id()).write_header(log);
+        }
+        server::run(listen, log, args.get_server_policy());
+        return;
+    }
+    if let Some((a, b, alpha)) = args.get_compare() {
+        if let Err(e) = compare::run(&a, &b, alpha) {
+            args.print_error(&e);
+        }
+        return;
+    }
+    if let Some((path, speed)) = args.get_replay() {
+        replay(&args, &path, speed);
+        return;
+    }
+    if let Some(path) = args.get_jobs() {
+        run_jobs(&args, &path);
+        return;
+    }
+    if let Some(shell) = args.get_completions() {
+        ArgumentParser::create_app().gen_completions_to("ping-rs", shell, &mut ::std::io::stdout());
+        return;
+    }
+    run(&args, None);
+}
 
-    let (_, iface) = args.get_iface();
+// runs every job of the jobs file at `path` in a thread of its own, each with
+// the arguments of its line and its files in a directory named after it. A
+// job which fails stops alone
+fn run_jobs(args: &ArgumentParser, path: &str) {
+    let jobs = match jobs::load(path) {
+        Ok(jobs) => jobs,
+        Err(e) => args.print_error(&e),
+    };
+    // every job's arguments are parsed before any starts
+    for job in &jobs {
+        let parsed = ArgumentParser::for_job(job);
+        for flag in PROCESS_FLAGS {
+            if parsed.given(flag) {
+                args.print_error(&format!("Job {} gives --{}, which applies to every job. Give \
+                                           it before jobs instead",
+                                          job.name,
+                                          flag));
+            }
+        }
+        if job.schedule.is_some() && parsed.matches.value_of("windows") == Some("0") {
+            args.print_error(&format!("Job {} is scheduled, so must end. Give it --windows 1 or \
+                                       more",
+                                      job.name));
+        }
+        if let Err(e) = fs::create_dir_all(&job.name) {
+            args.print_error(&format!("Unable to create directory {}. {}", job.name, e));
+        }
+    }
+    let registry = Arc::new(Registry::new());
+    if let Some(listen) = args.get_jobs_control() {
+        if let Err(e) = control::listen_jobs(listen, registry.clone()) {
+            args.print_error(&e);
+        }
+    }
+    let mut handles = Vec::with_capacity(jobs.len());
+    for job in jobs {
+        let registry = registry.clone();
+        let name = job.name.clone();
+        // clap's parser isn't Send, so each job parses its arguments again
+        let handle = thread::Builder::new()
+            .name(job.name.clone())
+            .spawn(move || match job.schedule {
+                Some(ref schedule) => run_scheduled(&job, schedule, &registry),
+                None => run(&ArgumentParser::for_job(&job), Some(&registry)),
+            });
+        match handle {
+            Ok(handle) => handles.push((name, handle)),
+            Err(e) => args.print_error(&format!("Unable to start job {}. {}", name, e)),
+        }
+    }
+    let mut failed = 0;
+    for (name, handle) in handles {
+        if handle.join().is_err() {
+            error!("job {} failed", name);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        error!("{} of the jobs failed", failed);
+        process::exit(1);
+    }
+}
+
+/// Each scheduled job appends the start, end, directory and outcome of its
+/// runs here, within its directory
+const RUNS_FILE: &'static str = "runs.txt";
+
+// runs a scheduled job whenever its schedule matches, each run in a directory
+// of its own named after the time it was scheduled for. A run which fails
+// doesn't stop the schedule, and one which overruns its next time skips it
+fn run_scheduled(job: &jobs::Job, schedule: &cron::Schedule, registry: &Registry) {
+    loop {
+        let next = match schedule.next(time::get_time()) {
+            Some(next) => next,
+            None => {
+                error!("job {}: schedule {} matches no time within a year", job.name, schedule);
+                return;
+            }
+        };
+        info!("job {}: next run at {}",
+              job.name,
+              time::strftime("%Y-%m-%d %H:%M", &time::at(next)).unwrap());
+        loop {
+            let wait = next.sec - time::get_time().sec;
+            if wait <= 0 {
+                break;
+            }
+            thread::sleep(Duration::from_secs(cmp::min(wait as u64, 60)));
+        }
+        let start = time::now_utc();
+        let mut scheduled = job.clone();
+        scheduled.dir = format!("{}/{}",
+                                job.name,
+                                time::strftime("%Y%m%dT%H%M", &time::at(next)).unwrap());
+        let ok = match fs::create_dir_all(&scheduled.dir) {
+            Ok(()) => {
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                        run(&ArgumentParser::for_job(&scheduled), Some(registry))
+                    }))
+                    .is_ok()
+            }
+            Err(e) => {
+                error!("job {}: unable to create {}. {}", job.name, scheduled.dir, e);
+                false
+            }
+        };
+        if !ok {
+            error!("job {}: run {} failed", job.name, scheduled.dir);
+        }
+        let path = format!("{}/{}", job.name, RUNS_FILE);
+        let appended = OpenOptions::new().append(true).create(true).open(&path).and_then(|mut f| {
+            writeln!(f,
+                     "{} {} {} {}",
+                     time::strftime("%Y-%m-%dT%H:%M:%SZ", &start).unwrap(),
+                     time::strftime("%Y-%m-%dT%H:%M:%SZ", &time::now_utc()).unwrap(),
+                     scheduled.dir,
+                     if ok { "ok" } else { "failed" })
+        });
+        if let Err(e) = appended {
+            error!("job {}: unable to append to {}. {}", job.name, path, e);
+        }
+    }
+}
+
+// one measurement run, as given on the command line or by a line of a jobs
+// file, whose control is then served through `registry` alongside the others
+fn run(args: &ArgumentParser, registry: Option<&Registry>) {
     let src_net = args.get_src_net();
-    let gateway = args.get_gw();
-    let channel = args.create_channel();
     let duration = args.get_duration();
     let windows = args.get_windows();
     let stats_qlen = args.get_stats_qlen();
     let dst = args.get_dst();
     let threads = args.get_threads();
+    let flows = args.get_flows().unwrap_or(threads);
+    if flows < threads {
+        args.print_error("--flows must be at least the number of threads");
+    }
     let noop = args.get_noop();
-    let stdnet = args.get_stdnet();
+    let simulate = args.get_simulate();
+    // whether probes go out over a real path
+    let network = !noop && simulate.is_none();
+    // without the userspace stack, kernel sockets are the only way to probe
+    let stdnet = args.get_stdnet() || !cfg!(feature = "userspace");
+    let tcp = args.get_tcp();
+    let expect_size = args.get_expect_size();
+    let burst = args.get_burst();
+    // replies to a burst are matched to their position by probe ID
+    let mut probe_ids = args.get_probe_ids() || burst.is_some();
+    let probe_log = args.get_probe_log();
+    let request_size = args.get_request_size();
+    let reply_size = args.get_reply_size();
+    let pattern = args.get_pattern();
+    let on_error = args.get_on_worker_error();
+    let truncated_error = args.get_truncated_error();
+    let compression = args.get_compression();
+    let rate = args.get_rate();
+    let adaptive = args.get_adaptive_floor();
+    let pacing = args.get_pacing();
+    let steer = args.get_steer();
+    let by_rx = args.get_by_rx();
+    let time_of_day = args.get_time_of_day();
+    let by_size = args.get_by_size();
+    let subsecond = args.get_subsecond();
+    let protocol = args.get_protocol();
+    {
+        // fail now rather than in every worker
+        let probe = match protocol::create(&protocol) {
+            Ok(probe) => probe,
+            Err(e) => args.print_error(&format!("Invalid protocol param. {}", e)),
+        };
+        if tcp.is_some() && !probe.tcp() {
+            args.print_error(&format!("Protocol {} doesn't run over TCP", protocol));
+        }
+        if tcp.is_none() && network && !probe.udp() {
+            args.print_error(&format!("Protocol {} runs over TCP only. Use it with --tcp",
+                                      protocol));
+        }
+    }
+    // only the echo protocol carries IDs and padding
+    let echo = protocol == "echo";
+    if !echo && (probe_ids || request_size > 0 || reply_size.is_some() || burst.is_some() ||
+                 tcp.as_ref().map_or(false, |t| t.request.is_some() || t.expect.is_some())) {
+        args.print_error("--probe-ids, --burst, --request-size, --reply-size, --tcp-request and \
+                          --tcp-expect apply only to the echo protocol");
+    }
+    let key = args.get_key();
+    if let Some(size) = reply_size {
+        if network && tcp.is_none() && key.is_none() && size > request_size {
+            args.print_error("A reflector answers unsigned UDP requests with no more than they \
+                              carry. Pad requests to at least --reply-size with --request-size, \
+                              or sign them with --key");
+        }
+    }
+    if key.is_some() &&
+       (!echo || tcp.as_ref().map_or(false, |t| t.request.is_some() || t.expect.is_some())) {
+        args.print_error("--key applies only to the echo protocol, without --tcp-request or \
+                          --tcp-expect");
+    }
+    let localize_loss = args.get_localize_loss();
+    if localize_loss {
+        if !network || !echo || tcp.is_some() {
+            args.print_error("--localize-loss applies only to the echo protocol over UDP");
+        }
+        // the reflector tallies probes by the thread in their ID
+        probe_ids = true;
+    }
+    let direction_jitter = args.get_direction_jitter();
+    if direction_jitter {
+        if !network || !echo || tcp.is_some() || burst.is_some() {
+            args.print_error("--direction-jitter applies only to the echo protocol over UDP, \
+                              without --burst");
+        }
+        // the reflector's timestamps follow the ID
+        probe_ids = true;
+    }
+    let reflector = handshake(args,
+                              dst,
+                              network && echo && tcp.is_none(),
+                              key.is_some(),
+                              &mut probe_ids,
+                              localize_loss,
+                              direction_jitter,
+                              reply_size.is_some(),
+                              pattern.is_some());
+    check_paths(args, noop, network, stdnet, tcp.is_some(), burst.is_some());
+    let control_listen = args.get_control_listen();
+    let sliding_window = args.get_sliding_window();
+    let outlier_threshold = args.get_outlier_threshold();
+    let outlier_path = args.get_outlier_log();
+    let ramp = args.get_ramp_threads();
+    let priority = args.get_so_priority();
+    let receiver_cpus = args.get_receiver_cpus();
+    let receiver_priority = args.get_receiver_priority();
+    let slowest = args.get_slowest();
+    let mtu_probe = args.get_mtu_probe();
+    let drift_threshold = args.get_clock_drift();
+    let warn_p99 = args.get_warn_p99();
+    let upload = args.get_upload();
+    let watch_link = args.get_watch_link();
+    let starvation_threshold = args.get_starvation();
+    let (sources, source_mode, failover_after) = args.get_sources();
+    let route_shift = args.get_route_shift();
+    let use_baseline = args.get_baseline();
+    let router_alert = args.get_router_alert();
+
+    let resume = args.get_resume();
+    let checkpoint_path = args.get_checkpoint().or_else(|| resume.as_ref().map(|r| r.0.clone()));
+    let resume = resume.map(|(_, checkpoint)| checkpoint);
+    let run_id = resume.as_ref().map_or_else(probe::run_id, |c| c.run_id);
+    let mut metadata = match args.job {
+        // a job's configuration is its line of the jobs file
+        Some(ref job) => Metadata::with_args(run_id, &job.args),
+        None => Metadata::new(run_id),
+    };
+    if let Some(ref job) = args.job {
+        metadata.set("job", job.name.clone());
+        if let Some(ref schedule) = job.schedule {
+            metadata.set("schedule", format!("{}", schedule));
+        }
+    }
+    info!("run id: {:016x}", run_id);
+    if let Some(ref resume) = resume {
+        info!("resuming after window {} with {} probes recorded",
+              resume.window,
+              resume.recorded);
+        metadata.start = resume.start.clone();
+        metadata.set("resumed_after_window", format!("{}", resume.window));
+    }
+    // tic's receiver doesn't expose the configuration of its histograms
+    info!("sketch precision: {}", sketch::describe());
+    metadata.set("sketch_precision", sketch::describe());
+    // what the kernel and the reflector granted, read back rather than taken
+    // from the flags, failing now rather than in every worker where a setting
+    // is refused outright, e.g. SO_PRIORITY without CAP_NET_ADMIN
+    let mut granted = vec![achieved::timestamps(direction_jitter)];
+    if priority.is_some() || (network && (stdnet || tcp.is_some())) {
+        match achieved::sockets(src_net.ip(), priority) {
+            Ok(settings) => granted.extend(settings),
+            Err(e) => args.print_error(&e),
+        }
+    }
+    if receiver_cpus.is_some() || receiver_priority.is_some() {
+        match achieved::receiver(receiver_cpus.clone(), receiver_priority) {
+            Ok(settings) => granted.extend(settings),
+            Err(e) => args.print_error(&e),
+        }
+    }
+    if let Some(ref reflector) = reflector {
+        if reply_size.is_some() {
+            granted.push(achieved::reply_padding(reflector.supports(capability::PADDING)));
+        }
+    }
+    let mut downgraded = Vec::new();
+    for setting in &granted {
+        match setting.requested {
+            Some(ref requested) if setting.downgraded() => {
+                warn!("achieved: {} {} was requested, but {} was granted",
+                      setting.name,
+                      requested,
+                      setting.achieved);
+                downgraded.push(setting.name);
+            }
+            _ => info!("achieved: {}: {}", setting.name, setting.achieved),
+        }
+        metadata.set(&format!("achieved_{}", setting.name), setting.achieved.clone());
+    }
+    metadata.set("downgraded",
+                 if downgraded.is_empty() {
+                     "none".to_owned()
+                 } else {
+                     downgraded.join(",")
+                 });
+    if let Some(priority) = priority {
+        metadata.set("so_priority", format!("{}", priority));
+    }
+    if router_alert {
+        metadata.set("router_alert", "true".to_owned());
+    }
+    if let Some(ref cpus) = receiver_cpus {
+        let cpus: Vec<String> = cpus.iter().map(|c| format!("{}", c)).collect();
+        metadata.set("receiver_cpus", cpus.join(","));
+    }
+    if let Some(priority) = receiver_priority {
+        metadata.set("receiver_priority", format!("{}", priority));
+    }
+    if pacing == Pacing::Timerfd {
+        if let Err(e) = timer::Timer::new() {
+            args.print_error(&format!("Unable to create timerfd. {}", e));
+        }
+    }
+    metadata.set("pacing", pacing.name().to_owned());
+    if let Some(ref impairment) = simulate {
+        metadata.set("simulate", format!("{}", impairment));
+        // what the reports at the end of each window should show
+        let values: Vec<String> = PERCENTILES.iter()
+            .map(|&(name, p)| {
+                format!("{}: {}", name, units::latency(impairment.expected_percentile(p)))
+            })
+            .collect();
+        info!("simulate: expected loss: {}% reorder: {}% latency: {}",
+              impairment.loss,
+              impairment.reorder,
+              values.join(" "));
+    }
+    if let Some(value) = args.get_pattern_name() {
+        metadata.set("pattern", value);
+    }
+    if let Some(key) = by_rx {
+        metadata.set("by_rx", key.name().to_owned());
+    }
+    if let Some(bytes) = by_size {
+        metadata.set("by_size", format!("{}", bytes));
+    }
+    if key.is_some() {
+        metadata.set("signed", "true".to_owned());
+    }
+    if let Some(ref reflector) = reflector {
+        metadata.set("reflector_version", reflector.version.clone());
+        metadata.set("reflector_features", reflector.features.join(","));
+    }
+    if let Some(threshold) = drift_threshold {
+        metadata.set("clock_drift_threshold_ns", format!("{}", threshold));
+    }
+    if let Some(threshold) = starvation_threshold {
+        metadata.set("starvation_threshold_percent", format!("{}", threshold));
+    }
+    if let Some(minutes) = time_of_day {
+        // bins are in local time, so record where that is
+        metadata.set("time_of_day_minutes", format!("{}", minutes));
+        metadata.set("utc_offset", format!("{}", time::now().tm_utcoff));
+    }
+    if let Some(ms) = subsecond {
+        metadata.set("subsecond_ms", format!("{}", ms));
+    }
+    // worker i's flows are steered to receive queue i, wrapping if the
+    // interface has fewer queues than threads
+    let mut steering = None;
+    if let Some(ref iface) = steer {
+        let queues = match steer::rx_queues(iface) {
+            Ok(queues) => queues,
+            Err(e) => args.print_error(&e),
+        };
+        if queues < threads {
+            warn!("steering: {} has {} receive queues for {} threads, so some threads share \
+                   a queue",
+                  iface,
+                  queues,
+                  threads);
+        }
+        metadata.set("steer", iface.clone());
+        steering = Some((steer::Steering::new(iface), queues));
+    }
+    if let Some(ref tcp) = tcp {
+        if let Some(ref algorithm) = tcp.congestion {
+            if let Some(available) = sockopt::available_congestion() {
+                if !available.contains(algorithm) {
+                    args.print_error(&format!("Congestion control {} is not available. Choose \
+                                               from: {}",
+                                              algorithm,
+                                              available.join(" ")));
+                }
+            }
+            metadata.set("tcp_congestion", algorithm.clone());
+        }
+        metadata.set("tcp_nodelay", format!("{}", tcp.nodelay));
+        metadata.set("tcp_quickack", format!("{}", tcp.quickack));
+    }
+
+    // the files the run writes, which it refuses to clobber and uploads
+    let mut outputs = vec![args.file("ok_waterfall.png"),
+                           args.file(METADATA_FILE),
+                           compression.apply(&args.file(WINDOW_FILE)).0];
+    for trace in &["ok_trace.txt", "stack_trace.txt"] {
+        outputs.push(format!("{}{}", args.file(trace), compression.extension()));
+    }
+    if let Some(ref path) = probe_log {
+        for i in 0..threads {
+            outputs.push(compression.apply(&output::suffixed(path, &format!("{}", i))).0);
+        }
+    }
+    if let Some(ref path) = outlier_path {
+        outputs.push(compression.apply(path).0);
+    }
+    if burst.is_some() {
+        outputs.push(compression.apply(&args.file(BURST_FILE)).0);
+    }
+    if localize_loss {
+        outputs.push(compression.apply(&args.file(LOSS_FILE)).0);
+    }
+    if route_shift.is_some() {
+        outputs.push(compression.apply(&args.file(ROUTE_FILE)).0);
+    }
+    if time_of_day.is_some() {
+        outputs.push(compression.apply(&args.file(TIME_OF_DAY_FILE)).0);
+    }
+    if by_size.is_some() {
+        outputs.push(compression.apply(&args.file(BY_SIZE_FILE)).0);
+    }
+    if subsecond.is_some() {
+        outputs.push(compression.apply(&args.file(SUBSECOND_FILE)).0);
+    }
+    if drift_threshold.is_some() {
+        outputs.push(compression.apply(&args.file(DRIFT_FILE)).0);
+    }
+    if !sources.is_empty() {
+        let names: Vec<String> = sources.iter().map(|s| format!("{}", s)).collect();
+        metadata.set("sources", names.join(","));
+        metadata.set("source_mode", source_mode.name().to_owned());
+    }
+    if watch_link {
+        outputs.push(compression.apply(&args.file(LINK_FILE)).0);
+    }
+    if starvation_threshold.is_some() {
+        outputs.push(compression.apply(&args.file(STARVATION_FILE)).0);
+    }
+    if let Some(ref path) = checkpoint_path {
+        outputs.push(path.clone());
+    }
+    if !args.get_force() {
+        // unless resuming, when its checkpoint is expected to exist
+        let existing: Vec<String> = outputs.iter()
+            .filter(|p| resume.is_none() || Some(*p) != checkpoint_path.as_ref())
+            .filter(|p| Path::new(p).exists())
+            .cloned()
+            .collect();
+        if !existing.is_empty() {
+            args.print_error(&format!("Output files from a previous run exist: {}. Use --force \
+                                       to overwrite them",
+                                      existing.join(", ")));
+        }
+    }
+    {
+        let mut file = args.create_output(&args.file(METADATA_FILE), Compression::None);
+        if let Err(e) = metadata.write_header(&mut file) {
+            args.print_error(&format!("Unable to write {}. {}", METADATA_FILE, e));
+        }
+    }
+
+    let mut route = route_shift.map(|(shift, windows)| {
+        let mut log = args.create_output(&args.file(ROUTE_FILE), compression);
+        let _ = metadata.write_header(&mut log);
+        let _ = writeln!(log,
+                         "# unix time ns, monotonic ns, window, previous minimum rtt ns, new \
+                          minimum rtt ns");
+        let _ = log.flush();
+        (route::MinimumLock::new(windows, shift), log)
+    });
+
+    let mut drift_log = drift_threshold.map(|_| {
+        let mut log = args.create_output(&args.file(DRIFT_FILE), compression);
+        let _ = metadata.write_header(&mut log);
+        let _ = writeln!(log,
+                         "# window, unix time ns, monotonic ns, clocksource offset ns, drift ppm");
+        let _ = log.flush();
+        log
+    });
+
+    let mut window_log = args.create_output(&args.file(WINDOW_FILE), compression);
+    let _ = metadata.write_header(&mut window_log);
+    let _ = writeln!(window_log,
+                     "# window, start unix time ns, start monotonic ns, end unix time ns, end \
+                      monotonic ns, probes");
+
+    let outlier_log = outlier_path.map(|path| {
+        let mut log = args.create_output(&path, compression);
+        let _ = metadata.write_header(&mut log);
+        Arc::new(Mutex::new(log))
+    });
+
+    // the raw channel needs root, so is only opened for the userspace path
+    let stack = if network && !stdnet && tcp.is_none() {
+        Some(create_stack(&args, src_net))
+    } else {
+        None
+    };
+
+    // initialize a tic::Receiver to ingest stats
+    let mut receiver = Receiver::configure()
+        .windows(cmp::max(windows, 1))
+        .duration(duration)
+        .capacity(stats_qlen)
+        .http_listen(args.get_stats_listen())
+        .build();
+
+    receiver.add_interest(Interest::Waterfall(Metric::Ok, args.file("ok_waterfall.png")));
+    receiver.add_interest(Interest::Trace(Metric::Ok, args.file("ok_trace.txt")));
+    receiver.add_interest(Interest::Count(Metric::Ok));
+    receiver.add_interest(Interest::Percentile(Metric::Ok));
+    receiver.add_interest(Interest::Count(Metric::WorkerError));
+    receiver.add_interest(Interest::Count(Metric::SendError));
+    receiver.add_interest(Interest::Count(Metric::Backoff));
+    if mtu_probe.is_some() {
+        receiver.add_interest(Interest::Count(Metric::MtuBlackhole));
+    }
+    if expect_size.is_some() {
+        receiver.add_interest(Interest::Count(Metric::SizeMismatch));
+    }
+    receiver.add_interest(Interest::Count(Metric::Truncated));
+    receiver.add_interest(Interest::Count(Metric::Malformed));
+    if watch_link {
+        receiver.add_interest(Interest::Count(Metric::LinkDown));
+    }
+    if key.is_some() {
+        receiver.add_interest(Interest::Count(Metric::AuthFailure));
+    }
+    if outlier_log.is_some() {
+        receiver.add_interest(Interest::Count(Metric::Outlier));
+    }
+
+    // time spent inside the userspace stack on send, including waiting for the
+    // shared stack lock, is only meaningful on the rips path
+    let rips = network && !stdnet && tcp.is_none();
+    let kernel = network && (stdnet || tcp.is_some());
+    if kernel || simulate.is_some() {
+        for metric in &ERROR_METRICS {
+            receiver.add_interest(Interest::Count(metric.clone()));
+        }
+    } else if rips && !echo {
+        receiver.add_interest(Interest::Count(Metric::Mismatch));
+    }
+    if rips {
+        receiver.add_interest(Interest::Trace(Metric::Stack, args.file("stack_trace.txt")));
+        receiver.add_interest(Interest::Count(Metric::Stack));
+        receiver.add_interest(Interest::Percentile(Metric::Stack));
+    }
+
+    // measured before any workers start, so they don't inflate it
+    let baseline = if use_baseline {
+        let baseline = client::baseline(&receiver.get_clocksource(), BASELINE_SAMPLES);
+        let values: Vec<String> = PERCENTILES.iter()
+            .map(|&(name, p)| format!("{}: {}", name, units::latency(baseline.percentile(p))))
+            .collect();
+        info!("noop baseline: {}", values.join(" "));
+        Some(baseline)
+    } else {
+        None
+    };
+
+    let control = Arc::new(Control::new(dst, rate, metadata.clone()));
+    for target in args.get_rotate() {
+        control.add_target(target);
+    }
+    if let Some(listen) = control_listen {
+        if let Err(e) = control::listen(listen, control.clone()) {
+            args.print_error(&e);
+        }
+    }
+    if let (Some(registry), Some(name)) = (registry, args.get_job_name()) {
+        registry.add(name, control.clone());
+    }
+
+    let (done_tx, done_rx) = mpsc::channel();
+    let mut workers = Workers {
+        control: control.clone(),
+        handles: Vec::with_capacity(threads),
+    };
+    let mut slidings = Vec::new();
+    let mut distributions = Vec::with_capacity(threads);
+
+    for i in 0..threads {
+        let src = SocketAddr::V4(SocketAddrV4::new(src_net.ip(), 0));
+        let log = probe_log.as_ref().map(|path| {
+            let mut log = args.create_output(&output::suffixed(path, &format!("{}", i)),
+                                             compression);
+            let _ = metadata.write_header(&mut log);
+            log
+        });
+        let dists = Arc::new(Mutex::new(Distributions::new(slowest)));
+        distributions.push(dists.clone());
+        let mut probes = Probes::new(run_id, i, probe_ids, log);
+        probes.set_sizes(request_size, reply_size);
+        probes.set_pattern(pattern.clone());
+        probes.set_stamped(direction_jitter);
+        let mut worker = Worker::new(i,
+                                     probes,
+                                     receiver.get_clocksource(),
+                                     receiver.get_sender(),
+                                     control.clone(),
+                                     dists.clone());
+        let mut probe = protocol::create(&protocol).unwrap();
+        if tcp.is_some() {
+            probe.use_tcp();
+        }
+        worker.set_protocol(match key {
+            Some(ref key) => Box::new(Signed::new(probe, key.clone())),
+            None => probe,
+        });
+        worker.set_expect_size(expect_size);
+        worker.set_truncated_error(truncated_error);
+        worker.set_adaptive(adaptive);
+        worker.set_pacing(pacing);
+        worker.set_priority(priority);
+        worker.set_router_alert(router_alert);
+        worker.set_rx_queues(steering.is_some());
+        worker.set_by_rx(by_rx);
+        worker.set_time_of_day(time_of_day);
+        worker.set_by_size(by_size);
+        worker.set_subsecond(subsecond);
+        worker.set_stamped(direction_jitter);
+        worker.set_sources(sources.clone(), source_mode, failover_after);
+        // spread thread starts evenly over the ramp, the first starting at once
+        worker.set_ramp_delay(ramp * i as u64 / threads as u64);
+        worker.set_outliers(outlier_log.as_ref()
+            .map(|log| Outliers::new(i, outlier_threshold, log.clone())));
+        if let Some(window) = sliding_window {
+            let sliding = Arc::new(Mutex::new(Sliding::new(window)));
+            slidings.push(sliding.clone());
+            worker.set_sliding(Some(sliding));
+        }
+        // flow f is carried by worker f % threads
+        let slots = (flows - i + threads - 1) / threads;
+        let done = done_tx.clone();
+        let handle = if noop {
+            spawn(worker, on_error, i, done, |w| w.run_noop())
+        } else if let Some(ref impairment) = simulate {
+            let impairment = impairment.clone();
+            spawn(worker, on_error, i, done, move |w| w.run_simulate(&impairment))
+        } else if let Some(ref tcp) = tcp {
+            let tcp = tcp.clone();
+            spawn(worker, on_error, i, done, move |w| w.run_tcp(&tcp))
+        } else if let Some(size) = burst {
+            spawn_burst(args, src, size, worker, on_error, i, done)
+        } else if stdnet {
+            spawn_stdnet(args, src, &sources, slots, &mut steering, worker, on_error, i, done)
+        } else {
+            spawn_rips(args, stack.as_ref().unwrap(), src, slots, worker, on_error, i, done)
+        };
+        workers.handles.push(Some(handle));
+    }
+
+    if let Some(window) = sliding_window {
+        sketch::report(window, slidings, receiver.get_clocksource(), control.clone());
+    }
+    if let Some((size, interval)) = mtu_probe {
+        let src = SocketAddr::V4(SocketAddrV4::new(src_net.ip(), 0));
+        if let Err(e) = mtu::watchdog(src,
+                                      size,
+                                      interval,
+                                      control.clone(),
+                                      receiver.get_clocksource(),
+                                      receiver.get_sender()) {
+            args.print_error(&e);
+        }
+    }
+
+    let cs = receiver.get_clocksource();
+    let recalibrations =
+        drift_threshold.map(|threshold| drift::monitor(cs.clone(), threshold, control.clone()));
+    let mut link = if watch_link {
+        let iface = args.get_iface_name();
+        let events = match link::watch(&iface, control.clone()) {
+            Ok(events) => events,
+            Err(e) => args.print_error(&e),
+        };
+        let mut log = args.create_output(&args.file(LINK_FILE), compression);
+        let _ = metadata.write_header(&mut log);
+        let _ = writeln!(log, "# window, unix time ns, monotonic ns, state");
+        let _ = log.flush();
+        Some((iface, events, log))
+    } else {
+        None
+    };
+    // the first reading is taken once the workers run, so that it covers
+    // their threads
+    let mut starvation = starvation_threshold.map(|threshold| {
+        let sampler = match starvation::Sampler::new(threshold) {
+            Ok(sampler) => sampler,
+            Err(e) => args.print_error(&e),
+        };
+        let mut log = args.create_output(&args.file(STARVATION_FILE), compression);
+        let _ = metadata.write_header(&mut log);
+        let _ = writeln!(log,
+                         "# window, involuntary context switches, max run queue wait %, steal %, \
+                          starved");
+        let _ = log.flush();
+        (sampler, log)
+    });
+    let mut starved = 0;
+    // downtime over the run, and when the link last went down
+    let mut downtime = 0;
+    let mut down_since = None;
+
+    let mut total = 0;
+    // a resumed run carries on from its checkpoint, counting windows across
+    // both processes
+    let (mut window, recorded, mut cumulative) = match resume {
+        Some(resume) => (resume.window, resume.recorded, resume.latency),
+        None => (0, 0, Sketch::new()),
+    };
+
+    // percentiles of the previous window, which each window is compared to
+    let mut previous = Vec::new();
+    let highlight = args.get_highlight();
+    // latency by time-of-day bin and position within the second over the
+    // whole run
+    let mut bins = Bins::default();
+    // what the verdict at the end of the run is drawn from
+    let mut summary = verdict::Run {
+        route_changes: route.as_ref().map(|_| 0),
+        regressions: highlight.map(|_| 0),
+        exceeded: warn_p99.map(|threshold| (threshold, 0)),
+        ..verdict::Run::default()
+    };
+
+    // windows are back to back, each starting where the previous one ended,
+    // so that probes completed while the previous one was being reported are
+    // counted against the time they were completed in rather than inflating
+    // the next window's rate
+    let mut mark = None;
+
+    // this thread drains the stats queue, and is set apart from the workers
+    // only now that they have inherited its affinity and priority
+    if let Some(ref cpus) = receiver_cpus {
+        if let Err(e) = sched::set_affinity(cpus) {
+            args.print_error(&format!("Unable to set the receiver's CPU affinity. {}", e));
+        }
+    }
+    if let Some(priority) = receiver_priority {
+        if let Err(e) = sched::set_priority(priority) {
+            args.print_error(&format!("Unable to set the receiver's priority {}. {}", priority, e));
+        }
+    }
+
+    // with --windows 0 the run continues until shut down via the control API
+    while (windows == 0 || window < windows) && !control.shutdown_requested() {
+        // each window probes the next target in turn, as given with --rotate
+        // or added through the control API
+        if let Some(target) = control.rotate(window) {
+            info!("window {}: target {}", window + 1, target);
+        }
+        let probed = control.target();
+        window += 1;
+        let (w0, t0) = mark.unwrap_or_else(|| (unix_ns(), cs.time()));
+        let reported = cs.time();
+        receiver.run_once();
+        let (w1, t1) = (unix_ns(), cs.time());
+        mark = Some((w1, t1));
+        if reported > t0 {
+            debug!("reporting the previous window took {}",
+                   units::latency((reported - t0) as u64));
+        }
+        let m = receiver.clone_meters();
+        let mut c = 0;
+        if let Some(t) = m.get_count(&Metric::Ok) {
+            c = *t - total;
+            total = *t;
+        }
+        let r = c as f64 / ((t1 - t0) as f64 / 1_000_000_000.0);
+        info!("rate: {} rps", units::number(r));
+        if log_latency_deltas(&m, Metric::Ok, &mut previous, highlight) {
+            if let Some(ref mut regressions) = summary.regressions {
+                *regressions += 1;
+            }
+        }
+        if let Some(ref baseline) = baseline {
+            log_adjusted(&m, Metric::Ok, baseline);
+        }
+        if let Some((ref mut sampler, ref mut log)) = starvation {
+            if sample_starvation(window, sampler, log) {
+                starved += 1;
+            }
+        }
+        let _ = writeln!(window_log, "{} {} {} {} {} {}", window, w0, t0, w1, t1, c);
+        let _ = window_log.flush();
+        control.set_summary(window, (w0, t0 as u64), (w1, t1 as u64), r, &m);
+        let latency = log_distributions(&distributions,
+                                        &control,
+                                        &mut cumulative,
+                                        &mut bins,
+                                        pacing,
+                                        by_rx,
+                                        &sources);
+        control.record_target(probed, &latency);
+        if latency.count() > 0 {
+            summary.medians.push(latency.percentile(50.0));
+            if let Some((threshold, ref mut exceeded)) = summary.exceeded {
+                let p99 = latency.percentile(99.0);
+                if p99 > threshold {
+                    *exceeded += 1;
+                    warn!("window {}: p99 {} exceeded the warn threshold of {}",
+                          window,
+                          units::latency(p99),
+                          units::latency(threshold));
+                }
+            }
+        }
+        write_bins(args, &metadata, compression, &bins, time_of_day, by_size, subsecond);
+        if let Some(ref path) = checkpoint_path {
+            let checkpoint = Checkpoint {
+                run_id: run_id,
+                start: metadata.start.clone(),
+                window: window,
+                recorded: recorded + total,
+                latency: cumulative.clone(),
+            };
+            if let Err(e) = checkpoint.save(path) {
+                warn!("unable to write checkpoint {}: {}", path, e);
+            }
+        }
+        if let Some((ref iface, ref events, ref mut log)) = link {
+            downtime += log_link_events(window, iface, events, log, &mut down_since);
+            if let Some(lost) = m.get_count(&Metric::LinkDown) {
+                if *lost > 0 {
+                    warn!("link down: probes not sent: {}", lost);
+                }
+            }
+        }
+        if let (Some(recalibrations), Some(log)) = (recalibrations.as_ref(), drift_log.as_mut()) {
+            log_recalibrations(window, recalibrations, log);
+        }
+        if let Some((ref mut lock, ref mut log)) = route {
+            if latency.count() > 0 && observe_route(window, (w1, t1 as u64), &latency, lock, log) {
+                if let Some(ref mut changes) = summary.route_changes {
+                    *changes += 1;
+                }
+            }
+        }
+        if let Some(errors) = m.get_count(&Metric::WorkerError) {
+            summary.worker_errors = *errors;
+        }
+        log_failures(&m, kernel || simulate.is_some(), rips);
+    }
+    // stop the workers and wait for in-flight probes before saving, so probe
+    // logs are flushed and the final counts are exact
+    info!("stopping workers...");
+    let mut completed = 0;
+    let mut maxima = Vec::with_capacity(threads);
+    let mut positions: Vec<Sketch> = Vec::new();
+    // the probes each worker sent and got replies to
+    let mut counts = Vec::with_capacity(threads);
+    for (i, report) in workers.stop(&done_rx) {
+        debug!("worker {} completed {} probes", i, report.completed);
+        completed += report.completed;
+        counts.push((i, report.sent, report.completed));
+        maxima.push((report.maxima_from, report.maxima));
+        if positions.len() < report.positions.len() {
+            positions.resize(report.positions.len(), Sketch::new());
+        }
+        for (p, sketch) in positions.iter_mut().zip(&report.positions) {
+            p.merge(sketch);
+        }
+    }
+    if let Some((ref mut steering, _)) = steering {
+        steering.remove();
+    }
+    info!("probes: completed: {} recorded: {}", completed, total);
+    if recorded > 0 {
+        info!("probes: recorded over the whole run: {}", recorded + total);
+        info!("run latency: count: {} p50: {} p90: {} p99: {} p999: {}",
+              cumulative.count(),
+              units::latency(cumulative.percentile(50.0)),
+              units::latency(cumulative.percentile(90.0)),
+              units::latency(cumulative.percentile(99.0)),
+              units::latency(cumulative.percentile(99.9)));
+    }
+    for (worker, slot, flow) in control.flows() {
+        info!("flow: worker {} flow {}: {}", worker, slot, flow);
+    }
+    let series = control.target_series();
+    if series.len() > 1 {
+        for (target, windows, latency) in series {
+            info!("target {}: windows: {} count: {} p50: {} p90: {} p99: {} p999: {}",
+                  target,
+                  windows,
+                  latency.count(),
+                  units::latency(latency.percentile(50.0)),
+                  units::latency(latency.percentile(90.0)),
+                  units::latency(latency.percentile(99.0)),
+                  units::latency(latency.percentile(99.9)));
+        }
+    }
+    if !noop {
+        let periods = analysis::periods(&analysis::merge_maxima(&maxima));
+        if periods.is_empty() {
+            info!("periodicity: no periodic latency spikes detected");
+        }
+        for (period, r) in periods {
+            info!("periodicity: latency spikes every {}s (autocorrelation {:.2})", period, r);
+            summary.periods.push(period);
+        }
+    }
+    if burst.is_some() {
+        let mut output = args.create_output(&args.file(BURST_FILE), compression);
+        let _ = metadata.write_header(&mut output);
+        if let Err(e) = write_positions(&mut output, &positions) {
+            error!("unable to write {}: {}", BURST_FILE, e);
+        }
+        if let (Some(first), Some(last)) = (positions.first(), positions.last()) {
+            info!("burst: first: p50: {} p99: {} last: p50: {} p99: {}",
+                  units::latency(first.percentile(50.0)),
+                  units::latency(first.percentile(99.0)),
+                  units::latency(last.percentile(50.0)),
+                  units::latency(last.percentile(99.0)));
+        }
+    }
+    if localize_loss {
+        counts.sort();
+        let mut output = args.create_output(&args.file(LOSS_FILE), compression);
+        let _ = metadata.write_header(&mut output);
+        if let Err(e) = localize(&mut output, control.target(), run_id, &counts) {
+            error!("unable to write {}: {}", LOSS_FILE, e);
+        }
+    }
+    if let Some(ref log) = outlier_log {
+        if let Ok(mut log) = log.lock() {
+            let _ = log.finish();
+        }
+    }
+    if let Some((_, ref mut log)) = route {
+        let _ = log.finish();
+    }
+    if let Some(ref mut log) = drift_log {
+        let _ = log.finish();
+    }
+    if let Some((ref iface, _, ref mut log)) = link {
+        if let Some(since) = down_since {
+            downtime += time::precise_time_ns() - since;
+        }
+        info!("link: {} was down for {}", iface, units::latency(downtime));
+        summary.downtime = Some(downtime);
+        let _ = log.finish();
+    }
+    if let Some((_, ref mut log)) = starvation {
+        info!("host: starved in {} of {} windows", starved, window);
+        summary.starved = Some(starved);
+        let _ = log.finish();
+    }
+    for &(_, sent, completed) in &counts {
+        summary.sent += sent;
+        summary.completed += completed;
+    }
+    if cumulative.count() > 0 {
+        summary.p99 = Some(cumulative.percentile(99.0));
+    }
+    info!("verdict: {}", verdict::verdict(&summary));
+    let _ = window_log.finish();
+
+    info!("saving files...");
+    receiver.save_files();
+    let mut traces = vec![args.file("ok_trace.txt")];
+    if rips {
+        traces.push(args.file("stack_trace.txt"));
+    }
+    for trace in traces {
+        if let Err(e) = output::compress_file(&trace, compression) {
+            error!("unable to compress {}: {}", trace, e);
+        }
+    }
+    if let Some(ref destination) = upload {
+        info!("uploading files...");
+        match destination.upload(&metadata.host, run_id, &outputs) {
+            Ok(count) => info!("upload: {} files uploaded", count),
+            Err(e) => error!("{}", e),
+        }
+    }
+    info!("complete");
+}
+
+// refuses flags which need a network path, kernel sockets or the userspace
+// stack, when the run's mode doesn't probe over one
+fn check_paths(args: &ArgumentParser,
+               noop: bool,
+               network: bool,
+               stdnet: bool,
+               tcp: bool,
+               burst: bool) {
+    if args.get_by_rx().is_some() && (noop || (!stdnet && !tcp) || burst) {
+        args.print_error("--by-rx applies to kernel sockets. Use it with --stdnet or --tcp");
+    }
+    if args.get_watch_link() && !network {
+        args.print_error("--watch-link needs a network path. It cannot be used with --noop or \
+                          --simulate");
+    }
+    if args.get_mtu_probe().is_some() && noop {
+        args.print_error("--mtu-probe needs a network path. It cannot be used with --noop");
+    }
+    if !network {
+        let mode = if noop { "--noop" } else { "--simulate" };
+        for flag in PATH_FLAGS {
+            if args.given(flag) {
+                args.print_error(&format!("--{} applies to the path probes take, and {} sends \
+                                           none. Remove one of them",
+                                          flag,
+                                          mode));
+            }
+        }
+    }
+    for flag in &["gateway", "routes", "src-mac"] {
+        if args.given(flag) && (stdnet || tcp || !network) {
+            args.print_error(&format!("--{} applies to the userspace stack, which is only used \
+                                       without --stdnet, --tcp, --noop and --simulate",
+                                      flag));
+        }
+    }
+    if args.get_so_priority().is_some() && (noop || (!stdnet && !tcp)) {
+        args.print_error("--so-priority applies to kernel sockets. Use it with --stdnet or --tcp");
+    }
+    if args.get_router_alert() && (noop || (!stdnet && !tcp)) {
+        args.print_error("--router-alert applies to kernel sockets. Use it with --stdnet or --tcp");
+    }
+}
+
+// asks the target for the features of a ping-rs reflector over UDP echo,
+// checking them against those the run needs, and tags probes with IDs if the
+// reflector matches replies by them
+fn handshake(args: &ArgumentParser,
+             dst: SocketAddr,
+             udp_echo: bool,
+             key: bool,
+             probe_ids: &mut bool,
+             localize_loss: bool,
+             direction_jitter: bool,
+             padded: bool,
+             pattern: bool)
+             -> Option<capability::Capabilities> {
+    let reflector = if udp_echo && !args.get_no_handshake() {
+        let timeout = Duration::from_millis(HANDSHAKE_TIMEOUT_MS);
+        match capability::query(dst, timeout, 3) {
+            Ok(reflector) => reflector,
+            Err(e) => {
+                warn!("handshake: unable to reach {}: {}", dst, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    match reflector {
+        Some(ref reflector) => {
+            info!("handshake: ping-rs {} reflector, supporting: {}",
+                  reflector.version,
+                  reflector.features.join(" "));
+            if reflector.supports(capability::AUTH) && !key {
+                args.print_error("The reflector only answers signed requests. Pass its key with \
+                                  --key");
+            }
+            if !reflector.supports(capability::AUTH) && key {
+                args.print_error("The reflector doesn't sign its replies, so none would be \
+                                  accepted. Run it with --key too");
+            }
+            if reflector.supports(capability::IDS) && !*probe_ids {
+                // replies to other probes then count as mismatches rather
+                // than as this probe's RTT
+                info!("handshake: tagging probes with IDs to match replies");
+                *probe_ids = true;
+            }
+            if localize_loss && !reflector.supports(capability::LEDGER) {
+                args.print_error("The reflector doesn't tally the probes it receives, so \
+                                  --localize-loss can't tell the directions apart. Upgrade it");
+            }
+            if direction_jitter && !reflector.supports(capability::STAMPS) {
+                args.print_error("The reflector doesn't timestamp its replies, so \
+                                  --direction-jitter can't tell the directions apart. Upgrade it");
+            }
+            if padded && !reflector.supports(capability::PADDING) {
+                warn!("handshake: the reflector doesn't pad replies, so --reply-size has no \
+                       effect");
+            }
+            if padded && pattern && !reflector.supports(capability::PATTERN) {
+                warn!("handshake: the reflector doesn't repeat fill patterns in replies");
+            }
+        }
+        None if udp_echo => {
+            info!("handshake: no answer, assuming an echo service without ping-rs features");
+            if localize_loss && !args.get_no_handshake() {
+                args.print_error("--localize-loss needs a ping-rs reflector, which would have \
+                                  answered the handshake");
+            }
+            if direction_jitter && !args.get_no_handshake() {
+                args.print_error("--direction-jitter needs a ping-rs reflector, which would \
+                                  have answered the handshake");
+            }
+            if padded {
+                warn!("handshake: --reply-size needs a ping-rs reflector, and an echo service \
+                       will answer at the request size");
+            }
+        }
+        None => {}
+    }
+    reflector
+}
+
+// replays the probes of a probe log through tic and the control API as if
+// they were being measured, `speed` times faster than they were, so that the
+// consumers of a run's output can be tried out without a network
+fn replay(args: &ArgumentParser, path: &str, speed: f64) {
+    let mut probes = match compare::load_timed(path) {
+        Ok(probes) => probes,
+        Err(e) => args.print_error(&e),
+    };
+    if probes.is_empty() {
+        args.print_error(&format!("No probes in {}", path));
+    }
+    probes.sort();
+    let duration = args.get_duration();
+    // tic's windows are whole seconds of the replay
+    let seconds = duration as f64 / speed;
+    if seconds < 1.0 || seconds.fract() > 0.0 {
+        args.print_error(&format!("Invalid speed param. A window of {}s replayed {} times \
+                                   faster must last whole seconds",
+                                  duration,
+                                  speed));
+    }
+    let window_ns = duration as u64 * units::SECOND;
+    let first = probes[0].0;
+    let windows = ((probes[probes.len() - 1].0 - first) / window_ns) as usize + 1;
+    let compression = args.get_compression();
+
+    let run_id = probe::run_id();
+    let mut metadata = Metadata::new(run_id);
+    metadata.set("replay", path.to_owned());
+    metadata.set("speed", format!("{}", speed));
+    metadata.set("duration", format!("{}", duration));
+    let outputs = vec![args.file("ok_waterfall.png"),
+                       args.file(METADATA_FILE),
+                       format!("{}{}", args.file("ok_trace.txt"), compression.extension())];
+    if !args.get_force() {
+        let existing: Vec<String> = outputs.into_iter().filter(|p| Path::new(p).exists()).collect();
+        if !existing.is_empty() {
+            args.print_error(&format!("Output files from a previous run exist: {}. Use --force \
+                                       to overwrite them",
+                                      existing.join(", ")));
+        }
+    }
+    {
+        let mut file = args.create_output(&args.file(METADATA_FILE), Compression::None);
+        if let Err(e) = metadata.write_header(&mut file) {
+            args.print_error(&format!("Unable to write {}. {}", METADATA_FILE, e));
+        }
+    }
+
+    let mut receiver = Receiver::configure()
+        .windows(windows)
+        .duration(seconds as usize)
+        .capacity(args.get_stats_qlen())
+        .http_listen(args.get_stats_listen())
+        .build();
+    receiver.add_interest(Interest::Waterfall(Metric::Ok, args.file("ok_waterfall.png")));
+    receiver.add_interest(Interest::Trace(Metric::Ok, args.file("ok_trace.txt")));
+    receiver.add_interest(Interest::Count(Metric::Ok));
+    receiver.add_interest(Interest::Percentile(Metric::Ok));
+
+    // a replay has no target
+    let target = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0));
+    let control = Arc::new(Control::new(target, 0, metadata));
+    if let Some(listen) = args.get_control_listen() {
+        if let Err(e) = control::listen(listen, control.clone()) {
+            args.print_error(&e);
+        }
+    }
+
+    info!("replaying {} probes from {} in {} windows of {}s at {}x",
+          probes.len(),
+          path,
+          windows,
+          duration,
+          speed);
+    let cs = receiver.get_clocksource();
+    let sender = receiver.get_sender();
+    let feeder = cs.clone();
+    thread::spawn(move || feed(&probes, speed, &feeder, &sender));
+
+    let highlight = args.get_highlight();
+    let mut previous = Vec::new();
+    let mut total = 0;
+    for window in 1..windows + 1 {
+        if control.shutdown_requested() {
+            break;
+        }
+        let (w0, t0) = (unix_ns(), cs.time());
+        receiver.run_once();
+        let (w1, t1) = (unix_ns(), cs.time());
+        let m = receiver.clone_meters();
+        let mut c = 0;
+        if let Some(t) = m.get_count(&Metric::Ok) {
+            c = *t - total;
+            total = *t;
+        }
+        // rates are those recorded, not those of the replay
+        let r = c as f64 / duration as f64;
+        info!("window {}: rate: {} rps", window, units::number(r));
+        log_latency_deltas(&m, Metric::Ok, &mut previous, highlight);
+        control.set_summary(window, (w0, t0 as u64), (w1, t1 as u64), r, &m);
+    }
+    info!("probes: replayed: {}", total);
+    info!("saving files...");
+    receiver.save_files();
+    let trace = args.file("ok_trace.txt");
+    if let Err(e) = output::compress_file(&trace, compression) {
+        error!("unable to compress {}: {}", trace, e);
+    }
+    info!("complete");
+}
+
+// sends each probe to tic as it falls due, `speed` times faster than it was
+// sent, with its recorded RTT
+fn feed(probes: &[(u64, u64)], speed: f64, cs: &Clocksource, sender: &Sender<Metric>) {
+    // tic times samples in clocksource counts, so RTTs are scaled to them
+    let c0 = cs.counter();
+    thread::sleep(Duration::from_millis(10));
+    let c1 = cs.counter();
+    let per_ns = (c1 - c0) as f64 / (cs.convert(c1) - cs.convert(c0)) as f64;
+    let start = Instant::now();
+    let first = match probes.first() {
+        Some(&(first, _)) => first,
+        None => return,
+    };
+    for &(sent, rtt) in probes {
+        let due = ((sent - first) as f64 / speed) as u64;
+        let elapsed = start.elapsed();
+        let elapsed = elapsed.as_secs() * units::SECOND + elapsed.subsec_nanos() as u64;
+        if due > elapsed {
+            let wait = due - elapsed;
+            thread::sleep(Duration::new(wait / units::SECOND, (wait % units::SECOND) as u32));
+        }
+        let t = cs.counter();
+        let _ = sender.send(Sample::new(t, t + (rtt as f64 * per_ns) as u64, Metric::Ok));
+    }
+}
+
+// opens the raw channel on the interface and sets up the userspace stack on it
+#[cfg(feature = "userspace")]
+fn create_stack(args: &ArgumentParser, src_net: Ipv4Network) -> Stack {
+    let (mut device, mut iface) = args.get_iface();
+    if let Some(mac) = args.get_src_mac() {
+        info!("sending from {} as {}", device.name, mac);
+        device.mac = Some(mac);
+        iface = match rips::convert_interface(&device) {
+            Ok(iface) => iface,
+            Err(_) => {
+                args.print_error(&format!("Interface {} can't be used with rips as {}",
+                                          device.name,
+                                          mac))
+            }
+        };
+    }
+    let routes = args.get_routes();
+    let channel = args.create_channel();
+    let mut stack = rips::NetworkStack::new();
+    stack.add_interface(iface.clone(), channel).unwrap();
+    stack.add_ipv4(&iface, src_net).unwrap();
+    {
+        let routing_table = stack.routing_table();
+        // the most specific route to a target is taken, so the default route
+        // only carries targets the routes file doesn't cover, unless it gives
+        // one of its own
+        if !routes.iter().any(|route| route.prefix.prefix() == 0) {
+            routing_table.add_route(*DEFAULT_ROUTE, Some(args.get_gw()), iface.clone());
+        }
+        for route in &routes {
+            info!("route: {} via {}", route.prefix, route.gateway);
+            routing_table.add_route(route.prefix, Some(route.gateway), iface.clone());
+        }
+    }
+    Arc::new(Mutex::new(stack))
+}
+
+#[cfg(not(feature = "userspace"))]
+fn create_stack(_: &ArgumentParser, _: Ipv4Network) -> Stack {
+    unreachable!("kernel sockets are always used without the userspace feature")
+}
+
+// runs a worker over `slots` sockets of the userspace stack
+#[cfg(feature = "userspace")]
+fn spawn_rips(args: &ArgumentParser,
+              stack: &Stack,
+              src: SocketAddr,
+              slots: usize,
+              worker: Worker,
+              on_error: OnError,
+              i: usize,
+              done: mpsc::Sender<usize>)
+              -> thread::JoinHandle<Report> {
+    let mut sockets = Vec::with_capacity(slots);
+    for _ in 0..slots {
+        match UdpSocket::bind(stack.clone(), src) {
+            Ok(socket) => sockets.push(socket),
+            Err(e) => args.print_error(&format!("Unable to bind {}. {}", src, e)),
+        }
+    }
+    spawn(worker, on_error, i, done, move |w| w.run_rips(&mut sockets))
+}
+
+#[cfg(not(feature = "userspace"))]
+fn spawn_rips(_: &ArgumentParser,
+              _: &Stack,
+              _: SocketAddr,
+              _: usize,
+              _: Worker,
+              _: OnError,
+              _: usize,
+              _: mpsc::Sender<usize>)
+              -> thread::JoinHandle<Report> {
+    unreachable!("kernel sockets are always used without the userspace feature")
+}
+
+// runs `worker` in a thread of its own with `probe`, the loop of its mode,
+// supervised as `on_error` says
+fn spawn<F>(mut worker: Worker,
+            on_error: OnError,
+            i: usize,
+            done: mpsc::Sender<usize>,
+            probe: F)
+            -> thread::JoinHandle<Report>
+    where F: FnMut(&mut Worker) -> Result<(), String> + Send + 'static
+{
+    thread::spawn(move || {
+        worker.supervise(on_error, probe);
+        finish(worker, i, &done)
+    })
+}
+
+// runs a worker sending bursts of `size` probes from a kernel socket
+fn spawn_burst(args: &ArgumentParser,
+               src: SocketAddr,
+               size: usize,
+               worker: Worker,
+               on_error: OnError,
+               i: usize,
+               done: mpsc::Sender<usize>)
+               -> thread::JoinHandle<Report> {
+    let socket = match std::net::UdpSocket::bind(src) {
+        Ok(socket) => socket,
+        Err(e) => args.print_error(&format!("Unable to bind {}. {}", src, e)),
+    };
+    spawn(worker, on_error, i, done, move |w| w.run_burst(&socket, size))
+}
+
+// runs a worker over `slots` kernel sockets for each of `sources`, or for
+// `src` if there are none, steering their replies to the worker's queue
+fn spawn_stdnet(args: &ArgumentParser,
+                src: SocketAddr,
+                sources: &[Ipv4Addr],
+                slots: usize,
+                steering: &mut Option<(steer::Steering, usize)>,
+                worker: Worker,
+                on_error: OnError,
+                i: usize,
+                done: mpsc::Sender<usize>)
+                -> thread::JoinHandle<Report> {
+    // one set of slots per source address, in order
+    let srcs = if sources.is_empty() {
+        vec![src]
+    } else {
+        sources.iter().map(|&ip| SocketAddr::V4(SocketAddrV4::new(ip, 0))).collect()
+    };
+    let mut sockets = Vec::with_capacity(slots * srcs.len());
+    for src in srcs {
+        for _ in 0..slots {
+            match std::net::UdpSocket::bind(src) {
+                Ok(socket) => sockets.push(socket),
+                Err(e) => args.print_error(&format!("Unable to bind {}. {}", src, e)),
+            }
+        }
+    }
+    if let Some((ref mut steering, queues)) = *steering {
+        for socket in &sockets {
+            let port = socket.local_addr().unwrap().port();
+            let queue = i % queues;
+            if let Err(e) = steering.add(port, queue) {
+                steering.remove();
+                args.print_error(&e);
+            }
+            info!("steering: worker {} port {} to rx queue {}", i, port, queue);
+        }
+    }
+    spawn(worker, on_error, i, done, move |w| w.run_stdnet(&sockets))
+}
+
+// drops the worker, flushing its logs, before reporting it as done
+fn finish(worker: Worker, id: usize, done: &mpsc::Sender<usize>) -> Report {
+    let report = worker.into_report();
+    let _ = done.send(id);
+    report
+}
+
+// logs and resets what the workers collected beyond tic's metrics during the
+// last window, e.g. schedule error and response sizes, publishes its latency
+// alongside that of the whole run and returns it
+fn log_distributions(distributions: &[Arc<Mutex<Distributions>>],
+                     control: &Control,
+                     cumulative: &mut Sketch,
+                     bins: &mut Bins,
+                     pacing: Pacing,
+                     by_rx: Option<RxKey>,
+                     sources: &[Ipv4Addr])
+                     -> Sketch {
+    let mut merged = Distributions::new(0);
+    for d in distributions {
+        let mut d = d.lock().unwrap();
+        merged.merge(&d);
+        d.clear();
+    }
+    cumulative.merge(&merged.latency);
+    for (&bin, sketch) in &merged.time_of_day {
+        bins.time_of_day.entry(bin).or_insert_with(Sketch::new).merge(sketch);
+    }
+    for (&bucket, sketch) in &merged.subsecond {
+        bins.subsecond.entry(bucket).or_insert_with(Sketch::new).merge(sketch);
+    }
+    for (&bucket, sketch) in &merged.by_size {
+        bins.by_size.entry(bucket).or_insert_with(Sketch::new).merge(sketch);
+    }
+    if !merged.by_size.is_empty() {
+        control.set_by_size(&merged.by_size, &bins.by_size);
+    }
+    // large replies slowed by the return path stand out from congestion
+    // slowing every size alike
+    for (bytes, sketch) in &merged.by_size {
+        info!("reply size {} B and up: count: {} p50: {} p90: {} p99: {} p999: {}",
+              bytes,
+              sketch.count(),
+              units::latency(sketch.percentile(50.0)),
+              units::latency(sketch.percentile(90.0)),
+              units::latency(sketch.percentile(99.0)),
+              units::latency(sketch.percentile(99.9)));
+    }
+    log_subsecond(&merged.subsecond);
+    control.set_percentiles(&merged.latency, cumulative);
+    let schedule = &merged.schedule;
+    if schedule.count() > 0 {
+        info!("schedule error ({}): p50: {} p90: {} p99: {} p999: {}",
+              pacing.name(),
+              units::latency(schedule.percentile(50.0)),
+              units::latency(schedule.percentile(90.0)),
+              units::latency(schedule.percentile(99.0)),
+              units::latency(schedule.percentile(99.9)));
+    }
+    if !merged.rx_queues.is_empty() {
+        // a NAPI ID of 0 means the kernel didn't record the queue
+        let queues: Vec<String> = merged.rx_queues
+            .iter()
+            .map(|(&(worker, napi), count)| format!("worker {} napi {}: {}", worker, napi, count))
+            .collect();
+        info!("rx queues: {}", queues.join(" "));
+    }
+    if let Some(key) = by_rx {
+        for (rx, sketch) in &merged.by_rx {
+            info!("rx {} {}: count: {} p50: {} p90: {} p99: {} p999: {}",
+                  key.name(),
+                  rx,
+                  sketch.count(),
+                  units::latency(sketch.percentile(50.0)),
+                  units::latency(sketch.percentile(90.0)),
+                  units::latency(sketch.percentile(99.0)),
+                  units::latency(sketch.percentile(99.9)));
+        }
+    }
+    for (&source, &sent) in &merged.source_probes {
+        let empty = Sketch::new();
+        let sketch = merged.by_source.get(&source).unwrap_or(&empty);
+        let loss = 100.0 * (sent - cmp::min(sketch.count(), sent)) as f64 / sent as f64;
+        info!("source {}: sent: {} loss: {:.2}% p50: {} p90: {} p99: {} p999: {}",
+              sources[source as usize],
+              sent,
+              loss,
+              units::latency(sketch.percentile(50.0)),
+              units::latency(sketch.percentile(90.0)),
+              units::latency(sketch.percentile(99.0)),
+              units::latency(sketch.percentile(99.9)));
+    }
+    if merged.rtt_jitter.count() > 0 {
+        // the direction with the larger jitter is where queues built up
+        info!("jitter: forward: p50: {} p99: {} return: p50: {} p99: {} rtt: p50: {} p99: {}",
+              units::latency(merged.forward_jitter.percentile(50.0)),
+              units::latency(merged.forward_jitter.percentile(99.0)),
+              units::latency(merged.return_jitter.percentile(50.0)),
+              units::latency(merged.return_jitter.percentile(99.0)),
+              units::latency(merged.rtt_jitter.percentile(50.0)),
+              units::latency(merged.rtt_jitter.percentile(99.0)));
+    }
+    let sizes = &merged.sizes;
+    if sizes.count() > 0 {
+        info!("response size: p0: {} B p50: {} B p99: {} B p100: {} B",
+              sizes.percentile(0.0),
+              sizes.percentile(50.0),
+              sizes.percentile(99.0),
+              sizes.percentile(100.0));
+    }
+    if merged.slowest.capacity() > 0 {
+        for (rank, slow) in merged.slowest.probes().iter().enumerate() {
+            info!("slowest {}: worker: {} seq: {} sent: {} monotonic: {} rtt: {}",
+                  rank + 1,
+                  slow.worker,
+                  slow.seq,
+                  slow.sent,
+                  slow.monotonic,
+                  units::latency(slow.rtt));
+        }
+        control.set_slowest(merged.slowest.probes());
+    }
+    merged.latency
+}
+
+/// The workers of a run, which are stopped and waited for if the run unwinds,
+/// as a job does on an error, so that they don't go on probing without it
+struct Workers {
+    control: Arc<Control>,
+    handles: Vec<Option<thread::JoinHandle<Report>>>,
+}
+
+impl Workers {
+    /// Stops the workers and collects the reports of those which finish
+    /// within the shutdown grace period, as they announce on `done`
+    fn stop(&mut self, done: &mpsc::Receiver<usize>) -> Vec<(usize, Report)> {
+        self.control.stop();
+        let deadline = Instant::now() + Duration::from_millis(SHUTDOWN_GRACE_MS);
+        let threads = self.handles.len();
+        let mut stopped = 0;
+        let mut reports = Vec::with_capacity(threads);
+        while stopped < threads {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match done.recv_timeout(deadline - now) {
+                Ok(i) => {
+                    if let Some(handle) = self.handles[i].take() {
+                        match handle.join() {
+                            Ok(report) => reports.push((i, report)),
+                            Err(_) => warn!("worker {} panicked", i),
+                        }
+                    }
+                    stopped += 1;
+                }
+                Err(_) => break,
+            }
+        }
+        if stopped < threads {
+            warn!("{} workers did not stop within {} ms", threads - stopped, SHUTDOWN_GRACE_MS);
+        }
+        reports
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        self.control.stop();
+        for handle in self.handles.iter_mut().filter_map(|h| h.take()) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Latency binned over the whole run
+#[derive(Default)]
+struct Bins {
+    /// By the minute of the local day starting each bin
+    time_of_day: BTreeMap<u32, Sketch>,
+    /// By the millisecond of the second starting each bucket
+    subsecond: BTreeMap<u32, Sketch>,
+    /// By the reply size starting each bucket
+    by_size: BTreeMap<u32, Sketch>,
+}
+
+// samples the host's scheduling over the window just ended into `log`,
+// returning whether it starved the run's threads
+fn sample_starvation(window: usize, sampler: &mut starvation::Sampler, log: &mut Output) -> bool {
+    match sampler.sample() {
+        Ok(s) => {
+            if s.starved {
+                warn!("window {}: host starved: run queue wait: {:.1}% steal: {:.1}% \
+                       involuntary context switches: {}. Latency may be this host's rather \
+                       than the network's",
+                      window,
+                      s.wait,
+                      s.steal,
+                      s.involuntary);
+            }
+            let _ = writeln!(log,
+                             "{} {} {:.2} {:.2} {}",
+                             window,
+                             s.involuntary,
+                             s.wait,
+                             s.steal,
+                             s.starved);
+            let _ = log.flush();
+            s.starved
+        }
+        Err(e) => {
+            warn!("host starvation: {}", e);
+            false
+        }
+    }
+}
+
+// logs the link changes of `iface` seen during the window to `log`,
+// returning the downtime of the link's outages that ended in it. The start of
+// one still going on is kept in `down_since`
+fn log_link_events(window: usize,
+                   iface: &str,
+                   events: &Mutex<Vec<link::LinkEvent>>,
+                   log: &mut Output,
+                   down_since: &mut Option<u64>)
+                   -> u64 {
+    let mut downtime = 0;
+    for event in events.lock().unwrap().drain(..) {
+        let state = if event.up { "up" } else { "down" };
+        info!("window {}: link {} {}", window, iface, state);
+        let _ = writeln!(log, "{} {} {} {}", window, event.unix, event.monotonic, state);
+        let _ = log.flush();
+        if event.up {
+            if let Some(since) = down_since.take() {
+                downtime += event.monotonic - since;
+            }
+        } else {
+            *down_since = Some(event.monotonic);
+        }
+    }
+    downtime
+}
+
+// logs the clocksource's recalibrations during the window to `log`
+fn log_recalibrations(window: usize,
+                      recalibrations: &Mutex<Vec<drift::Recalibration>>,
+                      log: &mut Output) {
+    for r in recalibrations.lock().unwrap().drain(..) {
+        info!("window {}: clocksource recalibrated after drifting {} ({:.3} ppm)",
+              window,
+              units::difference(r.offset),
+              r.ppm);
+        let _ = writeln!(log, "{} {} {} {} {:.3}", window, r.unix, r.monotonic, r.offset, r.ppm);
+        let _ = log.flush();
+    }
+}
+
+// checks the minimum RTT of the window ended at `end`, as unix and monotonic
+// ns, against the one `lock` holds, logging a shift to `log`. Returns whether
+// the route changed
+fn observe_route(window: usize,
+                 end: (u64, u64),
+                 latency: &Sketch,
+                 lock: &mut route::MinimumLock,
+                 log: &mut Output)
+                 -> bool {
+    let minimum = latency.percentile(0.0);
+    match lock.observe(minimum) {
+        Some(previous) => {
+            warn!("route change: minimum rtt {} -> {} ({})",
+                  units::latency(previous),
+                  units::latency(minimum),
+                  units::difference(minimum as i64 - previous as i64));
+            let _ = writeln!(log, "{} {} {} {} {}", end.0, end.1, window, previous, minimum);
+            let _ = log.flush();
+            true
+        }
+        None => false,
+    }
+}
+
+// rewrites the files of latency binned over the run that are enabled, each
+// window, so a continuous run always has them current
+fn write_bins(args: &ArgumentParser,
+              metadata: &Metadata,
+              compression: Compression,
+              bins: &Bins,
+              time_of_day: Option<u32>,
+              by_size: Option<u32>,
+              subsecond: Option<u32>) {
+    if time_of_day.is_some() {
+        let mut output = args.create_output(&args.file(TIME_OF_DAY_FILE), compression);
+        let _ = metadata.write_header(&mut output);
+        let written = write_time_of_day(&mut output, &bins.time_of_day);
+        if let Err(e) = written.and_then(|_| output.finish()) {
+            error!("unable to write {}: {}", TIME_OF_DAY_FILE, e);
+        }
+    }
+    if let Some(bytes) = by_size {
+        let mut output = args.create_output(&args.file(BY_SIZE_FILE), compression);
+        let _ = metadata.write_header(&mut output);
+        let written = write_by_size(&mut output, bytes, &bins.by_size);
+        if let Err(e) = written.and_then(|_| output.finish()) {
+            error!("unable to write {}: {}", BY_SIZE_FILE, e);
+        }
+    }
+    if let Some(ms) = subsecond {
+        let mut output = args.create_output(&args.file(SUBSECOND_FILE), compression);
+        let _ = metadata.write_header(&mut output);
+        let written = write_subsecond(&mut output, ms, &bins.subsecond);
+        if let Err(e) = written.and_then(|_| output.finish()) {
+            error!("unable to write {}: {}", SUBSECOND_FILE, e);
+        }
+    }
+}
+
+// compares the first and last buckets of the second, and names the slowest,
+// as a policer refilling once a second delays or drops the probes sent late
+// in it
+fn log_subsecond(buckets: &BTreeMap<u32, Sketch>) {
+    let (first, last) = match (buckets.iter().next(), buckets.iter().next_back()) {
+        (Some(first), Some(last)) if first.0 != last.0 => (first, last),
+        _ => return,
+    };
+    let slowest = buckets.iter()
+        .max_by_key(|&(_, sketch)| sketch.percentile(99.0))
+        .unwrap();
+    info!("subsecond: {} ms: count: {} p50: {} p99: {} {} ms: count: {} p50: {} p99: {} \
+           slowest: {} ms: p99: {}",
+          first.0,
+          first.1.count(),
+          units::latency(first.1.percentile(50.0)),
+          units::latency(first.1.percentile(99.0)),
+          last.0,
+          last.1.count(),
+          units::latency(last.1.percentile(50.0)),
+          units::latency(last.1.percentile(99.0)),
+          slowest.0,
+          units::latency(slowest.1.percentile(99.0)));
+}
+
+// wall-clock time in ns since the unix epoch
+fn unix_ns() -> u64 {
+    let now = time::get_time();
+    now.sec as u64 * 1_000_000_000 + now.nsec as u64
+}
+
+// one line per position within a burst: `<position> <count> <p50> <p90>
+// <p99> <p999>`, with latencies in the configured unit
+fn write_positions<W: Write>(w: &mut W, positions: &[Sketch]) -> std::io::Result<()> {
+    try!(writeln!(w, "# position count p50 p90 p99 p999 ({})", units::unit().name()));
+    for (position, sketch) in positions.iter().enumerate() {
+        try!(writeln!(w,
+                      "{} {} {} {} {} {}",
+                      position,
+                      sketch.count(),
+                      units::value(sketch.percentile(50.0)),
+                      units::value(sketch.percentile(90.0)),
+                      units::value(sketch.percentile(99.0)),
+                      units::value(sketch.percentile(99.9))));
+    }
+    Ok(())
+}
+
+// compares the probes each worker sent and got replies to with the
+// reflector's tally of them at `target`: a probe the reflector didn't receive
+// was lost on the way there, and one it received without a reply arriving on
+// the way back. One line per worker: `<worker> <sent> <reflected> <returned>
+// <forward lost> <return lost> <duplicates>`
+fn localize<W: Write>(w: &mut W,
+                      target: SocketAddr,
+                      run_id: u64,
+                      counts: &[(usize, u64, u64)])
+                      -> std::io::Result<()> {
+    try!(writeln!(w, "# worker sent reflected returned forward_lost return_lost duplicates"));
+    let timeout = Duration::from_millis(HANDSHAKE_TIMEOUT_MS);
+    // sent, reflected and returned over all workers
+    let mut total = (0, 0, 0);
+    for &(worker, sent, returned) in counts {
+        let stream = probe::stream(run_id, worker);
+        let tally = match ledger::query(target, &stream, timeout, 3) {
+            Ok(Some(tally)) => tally,
+            Ok(None) => {
+                warn!("loss: no tally of worker {} from {}", worker, target);
+                continue;
+            }
+            Err(e) => {
+                warn!("loss: unable to reach {}: {}", target, e);
+                continue;
+            }
+        };
+        try!(writeln!(w,
+                      "{} {} {} {} {} {} {}",
+                      worker,
+                      sent,
+                      tally.received,
+                      returned,
+                      sent.saturating_sub(tally.received),
+                      tally.received.saturating_sub(returned),
+                      tally.duplicates));
+        total.0 += sent;
+        total.1 += tally.received;
+        total.2 += returned;
+    }
+    let forward = total.0.saturating_sub(total.1);
+    let back = total.1.saturating_sub(total.2);
+    info!("loss: forward: {} of {} ({:.3}%) return: {} of {} ({:.3}%)",
+          forward,
+          total.0,
+          100.0 * forward as f64 / cmp::max(total.0, 1) as f64,
+          back,
+          total.1,
+          100.0 * back as f64 / cmp::max(total.1, 1) as f64);
+    Ok(())
+}
+
+// one line per time-of-day bin: `<HH:MM> <count> <p50> <p90> <p99> <p999>`,
+// each bin named by the local time at which it starts
+fn write_time_of_day<W: Write>(w: &mut W, bins: &BTreeMap<u32, Sketch>) -> std::io::Result<()> {
+    try!(writeln!(w, "# bin count p50 p90 p99 p999 ({})", units::unit().name()));
+    for (minute, sketch) in bins {
+        try!(writeln!(w,
+                      "{:02}:{:02} {} {} {} {} {}",
+                      minute / 60,
+                      minute % 60,
+                      sketch.count(),
+                      units::value(sketch.percentile(50.0)),
+                      units::value(sketch.percentile(90.0)),
+                      units::value(sketch.percentile(99.0)),
+                      units::value(sketch.percentile(99.9))));
+    }
+    Ok(())
+}
+
+// one line per reply size bucket: `<from bytes> <to bytes> <count> <p50> <p90>
+// <p99> <p999>`, over the run so far
+fn write_by_size<W: Write>(w: &mut W,
+                           bytes: u32,
+                           buckets: &BTreeMap<u32, Sketch>)
+                           -> std::io::Result<()> {
+    try!(writeln!(w, "# from to count p50 p90 p99 p999 ({})", units::unit().name()));
+    for (from, sketch) in buckets {
+        try!(writeln!(w,
+                      "{} {} {} {} {} {} {}",
+                      from,
+                      from + bytes - 1,
+                      sketch.count(),
+                      units::value(sketch.percentile(50.0)),
+                      units::value(sketch.percentile(90.0)),
+                      units::value(sketch.percentile(99.0)),
+                      units::value(sketch.percentile(99.9))));
+    }
+    Ok(())
+}
+
+// one line per bucket of the second: `<start ms> <end ms> <count> <p50> <p90>
+// <p99> <p999>`, where counts falling off towards the end of the second show
+// probes dropped by a per-second limit
+fn write_subsecond<W: Write>(w: &mut W,
+                             ms: u32,
+                             buckets: &BTreeMap<u32, Sketch>)
+                             -> std::io::Result<()> {
+    try!(writeln!(w, "# start end count p50 p90 p99 p999 ({})", units::unit().name()));
+    for (start, sketch) in buckets {
+        try!(writeln!(w,
+                      "{} {} {} {} {} {} {}",
+                      start,
+                      start + ms,
+                      sketch.count(),
+                      units::value(sketch.percentile(50.0)),
+                      units::value(sketch.percentile(90.0)),
+                      units::value(sketch.percentile(99.0)),
+                      units::value(sketch.percentile(99.9))));
+    }
+    Ok(())
+}
+
+// logs the window's failed probes and odd responses, by kind: every error
+// with kernel sockets or a simulated path, which can tell them apart, and
+// mismatches otherwise. On the userspace stack, time spent in it is logged too
+fn log_failures(meters: &Meters<Metric>, by_kind: bool, rips: bool) {
+    if let Some(errors) = meters.get_count(&Metric::WorkerError) {
+        if *errors > 0 {
+            warn!("worker errors: {}", errors);
+        }
+    }
+    if by_kind {
+        log_errors(meters);
+    } else if let Some(mismatched) = meters.get_count(&Metric::Mismatch) {
+        info!("mismatches: {}", mismatched);
+    }
+    if let Some(failed) = meters.get_count(&Metric::SendError) {
+        if *failed > 0 {
+            warn!("send errors: {} backoffs: {}",
+                  failed,
+                  meters.get_count(&Metric::Backoff).unwrap_or(&0));
+        }
+    }
+    if let Some(failed) = meters.get_count(&Metric::MtuBlackhole) {
+        if *failed > 0 {
+            warn!("mtu blackhole checks failed: {}", failed);
+        }
+    }
+    if let Some(truncated) = meters.get_count(&Metric::Truncated) {
+        if *truncated > 0 {
+            warn!("truncated responses: {}", truncated);
+        }
+    }
+    if let Some(malformed) = meters.get_count(&Metric::Malformed) {
+        if *malformed > 0 {
+            warn!("malformed responses: {}", malformed);
+        }
+    }
+    if let Some(failures) = meters.get_count(&Metric::AuthFailure) {
+        if *failures > 0 {
+            warn!("authentication failures: {}", failures);
+        }
+    }
+    if let Some(mismatched) = meters.get_count(&Metric::SizeMismatch) {
+        info!("size mismatches: {}", mismatched);
+    }
+    if let Some(outliers) = meters.get_count(&Metric::Outlier) {
+        info!("outliers: {}", outliers);
+    }
+    if rips {
+        log_latency(meters, Metric::Stack);
+    }
+}
+
+fn log_errors(meters: &Meters<Metric>) {
+    let counts: Vec<String> = ERROR_METRICS.iter()
+        .map(|metric| format!("{}: {}", metric, meters.get_count(metric).unwrap_or(&0)))
+        .collect();
+    info!("errors: {}", counts.join(" "));
+}
+
+fn log_latency(meters: &Meters<Metric>, metric: Metric) {
+    let values: Vec<String> = PERCENTILES.iter()
+        .map(|&(name, p)| {
+            let value = *meters.get_percentile(&metric, Percentile(name.to_owned(), p))
+                .unwrap_or(&0);
+            format!("{}: {}", name, units::latency(value))
+        })
+        .collect();
+    info!("{} latency: {}", metric, values.join(" "));
+}
+
+// logs a window's latency percentiles with their change since the previous
+// window, so that drift shows during a run. Rises of more than `highlight`
+// percent are shown in red on a terminal, and marked with '!' otherwise
+fn log_latency_deltas(meters: &Meters<Metric>,
+                      metric: Metric,
+                      previous: &mut Vec<u64>,
+                      highlight: Option<f64>)
+                      -> bool {
+    let current: Vec<u64> = PERCENTILES.iter()
+        .map(|&(name, p)| {
+            *meters.get_percentile(&metric, Percentile(name.to_owned(), p)).unwrap_or(&0)
+        })
+        .collect();
+    let terminal = highlight.is_some() && logging::is_terminal();
+    let mut any = false;
+    let values: Vec<String> = PERCENTILES.iter()
+        .zip(&current)
+        .enumerate()
+        .map(|(i, (&(name, _), &value))| {
+            let before = match previous.get(i) {
+                Some(&before) if before > 0 && value > 0 => before,
+                _ => return format!("{}: {}", name, units::latency(value)),
+            };
+            let delta = units::difference(value as i64 - before as i64);
+            let regressed = highlight.map_or(false, |h| {
+                value as f64 > before as f64 * (1.0 + h / 100.0)
+            });
+            any |= regressed;
+            match (regressed, terminal) {
+                (true, true) => {
+                    format!("{}: {} (\x1b[31m{}\x1b[0m)", name, units::latency(value), delta)
+                }
+                (true, false) => format!("{}: {} ({} !)", name, units::latency(value), delta),
+                (false, _) => format!("{}: {} ({})", name, units::latency(value), delta),
+            }
+        })
+        .collect();
+    info!("{} latency: {}", metric, values.join(" "));
+    *previous = current;
+    any
+}
+
+// logs latency percentiles less the same percentiles of the noop baseline,
+// so harness overhead isn't attributed to the network
+fn log_adjusted(meters: &Meters<Metric>, metric: Metric, baseline: &Sketch) {
+    let values: Vec<String> = PERCENTILES.iter()
+        .map(|&(name, p)| {
+            let raw = *meters.get_percentile(&metric, Percentile(name.to_owned(), p)).unwrap_or(&0);
+            format!("{}: {}", name, units::latency(raw.saturating_sub(baseline.percentile(p))))
+        })
+        .collect();
+    info!("{} latency (less noop baseline): {}", metric, values.join(" "));
+}
+
+// turns the escapes `\r`, `\n` and `\\` given on the command line into bytes
+fn unescape(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut chars = s.bytes();
+    while let Some(c) = chars.next() {
+        if c != b'\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(b'r') => out.push(b'\r'),
+            Some(b'n') => out.push(b'\n'),
+            Some(c) => out.push(c),
+            None => out.push(b'\\'),
+        }
+    }
+    out
+}
+
+struct ArgumentParser {
+    app: clap::App<'static, 'static>,
+    matches: clap::ArgMatches<'static>,
+    /// The job of a jobs file these are the arguments of, if any
+    job: Option<jobs::Job>,
+}
+
+impl ArgumentParser {
+    pub fn new() -> ArgumentParser {
+        let app = Self::create_app();
+        let matches = app.clone().get_matches();
+        ArgumentParser {
+            app: app,
+            matches: matches,
+            job: None,
+        }
+    }
+
+    /// The arguments of a line of a jobs file, as if given on the command
+    /// line
+    pub fn for_job(job: &jobs::Job) -> ArgumentParser {
+        let app = Self::create_app();
+        let args = Some("ping-rs".to_owned()).into_iter().chain(job.args.iter().cloned());
+        let matches = match app.clone().get_matches_from_safe(args) {
+            Ok(matches) => matches,
+            Err(e) => {
+                eprintln!("ERROR: job {}: {}", job.name, e.message);
+                process::exit(1);
+            }
+        };
+        if let Some(name) = matches.subcommand_name() {
+            eprintln!("ERROR: job {}: the {} subcommand can't be run as a job", job.name, name);
+            process::exit(1);
+        }
+        ArgumentParser {
+            app: app,
+            matches: matches,
+            job: Some(job.clone()),
+        }
+    }
+
+    /// The name of the job these are the arguments of, if any
+    pub fn get_job_name(&self) -> Option<&str> {
+        self.job.as_ref().map(|job| &job.name[..])
+    }
+
+    /// `path` within the job's directory, for a job and a relative path
+    pub fn file(&self, path: &str) -> String {
+        match self.job {
+            Some(ref job) if Path::new(path).is_relative() => format!("{}/{}", job.dir, path),
+            _ => path.to_owned(),
+        }
+    }
+
+    /// Where tic serves the run's stats, on a port of its own for each job
+    pub fn get_stats_listen(&self) -> String {
+        let index = self.job.as_ref().map_or(0, |job| job.index);
+        format!("0.0.0.0:{}", STATS_PORT as usize + index)
+    }
+
+    /// The jobs file to run
+    pub fn get_jobs(&self) -> Option<String> {
+        self.matches
+            .subcommand_matches("jobs")
+            .and_then(|matches| matches.value_of("file"))
+            .map(|path| path.to_owned())
+    }
+
+    /// Where the control API of every job is served
+    pub fn get_jobs_control(&self) -> Option<SocketAddr> {
+        let matches = match self.matches.subcommand_matches("jobs") {
+            Some(matches) if matches.is_present("control") => matches,
+            _ => return None,
+        };
+        match value_t!(matches, "control", SocketAddr) {
+            Ok(v) => Some(v),
+            Err(e) => self.print_error(&format!("Invalid control param. {}", e)),
+        }
+    }
+
+    #[cfg(feature = "userspace")]
+    pub fn get_iface(&self) -> (NetworkInterface, rips::Interface) {
+        let iface_name = self.matches.value_of("iface").unwrap();
+        for iface in datalink::interfaces() {
+            if iface.name == iface_name {
+                if let Ok(rips_iface) = rips::convert_interface(&iface) {
+                    return (iface, rips_iface);
+                } else {
+                    self.print_error(&format!("Interface {} can't be used with rips", iface_name));
+                }
+            }
+        }
+        self.print_error(&format!("Found no interface named {}", iface_name));
+    }
+
+    #[cfg(feature = "userspace")]
+    pub fn get_src_net(&self) -> Ipv4Network {
+        if let Some(src_net) = self.matches.value_of("src_net") {
+            match Ipv4Network::from_cidr(src_net) {
+                Ok(src_net) => src_net,
+                Err(_) => self.print_error("Invalid CIDR"),
+            }
+        } else {
+            let (iface, _) = self.get_iface();
+            match iface::ipv4_networks(&iface.name) {
+                Ok(networks) => {
+                    if let Some(network) = networks.first() {
+                        return *network;
+                    }
+                }
+                Err(e) => debug!("unable to list addresses of {}: {}", iface.name, e),
+            }
+            // fall back to the addresses pnet found, whose prefix is unknown
+            if let Some(ips) = iface.ips.as_ref() {
+                for ip in ips {
+                    if let IpAddr::V4(ip) = *ip {
+                        return Ipv4Network::new(ip, 24).unwrap();
+                    }
+                }
+            }
+            self.print_error("No IPv4 to use on given interface");
+        }
+    }
+
+    /// Without the userspace feature interfaces aren't enumerated, so unless
+    /// `--ip` is given the kernel picks the source address
+    #[cfg(not(feature = "userspace"))]
+    pub fn get_src_net(&self) -> Ipv4Network {
+        match self.matches.value_of("src_net") {
+            Some(src_net) => {
+                match Ipv4Network::from_cidr(src_net) {
+                    Ok(src_net) => src_net,
+                    Err(_) => self.print_error("Invalid CIDR"),
+                }
+            }
+            None => Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), 0).unwrap(),
+        }
+    }
+
+    /// The MAC the userspace stack sends from and answers ARP for, in place
+    /// of the interface's own
+    #[cfg(feature = "userspace")]
+    pub fn get_src_mac(&self) -> Option<MacAddr> {
+        let value = match self.matches.value_of("src-mac") {
+            Some(value) => value,
+            None => return None,
+        };
+        let mac = if value == "random" {
+            // xorshift of the time, as nothing depends on it being unguessable
+            let mut x = time::precise_time_ns() | 1;
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let b: Vec<u8> = (0..6).map(|i| (x >> (8 * i)) as u8).collect();
+            // locally administered and unicast
+            MacAddr::new((b[0] & 0xfc) | 0x02, b[1], b[2], b[3], b[4], b[5])
+        } else {
+            match MacAddr::from_str(value) {
+                Ok(mac) => mac,
+                Err(_) => self.print_error(&format!("Invalid src-mac param. {}", value)),
+            }
+        };
+        if mac.0 & 0x01 != 0 {
+            self.print_error(&format!("Invalid src-mac param. {} is a multicast address", mac));
+        }
+        Some(mac)
+    }
+
+    /// The routes of the `--routes` file, if one was given
+    #[cfg(feature = "userspace")]
+    pub fn get_routes(&self) -> Vec<routes::Route> {
+        match self.matches.value_of("routes") {
+            Some(path) => {
+                match routes::load(path) {
+                    Ok(routes) => routes,
+                    Err(e) => self.print_error(&e),
+                }
+            }
+            None => Vec::new(),
+        }
+    }
+
+    #[cfg(feature = "userspace")]
+    pub fn get_gw(&self) -> Ipv4Addr {
+        if let Some(gw_str) = self.matches.value_of("gw") {
+            if let Ok(gw) = Ipv4Addr::from_str(gw_str) {
+                gw
+            } else {
+                self.print_error("Unable to parse gateway ip");
+            }
+        } else {
+            let iface = self.matches.value_of("iface").unwrap();
+            if let Some(gw) = iface::default_gateway(iface) {
+                return gw;
+            }
+            let src_net = self.get_src_net();
+            if let Some(gw) = src_net.nth(1) {
+                gw
+            } else {
+                self.print_error(&format!("Could not guess a default gateway inside {}", src_net));
+            }
+        }
+    }
+
+    pub fn get_dst(&self) -> SocketAddr {
+        let target = self.matches.value_of("target").unwrap();
+        let addrs = match target::resolve(target) {
+            Ok(addrs) => addrs,
+            Err(e) => self.print_error(&format!("Invalid target. {}", e)),
+        };
+        if addrs.len() == 1 || !self.matches.is_present("race") {
+            return addrs[0];
+        }
+        let matches = &self.matches;
+        let count = match value_t!(matches, "race", Count) {
+            Ok(Count(v)) => v as usize,
+            Err(e) => self.print_error(&format!("Invalid race param. {}", e)),
+        };
+        info!("racing {} probes to each of {} addresses for {}", count, addrs.len(), target);
+        let results = target::race(&addrs, count, Duration::from_millis(RACE_TIMEOUT_MS));
+        for &(addr, rtt) in &results {
+            match rtt {
+                Some(rtt) => info!("race: {} initial rtt: {}", addr, units::latency(rtt)),
+                None => info!("race: {} did not answer", addr),
+            }
+        }
+        match target::fastest(&results) {
+            Some(addr) => {
+                info!("race: selected {}", addr);
+                addr
+            }
+            None => self.print_error(&format!("No address for {} answered", target)),
+        }
+    }
+
+    /// Further targets to rotate through, one per window, after the first
+    pub fn get_rotate(&self) -> Vec<SocketAddr> {
+        match self.matches.values_of("rotate") {
+            Some(targets) => {
+                targets.map(|target| match target::resolve(target) {
+                        Ok(addrs) => addrs[0],
+                        Err(e) => {
+                            self.print_error(&format!("Invalid rotate target {}. {}", target, e))
+                        }
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get_windows(&self) -> usize {
+        let matches = &self.matches;
+        match value_t!(matches, "windows", Count) {
+            Ok(Count(v)) => v as usize,
+            Err(e) => self.print_error(&format!("Invalid windows param. {}", e)),
+        }
+    }
+
+    pub fn get_duration(&self) -> usize {
+        let matches = &self.matches;
+        match value_t!(matches, "duration", Span) {
+            Ok(v) => {
+                match v.whole(units::SECOND) {
+                    Some(v) => v as usize,
+                    None => self.print_error("Invalid duration param. Must be whole seconds"),
+                }
+            }
+            Err(e) => self.print_error(&format!("Invalid duration param. {}", e)),
+        }
+    }
+
+    pub fn get_stats_qlen(&self) -> usize {
+        let matches = &self.matches;
+        match value_t!(matches, "stats-qlen", Count) {
+            Ok(Count(v)) => v as usize,
+            Err(e) => self.print_error(&format!("Invalid stats-qlen param. {}", e)),
+        }
+    }
+
+    pub fn get_threads(&self) -> usize {
+        let matches = &self.matches;
+        match value_t!(matches, "threads", Count) {
+            Ok(Count(v)) => v as usize,
+            Err(e) => self.print_error(&format!("Invalid threads param. {}", e)),
+        }
+    }
+
+    pub fn get_flows(&self) -> Option<usize> {
+        if self.matches.is_present("flows") {
+            let matches = &self.matches;
+            match value_t!(matches, "flows", Count) {
+                Ok(Count(v)) => Some(v as usize),
+                Err(e) => self.print_error(&format!("Invalid flows param. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
+
+    pub fn get_noop(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("noop")
+    }
+
+    pub fn get_stdnet(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("stdnet")
+    }
+
+    pub fn get_on_worker_error(&self) -> OnError {
+        let matches = &self.matches;
+        match value_t!(matches, "on-worker-error", OnError) {
+            Ok(v) => v,
+            Err(e) => self.print_error(&format!("Invalid on-worker-error param. {}", e)),
+        }
+    }
+
+    pub fn get_pacing(&self) -> Pacing {
+        let matches = &self.matches;
+        match value_t!(matches, "pacing", Pacing) {
+            Ok(v) => v,
+            Err(e) => self.print_error(&format!("Invalid pacing param. {}", e)),
+        }
+    }
+
+    pub fn get_protocol(&self) -> String {
+        self.matches.value_of("protocol").unwrap_or("echo").to_owned()
+    }
+
+    pub fn get_truncated_error(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("truncated-error")
+    }
+
+    pub fn get_rate(&self) -> usize {
+        let matches = &self.matches;
+        match value_t!(matches, "rate", Count) {
+            Ok(v) => v.0 as usize,
+            Err(e) => self.print_error(&format!("Invalid rate param. {}", e)),
+        }
+    }
+
+    /// Minimum probe interval in nanoseconds when adaptive pacing is enabled
+    pub fn get_adaptive_floor(&self) -> Option<u64> {
+        if self.matches.is_present("adaptive") {
+            let matches = &self.matches;
+            match value_t!(matches, "adaptive-floor", Span) {
+                Ok(v) => Some(v.ns(units::MICROSECOND)),
+                Err(e) => self.print_error(&format!("Invalid adaptive-floor param. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
+
+    pub fn get_sliding_window(&self) -> Option<usize> {
+        if self.matches.is_present("sliding") {
+            let matches = &self.matches;
+            match value_t!(matches, "sliding", Span) {
+                Ok(v) => {
+                    match v.whole(units::SECOND) {
+                        Some(v) if v > 0 => Some(v as usize),
+                        _ => self.print_error("Invalid sliding param. Must be whole seconds, at \
+                                               least 1"),
+                    }
+                }
+                Err(e) => self.print_error(&format!("Invalid sliding param. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
+
+    pub fn get_checkpoint(&self) -> Option<String> {
+        self.matches.value_of("checkpoint").map(|v| self.file(v))
+    }
+
+    /// The checkpoint to resume from, with its path
+    pub fn get_resume(&self) -> Option<(String, Checkpoint)> {
+        self.matches.value_of("resume").map(|path| {
+            let path = self.file(path);
+            match Checkpoint::load(&path) {
+                Ok(checkpoint) => (path, checkpoint),
+                Err(e) => self.print_error(&e),
+            }
+        })
+    }
+
+    /// The p99 latency in ns above which a window is warned about
+    pub fn get_warn_p99(&self) -> Option<u64> {
+        if self.matches.is_present("warn-p99") {
+            let matches = &self.matches;
+            match value_t!(matches, "warn-p99", Span) {
+                Ok(v) => Some(v.ns(units::MICROSECOND)),
+                Err(e) => self.print_error(&format!("Invalid warn-p99 param. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
+
+    pub fn get_highlight(&self) -> Option<f64> {
+        if self.matches.is_present("highlight") {
+            let matches = &self.matches;
+            match value_t!(matches, "highlight", f64) {
+                Ok(v) if v >= 0.0 => Some(v),
+                Ok(_) => self.print_error("Invalid highlight param. Must not be negative"),
+                Err(e) => self.print_error(&format!("Invalid highlight param. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
+
+    pub fn get_outlier_log(&self) -> Option<String> {
+        self.matches.value_of("outliers").map(|v| self.file(v))
+    }
+
+    pub fn get_outlier_threshold(&self) -> f64 {
+        let matches = &self.matches;
+        match value_t!(matches, "outlier-threshold", Factor) {
+            Ok(Factor(v)) => v,
+            Err(e) => self.print_error(&format!("Invalid outlier-threshold param. {}", e)),
+        }
+    }
+
+    pub fn get_control_listen(&self) -> Option<SocketAddr> {
+        if self.matches.is_present("control") {
+            let matches = &self.matches;
+            match value_t!(matches, "control", SocketAddr) {
+                Ok(v) => Some(v),
+                Err(e) => self.print_error(&format!("Invalid control address. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
+
+    pub fn get_tcp(&self) -> Option<TcpConfig> {
+        if self.matches.is_present("tcp") {
+            Some(TcpConfig {
+                request: self.matches.value_of("tcp-request").map(unescape),
+                expect: self.matches.value_of("tcp-expect").map(unescape),
+                nodelay: self.matches.is_present("tcp-nodelay"),
+                quickack: self.matches.is_present("tcp-quickack"),
+                congestion: self.matches.value_of("tcp-congestion").map(|v| v.to_owned()),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_baseline(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("baseline")
+    }
+
+    pub fn get_units(&self) -> Unit {
+        let matches = &self.matches;
+        match value_t!(matches, "units", Unit) {
+            Ok(v) => v,
+            Err(e) => self.print_error(&format!("Invalid units param. {}", e)),
+        }
+    }
+
+    pub fn get_precision(&self) -> usize {
+        let matches = &self.matches;
+        match value_t!(matches, "precision", usize) {
+            Ok(v) => v,
+            Err(e) => self.print_error(&format!("Invalid precision param. {}", e)),
+        }
+    }
+
+    /// Significant digits and maximum value, in ns, of ping-rs's sketches
+    pub fn get_histogram(&self) -> (u32, u64) {
+        let matches = &self.matches;
+        let digits = match value_t!(matches, "histogram-digits", u32) {
+            Ok(v) if v >= 1 && v <= 4 => v,
+            Ok(_) => self.print_error("Invalid histogram-digits param. Must be 1 to 4"),
+            Err(e) => self.print_error(&format!("Invalid histogram-digits param. {}", e)),
+        };
+        let max = match value_t!(matches, "histogram-max", Span) {
+            Ok(v) if v.ns(units::SECOND) > 0 => v.ns(units::SECOND),
+            Ok(_) => self.print_error("Invalid histogram-max param. Must be positive"),
+            Err(e) => self.print_error(&format!("Invalid histogram-max param. {}", e)),
+        };
+        (digits, max)
+    }
+
+    /// Size in bytes and interval of MTU watchdog probes, if enabled
+    pub fn get_mtu_probe(&self) -> Option<(usize, Duration)> {
+        if !self.matches.is_present("mtu-probe") {
+            return None;
+        }
+        let matches = &self.matches;
+        let size = match value_t!(matches, "mtu-probe", Size) {
+            Ok(Size(v)) if v <= probe::MAX_REPLY => v,
+            Ok(_) => {
+                self.print_error(&format!("Invalid mtu-probe param. Must be at most {}",
+                                          probe::MAX_REPLY))
+            }
+            Err(e) => self.print_error(&format!("Invalid mtu-probe param. {}", e)),
+        };
+        let interval = match value_t!(matches, "mtu-interval", Span) {
+            Ok(v) if v.ns(units::SECOND) > 0 => {
+                let ns = v.ns(units::SECOND);
+                Duration::new(ns / units::SECOND, (ns % units::SECOND) as u32)
+            }
+            Ok(_) => self.print_error("Invalid mtu-interval param. Must be more than 0"),
+            Err(e) => self.print_error(&format!("Invalid mtu-interval param. {}", e)),
+        };
+        Some((size, interval))
+    }
+
+    /// Minimum RTT shift in ns which counts as a route change, and the number
+    /// of windows the minimum is tracked over
+    pub fn get_route_shift(&self) -> Option<(u64, usize)> {
+        if !self.matches.is_present("route-shift") {
+            return None;
+        }
+        let matches = &self.matches;
+        let shift = match value_t!(matches, "route-shift", Span) {
+            Ok(v) => v.ns(units::MICROSECOND),
+            Err(e) => self.print_error(&format!("Invalid route-shift param. {}", e)),
+        };
+        let windows = match value_t!(matches, "route-windows", usize) {
+            Ok(v) if v > 0 => v,
+            Ok(_) => self.print_error("Invalid route-windows param. Must be at least 1"),
+            Err(e) => self.print_error(&format!("Invalid route-windows param. {}", e)),
+        };
+        Some((shift, windows))
+    }
+
+    pub fn get_upload(&self) -> Option<Destination> {
+        if self.matches.is_present("upload") {
+            let matches = &self.matches;
+            match value_t!(matches, "upload", Destination) {
+                Ok(v) => Some(v),
+                Err(e) => self.print_error(&format!("Invalid upload param. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
+
+    /// The offset from CLOCK_MONOTONIC, in ns, beyond which the clocksource
+    /// is recalibrated
+    pub fn get_clock_drift(&self) -> Option<u64> {
+        if self.matches.is_present("clock-drift") {
+            let matches = &self.matches;
+            match value_t!(matches, "clock-drift", Span) {
+                Ok(v) if v.ns(units::MICROSECOND) > 0 => Some(v.ns(units::MICROSECOND)),
+                Ok(_) => self.print_error("Invalid clock-drift param. Must be more than 0"),
+                Err(e) => self.print_error(&format!("Invalid clock-drift param. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
+
+    /// The share of a window, in percent, which a thread may wait for a CPU
+    /// or the hypervisor may steal before the window is flagged as starved
+    pub fn get_starvation(&self) -> Option<f64> {
+        if self.matches.is_present("starvation") {
+            let matches = &self.matches;
+            match value_t!(matches, "starvation", f64) {
+                Ok(v) if v > 0.0 && v <= 100.0 => Some(v),
+                Ok(_) => self.print_error("Invalid starvation param. Must be in (0, 100]"),
+                Err(e) => self.print_error(&format!("Invalid starvation param. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
+
+    pub fn get_slowest(&self) -> usize {
+        let matches = &self.matches;
+        match value_t!(matches, "slowest", Count) {
+            Ok(Count(v)) => v as usize,
+            Err(e) => self.print_error(&format!("Invalid slowest param. {}", e)),
+        }
+    }
+
+    pub fn get_so_priority(&self) -> Option<u32> {
+        if self.matches.is_present("so-priority") {
+            let matches = &self.matches;
+            match value_t!(matches, "so-priority", u32) {
+                Ok(v) => Some(v),
+                Err(e) => self.print_error(&format!("Invalid so-priority param. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
+
+    /// The CPUs the thread reading the stats queue may run on
+    pub fn get_receiver_cpus(&self) -> Option<Vec<usize>> {
+        match self.matches.value_of("receiver-cpus") {
+            Some(v) => {
+                match sched::parse_cpus(v) {
+                    Ok(cpus) => Some(cpus),
+                    Err(e) => self.print_error(&format!("Invalid receiver-cpus param. {}", e)),
+                }
+            }
+            None => None,
+        }
+    }
 
-    let mut stack = rips::NetworkStack::new();
-    stack.add_interface(iface.clone(), channel).unwrap();
-    stack.add_ipv4(&iface, src_net).unwrap();
-    {
-        let routing_table = stack.routing_table();
-        routing_table.add_route(*DEFAULT_ROUTE, Some(gateway), iface);
+    pub fn get_receiver_priority(&self) -> Option<Priority> {
+        if self.matches.is_present("receiver-priority") {
+            let matches = &self.matches;
+            match value_t!(matches, "receiver-priority", Priority) {
+                Ok(v) => Some(v),
+                Err(e) => self.print_error(&format!("Invalid receiver-priority param. {}", e)),
+            }
+        } else {
+            None
+        }
     }
 
-    let stack = Arc::new(Mutex::new(stack));
+    pub fn get_router_alert(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("router-alert")
+    }
 
-    // initialize a tic::Receiver to ingest stats
-    let mut receiver = Receiver::configure()
-        .windows(windows)
-        .duration(duration)
-        .capacity(stats_qlen)
-        .http_listen("0.0.0.0:42024".to_owned())
-        .build();
+    pub fn get_watch_link(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("watch-link")
+    }
 
-    receiver.add_interest(Interest::Waterfall(Metric::Ok, "ok_waterfall.png".to_owned()));
-    receiver.add_interest(Interest::Trace(Metric::Ok, "ok_trace.txt".to_owned()));
-    receiver.add_interest(Interest::Count(Metric::Ok));
+    pub fn get_iface_name(&self) -> String {
+        self.matches.value_of("iface").unwrap().to_owned()
+    }
 
-    for _ in 0..threads {
-        let sender = receiver.get_sender();
-        let clocksource = receiver.get_clocksource();
-        let src = SocketAddr::V4(SocketAddrV4::new(src_net.ip(), 0));
-        let dst = dst;
-        if noop {
-            thread::spawn(move || {
-                handle_noop(clocksource, sender);
-            });
-        } else if stdnet {
-            let socket = std::net::UdpSocket::bind(src).unwrap();
-            thread::spawn(move || {
-                handle_stdnet(socket, dst, clocksource, sender);
-            });
+    pub fn get_burst(&self) -> Option<usize> {
+        if self.matches.is_present("burst") {
+            let matches = &self.matches;
+            match value_t!(matches, "burst", Count) {
+                Ok(Count(v)) if v > 0 => Some(v as usize),
+                Ok(_) => self.print_error("Invalid burst param. Must be at least 1"),
+                Err(e) => self.print_error(&format!("Invalid burst param. {}", e)),
+            }
         } else {
-            let socket = UdpSocket::bind(stack.clone(), src).unwrap();
-            thread::spawn(move || {
-                handle_rips(socket, dst, clocksource, sender);
-            });
+            None
         }
     }
 
-    let cs = receiver.get_clocksource();
-
-    let mut total = 0;
-
-    for _ in 0..windows {
-        let t0 = cs.time();
-        receiver.run_once();
-        let t1 = cs.time();
-        let m = receiver.clone_meters();
-        let mut c = 0;
-        if let Some(t) = m.get_combined_count() {
-            c = *t - total;
-            total = *t;
+    pub fn get_expect_size(&self) -> Option<usize> {
+        if self.matches.is_present("expect-size") {
+            let matches = &self.matches;
+            match value_t!(matches, "expect-size", Size) {
+                Ok(Size(v)) => Some(v),
+                Err(e) => self.print_error(&format!("Invalid expect-size param. {}", e)),
+            }
+        } else {
+            None
         }
-        let r = c as f64 / ((t1 - t0) as f64 / 1_000_000_000.0);
-        info!("rate: {} rps", r);
-        info!("latency: p50: {} ns p90: {} ns p99: {} ns p999: {} ns p9999: {} ns",
-                    m.get_combined_percentile(
-                        tic::Percentile("p50".to_owned(), 50.0)).unwrap_or(&0),
-                    m.get_combined_percentile(
-                        tic::Percentile("p90".to_owned(), 90.0)).unwrap_or(&0),
-                    m.get_combined_percentile(
-                        tic::Percentile("p99".to_owned(), 99.0)).unwrap_or(&0),
-                    m.get_combined_percentile(
-                        tic::Percentile("p999".to_owned(), 99.9)).unwrap_or(&0),
-                    m.get_combined_percentile(
-                        tic::Percentile("p9999".to_owned(), 99.99)).unwrap_or(&0),
-                );
     }
-    info!("saving files...");
-    receiver.save_files();
-    info!("complete");
-}
 
-fn handle_rips(mut socket: UdpSocket,
-               dst: SocketAddr,
-               clocksource: Clocksource,
-               stats: Sender<Metric>) {
-    let request = "PING\r\n".to_owned().into_bytes();
-    let mut buffer = vec![0; 1024*2];
-    loop {
-        let t0 = clocksource.counter();
-        let _ = socket.send_to(&request, dst);
-        let (_, _) = socket.recv_from(&mut buffer).expect("Unable to read from socket");
-        let t1 = clocksource.counter();
-        let _ = stats.send(Sample::new(t0, t1, Metric::Ok));
+    /// Period over which worker threads are started, in ns
+    pub fn get_ramp_threads(&self) -> u64 {
+        let matches = &self.matches;
+        match value_t!(matches, "ramp-threads", Span) {
+            Ok(v) => v.ns(units::SECOND),
+            Err(e) => self.print_error(&format!("Invalid ramp-threads param. {}", e)),
+        }
     }
-}
 
-fn handle_stdnet(socket: std::net::UdpSocket,
-                 dst: SocketAddr,
-                 clocksource: Clocksource,
-                 stats: Sender<Metric>) {
-    let request = "PING\r\n".to_owned().into_bytes();
-    let mut buffer = vec![0; 1024*2];
-    loop {
-        let t0 = clocksource.counter();
-        let _ = socket.send_to(&request, dst);
-        let (_, _) = socket.recv_from(&mut buffer).expect("Unable to read from socket");
-        let t1 = clocksource.counter();
-        let _ = stats.send(Sample::new(t0, t1, Metric::Ok));
+    pub fn get_force(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("force")
     }
-}
 
-fn handle_noop(clocksource: Clocksource, stats: Sender<Metric>) {
-    loop {
-        let t0 = clocksource.counter();
-        let t1 = clocksource.counter();
-        let _ = stats.send(Sample::new(t0, t1, Metric::Ok));
+    pub fn get_request_size(&self) -> usize {
+        let matches = &self.matches;
+        match value_t!(matches, "request-size", Size) {
+            Ok(Size(v)) if v <= probe::MAX_REPLY => v,
+            Ok(_) => {
+                self.print_error(&format!("Invalid request-size param. Must be at most {}",
+                                          probe::MAX_REPLY))
+            }
+            Err(e) => self.print_error(&format!("Invalid request-size param. {}", e)),
+        }
     }
-}
 
-struct ArgumentParser {
-    app: clap::App<'static, 'static>,
-    matches: clap::ArgMatches<'static>,
-}
+    pub fn get_pattern(&self) -> Option<Pattern> {
+        if self.matches.is_present("pattern") {
+            let matches = &self.matches;
+            match value_t!(matches, "pattern", Pattern) {
+                Ok(v) => Some(v),
+                Err(e) => self.print_error(&format!("Invalid pattern param. {}", e)),
+            }
+        } else {
+            None
+        }
+    }
 
-impl ArgumentParser {
-    pub fn new() -> ArgumentParser {
-        let app = Self::create_app();
-        let matches = app.clone().get_matches();
-        ArgumentParser {
-            app: app,
-            matches: matches,
+    pub fn get_simulate(&self) -> Option<Impairment> {
+        if self.matches.is_present("simulate") {
+            let matches = &self.matches;
+            match value_t!(matches, "simulate", Impairment) {
+                Ok(v) => Some(v),
+                Err(e) => self.print_error(&format!("Invalid simulate param. {}", e)),
+            }
+        } else {
+            None
         }
     }
 
-    pub fn get_iface(&self) -> (NetworkInterface, rips::Interface) {
-        let iface_name = self.matches.value_of("iface").unwrap();
-        for iface in datalink::interfaces() {
-            if iface.name == iface_name {
-                if let Ok(rips_iface) = rips::convert_interface(&iface) {
-                    return (iface, rips_iface);
-                } else {
-                    self.print_error(&format!("Interface {} can't be used with rips", iface_name));
+    pub fn get_pattern_name(&self) -> Option<String> {
+        self.matches.value_of("pattern").map(|v| v.to_owned())
+    }
+
+    pub fn get_reply_size(&self) -> Option<usize> {
+        if self.matches.is_present("reply-size") {
+            let matches = &self.matches;
+            match value_t!(matches, "reply-size", Size) {
+                Ok(Size(v)) if v <= probe::MAX_REPLY => Some(v),
+                Ok(_) => {
+                    self.print_error(&format!("Invalid reply-size param. Must be at most {}",
+                                              probe::MAX_REPLY))
                 }
+                Err(e) => self.print_error(&format!("Invalid reply-size param. {}", e)),
             }
+        } else {
+            None
         }
-        self.print_error(&format!("Found no interface named {}", iface_name));
     }
 
-    pub fn get_src_net(&self) -> Ipv4Network {
-        if let Some(src_net) = self.matches.value_of("src_net") {
-            match Ipv4Network::from_cidr(src_net) {
-                Ok(src_net) => src_net,
-                Err(_) => self.print_error("Invalid CIDR"),
+    pub fn get_no_handshake(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("no-handshake")
+    }
+
+    pub fn get_direction_jitter(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("direction-jitter")
+    }
+
+    pub fn get_localize_loss(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("localize-loss")
+    }
+
+    pub fn get_probe_ids(&self) -> bool {
+        let matches = &self.matches;
+        matches.is_present("probe-ids")
+    }
+
+    pub fn get_by_rx(&self) -> Option<RxKey> {
+        if self.matches.is_present("by-rx") {
+            let matches = &self.matches;
+            match value_t!(matches, "by-rx", RxKey) {
+                Ok(v) => Some(v),
+                Err(e) => self.print_error(&format!("Invalid by-rx param. {}", e)),
             }
         } else {
-            let (iface, _) = self.get_iface();
-            if let Some(ips) = iface.ips.as_ref() {
-                for ip in ips {
-                    if let IpAddr::V4(ip) = *ip {
-                        return Ipv4Network::new(ip, 24).unwrap();
-                    }
+            None
+        }
+    }
+
+    /// Minutes per time-of-day bin, which must divide a day evenly
+    pub fn get_time_of_day(&self) -> Option<u32> {
+        if self.matches.is_present("time-of-day") {
+            let matches = &self.matches;
+            match value_t!(matches, "time-of-day", Span).map(|v| v.whole(units::MINUTE)) {
+                Ok(Some(v)) if v > 0 && 1440 % v == 0 => Some(v as u32),
+                Ok(_) => {
+                    self.print_error("Invalid time-of-day param. Must divide a day of 1440 \
+                                      minutes evenly")
                 }
+                Err(e) => self.print_error(&format!("Invalid time-of-day param. {}", e)),
             }
-            self.print_error("No IPv4 to use on given interface");
+        } else {
+            None
         }
     }
 
-    pub fn get_gw(&self) -> Ipv4Addr {
-        if let Some(gw_str) = self.matches.value_of("gw") {
-            if let Ok(gw) = Ipv4Addr::from_str(gw_str) {
-                gw
-            } else {
-                self.print_error("Unable to parse gateway ip");
+    /// Bytes per reply size bucket
+    pub fn get_by_size(&self) -> Option<u32> {
+        if self.matches.is_present("by-size") {
+            let matches = &self.matches;
+            match value_t!(matches, "by-size", Size) {
+                Ok(Size(v)) if v > 0 && v <= probe::MAX_REPLY => Some(v as u32),
+                Ok(_) => {
+                    self.print_error(&format!("Invalid by-size param. Must be 1 to {}",
+                                              probe::MAX_REPLY))
+                }
+                Err(e) => self.print_error(&format!("Invalid by-size param. {}", e)),
             }
         } else {
-            let src_net = self.get_src_net();
-            if let Some(gw) = src_net.nth(1) {
-                gw
-            } else {
-                self.print_error(&format!("Could not guess a default gateway inside {}", src_net));
-            }
+            None
         }
     }
 
-    pub fn get_dst(&self) -> SocketAddr {
-        let matches = &self.matches;
-        match value_t!(matches, "target", SocketAddr) {
-            Ok(dst) => dst,
-            Err(e) => self.print_error(&format!("Invalid target. {}", e)),
+    /// Milliseconds per bucket of the second, which must divide it evenly
+    pub fn get_subsecond(&self) -> Option<u32> {
+        if self.matches.is_present("subsecond") {
+            let matches = &self.matches;
+            match value_t!(matches, "subsecond", Span).map(|v| v.whole(units::MILLISECOND)) {
+                Ok(Some(v)) if v > 0 && 1000 % v == 0 => Some(v as u32),
+                Ok(_) => {
+                    self.print_error("Invalid subsecond param. Must divide a second of 1000 ms \
+                                      evenly")
+                }
+                Err(e) => self.print_error(&format!("Invalid subsecond param. {}", e)),
+            }
+        } else {
+            None
         }
     }
 
-    pub fn get_windows(&self) -> usize {
+    /// The source addresses to send from, how to choose between them and
+    /// after how many consecutive failed probes to fail over
+    pub fn get_sources(&self) -> (Vec<Ipv4Addr>, SourceMode, u32) {
         let matches = &self.matches;
-        match value_t!(matches, "windows", usize) {
+        let sources = match matches.values_of("sources") {
+            Some(sources) => {
+                sources.map(|source| match Ipv4Addr::from_str(source) {
+                        Ok(ip) => ip,
+                        Err(_) => self.print_error(&format!("Invalid source address {}", source)),
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let mode = match value_t!(matches, "source-mode", SourceMode) {
             Ok(v) => v,
-            Err(e) => self.print_error(&format!("Invalid windows param. {}", e)),
-        }
+            Err(e) => self.print_error(&format!("Invalid source-mode param. {}", e)),
+        };
+        let failover_after = match value_t!(matches, "failover-after", u32) {
+            Ok(v) if v > 0 => v,
+            Ok(_) => self.print_error("Invalid failover-after param. Must be at least 1"),
+            Err(e) => self.print_error(&format!("Invalid failover-after param. {}", e)),
+        };
+        (sources, mode, failover_after)
     }
 
-    pub fn get_duration(&self) -> usize {
-        let matches = &self.matches;
-        match value_t!(matches, "duration", usize) {
-            Ok(v) => v,
-            Err(e) => self.print_error(&format!("Invalid duration param. {}", e)),
-        }
+    pub fn get_steer(&self) -> Option<String> {
+        self.matches.value_of("steer").map(|v| v.to_owned())
     }
 
-    pub fn get_stats_qlen(&self) -> usize {
-        let matches = &self.matches;
-        match value_t!(matches, "stats-qlen", usize) {
-            Ok(v) => v,
-            Err(e) => self.print_error(&format!("Invalid duration param. {}", e)),
+    pub fn get_probe_log(&self) -> Option<String> {
+        self.matches.value_of("probe-log").map(|v| self.file(v))
+    }
+
+    pub fn get_server_listen(&self) -> Option<SocketAddr> {
+        if let Some(matches) = self.matches.subcommand_matches("server") {
+            match value_t!(matches, "listen", SocketAddr) {
+                Ok(v) => Some(v),
+                Err(e) => self.print_error(&format!("Invalid listen address. {}", e)),
+            }
+        } else {
+            None
         }
     }
 
-    pub fn get_threads(&self) -> usize {
-        let matches = &self.matches;
-        match value_t!(matches, "threads", usize) {
-            Ok(v) => v,
-            Err(e) => self.print_error(&format!("Invalid duration param. {}", e)),
+    pub fn get_server_log(&self) -> Option<Output> {
+        self.matches
+            .subcommand_matches("server")
+            .and_then(|matches| matches.value_of("probe-log"))
+            .map(|path| self.create_output(path, Compression::None))
+    }
+
+    /// The shared secret requests are signed with, if any
+    pub fn get_key(&self) -> Option<Key> {
+        self.matches.value_of("key").map(|path| {
+            match Key::load(path) {
+                Ok(key) => key,
+                Err(e) => self.print_error(&e),
+            }
+        })
+    }
+
+    /// Who the reflector answers
+    pub fn get_server_policy(&self) -> Policy {
+        let mut policy = Policy::default();
+        let matches = match self.matches.subcommand_matches("server") {
+            Some(matches) => matches,
+            None => return policy,
+        };
+        if let Some(networks) = matches.values_of("allow") {
+            for network in networks {
+                match Ipv4Network::from_cidr(network) {
+                    Ok(network) => policy.allow.push(network),
+                    Err(_) => self.print_error(&format!("Invalid allow param. {} is not a CIDR",
+                                                        network)),
+                }
+            }
+        }
+        if matches.is_present("rate-limit") {
+            policy.rate = match value_t!(matches, "rate-limit", Size) {
+                Ok(Size(v)) if v > 0 && v as u64 <= u32::max_value() as u64 => Some(v as u32),
+                Ok(_) => {
+                    self.print_error(&format!("Invalid rate-limit param. Must be 1 to {}",
+                                              u32::max_value()))
+                }
+                Err(e) => self.print_error(&format!("Invalid rate-limit param. {}", e)),
+            };
+        }
+        if let Some(path) = matches.value_of("key") {
+            policy.key = match Key::load(path) {
+                Ok(key) => Some(key),
+                Err(e) => self.print_error(&e),
+            };
         }
+        policy
     }
 
-    pub fn get_noop(&self) -> bool {
-        let matches = &self.matches;
-        matches.is_present("noop")
+    /// Whether `name`, the long form of a flag, was given rather than
+    /// defaulted
+    pub fn given(&self, name: &str) -> bool {
+        // a few flags are named differently than their long form
+        let name = match name {
+            "gateway" => "gw",
+            "ip" => "src_net",
+            name => name,
+        };
+        self.matches.occurrences_of(name) > 0
     }
 
-    pub fn get_stdnet(&self) -> bool {
+    /// The shell to write a completion script for, if running the completions
+    /// subcommand
+    pub fn get_completions(&self) -> Option<clap::Shell> {
+        self.matches.subcommand_matches("completions").map(|matches| {
+            match value_t!(matches, "shell", clap::Shell) {
+                Ok(shell) => shell,
+                Err(e) => self.print_error(&format!("Invalid shell param. {}", e)),
+            }
+        })
+    }
+
+    /// The two runs to compare and the significance level, if running the
+    /// compare subcommand
+    pub fn get_compare(&self) -> Option<(String, String, f64)> {
+        self.matches.subcommand_matches("compare").map(|matches| {
+            let alpha = match value_t!(matches, "alpha", f64) {
+                Ok(v) if v > 0.0 && v < 1.0 => v,
+                Ok(_) => self.print_error("Invalid alpha param. Must be between 0 and 1"),
+                Err(e) => self.print_error(&format!("Invalid alpha param. {}", e)),
+            };
+            (matches.value_of("run-a").unwrap().to_owned(),
+             matches.value_of("run-b").unwrap().to_owned(),
+             alpha)
+        })
+    }
+
+    /// The probe log to replay, and how many times faster than recorded
+    pub fn get_replay(&self) -> Option<(String, f64)> {
+        self.matches.subcommand_matches("replay").map(|matches| {
+            let speed = match value_t!(matches, "speed", f64) {
+                Ok(v) if v > 0.0 => v,
+                Ok(_) => self.print_error("Invalid speed param. Must be more than 0"),
+                Err(e) => self.print_error(&format!("Invalid speed param. {}", e)),
+            };
+            (matches.value_of("log").unwrap().to_owned(), speed)
+        })
+    }
+
+    pub fn get_compression(&self) -> Compression {
         let matches = &self.matches;
-        matches.is_present("stdnet")
+        match value_t!(matches, "compress", Compression) {
+            Ok(v) => v,
+            Err(e) => self.print_error(&format!("Invalid compress param. {}", e)),
+        }
     }
 
+    /// Creates an output file, compressed according to its extension or else
+    /// the given default
+    pub fn create_output(&self, path: &str, default: Compression) -> Output {
+        let (path, compression) = default.apply(path);
+        match Output::create(&path, compression) {
+            Ok(o) => o,
+            Err(e) => self.print_error(&format!("Unable to create {}. {}", path, e)),
+        }
+    }
+
+    #[cfg(feature = "userspace")]
     pub fn create_channel(&self) -> rips::EthernetChannel {
         let (iface, _) = self.get_iface();
         let mut config = datalink::Config::default();
@@ -306,7 +3313,15 @@ impl ArgumentParser {
         config.read_buffer_size = 1024 * 64;
         match datalink::channel(&iface, config) {
             Ok(datalink::Channel::Ethernet(tx, rx)) => rips::EthernetChannel(tx, rx),
-            _ => self.print_error(&format!("Unable to open network channel on {}", iface.name)),
+            Ok(_) => {
+                self.print_error(&format!("{} is not an Ethernet interface", iface.name))
+            }
+            Err(e) => {
+                self.print_error(&format!("Unable to open network channel on {}. {}{}",
+                                          iface.name,
+                                          e,
+                                          CHANNEL_HINT))
+            }
         }
     }
 
@@ -324,24 +3339,38 @@ impl ArgumentParser {
                    Must be inside the network given to --ip. Defaults to the first address in \
                    the network given to --ip")
             .takes_value(true);
+        let src_mac = clap::Arg::with_name("src-mac")
+            .long("src-mac")
+            .value_name("MAC")
+            .help("Send from MAC, e.g. 02:00:00:00:00:01, instead of the interface's own, or \
+                   from a random locally administered MAC with 'random'")
+            .takes_value(true);
+        let routes = clap::Arg::with_name("routes")
+            .long("routes")
+            .value_name("FILE")
+            .help("Route the prefixes in FILE, one '<prefix> <gateway>' per line, through their \
+                   own gateways, and only the rest through --gateway")
+            .takes_value(true);
         let iface_arg = clap::Arg::with_name("iface")
             .help("Network interface to use")
             .required(true)
             .index(1);
         let dst_arg = clap::Arg::with_name("target")
-            .help("Target to connect to. Given as <ip>:<port>")
+            .help("Target to connect to. Given as <host>:<port>")
             .required(true)
             .index(2);
         let windows = clap::Arg::with_name("windows")
             .long("windows")
             .value_name("COUNT")
-            .help("Number of integration windows per run")
+            .help("Number of integration windows per run, 0 to run until shut down via the \
+                   control endpoint")
             .takes_value(true)
             .default_value("5");
         let duration = clap::Arg::with_name("duration")
             .long("duration")
-            .value_name("SECONDS")
-            .help("Seconds per integration window")
+            .value_name("DURATION")
+            .help("Length of each integration window, e.g. 60s or 5m. A bare number is in \
+                   seconds")
             .takes_value(true)
             .default_value("60");
         let stats_qlen = clap::Arg::with_name("stats-qlen")
@@ -356,32 +3385,587 @@ impl ArgumentParser {
             .help("Number of client threads to use")
             .takes_value(true)
             .default_value("1");
+        let race = clap::Arg::with_name("race")
+            .long("race")
+            .value_name("COUNT")
+            .help("If the target resolves to several addresses, race COUNT probes to each and \
+                   measure the fastest")
+            .takes_value(true);
+        let rotate = clap::Arg::with_name("rotate")
+            .long("rotate")
+            .value_name("TARGETS")
+            .help("Comma separated <host>:<port> targets to rotate through after the first, \
+                   probing one per window")
+            .takes_value(true)
+            .use_delimiter(true)
+            .multiple(true);
+        let rate = clap::Arg::with_name("rate")
+            .long("rate")
+            .value_name("COUNT")
+            .help("Probes per second per client thread, e.g. 50k, 0 for unthrottled")
+            .takes_value(true)
+            .default_value("0");
+        let adaptive = clap::Arg::with_name("adaptive")
+            .long("adaptive")
+            .help("Pace each thread's probes at the smoothed RTT, like ping -A")
+            .takes_value(false);
+        let adaptive_floor = clap::Arg::with_name("adaptive-floor")
+            .long("adaptive-floor")
+            .value_name("DURATION")
+            .help("Minimum interval between probes in adaptive mode, e.g. 200ms. A bare \
+                   number is in microseconds")
+            .takes_value(true)
+            .default_value("200000");
+        let pacing = clap::Arg::with_name("pacing")
+            .long("pacing")
+            .value_name("STRATEGY")
+            .help("How paced threads wait between probes: sleep, spin, sleep then spin \
+                   (hybrid), or a timerfd")
+            .takes_value(true)
+            .possible_values(&["sleep", "spin", "hybrid", "timerfd"])
+            .default_value("sleep");
+        let control = clap::Arg::with_name("control")
+            .long("control")
+            .value_name("ADDR")
+            .help("Serve the JSON control API on <ip>:<port>")
+            .takes_value(true);
         let noop = clap::Arg::with_name("noop")
             .long("noop")
             .help("no-op validation of stats")
             .takes_value(false);
+        let simulate = clap::Arg::with_name("simulate")
+            .long("simulate")
+            .value_name("IMPAIRMENTS")
+            .help("Generate samples for a simulated path instead of probing, e.g. \
+                   loss=1%,delay=2ms±500us,reorder=0.1%[,seed=N]")
+            .takes_value(true)
+            .conflicts_with_all(&["noop", "stdnet", "tcp", "burst", "race", "mtu-probe",
+                                  "so-priority", "router-alert", "steer", "by-rx"]);
         let stdnet = clap::Arg::with_name("stdnet")
             .long("stdnet")
             .help("use std::net::UdpSocket")
             .takes_value(false);
+        let baseline = clap::Arg::with_name("baseline")
+            .long("baseline")
+            .help("Also report latency less the harness overhead measured in noop mode")
+            .takes_value(false)
+            .conflicts_with("noop");
+        let units = clap::Arg::with_name("units")
+            .long("units")
+            .value_name("UNIT")
+            .help("Unit in which latencies are reported")
+            .takes_value(true)
+            .possible_values(&["ns", "us", "ms", "s"])
+            .default_value("ns")
+            .global(true);
+        let precision = clap::Arg::with_name("precision")
+            .long("precision")
+            .value_name("DIGITS")
+            .help("Decimal places in reported latencies and rates")
+            .takes_value(true)
+            .default_value("0")
+            .global(true);
+        let histogram_digits = clap::Arg::with_name("histogram-digits")
+            .long("histogram-digits")
+            .value_name("DIGITS")
+            .help("Significant digits kept by sketches (sliding window, schedule, sizes, bursts)")
+            .takes_value(true)
+            .default_value("2");
+        let histogram_max = clap::Arg::with_name("histogram-max")
+            .long("histogram-max")
+            .value_name("DURATION")
+            .help("Largest value sketches distinguish; larger ones are counted as this. A bare \
+                   number is in seconds")
+            .takes_value(true)
+            .default_value("100");
+        let mtu_probe = clap::Arg::with_name("mtu-probe")
+            .long("mtu-probe")
+            .value_name("BYTES")
+            .help("Periodically send a BYTES-sized UDP probe with DF set and warn if it fails \
+                   while small probes succeed, e.g. 1472 for a 1500 byte MTU")
+            .takes_value(true);
+        let mtu_interval = clap::Arg::with_name("mtu-interval")
+            .long("mtu-interval")
+            .value_name("DURATION")
+            .help("Interval between MTU watchdog probes. A bare number is in seconds")
+            .takes_value(true)
+            .default_value("10");
+        let slowest = clap::Arg::with_name("slowest")
+            .long("slowest")
+            .value_name("N")
+            .help("Report the N slowest probes of each window")
+            .takes_value(true)
+            .default_value("0");
+        let so_priority = clap::Arg::with_name("so-priority")
+            .long("so-priority")
+            .value_name("N")
+            .help("Set SO_PRIORITY on kernel sockets, selecting the local qdisc band")
+            .takes_value(true);
+        let receiver_cpus = clap::Arg::with_name("receiver-cpus")
+            .long("receiver-cpus")
+            .value_name("CPUS")
+            .help("Pin the thread reading the stats queue to CPUS, e.g. 0 or 0,4-7, apart from \
+                   the worker threads")
+            .takes_value(true);
+        let receiver_priority = clap::Arg::with_name("receiver-priority")
+            .long("receiver-priority")
+            .value_name("PRIORITY")
+            .help("Schedule the thread reading the stats queue at a nice value of -20 to 19, or \
+                   at fifo:<1-99> for SCHED_FIFO, apart from the worker threads")
+            .takes_value(true);
+        let router_alert = clap::Arg::with_name("router-alert")
+            .long("router-alert")
+            .help("Add the IPv4 Router Alert option to probes on kernel sockets")
+            .takes_value(false);
+        let watch_link = clap::Arg::with_name("watch-link")
+            .long("watch-link")
+            .help("Pause probing while the interface is down, counting the probes not sent as \
+                   link_down and reporting each change")
+            .takes_value(false);
+        let burst = clap::Arg::with_name("burst")
+            .long("burst")
+            .value_name("PACKETS")
+            .help("Send bursts of PACKETS back-to-back and report latency by position in the \
+                   burst")
+            .takes_value(true)
+            .requires("stdnet")
+            .conflicts_with("tcp");
+        let on_worker_error = clap::Arg::with_name("on-worker-error")
+            .long("on-worker-error")
+            .value_name("POLICY")
+            .help("What to do when a worker fails: restart it, stop it, or abort the run")
+            .takes_value(true)
+            .possible_values(&["restart", "stop", "abort"])
+            .default_value("restart");
+        let truncated_error = clap::Arg::with_name("truncated-error")
+            .long("truncated-error")
+            .help("Treat a truncated response as a worker failure, handled by --on-worker-error")
+            .takes_value(false)
+            .conflicts_with("tcp");
+        let flows = clap::Arg::with_name("flows")
+            .long("flows")
+            .value_name("COUNT")
+            .help("Number of flows, each with its own five-tuple, spread across the threads. \
+                   Defaults to one per thread")
+            .takes_value(true)
+            .conflicts_with_all(&["tcp", "burst"]);
+        let steer = clap::Arg::with_name("steer")
+            .long("steer")
+            .value_name("INTERFACE")
+            .help("Install ethtool ntuple rules on INTERFACE steering each thread's replies to \
+                   its own receive queue, and report the queue of each reply")
+            .takes_value(true)
+            .requires("stdnet")
+            .conflicts_with("burst");
+        let sources = clap::Arg::with_name("sources")
+            .long("sources")
+            .value_name("IPS")
+            .help("Comma separated local addresses to send from, failing over between them or \
+                   taking turns, and reporting each one's loss and latency")
+            .takes_value(true)
+            .use_delimiter(true)
+            .multiple(true)
+            .requires("stdnet")
+            .conflicts_with_all(&["burst", "tcp"]);
+        let source_mode = clap::Arg::with_name("source-mode")
+            .long("source-mode")
+            .value_name("MODE")
+            .help("How to choose between --sources")
+            .takes_value(true)
+            .possible_values(&["failover", "round-robin"])
+            .default_value("failover");
+        let failover_after = clap::Arg::with_name("failover-after")
+            .long("failover-after")
+            .value_name("COUNT")
+            .help("Fail over to the next of --sources after COUNT consecutive failed probes")
+            .takes_value(true)
+            .default_value("3");
+        let by_rx = clap::Arg::with_name("by-rx")
+            .long("by-rx")
+            .value_name("KEY")
+            .help("Break latency down by the cpu or queue which handled each reply")
+            .takes_value(true)
+            .possible_values(&["cpu", "queue"]);
+        let by_size = clap::Arg::with_name("by-size")
+            .long("by-size")
+            .value_name("BYTES")
+            .help("Break latency down by the size of replies, in buckets of BYTES, e.g. 256B")
+            .takes_value(true);
+        let time_of_day = clap::Arg::with_name("time-of-day")
+            .long("time-of-day")
+            .value_name("DURATION")
+            .help("Break latency down by the local time of day probes were sent, in bins of \
+                   DURATION, e.g. 1h for hour of day. A bare number is in minutes")
+            .takes_value(true)
+            .conflicts_with("noop");
+        let subsecond = clap::Arg::with_name("subsecond")
+            .long("subsecond")
+            .value_name("DURATION")
+            .help("Break latency down by when within the second probes were sent, in buckets of \
+                   DURATION, to show per-second policers and rate limits. A bare number is in \
+                   milliseconds")
+            .takes_value(true)
+            .conflicts_with("noop");
+        let ramp_threads = clap::Arg::with_name("ramp-threads")
+            .long("ramp-threads")
+            .value_name("DURATION")
+            .help("Start worker threads gradually over DURATION. A bare number is in seconds")
+            .takes_value(true)
+            .default_value("0");
+        let highlight = clap::Arg::with_name("highlight")
+            .long("highlight")
+            .value_name("PERCENT")
+            .help("Highlight percentiles which rose by more than PERCENT since the previous \
+                   window")
+            .takes_value(true);
+        let warn_p99 = clap::Arg::with_name("warn-p99")
+            .long("warn-p99")
+            .value_name("DURATION")
+            .help("Warn about windows whose p99 latency exceeds DURATION, e.g. 2ms, and count \
+                   them in the verdict. A bare number is in microseconds")
+            .takes_value(true);
+        let checkpoint = clap::Arg::with_name("checkpoint")
+            .long("checkpoint")
+            .value_name("FILE")
+            .help("Save the run's state to FILE after every window, for --resume")
+            .takes_value(true);
+        let resume = clap::Arg::with_name("resume")
+            .long("resume")
+            .value_name("FILE")
+            .help("Continue the run checkpointed to FILE, which is checkpointed to from then on \
+                   unless --checkpoint is given")
+            .takes_value(true);
+        let key = clap::Arg::with_name("key")
+            .long("key")
+            .value_name("FILE")
+            .help("Sign requests with the shared secret in FILE, and accept only replies signed \
+                   with it")
+            .takes_value(true);
+        let upload = clap::Arg::with_name("upload")
+            .long("upload")
+            .value_name("URL")
+            .help("Upload the run's output files under URL/<host>/<run id>/ once it completes. \
+                   s3:// and gs:// URLs use the aws and gsutil clients, http(s):// URLs are PUT \
+                   to with curl")
+            .takes_value(true);
+        let force = clap::Arg::with_name("force")
+            .long("force")
+            .help("Overwrite output files left by a previous run")
+            .takes_value(false);
+        let compress = clap::Arg::with_name("compress")
+            .long("compress")
+            .value_name("METHOD")
+            .help("Compress trace files and logs. Files given with a .gz or .zst extension are \
+                   compressed accordingly regardless of this setting")
+            .takes_value(true)
+            .possible_values(&["none", "gzip", "zstd"])
+            .default_value("none");
+        let sliding = clap::Arg::with_name("sliding")
+            .long("sliding")
+            .value_name("DURATION")
+            .help("Report percentiles over the last DURATION every second, independent of the \
+                   integration windows. A bare number is in seconds")
+            .takes_value(true);
+        let outliers = clap::Arg::with_name("outliers")
+            .long("outliers")
+            .value_name("FILE")
+            .help("Write probes slower than --outlier-threshold times the rolling median to FILE, \
+                   along with the RTTs of their neighbors")
+            .takes_value(true);
+        let outlier_threshold = clap::Arg::with_name("outlier-threshold")
+            .long("outlier-threshold")
+            .value_name("MULTIPLE")
+            .help("Multiple of the rolling median RTT above which a probe is an outlier")
+            .takes_value(true)
+            .default_value("10");
+        let route_shift = clap::Arg::with_name("route-shift")
+            .long("route-shift")
+            .value_name("DURATION")
+            .help("Report a route change when the minimum RTT shifts by more than DURATION, e.g. \
+                   500us. A bare number is in microseconds")
+            .takes_value(true)
+            .conflicts_with("noop");
+        let clock_drift = clap::Arg::with_name("clock-drift")
+            .long("clock-drift")
+            .value_name("DURATION")
+            .help("Compare the clocksource to CLOCK_MONOTONIC every few seconds, recalibrating \
+                   it once they differ by more than DURATION. A bare number is in microseconds")
+            .takes_value(true);
+        let starvation = clap::Arg::with_name("starvation")
+            .long("starvation")
+            .value_name("PERCENT")
+            .help("Flag windows in which a thread waited for a CPU, or the hypervisor stole, \
+                   PERCENT of the time or more")
+            .takes_value(true);
+        let route_windows = clap::Arg::with_name("route-windows")
+            .long("route-windows")
+            .value_name("COUNT")
+            .help("Number of windows the minimum RTT is tracked over")
+            .takes_value(true)
+            .default_value("5");
+        let tcp = clap::Arg::with_name("tcp")
+            .long("tcp")
+            .help("Time request/response exchanges over a persistent kernel TCP connection")
+            .takes_value(false);
+        let protocol = clap::Arg::with_name("protocol")
+            .long("protocol")
+            .value_name("PROTOCOL")
+            .help("Protocol probes speak: echo, dns[:<name>] or redis (with --tcp)")
+            .takes_value(true)
+            .default_value("echo");
+        let tcp_request = clap::Arg::with_name("tcp-request")
+            .long("tcp-request")
+            .value_name("STRING")
+            .help("Request to send in TCP mode instead of PING. \\r and \\n are unescaped")
+            .takes_value(true);
+        let tcp_expect = clap::Arg::with_name("tcp-expect")
+            .long("tcp-expect")
+            .value_name("STRING")
+            .help("Exact response expected in TCP mode. Defaults to any line. \\r and \\n are \
+                   unescaped")
+            .takes_value(true);
+        let tcp_nodelay = clap::Arg::with_name("tcp-nodelay")
+            .long("tcp-nodelay")
+            .help("Disable Nagle's algorithm in TCP mode")
+            .takes_value(false)
+            .requires("tcp");
+        let tcp_quickack = clap::Arg::with_name("tcp-quickack")
+            .long("tcp-quickack")
+            .help("Set TCP_QUICKACK before each read in TCP mode")
+            .takes_value(false)
+            .requires("tcp");
+        let tcp_congestion = clap::Arg::with_name("tcp-congestion")
+            .long("tcp-congestion")
+            .value_name("ALGORITHM")
+            .help("Congestion control algorithm in TCP mode, e.g. cubic or bbr")
+            .takes_value(true)
+            .requires("tcp");
+        let expect_size = clap::Arg::with_name("expect-size")
+            .long("expect-size")
+            .value_name("BYTES")
+            .help("Count responses of any other size as size_mismatch")
+            .takes_value(true);
+        let request_size = clap::Arg::with_name("request-size")
+            .long("request-size")
+            .value_name("BYTES")
+            .help("Pad requests to BYTES")
+            .takes_value(true)
+            .default_value("0");
+        let reply_size = clap::Arg::with_name("reply-size")
+            .long("reply-size")
+            .value_name("BYTES")
+            .help("Ask the ping-rs reflector to pad replies to BYTES")
+            .takes_value(true);
+        let pattern = clap::Arg::with_name("pattern")
+            .long("pattern")
+            .value_name("PATTERN")
+            .help("Fill request padding with zero, random or incrementing bytes, or repeated \
+                   hex bytes such as 0xDEADBEEF, instead of '.'")
+            .takes_value(true)
+            .requires("request-size")
+            .conflicts_with("tcp");
+        let probe_ids = clap::Arg::with_name("probe-ids")
+            .long("probe-ids")
+            .help("Tag each request with a run ID and per-probe ID (`PING <run>-<thread>-<seq>`). \
+                   The target must tolerate the extra payload.")
+            .takes_value(false);
+        let direction_jitter = clap::Arg::with_name("direction-jitter")
+            .long("direction-jitter")
+            .help("Ask the reflector to timestamp replies, and report jitter on the way there \
+                   and back separately")
+            .takes_value(false);
+        let localize_loss = clap::Arg::with_name("localize-loss")
+            .long("localize-loss")
+            .help("Ask the reflector which probes it received at the end, and report loss on \
+                   the way there and back separately")
+            .takes_value(false);
+        let no_handshake = clap::Arg::with_name("no-handshake")
+            .long("no-handshake")
+            .help("Don't ask the reflector which features it supports before the run")
+            .takes_value(false);
+        let probe_log = clap::Arg::with_name("probe-log")
+            .long("probe-log")
+            .value_name("FILE")
+            .help("Log ID, send time and RTT of every probe to FILE.<thread>")
+            .takes_value(true);
+
+        let server = clap::SubCommand::with_name("server")
+            .about("Run a reflector answering PING with PONG")
+            .arg(clap::Arg::with_name("listen")
+                .help("Address to listen on. Given as <ip>:<port>")
+                .required(true)
+                .index(1))
+            .arg(clap::Arg::with_name("probe-log")
+                .long("probe-log")
+                .value_name("FILE")
+                .help("Log ID, receive time and source of every tagged probe to FILE")
+                .takes_value(true))
+            .arg(clap::Arg::with_name("allow")
+                .long("allow")
+                .value_name("CIDR")
+                .help("Only answer sources within CIDR. May be given more than once")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(clap::Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .value_name("BYTES")
+                .help("Send at most BYTES of replies per second to each source, e.g. 1MB")
+                .takes_value(true))
+            .arg(clap::Arg::with_name("key")
+                .long("key")
+                .value_name("FILE")
+                .help("Only answer echo requests signed with the shared secret in FILE, and sign \
+                       replies with it")
+                .takes_value(true));
+
+        let compare = clap::SubCommand::with_name("compare")
+            .about("Test whether the latency of two runs differs significantly")
+            .arg(clap::Arg::with_name("run-a")
+                .help("Probe log of the first run, as given to --probe-log")
+                .required(true)
+                .index(1))
+            .arg(clap::Arg::with_name("run-b")
+                .help("Probe log of the second run, as given to --probe-log")
+                .required(true)
+                .index(2))
+            .arg(clap::Arg::with_name("alpha")
+                .long("alpha")
+                .value_name("LEVEL")
+                .help("Significance level")
+                .takes_value(true)
+                .default_value("0.05"));
+
+        let replay = clap::SubCommand::with_name("replay")
+            .about("Replay the probes of a probe log through the stats and the control API as \
+                    if they were being measured")
+            .arg(clap::Arg::with_name("log")
+                .help("Probe log to replay, as given to --probe-log")
+                .required(true)
+                .index(1))
+            .arg(clap::Arg::with_name("speed")
+                .long("speed")
+                .value_name("FACTOR")
+                .help("Replay FACTOR times faster than the probes were sent")
+                .takes_value(true)
+                .default_value("1"));
+
+        let jobs = clap::SubCommand::with_name("jobs")
+            .about("Run the independent measurement jobs of a file in one process")
+            .arg(clap::Arg::with_name("file")
+                .help("File with one job per line: a name, then the arguments of a run")
+                .required(true)
+                .index(1))
+            .arg(clap::Arg::with_name("control")
+                .long("control")
+                .value_name("ADDR")
+                .help("Serve the control API of every job on ADDR, under /jobs/<name>")
+                .takes_value(true));
+
+        let completions = clap::SubCommand::with_name("completions")
+            .about("Write a completion script for SHELL to stdout")
+            .arg(clap::Arg::with_name("shell")
+                .help("Shell to complete in")
+                .required(true)
+                .possible_values(&["bash", "fish", "zsh", "powershell"])
+                .index(1));
 
         clap::App::new("UDP Ping Client")
             .version(crate_version!())
             .author(crate_authors!())
             .about("A simple UDP ping client with a userspace network stack")
+            .setting(clap::AppSettings::SubcommandsNegateReqs)
             .arg(src_net_arg)
             .arg(gw)
+            .arg(routes)
+            .arg(src_mac)
             .arg(windows)
             .arg(duration)
             .arg(iface_arg)
             .arg(dst_arg)
+            .arg(race)
+            .arg(rotate)
             .arg(stats_qlen)
             .arg(threads)
+            .arg(flows)
+            .arg(steer)
+            .arg(sources)
+            .arg(source_mode)
+            .arg(failover_after)
+            .arg(by_rx)
+            .arg(time_of_day)
+            .arg(by_size)
+            .arg(subsecond)
+            .arg(ramp_threads)
+            .arg(rate)
+            .arg(adaptive)
+            .arg(adaptive_floor)
+            .arg(pacing)
+            .arg(control)
             .arg(noop)
+            .arg(simulate)
+            .arg(baseline)
             .arg(stdnet)
+            .arg(burst)
+            .arg(so_priority)
+            .arg(receiver_cpus)
+            .arg(receiver_priority)
+            .arg(router_alert)
+            .arg(watch_link)
+            .arg(tcp)
+            .arg(protocol)
+            .arg(tcp_request)
+            .arg(tcp_expect)
+            .arg(tcp_nodelay)
+            .arg(tcp_quickack)
+            .arg(tcp_congestion)
+            .arg(on_worker_error)
+            .arg(truncated_error)
+            .arg(compress)
+            .arg(units)
+            .arg(precision)
+            .arg(highlight)
+            .arg(warn_p99)
+            .arg(checkpoint)
+            .arg(resume)
+            .arg(key)
+            .arg(upload)
+            .arg(force)
+            .arg(sliding)
+            .arg(outliers)
+            .arg(outlier_threshold)
+            .arg(route_shift)
+            .arg(route_windows)
+            .arg(clock_drift)
+            .arg(starvation)
+            .arg(slowest)
+            .arg(mtu_probe)
+            .arg(mtu_interval)
+            .arg(histogram_digits)
+            .arg(histogram_max)
+            .arg(expect_size)
+            .arg(request_size)
+            .arg(pattern)
+            .arg(reply_size)
+            .arg(probe_ids)
+            .arg(localize_loss)
+            .arg(direction_jitter)
+            .arg(no_handshake)
+            .arg(probe_log)
+            .subcommand(server)
+            .subcommand(compare)
+            .subcommand(replay)
+            .subcommand(jobs)
+            .subcommand(completions)
     }
 
     fn print_error(&self, error: &str) -> ! {
+        if let Some(ref job) = self.job {
+            // only this job's thread stops, taking any workers it started
+            // with it as it unwinds
+            error!("job {}: {}", job.name, error);
+            ::std::panic::resume_unwind(Box::new(error.to_owned()));
+        }
         eprintln!("ERROR: {}\n", error);
         self.app.write_help(&mut ::std::io::stderr()).unwrap();
         eprintln!("");
@@ -0,0 +1,110 @@
+extern crate libc;
+extern crate time;
+
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+
+/// Describes a run, so that exported results remain attributable
+#[derive(Clone)]
+pub struct Metadata {
+    pub version: String,
+    pub command: String,
+    pub config_hash: u64,
+    pub start: String,
+    pub host: String,
+    pub run_id: u64,
+    /// Settings worth recording alongside the results, such as socket
+    /// options, as `(name, value)`
+    pub settings: Vec<(String, String)>,
+}
+
+impl Metadata {
+    pub fn new(run_id: u64) -> Metadata {
+        let args: Vec<String> = env::args().skip(1).collect();
+        Metadata::with_args(run_id, &args)
+    }
+
+    /// Describes a run configured by `args` rather than the command line,
+    /// as a job of a jobs file is
+    pub fn with_args(run_id: u64, args: &[String]) -> Metadata {
+        let program = env::args().next().unwrap_or_else(|| "ping-rs".to_owned());
+        // the program name doesn't change the configuration
+        let mut hasher = DefaultHasher::new();
+        args.hash(&mut hasher);
+        let mut command = vec![program];
+        command.extend(args.iter().cloned());
+        Metadata {
+            version: crate_version!().to_owned(),
+            command: command.join(" "),
+            config_hash: hasher.finish(),
+            start: time::strftime("%Y-%m-%dT%H:%M:%SZ", &time::now_utc()).unwrap(),
+            host: hostname(),
+            run_id: run_id,
+            settings: Vec::new(),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: String) {
+        self.settings.push((name.to_owned(), value));
+    }
+
+    /// Writes the metadata as `# key: value` comment lines
+    pub fn write_header<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(writeln!(w, "# ping-rs version: {}", self.version));
+        try!(writeln!(w, "# command: {}", self.command));
+        try!(writeln!(w, "# config hash: {:016x}", self.config_hash));
+        try!(writeln!(w, "# start: {}", self.start));
+        try!(writeln!(w, "# host: {}", self.host));
+        try!(writeln!(w, "# run id: {:016x}", self.run_id));
+        for &(ref name, ref value) in &self.settings {
+            try!(writeln!(w, "# {}: {}", name, value));
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        let settings: Vec<String> = self.settings
+            .iter()
+            .map(|&(ref name, ref value)| format!("\"{}\":\"{}\"", escape(name), escape(value)))
+            .collect();
+        format!("{{\"version\":\"{}\",\"command\":\"{}\",\"config_hash\":\"{:016x}\",\
+                 \"start\":\"{}\",\"host\":\"{}\",\"run_id\":\"{:016x}\",\
+                 \"settings\":{{{}}}}}",
+                escape(&self.version),
+                escape(&self.command),
+                self.config_hash,
+                escape(&self.start),
+                escape(&self.host),
+                self.run_id,
+                settings.join(","))
+    }
+}
+
+/// Escapes a string for inclusion in a JSON string literal
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "unknown".to_owned();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
@@ -0,0 +1,101 @@
+//! Detection of path MTU blackholes alongside normal probing
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tic::{Clocksource, Sample, Sender};
+
+use Metric;
+use control::Control;
+use probe::Probes;
+use sockopt;
+
+/// How long each watchdog probe waits for its reply
+const TIMEOUT_MS: u64 = 1000;
+
+/// Size of the small probe whose success shows the target is reachable
+const SMALL: usize = 64;
+
+/// Periodically sends a large probe with DF set, and a small one, to the
+/// current target from `src`. If the large probe starts failing while the
+/// small one succeeds, a PMTU blackhole is likely: this is logged when it
+/// begins and ends, and each failed check is counted as 'mtu_blackhole'
+pub fn watchdog(src: SocketAddr,
+                size: usize,
+                interval: Duration,
+                control: Arc<Control>,
+                clocksource: Clocksource,
+                stats: Sender<Metric>)
+                -> Result<(), String> {
+    let socket = match UdpSocket::bind(src) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Unable to bind MTU watchdog socket. {}", e)),
+    };
+    if let Err(e) = sockopt::set_dont_fragment(&socket) {
+        return Err(format!("Unable to set DF on MTU watchdog socket. {}", e));
+    }
+    let _ = socket.set_read_timeout(Some(Duration::from_millis(TIMEOUT_MS)));
+
+    let mut small = Probes::new(0, 0, false, None);
+    small.set_sizes(SMALL, None);
+    let mut large = Probes::new(0, 0, false, None);
+    large.set_sizes(size, None);
+
+    thread::spawn(move || {
+        let mut buffer = vec![0; 1024*64];
+        let mut failing = false;
+        while control.running() {
+            thread::sleep(interval);
+            if !control.active() {
+                continue;
+            }
+            let dst = control.target();
+            if let Err(e) = exchange(&socket, dst, &small.next_request(), &mut buffer) {
+                debug!("mtu: small probe to {} failed: {}", dst, e);
+                continue;
+            }
+            match exchange(&socket, dst, &large.next_request(), &mut buffer) {
+                Ok(()) => {
+                    if failing {
+                        info!("mtu: {}-byte DF probes to {} recovered", size, dst);
+                        failing = false;
+                    }
+                }
+                Err(e) => {
+                    if !failing {
+                        warn!("mtu: {}-byte DF probes to {} failing while small probes \
+                               succeed, possible PMTU blackhole: {}",
+                              size,
+                              dst,
+                              e);
+                        failing = true;
+                    }
+                    let t = clocksource.counter();
+                    let _ = stats.send(Sample::new(t, t, Metric::MtuBlackhole));
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// sends one request and waits for a reply of the same size
+fn exchange(socket: &UdpSocket,
+            dst: SocketAddr,
+            request: &[u8],
+            buffer: &mut [u8])
+            -> Result<(), String> {
+    if let Err(e) = socket.send_to(request, dst) {
+        return Err(format!("send failed: {}", e));
+    }
+    loop {
+        match socket.recv_from(buffer) {
+            // a late reply to an earlier probe of the other size
+            Ok((len, _)) if len != request.len() => continue,
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(format!("no reply: {}", e)),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+extern crate time;
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use output::Output;
+
+/// Number of recent RTTs the rolling median is taken over
+const HISTORY: usize = 128;
+
+/// The median is recomputed after this many samples to keep the hot path cheap
+const MEDIAN_REFRESH: usize = 32;
+
+/// Number of RTTs recorded on each side of an outlier
+const NEIGHBORS: usize = 8;
+
+struct Pending {
+    line: String,
+    after: Vec<u64>,
+}
+
+/// Detects probes slower than a multiple of the rolling median RTT and writes
+/// them, with the RTTs of neighboring probes, to a shared outliers file
+pub struct Outliers {
+    thread: usize,
+    threshold: f64,
+    history: VecDeque<u64>,
+    median: u64,
+    since_refresh: usize,
+    pending: Vec<Pending>,
+    log: Arc<Mutex<Output>>,
+}
+
+impl Outliers {
+    pub fn new(thread: usize, threshold: f64, log: Arc<Mutex<Output>>) -> Outliers {
+        Outliers {
+            thread: thread,
+            threshold: threshold,
+            history: VecDeque::with_capacity(HISTORY),
+            median: 0,
+            since_refresh: 0,
+            pending: Vec::new(),
+            log: log,
+        }
+    }
+
+    /// Observes a completed probe, returning true if it is an outlier
+    pub fn observe(&mut self,
+                   seq: u64,
+                   id: &str,
+                   sent: time::Timespec,
+                   monotonic: u64,
+                   rtt: u64)
+                   -> bool {
+        self.complete_pending(rtt);
+
+        let outlier = self.history.len() == HISTORY &&
+                      rtt as f64 > self.median as f64 * self.threshold;
+        if outlier {
+            let before: Vec<String> = self.history
+                .iter()
+                .skip(HISTORY - NEIGHBORS)
+                .map(|v| format!("{}", v))
+                .collect();
+            let sent = sent.sec as u64 * 1_000_000_000 + sent.nsec as u64;
+            self.pending.push(Pending {
+                line: format!("thread={} seq={} id={} sent={} monotonic={} rtt={} median={} \
+                               before={}",
+                              self.thread,
+                              seq,
+                              id,
+                              sent,
+                              monotonic,
+                              rtt,
+                              self.median,
+                              before.join(",")),
+                after: Vec::with_capacity(NEIGHBORS),
+            });
+        }
+
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(rtt);
+        self.since_refresh += 1;
+        if self.since_refresh >= MEDIAN_REFRESH {
+            self.since_refresh = 0;
+            let mut sorted: Vec<u64> = self.history.iter().cloned().collect();
+            sorted.sort();
+            self.median = sorted[sorted.len() / 2];
+        }
+        outlier
+    }
+
+    // adds an RTT to the trailing neighbors of pending outliers, writing out
+    // those which are complete
+    fn complete_pending(&mut self, rtt: u64) {
+        if self.pending.is_empty() {
+            return;
+        }
+        for p in &mut self.pending {
+            p.after.push(rtt);
+        }
+        let log = &self.log;
+        self.pending.retain(|p| {
+            if p.after.len() < NEIGHBORS {
+                return true;
+            }
+            write(log, p);
+            false
+        });
+    }
+}
+
+impl Drop for Outliers {
+    fn drop(&mut self) {
+        for p in &self.pending {
+            write(&self.log, p);
+        }
+    }
+}
+
+fn write(log: &Mutex<Output>, p: &Pending) {
+    let after: Vec<String> = p.after.iter().map(|v| format!("{}", v)).collect();
+    if let Ok(mut log) = log.lock() {
+        let _ = writeln!(log, "{} after={}", p.line, after.join(","));
+    }
+}
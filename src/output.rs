@@ -0,0 +1,179 @@
+#[cfg(feature = "compression")]
+extern crate flate2;
+#[cfg(feature = "compression")]
+extern crate zstd;
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::str::FromStr;
+
+/// Compression applied to files written by ping-rs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression implied by a path's extension
+    pub fn from_path(path: &str) -> Option<Compression> {
+        if path.ends_with(".gz") {
+            Some(Compression::Gzip)
+        } else if path.ends_with(".zst") {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// Returns the path a file should be written to, honoring an explicit
+    /// extension over this compression
+    pub fn apply(&self, path: &str) -> (String, Compression) {
+        match Compression::from_path(path) {
+            Some(c) => (path.to_owned(), c),
+            None => (format!("{}{}", path, self.extension()), *self),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression: {}", s)),
+        }
+    }
+}
+
+/// Inserts a suffix before any compression extension, so that `probes.gz`
+/// becomes `probes.0.gz` rather than `probes.gz.0`
+pub fn suffixed(path: &str, suffix: &str) -> String {
+    match Compression::from_path(path) {
+        Some(c) => {
+            let base = &path[..path.len() - c.extension().len()];
+            format!("{}.{}{}", base, suffix, c.extension())
+        }
+        None => format!("{}.{}", path, suffix),
+    }
+}
+
+enum Inner {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "compression")]
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    #[cfg(feature = "compression")]
+    Zstd(zstd::Encoder<BufWriter<File>>),
+}
+
+#[cfg(not(feature = "compression"))]
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "ping-rs was built without compression")
+}
+
+/// A buffered output file which is transparently compressed, and finalized
+/// when dropped
+pub struct Output {
+    inner: Option<Inner>,
+}
+
+impl Output {
+    pub fn create(path: &str, compression: Compression) -> io::Result<Output> {
+        let file = BufWriter::new(try!(File::create(path)));
+        let inner = match compression {
+            Compression::None => Inner::Plain(file),
+            #[cfg(feature = "compression")]
+            Compression::Gzip => {
+                Inner::Gzip(flate2::write::GzEncoder::new(file, flate2::Compression::Default))
+            }
+            #[cfg(feature = "compression")]
+            Compression::Zstd => Inner::Zstd(try!(zstd::Encoder::new(file, 0))),
+            #[cfg(not(feature = "compression"))]
+            Compression::Gzip | Compression::Zstd => return Err(unsupported()),
+        };
+        Ok(Output { inner: Some(inner) })
+    }
+
+    /// Writes any buffered data and the compression trailer
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.inner.take() {
+            Some(Inner::Plain(mut w)) => w.flush(),
+            #[cfg(feature = "compression")]
+            Some(Inner::Gzip(w)) => try!(w.finish()).flush(),
+            #[cfg(feature = "compression")]
+            Some(Inner::Zstd(w)) => try!(w.finish()).flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn writer(&mut self) -> io::Result<&mut Write> {
+        match self.inner {
+            Some(Inner::Plain(ref mut w)) => Ok(w as &mut Write),
+            #[cfg(feature = "compression")]
+            Some(Inner::Gzip(ref mut w)) => Ok(w as &mut Write),
+            #[cfg(feature = "compression")]
+            Some(Inner::Zstd(ref mut w)) => Ok(w as &mut Write),
+            None => Err(io::Error::new(io::ErrorKind::Other, "output already finished")),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.writer()).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.writer()).flush()
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Opens a file written by `Output`, decompressing it according to its
+/// extension
+pub fn open(path: &str) -> io::Result<Box<Read>> {
+    let file = BufReader::new(try!(File::open(path)));
+    Ok(match Compression::from_path(path) {
+        None | Some(Compression::None) => Box::new(file),
+        #[cfg(feature = "compression")]
+        Some(Compression::Gzip) => Box::new(try!(flate2::read::GzDecoder::new(file))),
+        #[cfg(feature = "compression")]
+        Some(Compression::Zstd) => Box::new(try!(zstd::Decoder::new(file))),
+        #[cfg(not(feature = "compression"))]
+        Some(Compression::Gzip) | Some(Compression::Zstd) => return Err(unsupported()),
+    })
+}
+
+/// Compresses a file written by another component (e.g. a tic trace) in
+/// place, replacing it with `<path><extension>`
+pub fn compress_file(path: &str, compression: Compression) -> io::Result<String> {
+    if compression == Compression::None {
+        return Ok(path.to_owned());
+    }
+    let target = format!("{}{}", path, compression.extension());
+    {
+        let mut input = try!(File::open(path));
+        let mut output = try!(Output::create(&target, compression));
+        try!(io::copy(&mut input, &mut output));
+        try!(output.finish());
+    }
+    try!(fs::remove_file(path));
+    Ok(target)
+}
@@ -0,0 +1,354 @@
+extern crate time;
+
+use std::io::Write;
+use std::str::FromStr;
+
+use output::Output;
+
+/// Generates a run ID which is unlikely to collide across hosts or restarts
+pub fn run_id() -> u64 {
+    let now = time::get_time();
+    ((now.sec as u64) << 32) ^ (now.nsec as u64) ^ time::precise_time_ns()
+}
+
+/// Largest reply a reflector will pad to, the most a UDP datagram can carry
+pub const MAX_REPLY: usize = 65507;
+
+// requests are `PING[ <id>][ stamp][ reply=<bytes>][ <filler>]\r\n`, where
+// the filler is a run of `.` padding the request to the configured size. With
+// a fill pattern the filler follows the CRLF instead, so that only the header
+// line is ever parsed. `stamp` asks the reflector to add the wall-clock times
+// at which it received the request and sent the reply, as `rx=<ns> tx=<ns>`
+
+/// Asks the reflector to timestamp the reply
+const STAMP: &'static str = "stamp";
+
+/// Bytes the reflector's ` rx=<ns> tx=<ns>` take in a reply, which stamped
+/// requests are padded to leave room for
+const STAMPS_LEN: usize = 46;
+
+/// Fills the padding of requests with something other than `.`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    Zero,
+    /// Fresh pseudo-random bytes for every probe, so payloads can't be
+    /// compressed or deduplicated
+    Random,
+    /// Bytes counting up from 0 and wrapping
+    Incrementing,
+    /// A sequence of bytes given in hex, e.g. `0xDEADBEEF`, repeated
+    Bytes(Vec<u8>),
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pattern, String> {
+        match s {
+            "zero" => return Ok(Pattern::Zero),
+            "random" => return Ok(Pattern::Random),
+            "incrementing" => return Ok(Pattern::Incrementing),
+            _ => {}
+        }
+        let hex = if s.starts_with("0x") || s.starts_with("0X") {
+            &s[2..]
+        } else {
+            return Err(format!("unknown pattern: {}", s));
+        };
+        if hex.is_empty() || hex.len() % 2 != 0 {
+            return Err(format!("pattern {} must have an even number of hex digits", s));
+        }
+        let mut bytes = Vec::with_capacity(hex.len() / 2);
+        for i in 0..hex.len() / 2 {
+            match u8::from_str_radix(&hex[2 * i..2 * i + 2], 16) {
+                Ok(b) => bytes.push(b),
+                Err(_) => return Err(format!("pattern {} is not valid hex", s)),
+            }
+        }
+        Ok(Pattern::Bytes(bytes))
+    }
+}
+
+impl Pattern {
+    // appends `len` bytes of the pattern, advancing `state` for random fill
+    fn fill(&self, payload: &mut Vec<u8>, len: usize, state: &mut u64) {
+        match *self {
+            Pattern::Zero => payload.extend(::std::iter::repeat(0).take(len)),
+            Pattern::Random => {
+                for _ in 0..len {
+                    // xorshift
+                    *state ^= *state << 13;
+                    *state ^= *state >> 7;
+                    *state ^= *state << 17;
+                    payload.push(*state as u8);
+                }
+            }
+            Pattern::Incrementing => payload.extend((0..len).map(|i| i as u8)),
+            Pattern::Bytes(ref bytes) => payload.extend(bytes.iter().cycle().take(len)),
+        }
+    }
+}
+
+// the header line of a payload, without any filler following it
+fn header(payload: &[u8]) -> (&[u8], &[u8]) {
+    match payload.iter().position(|&b| b == b'\r' || b == b'\n') {
+        Some(end) => {
+            let mut rest = &payload[end..];
+            if rest.starts_with(b"\r\n") {
+                rest = &rest[2..];
+            } else {
+                rest = &rest[1..];
+            }
+            (&payload[..end], rest)
+        }
+        None => (payload, &[]),
+    }
+}
+
+// the words of the header line, less any signature
+fn tokens(payload: &[u8]) -> Vec<&str> {
+    match ::std::str::from_utf8(header(payload).0) {
+        Ok(p) => p.split_whitespace().filter(|t| !t.starts_with("mac=")).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn is_filler(token: &str) -> bool {
+    token.bytes().all(|b| b == b'.')
+}
+
+/// The part of the IDs of a thread's probes before their sequence number
+pub fn stream(run: u64, thread: usize) -> String {
+    format!("{:016x}-{}", run, thread)
+}
+
+/// Returns the probe ID carried by a payload of the form `PING <id>\r\n`
+pub fn parse_id(payload: &[u8]) -> Option<&str> {
+    match tokens(payload).get(1) {
+        Some(id) if !id.starts_with("reply=") && *id != STAMP && !is_filler(id) => Some(id),
+        _ => None,
+    }
+}
+
+/// Returns the reply size a request asks the reflector to pad to, if any
+pub fn parse_reply_size(payload: &[u8]) -> Option<usize> {
+    tokens(payload)
+        .iter()
+        .filter_map(|t| if t.starts_with("reply=") { t[6..].parse().ok() } else { None })
+        .next()
+}
+
+/// Builds the reflector's answer to a request asking for a padded reply:
+/// the request's `PONG` line without its filler, padded with a filler of its
+/// own to the requested size, but to no more than `limit` bytes. If the
+/// request's filler follows its header line, the reply repeats that filler
+/// rather than using `.`
+pub fn padded_reply(payload: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let size = match parse_reply_size(payload) {
+        Some(size) => ::std::cmp::min(size, ::std::cmp::min(limit, MAX_REPLY)),
+        None => return None,
+    };
+    Some(reply(reply_words(payload).join(" "), header(payload).1, size))
+}
+
+/// Builds the reflector's answer to a request asking for timestamps, which
+/// was received at `rx` ns since the unix epoch: the request's `PONG` line
+/// with `rx=<ns> tx=<ns>` added, padded like a padded reply to the requested
+/// size or else the request's, but to no more than `limit` bytes. None if the
+/// stamped line alone is longer than that. The transmit time is taken as the
+/// reply is built, just before it is sent
+pub fn stamped_reply(payload: &[u8], rx: u64, limit: usize) -> Option<Vec<u8>> {
+    if !tokens(payload).contains(&STAMP) {
+        return None;
+    }
+    let size = parse_reply_size(payload).map_or(payload.len(), |s| ::std::cmp::min(s, MAX_REPLY));
+    let size = ::std::cmp::min(size, limit);
+    let rx = format!("rx={}", rx);
+    let now = time::get_time();
+    let tx = format!("tx={}", now.sec as u64 * 1_000_000_000 + now.nsec as u64);
+    let mut words = reply_words(payload);
+    words.push(&rx);
+    words.push(&tx);
+    let line = words.join(" ");
+    if line.len() + 2 > size {
+        return None;
+    }
+    Some(reply(line, header(payload).1, size))
+}
+
+/// The reflector's receive and transmit times carried by a reply, in ns since
+/// the unix epoch on its clock
+pub fn parse_stamps(payload: &[u8]) -> Option<(u64, u64)> {
+    let mut rx = None;
+    let mut tx = None;
+    for token in tokens(payload) {
+        if token.starts_with("rx=") {
+            rx = token[3..].parse().ok();
+        } else if token.starts_with("tx=") {
+            tx = token[3..].parse().ok();
+        }
+    }
+    match (rx, tx) {
+        (Some(rx), Some(tx)) => Some((rx, tx)),
+        _ => None,
+    }
+}
+
+// the words of the request's header line as the reply's, less its filler
+fn reply_words(payload: &[u8]) -> Vec<&str> {
+    let mut words: Vec<&str> = tokens(payload).into_iter().filter(|t| !is_filler(t)).collect();
+    if words.first() == Some(&"PING") {
+        words[0] = "PONG";
+    }
+    words
+}
+
+// a reply of `line` padded to `size` bytes, with `.` in the line, or with the
+// request's filler after it if it had one there
+fn reply(line: String, filler: &[u8], size: usize) -> Vec<u8> {
+    if filler.is_empty() {
+        return pad(line, size);
+    }
+    let mut reply = line.into_bytes();
+    reply.extend_from_slice(b"\r\n");
+    let len = size.saturating_sub(reply.len());
+    reply.extend(filler.iter().cycle().take(len));
+    reply
+}
+
+// pads a line to `size` bytes including the trailing CRLF, if it is shorter
+fn pad(mut line: String, size: usize) -> Vec<u8> {
+    if line.len() + 3 <= size {
+        line.push(' ');
+        let filler = size - line.len() - 2;
+        line.extend(::std::iter::repeat('.').take(filler));
+    }
+    line.push_str("\r\n");
+    line.into_bytes()
+}
+
+/// Returns the sequence number from a probe ID of the form
+/// `<run>-<thread>-<seq>`
+pub fn parse_seq(id: &str) -> Option<u64> {
+    id.rsplit('-').next().and_then(|seq| seq.parse().ok())
+}
+
+/// Builds the request payload for each probe sent by a worker, optionally
+/// tagging it with `<run>-<thread>-<seq>` and logging it for correlation with
+/// reflector logs and packet captures
+pub struct Probes {
+    run: u64,
+    thread: usize,
+    seq: u64,
+    tagged: bool,
+    stamped: bool,
+    request_size: usize,
+    reply_size: Option<usize>,
+    pattern: Option<Pattern>,
+    random: u64,
+    log: Option<Output>,
+}
+
+impl Probes {
+    pub fn new(run: u64, thread: usize, tagged: bool, log: Option<Output>) -> Probes {
+        Probes {
+            run: run,
+            thread: thread,
+            seq: 0,
+            tagged: tagged,
+            stamped: false,
+            request_size: 0,
+            reply_size: None,
+            pattern: None,
+            random: (run ^ ((thread as u64 + 1) << 32)) | 1,
+            log: log,
+        }
+    }
+
+    /// Pads requests to `request_size` bytes, and asks the reflector to pad
+    /// its replies to `reply_size` bytes
+    pub fn set_sizes(&mut self, request_size: usize, reply_size: Option<usize>) {
+        self.request_size = request_size;
+        self.reply_size = reply_size;
+    }
+
+    /// Fills the padding of requests with `pattern` after the header line,
+    /// rather than with `.` within it
+    pub fn set_pattern(&mut self, pattern: Option<Pattern>) {
+        self.pattern = pattern;
+    }
+
+    /// Asks the reflector to timestamp its replies
+    pub fn set_stamped(&mut self, stamped: bool) {
+        self.stamped = stamped;
+    }
+
+    /// Size the reflector was asked to pad replies to, if any
+    pub fn reply_size(&self) -> Option<usize> {
+        self.reply_size
+    }
+
+    /// Advances to the next probe and returns its request payload
+    pub fn next_request(&mut self) -> Vec<u8> {
+        self.seq += 1;
+        let mut line = "PING".to_owned();
+        if self.tagged {
+            line = format!("{} {}", line, self.id());
+        }
+        if self.stamped {
+            line = format!("{} {}", line, STAMP);
+        }
+        if let Some(size) = self.reply_size {
+            line = format!("{} reply={}", line, size);
+        }
+        // a reflector answers unsigned requests with no more than they carry,
+        // so stamped ones leave room for the stamps
+        let size = if self.stamped {
+            ::std::cmp::max(self.request_size, line.len() + STAMPS_LEN + 2)
+        } else {
+            self.request_size
+        };
+        match self.pattern {
+            Some(ref pattern) => {
+                let mut payload = line.into_bytes();
+                payload.extend_from_slice(b"\r\n");
+                let len = size.saturating_sub(payload.len());
+                pattern.fill(&mut payload, len, &mut self.random);
+                payload
+            }
+            None => pad(line, size),
+        }
+    }
+
+    /// Sequence number of the most recently generated probe
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Identifier of the most recently generated probe
+    pub fn id(&self) -> String {
+        self.id_of(self.seq)
+    }
+
+    /// Identifier of the probe with sequence number `seq`
+    pub fn id_of(&self, seq: u64) -> String {
+        format!("{}-{}", stream(self.run, self.thread), seq)
+    }
+
+    /// Logs a probe, if enabled, as `<id> <unix time ns> <monotonic ns> <rtt
+    /// ns>`, where both times are of the send
+    pub fn record(&mut self, seq: u64, sent: time::Timespec, monotonic: u64, rtt: u64) {
+        let id = self.id_of(seq);
+        if let Some(ref mut log) = self.log {
+            let sent = sent.sec as u64 * 1_000_000_000 + sent.nsec as u64;
+            let _ = writeln!(log, "{} {} {} {}", id, sent, monotonic, rtt);
+            if seq % 1024 == 0 {
+                let _ = log.flush();
+            }
+        }
+    }
+
+    pub fn logging(&self) -> bool {
+        self.log.is_some()
+    }
+}
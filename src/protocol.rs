@@ -0,0 +1,410 @@
+//! Protocols probes can speak. Each implements `Probe`, and is selected by
+//! name from a registry to which further protocols can be added with
+//! `register`
+//!
+//! Responses and requests are untrusted input: parsers check every length
+//! before indexing, and report what they can't parse as `Malformed`
+
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::RwLock;
+
+use probe::{self, Probes};
+
+/// What a response says about the probe it answers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// A valid answer, whose RTT is recorded
+    Ok,
+    /// An answer, but not one to this request, counted as 'mismatch'
+    Mismatch,
+    /// Not a valid message of the protocol, counted as 'malformed'
+    Malformed,
+    /// Not signed with the key requests are signed with, counted as
+    /// 'auth_failure'
+    Unauthenticated,
+}
+
+/// How the reflector answers the bytes received so far
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Answer {
+    /// A complete request, answered with this response
+    Reply(Vec<u8>),
+    /// The start of a request, whose remainder is still to arrive
+    Incomplete,
+    /// Not a request of this protocol
+    Unknown,
+}
+
+/// A request/response protocol
+pub trait Probe: Send {
+    /// Builds the request for the next probe. `probes` keeps the sequence
+    /// number and, for protocols which use them, the probe ID and padding
+    fn request(&mut self, probes: &mut Probes) -> Vec<u8>;
+
+    /// Classifies `response` as an answer to `request`
+    fn classify(&self, request: &[u8], response: &[u8]) -> Outcome;
+
+    /// Answers `received` as a server of the protocol would, so that the
+    /// reflector can stand in for one
+    fn answer(&self, _: &[u8]) -> Answer {
+        Answer::Unknown
+    }
+
+    /// Whether the protocol runs over UDP
+    fn udp(&self) -> bool {
+        true
+    }
+
+    /// Whether the protocol runs over TCP
+    fn tcp(&self) -> bool {
+        true
+    }
+
+    /// Frames messages for a TCP stream from now on, for protocols which
+    /// frame them differently there than in a datagram
+    fn use_tcp(&mut self) {}
+
+    /// Whether `received` on a TCP stream holds a whole message. Messages
+    /// there end in a newline unless the protocol frames them itself
+    fn framed(&self, received: &[u8]) -> bool {
+        received.contains(&b'\n')
+    }
+}
+
+/// Builds a protocol from the argument given after its name, as in
+/// `--protocol dns:example.com`
+pub type Constructor = fn(Option<&str>) -> Result<Box<Probe>, String>;
+
+lazy_static! {
+    static ref REGISTRY: RwLock<BTreeMap<String, Constructor>> = {
+        let mut registry = BTreeMap::new();
+        registry.insert("echo".to_owned(), echo as Constructor);
+        registry.insert("dns".to_owned(), dns as Constructor);
+        registry.insert("redis".to_owned(), redis as Constructor);
+        RwLock::new(registry)
+    };
+}
+
+/// Makes a protocol available to `create` under `name`, replacing any
+/// protocol of the same name
+pub fn register(name: &str, constructor: Constructor) {
+    REGISTRY.write().unwrap().insert(name.to_owned(), constructor);
+}
+
+/// Names of the registered protocols
+pub fn names() -> Vec<String> {
+    REGISTRY.read().unwrap().keys().cloned().collect()
+}
+
+/// Classifies `response` with `probe`, treating a parser which panics on it
+/// as having found it malformed, so that no response can bring down a worker
+pub fn classify(probe: &Probe, request: &[u8], response: &[u8]) -> Outcome {
+    match panic::catch_unwind(AssertUnwindSafe(|| probe.classify(request, response))) {
+        Ok(outcome) => outcome,
+        Err(_) => Outcome::Malformed,
+    }
+}
+
+/// Creates a protocol from a `<name>[:<argument>]` specification
+pub fn create(spec: &str) -> Result<Box<Probe>, String> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap_or("");
+    let argument = parts.next();
+    let constructor = REGISTRY.read().unwrap().get(name).cloned();
+    let constructor = match constructor {
+        Some(constructor) => constructor,
+        None => {
+            return Err(format!("unknown protocol: {}. Choose from: {}",
+                               name,
+                               names().join(" ")))
+        }
+    };
+    constructor(argument)
+}
+
+/// The ASCII echo protocol: `PING` requests, answered by an echo service or
+/// the included reflector. Any response is accepted, unless both it and the
+/// request carry a probe ID and they differ
+pub struct Echo;
+
+impl Probe for Echo {
+    fn request(&mut self, probes: &mut Probes) -> Vec<u8> {
+        probes.next_request()
+    }
+
+    fn classify(&self, request: &[u8], response: &[u8]) -> Outcome {
+        match (probe::parse_id(request), probe::parse_id(response)) {
+            (Some(sent), Some(received)) if sent != received => Outcome::Mismatch,
+            _ => Outcome::Ok,
+        }
+    }
+
+    /// `PONG`, padded to the size asked for by `reply=<bytes>` if given, to
+    /// `PING`, and anything else echoed back unchanged
+    fn answer(&self, received: &[u8]) -> Answer {
+        if let Some(reply) = probe::padded_reply(received, probe::MAX_REPLY) {
+            return Answer::Reply(reply);
+        }
+        let mut reply = received.to_vec();
+        if reply.starts_with(b"PING") {
+            reply[1] = b'O';
+        }
+        Answer::Reply(reply)
+    }
+}
+
+fn echo(argument: Option<&str>) -> Result<Box<Probe>, String> {
+    match argument {
+        Some(_) => Err("echo takes no argument".to_owned()),
+        None => Ok(Box::new(Echo)),
+    }
+}
+
+/// DNS queries for the A record of a name, e.g. against a resolver on port
+/// 53. A response must carry the query's ID and the response flag, whatever
+/// its response code, as an NXDOMAIN is as much an answer as any. Over TCP
+/// each message follows its length, in two bytes big-endian, as RFC 1035
+/// frames them on a stream
+pub struct Dns {
+    name: Vec<u8>,
+    stream: bool,
+}
+
+/// The longest query the reflector answers over TCP: the header, one
+/// question of the longest name, its type and class
+const DNS_MAX_QUERY: usize = 12 + 255 + 4;
+
+impl Dns {
+    pub fn new(name: &str) -> Result<Dns, String> {
+        let mut encoded = Vec::new();
+        let absolute = name.ends_with('.');
+        for label in name[..name.len() - absolute as usize].split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(format!("invalid DNS name: {}", name));
+            }
+            encoded.push(label.len() as u8);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+        encoded.push(0);
+        Ok(Dns {
+            name: encoded,
+            stream: false,
+        })
+    }
+}
+
+impl Probe for Dns {
+    fn request(&mut self, probes: &mut Probes) -> Vec<u8> {
+        // the ID consumes a sequence number, so probes stay countable
+        probes.next_request();
+        let id = probes.seq() as u16;
+        // ID, flags with recursion desired, one question
+        let mut query = vec![(id >> 8) as u8, id as u8, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(&self.name);
+        // type A, class IN
+        query.extend_from_slice(&[0, 1, 0, 1]);
+        if self.stream {
+            length_prefixed(query)
+        } else {
+            query
+        }
+    }
+
+    fn classify(&self, request: &[u8], response: &[u8]) -> Outcome {
+        let (request, response) = if self.stream {
+            match (unprefixed(request), unprefixed(response)) {
+                (Some(request), Some(response)) => (request, response),
+                _ => return Outcome::Malformed,
+            }
+        } else {
+            (request, response)
+        };
+        if !dns_message(response) {
+            return Outcome::Malformed;
+        }
+        if request.len() >= 2 && response[..2] == request[..2] && response[2] & 0x80 != 0 {
+            Outcome::Ok
+        } else {
+            Outcome::Mismatch
+        }
+    }
+
+    /// An empty answer with no error to a standard query
+    fn answer(&self, received: &[u8]) -> Answer {
+        if !self.stream {
+            return answer_query(received);
+        }
+        // a length no query could have is the start of another protocol's
+        // request, such as the `PI` of `PING`
+        let len = match prefix(received) {
+            Some(len) if len >= 12 && len <= DNS_MAX_QUERY => len,
+            _ => return Answer::Unknown,
+        };
+        if received.len() < 2 + len {
+            return Answer::Incomplete;
+        }
+        match unprefixed(received).map(answer_query) {
+            Some(Answer::Reply(reply)) => Answer::Reply(length_prefixed(reply)),
+            _ => Answer::Unknown,
+        }
+    }
+
+    fn use_tcp(&mut self) {
+        self.stream = true;
+    }
+
+    fn framed(&self, received: &[u8]) -> bool {
+        prefix(received).map_or(false, |len| received.len() >= 2 + len)
+    }
+}
+
+// a response with no answers to `query`, if it is a standard query
+fn answer_query(query: &[u8]) -> Answer {
+    // a query has the QR bit and opcode clear, and one question
+    if query.len() < 12 || query[2] & 0xf8 != 0 || query[4..6] != [0, 1] {
+        return Answer::Unknown;
+    }
+    let mut reply = query.to_vec();
+    // response, recursion desired as asked, recursion available
+    reply[2] = 0x80 | (query[2] & 0x01);
+    reply[3] = 0x80;
+    Answer::Reply(reply)
+}
+
+// `message` behind its length, as DNS frames messages on a stream
+fn length_prefixed(message: Vec<u8>) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(2 + message.len());
+    framed.push((message.len() >> 8) as u8);
+    framed.push(message.len() as u8);
+    framed.extend(message);
+    framed
+}
+
+// the length a message on a stream starts with, once it has arrived
+fn prefix(received: &[u8]) -> Option<usize> {
+    if received.len() < 2 {
+        return None;
+    }
+    Some((received[0] as usize) << 8 | received[1] as usize)
+}
+
+// the message behind the length `framed` starts with, if that is all it holds
+fn unprefixed(framed: &[u8]) -> Option<&[u8]> {
+    match prefix(framed) {
+        Some(len) if framed.len() == 2 + len => Some(&framed[2..]),
+        _ => None,
+    }
+}
+
+// whether `message` is a DNS message whose sections all fit within it
+fn dns_message(message: &[u8]) -> bool {
+    if message.len() < 12 {
+        return false;
+    }
+    let count = |i: usize| (message[i] as usize) << 8 | message[i + 1] as usize;
+    let mut offset = 12;
+    for _ in 0..count(4) {
+        // name, type, class
+        offset = match dns_name(message, offset) {
+            Some(end) => end + 4,
+            None => return false,
+        };
+    }
+    for _ in 0..count(6) + count(8) + count(10) {
+        // name, type, class, TTL, data length, data
+        offset = match dns_name(message, offset) {
+            Some(end) if end + 10 <= message.len() => {
+                end + 10 + ((message[end + 8] as usize) << 8 | message[end + 9] as usize)
+            }
+            _ => return false,
+        };
+    }
+    offset <= message.len()
+}
+
+// the offset after the name at `offset`, which ends at a zero length label
+// or a compression pointer
+fn dns_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = match message.get(offset) {
+            Some(&len) => len as usize,
+            None => return None,
+        };
+        match len & 0xc0 {
+            0x00 if len == 0 => return Some(offset + 1),
+            0x00 => offset += 1 + len,
+            0xc0 if offset + 2 <= message.len() => return Some(offset + 2),
+            _ => return None,
+        }
+    }
+}
+
+fn dns(argument: Option<&str>) -> Result<Box<Probe>, String> {
+    Dns::new(argument.unwrap_or("example.com")).map(|dns| Box::new(dns) as Box<Probe>)
+}
+
+/// Redis `PING` commands, answered with `+PONG`
+pub struct Redis;
+
+const REDIS_PING: &'static [u8] = b"*1\r\n$4\r\nPING\r\n";
+const REDIS_PONG: &'static [u8] = b"+PONG\r\n";
+
+impl Probe for Redis {
+    fn request(&mut self, probes: &mut Probes) -> Vec<u8> {
+        probes.next_request();
+        REDIS_PING.to_vec()
+    }
+
+    fn classify(&self, _: &[u8], response: &[u8]) -> Outcome {
+        if response == REDIS_PONG {
+            Outcome::Ok
+        } else if resp_reply(response) {
+            Outcome::Mismatch
+        } else {
+            Outcome::Malformed
+        }
+    }
+
+    fn answer(&self, received: &[u8]) -> Answer {
+        if received == REDIS_PING {
+            Answer::Reply(REDIS_PONG.to_vec())
+        } else if REDIS_PING.starts_with(received) {
+            Answer::Incomplete
+        } else {
+            Answer::Unknown
+        }
+    }
+
+    fn udp(&self) -> bool {
+        false
+    }
+}
+
+// whether `line` is the first line of a RESP reply: a simple string, error or
+// integer, or the length of a bulk string or array, ending in CRLF
+fn resp_reply(line: &[u8]) -> bool {
+    if line.len() < 3 || !line.ends_with(b"\r\n") {
+        return false;
+    }
+    let value = &line[1..line.len() - 2];
+    if value.iter().any(|&b| b == b'\r' || b == b'\n') {
+        return false;
+    }
+    let integer = || {
+        let digits = if value.first() == Some(&b'-') { &value[1..] } else { value };
+        !digits.is_empty() && digits.iter().all(|&b| b >= b'0' && b <= b'9')
+    };
+    match line[0] {
+        b'+' | b'-' => true,
+        b':' | b'$' | b'*' => integer(),
+        _ => false,
+    }
+}
+
+fn redis(argument: Option<&str>) -> Result<Box<Probe>, String> {
+    match argument {
+        Some(_) => Err("redis takes no argument".to_owned()),
+        None => Ok(Box::new(Redis)),
+    }
+}
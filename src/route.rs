@@ -0,0 +1,78 @@
+//! Detection of route changes from shifts in the minimum RTT
+
+use std::collections::VecDeque;
+
+/// Locks onto the minimum RTT over the most recent windows. The minimum is
+/// the propagation and fixed processing delay of a path, so a shift in it is
+/// the classic sign of a route change or failover
+pub struct MinimumLock {
+    windows: usize,
+    shift: u64,
+    minima: VecDeque<u64>,
+}
+
+impl MinimumLock {
+    /// Tracks the minimum over `windows` windows, reporting shifts of more
+    /// than `shift` ns
+    pub fn new(windows: usize, shift: u64) -> MinimumLock {
+        MinimumLock {
+            windows: windows,
+            shift: shift,
+            minima: VecDeque::with_capacity(windows),
+        }
+    }
+
+    /// The rolling minimum, once a window has been observed
+    pub fn minimum(&self) -> Option<u64> {
+        self.minima.iter().cloned().min()
+    }
+
+    /// Adds the minimum RTT of a window. If it differs from the rolling
+    /// minimum by more than the configured shift, the lock moves to the new
+    /// level and the previous rolling minimum is returned
+    pub fn observe(&mut self, minimum: u64) -> Option<u64> {
+        let shifted = match self.minimum() {
+            Some(locked) if difference(locked, minimum) > self.shift => Some(locked),
+            _ => None,
+        };
+        if shifted.is_some() {
+            self.minima.clear();
+        }
+        self.minima.push_back(minimum);
+        while self.minima.len() > self.windows {
+            self.minima.pop_front();
+        }
+        shifted
+    }
+}
+
+fn difference(a: u64, b: u64) -> u64 {
+    if a > b { a - b } else { b - a }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shifts_beyond_the_threshold_move_the_lock() {
+        let mut lock = MinimumLock::new(3, 1000);
+        assert_eq!(lock.minimum(), None);
+        assert_eq!(lock.observe(10_000), None);
+        assert_eq!(lock.observe(10_500), None);
+        assert_eq!(lock.observe(12_000), Some(10_000));
+        assert_eq!(lock.minimum(), Some(12_000));
+        assert_eq!(lock.observe(11_500), None);
+    }
+
+    #[test]
+    fn the_minimum_rolls_over_the_recent_windows() {
+        let mut lock = MinimumLock::new(2, 1000);
+        for minimum in &[10_000, 10_800, 10_900] {
+            assert_eq!(lock.observe(*minimum), None);
+        }
+        // 10000 has rolled out, so a slow drift isn't taken for a shift
+        assert_eq!(lock.minimum(), Some(10_800));
+        assert_eq!(lock.observe(11_500), None);
+    }
+}
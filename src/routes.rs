@@ -0,0 +1,68 @@
+//! Static routes for the userspace stack, read from a file with one route
+//! per line: a prefix and the gateway probes to it are sent through, e.g.
+//!
+//! ```text
+//! # prefix gateway
+//! 10.1.0.0/16 192.168.1.1
+//! 10.2.0.0/16 192.168.1.2
+//! ```
+//!
+//! so that targets behind different upstreams of one host are reached
+//! through their own next hops. The most specific route to a target is
+//! taken, and those no route covers go through `--gateway`. Blank lines and
+//! those starting with `#` are ignored
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use ipnetwork::Ipv4Network;
+
+/// A prefix and the gateway which reaches it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub prefix: Ipv4Network,
+    pub gateway: Ipv4Addr,
+}
+
+/// Reads the routes in `path`, whose prefixes must be unique
+pub fn load(path: &str) -> Result<Vec<Route>, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(format!("Unable to open routes file {}. {}", path, e)),
+    };
+    let mut routes: Vec<Route> = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Err(format!("Unable to read routes file {}. {}", path, e)),
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 2 {
+            return Err(format!("{}:{}: expected <prefix> <gateway>", path, number + 1));
+        }
+        let prefix = match Ipv4Network::from_cidr(fields[0]) {
+            Ok(prefix) => prefix,
+            Err(_) => return Err(format!("{}:{}: invalid prefix {}", path, number + 1, fields[0])),
+        };
+        let gateway = match Ipv4Addr::from_str(fields[1]) {
+            Ok(gateway) => gateway,
+            Err(_) => {
+                return Err(format!("{}:{}: invalid gateway {}", path, number + 1, fields[1]))
+            }
+        };
+        if routes.iter().any(|r| r.prefix == prefix) {
+            return Err(format!("{}:{}: {} is routed twice", path, number + 1, prefix));
+        }
+        routes.push(Route {
+            prefix: prefix,
+            gateway: gateway,
+        });
+    }
+    Ok(routes)
+}
@@ -0,0 +1,198 @@
+//! CPU affinity and scheduling priority of the calling thread, so that the
+//! thread draining the stats queue can be kept ahead of the workers filling
+//! it. Threads inherit both from the thread which starts them, so they are
+//! set once the workers are running
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// How the kernel schedules a thread
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// A nice value, -20 to 19. Below 0 needs CAP_SYS_NICE
+    Nice(i32),
+    /// The SCHED_FIFO realtime policy at 1 to 99, which always runs ahead of
+    /// normal threads. Needs CAP_SYS_NICE
+    Fifo(i32),
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    /// Parses a nice value, e.g. `-10`, or `fifo:<priority>`
+    fn from_str(s: &str) -> Result<Priority, String> {
+        if s.starts_with("fifo:") {
+            match s[5..].parse::<i32>() {
+                Ok(p) if p >= 1 && p <= 99 => Ok(Priority::Fifo(p)),
+                _ => Err(format!("invalid priority: {}. SCHED_FIFO priorities are 1 to 99", s)),
+            }
+        } else {
+            match s.parse::<i32>() {
+                Ok(n) if n >= -20 && n <= 19 => Ok(Priority::Nice(n)),
+                _ => {
+                    Err(format!("invalid priority: {}. Give a nice value of -20 to 19 or \
+                                 fifo:<1-99>",
+                                s))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Priority::Nice(n) => write!(f, "{}", n),
+            Priority::Fifo(p) => write!(f, "fifo:{}", p),
+        }
+    }
+}
+
+/// Parses a list of CPUs and ranges, e.g. `2` or `0,4-7`
+pub fn parse_cpus(s: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for part in s.split(',') {
+        let mut bounds = part.splitn(2, '-');
+        let first = bounds.next().unwrap_or("").trim().parse::<usize>();
+        let last = match bounds.next() {
+            Some(last) => last.trim().parse::<usize>(),
+            None => first.clone(),
+        };
+        match (first, last) {
+            (Ok(first), Ok(last)) if first <= last && last < imp::MAX_CPUS => {
+                for cpu in first..last + 1 {
+                    if !cpus.contains(&cpu) {
+                        cpus.push(cpu);
+                    }
+                }
+            }
+            _ => return Err(format!("invalid CPU list: {}. Give CPUs and ranges, e.g. 0,4-7", s)),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Restricts the calling thread to `cpus`
+pub fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    imp::set_affinity(cpus)
+}
+
+/// Sets the priority of the calling thread
+pub fn set_priority(priority: Priority) -> io::Result<()> {
+    imp::set_priority(priority)
+}
+
+/// The CPUs the calling thread may run on
+pub fn affinity() -> io::Result<Vec<usize>> {
+    imp::affinity()
+}
+
+/// The priority of the calling thread
+pub fn priority() -> io::Result<Priority> {
+    imp::priority()
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    extern crate libc;
+
+    use std::io;
+    use std::mem;
+
+    use super::Priority;
+
+    pub const MAX_CPUS: usize = 1024;
+
+    fn result(ret: libc::c_int) -> io::Result<()> {
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for &cpu in cpus {
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        // pid 0 is the calling thread
+        result(unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) })
+    }
+
+    pub fn affinity() -> io::Result<Vec<usize>> {
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        try!(result(unsafe {
+            libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set)
+        }));
+        Ok((0..MAX_CPUS).filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) }).collect())
+    }
+
+    pub fn priority() -> io::Result<Priority> {
+        let policy = unsafe { libc::sched_getscheduler(0) };
+        if policy < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if policy == libc::SCHED_FIFO {
+            let mut param: libc::sched_param = unsafe { mem::zeroed() };
+            try!(result(unsafe { libc::sched_getparam(0, &mut param) }));
+            return Ok(Priority::Fifo(param.sched_priority));
+        }
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+        // -1 is also a valid nice value, so errors are told apart by errno
+        unsafe { *libc::__errno_location() = 0 };
+        let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, tid) };
+        let error = io::Error::last_os_error();
+        if nice == -1 && error.raw_os_error() != Some(0) {
+            return Err(error);
+        }
+        Ok(Priority::Nice(nice))
+    }
+
+    pub fn set_priority(priority: Priority) -> io::Result<()> {
+        match priority {
+            Priority::Nice(nice) => {
+                // on Linux a nice value belongs to the thread, named by its
+                // thread ID
+                let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+                result(unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) })
+            }
+            Priority::Fifo(p) => {
+                let mut param: libc::sched_param = unsafe { mem::zeroed() };
+                param.sched_priority = p;
+                result(unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) })
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+
+    use super::Priority;
+
+    pub const MAX_CPUS: usize = 1024;
+
+    fn unsupported() -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other,
+                           "thread affinity and priority are only supported on Linux"))
+    }
+
+    pub fn set_affinity(_: &[usize]) -> io::Result<()> {
+        unsupported()
+    }
+
+    pub fn set_priority(_: Priority) -> io::Result<()> {
+        unsupported()
+    }
+
+    pub fn affinity() -> io::Result<Vec<usize>> {
+        unsupported().map(|_| Vec::new())
+    }
+
+    pub fn priority() -> io::Result<Priority> {
+        unsupported().map(|_| Priority::Nice(0))
+    }
+}
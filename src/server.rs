@@ -0,0 +1,382 @@
+extern crate ipnetwork;
+extern crate time;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+
+use self::ipnetwork::Ipv4Network;
+
+use auth::{Direction, Key};
+use capability::{self, Capabilities};
+use ledger::{self, Ledger};
+use output::Output;
+use probe;
+use protocol::{self, Answer, Probe};
+
+/// A minimal reflector, answering each registered protocol as its server
+/// would: for the ASCII ping protocol `PING` with `PONG`, padded to the size
+/// asked for by `reply=<bytes>` if given, though over UDP to no more than the
+/// request's own size unless it is signed, and any datagram no protocol
+/// recognizes is echoed back unchanged. TCP connections on the same address
+/// are answered line by line in the same way, each subject to `policy`. A
+/// client's handshake is answered with the features the reflector supports,
+/// and a query for the probes received of one of its threads with their tally.
+/// Probes asking for timestamps are answered over UDP with the times they
+/// were received and answered
+pub fn run(listen: SocketAddr, log: Option<Output>, policy: Policy) {
+    let policy = Arc::new(policy);
+    match TcpListener::bind(listen) {
+        Ok(listener) => {
+            let policy = policy.clone();
+            thread::spawn(move || accept(listener, policy));
+        }
+        Err(e) => warn!("unable to bind tcp {}: {}", listen, e),
+    }
+
+    let socket = match UdpSocket::bind(listen) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("unable to bind {}: {}", listen, e);
+            return;
+        }
+    };
+    info!("reflector listening on {}", listen);
+    reflect_udp(socket, log, policy);
+}
+
+/// Who the reflector answers, so that one exposed to the internet can't be
+/// used to reflect or amplify traffic towards addresses spoofed as sources
+#[derive(Clone, Default)]
+pub struct Policy {
+    /// Networks whose sources are answered, or any if empty. IPv6 sources
+    /// are refused when given
+    pub allow: Vec<Ipv4Network>,
+    /// Bytes answered per second to each source address, in bursts of up to
+    /// as many
+    pub rate: Option<u32>,
+    /// Only requests signed with this key are answered, and replies are
+    /// signed with it in turn. Other protocols than echo can't be signed, so
+    /// aren't answered
+    pub key: Option<Key>,
+}
+
+impl Policy {
+    fn allows(&self, source: IpAddr) -> bool {
+        if self.allow.is_empty() {
+            return true;
+        }
+        match source {
+            IpAddr::V4(ip) => self.allow.iter().any(|network| network.contains(ip)),
+            IpAddr::V6(_) => false,
+        }
+    }
+
+    // the answer to a handshake, given without authentication so that a
+    // client lacking the key learns that it needs one
+    fn welcome(&self, received: &[u8]) -> Option<Vec<u8>> {
+        capability::welcome(received, &Capabilities::local(self.key.is_some()))
+    }
+
+    // the reply to `received`, unless the key is missing from it
+    fn answer(&self, protocols: &[Box<Probe>], received: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+        if capability::is_hello(received) {
+            return self.welcome(received).map(Some).ok_or(());
+        }
+        match self.key {
+            Some(ref key) => {
+                match key.verify(received, Direction::Request) {
+                    Some(request) => {
+                        Ok(answer(protocols, &request).map(|r| key.sign(&r, Direction::Reply)))
+                    }
+                    None => Err(()),
+                }
+            }
+            None => Ok(answer(protocols, received)),
+        }
+    }
+}
+
+/// How long a source may be silent before it is forgotten by the rate limit
+const IDLE_NS: u64 = 60_000_000_000;
+
+// a token bucket of bytes per source address
+struct RateLimit {
+    rate: u32,
+    buckets: HashMap<IpAddr, (f64, u64)>,
+    pruned: u64,
+}
+
+impl RateLimit {
+    fn new(rate: u32) -> RateLimit {
+        RateLimit {
+            rate: rate,
+            buckets: HashMap::new(),
+            pruned: time::precise_time_ns(),
+        }
+    }
+
+    // whether `bytes` more may be sent to `source`, charging them if so
+    fn admit(&mut self, source: IpAddr, bytes: usize) -> bool {
+        let now = time::precise_time_ns();
+        // spoofed sources would otherwise grow the table without bound
+        if now - self.pruned > IDLE_NS {
+            self.buckets.retain(|_, &mut (_, last)| now - last < IDLE_NS);
+            self.pruned = now;
+        }
+        let rate = self.rate as f64;
+        let bucket = self.buckets.entry(source).or_insert((rate, now));
+        bucket.0 = (bucket.0 + (now - bucket.1) as f64 * rate / 1_000_000_000.0).min(rate);
+        bucket.1 = now;
+        if bucket.0 >= bytes as f64 {
+            bucket.0 -= bytes as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The reflector run in background threads of the current process, serving
+/// UDP and TCP on one address until the process exits
+pub struct EchoServer {
+    addr: SocketAddr,
+}
+
+impl EchoServer {
+    /// Starts a reflector on `listen`, logging tagged probes to `log`. A port
+    /// of 0 picks a free one, which `addr` then reports
+    pub fn spawn(listen: SocketAddr, log: Option<Output>) -> io::Result<EchoServer> {
+        EchoServer::spawn_with_policy(listen, log, Policy::default())
+    }
+
+    /// Starts a reflector on `listen` which answers only as `policy` allows
+    pub fn spawn_with_policy(listen: SocketAddr,
+                             log: Option<Output>,
+                             policy: Policy)
+                             -> io::Result<EchoServer> {
+        let socket = try!(UdpSocket::bind(listen));
+        let addr = try!(socket.local_addr());
+        let listener = try!(TcpListener::bind(addr));
+        let policy = Arc::new(policy);
+        let tcp_policy = policy.clone();
+        thread::spawn(move || accept(listener, tcp_policy));
+        thread::spawn(move || reflect_udp(socket, log, policy));
+        Ok(EchoServer { addr: addr })
+    }
+
+    /// Starts a reflector on a free port of 127.0.0.1
+    pub fn spawn_on_localhost() -> io::Result<EchoServer> {
+        EchoServer::spawn_on_localhost_with_policy(Policy::default())
+    }
+
+    /// Starts a reflector on a free port of 127.0.0.1 which answers only as
+    /// `policy` allows
+    pub fn spawn_on_localhost_with_policy(policy: Policy) -> io::Result<EchoServer> {
+        let listen = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0));
+        // the free UDP port may be taken for TCP, so retry on another
+        let mut attempts = 0;
+        loop {
+            match EchoServer::spawn_with_policy(listen, None, policy.clone()) {
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse && attempts < 16 => {
+                    attempts += 1
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The address the reflector serves
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+// the registered protocols which can be reflected over UDP or TCP, with echo
+// last as it answers anything
+fn protocols(udp: bool) -> Vec<Box<Probe>> {
+    let mut names: Vec<String> = protocol::names().into_iter().filter(|n| n != "echo").collect();
+    names.push("echo".to_owned());
+    let mut protocols: Vec<Box<Probe>> = names.iter()
+        .filter_map(|name| protocol::create(name).ok())
+        .filter(|p| if udp { p.udp() } else { p.tcp() })
+        .collect();
+    if !udp {
+        for protocol in &mut protocols {
+            protocol.use_tcp();
+        }
+    }
+    protocols
+}
+
+// the first answer to `received`, or None when a request is still arriving
+fn answer(protocols: &[Box<Probe>], received: &[u8]) -> Option<Vec<u8>> {
+    for protocol in protocols {
+        match protocol.answer(received) {
+            Answer::Reply(reply) => return Some(reply),
+            Answer::Incomplete => return None,
+            Answer::Unknown => {}
+        }
+    }
+    Some(received.to_vec())
+}
+
+/// How often the reflector reports the datagrams its policy dropped
+const DROP_REPORT_NS: u64 = 60_000_000_000;
+
+fn reflect_udp(socket: UdpSocket, mut log: Option<Output>, policy: Arc<Policy>) {
+    let protocols = protocols(true);
+    let mut buffer = vec![0; 1024*64];
+    let mut count: u64 = 0;
+    let mut limit = policy.rate.map(RateLimit::new);
+    // datagrams dropped as from sources not allowed, over the rate limit, and
+    // failing authentication
+    let mut dropped = (0, 0, 0);
+    let mut reported = time::precise_time_ns();
+    let mut ledger = Ledger::new();
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buffer) {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("recv error: {}", e);
+                continue;
+            }
+        };
+        let received = time::get_time();
+        if dropped != (0, 0, 0) && time::precise_time_ns() - reported > DROP_REPORT_NS {
+            info!("reflector: dropped: denied: {} rate: {} auth: {}",
+                  dropped.0,
+                  dropped.1,
+                  dropped.2);
+            dropped = (0, 0, 0);
+            reported = time::precise_time_ns();
+        }
+        if !policy.allows(peer.ip()) {
+            dropped.0 += 1;
+            continue;
+        }
+        let reply = if capability::is_hello(&buffer[..len]) {
+            policy.welcome(&buffer[..len])
+        } else if ledger::is_query(&buffer[..len]) {
+            // like the handshake, tallies are answered without
+            // authentication, though only authenticated probes are counted in
+            // them
+            ledger.answer(&buffer[..len])
+        } else {
+            let request = match policy.key {
+                Some(ref key) => {
+                    match key.verify(&buffer[..len], Direction::Request) {
+                        Some(request) => request,
+                        None => {
+                            dropped.2 += 1;
+                            continue;
+                        }
+                    }
+                }
+                None => buffer[..len].to_vec(),
+            };
+            let received = received.sec as u64 * 1_000_000_000 + received.nsec as u64;
+            if let Some(id) = probe::parse_id(&request) {
+                ledger.observe(id);
+                if let Some(ref mut log) = log {
+                    let _ = writeln!(log,
+                                     "{} {} {} {}",
+                                     id,
+                                     received,
+                                     time::precise_time_ns(),
+                                     peer);
+                }
+            }
+            if let Some(ref mut log) = log {
+                count += 1;
+                if count % 1024 == 0 {
+                    let _ = log.flush();
+                }
+            }
+            // padded replies are built here rather than by the echo protocol
+            // so that an unsigned request, whose source may be spoofed, is
+            // never answered with more than it carried
+            let cap = if policy.key.is_some() { probe::MAX_REPLY } else { len };
+            probe::stamped_reply(&request, received, cap)
+                .or_else(|| probe::padded_reply(&request, cap))
+                .or_else(|| answer(&protocols, &request))
+                .map(|reply| match policy.key {
+                    Some(ref key) => key.sign(&reply, Direction::Reply),
+                    None => reply,
+                })
+        };
+        let reply = match reply {
+            Some(reply) => reply,
+            None => continue,
+        };
+        if let Some(ref mut limit) = limit {
+            if !limit.admit(peer.ip(), reply.len()) {
+                dropped.1 += 1;
+                continue;
+            }
+        }
+        let _ = socket.send_to(&reply, peer);
+    }
+}
+
+fn accept(listener: TcpListener, policy: Arc<Policy>) {
+    for stream in listener.incoming() {
+        if let Ok(stream) = stream {
+            let allowed = stream.peer_addr().map(|peer| policy.allows(peer.ip())).unwrap_or(false);
+            if allowed {
+                let policy = policy.clone();
+                thread::spawn(move || reflect_tcp(stream, policy));
+            }
+        }
+    }
+}
+
+fn reflect_tcp(stream: TcpStream, policy: Arc<Policy>) {
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(_) => return,
+    };
+    let protocols = protocols(false);
+    let mut reader = BufReader::new(stream);
+    let mut received = Vec::new();
+    loop {
+        // a line at a time, so that protocols framing requests by newline see
+        // one at once, while those framing them by length needn't send one.
+        // No request is longer than the longest reply, so a peer which never
+        // ends one can't grow the buffer without bound
+        let read = match reader.fill_buf() {
+            Ok(available) if !available.is_empty() => {
+                let line = available.iter()
+                    .position(|&b| b == b'\n')
+                    .map_or(available.len(), |end| end + 1);
+                let read = cmp::min(line, probe::MAX_REPLY - received.len());
+                received.extend_from_slice(&available[..read]);
+                read
+            }
+            _ => return,
+        };
+        reader.consume(read);
+        if !protocols.iter().any(|p| p.framed(&received)) {
+            if received.len() >= probe::MAX_REPLY {
+                return;
+            }
+            continue;
+        }
+        // requests spanning several lines are answered once complete, while
+        // a connection failing authentication is closed
+        match policy.answer(&protocols, &received) {
+            Ok(Some(reply)) => {
+                if writer.write_all(&reply).is_err() {
+                    return;
+                }
+                received.clear();
+            }
+            Ok(None) if received.len() >= probe::MAX_REPLY => return,
+            Ok(None) => {}
+            Err(()) => return,
+        }
+    }
+}
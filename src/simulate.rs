@@ -0,0 +1,174 @@
+//! A synthetic path with known impairments, so that the reporting of loss,
+//! latency and percentiles can be checked against values known in advance
+
+use std::fmt;
+use std::str::FromStr;
+
+use units::{self, Span};
+
+/// Impairments of the simulated path, as given to `--simulate`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Impairment {
+    /// Percentage of probes lost, counted as 'timeout'
+    pub loss: f64,
+    /// Median RTT in ns
+    pub delay: u64,
+    /// RTTs are spread uniformly over `delay` +/- `jitter` ns
+    pub jitter: u64,
+    /// Percentage of replies overtaken by the next one, counted as 'mismatch'
+    pub reorder: f64,
+    /// Seed for reproducible runs, otherwise taken from the clock
+    pub seed: Option<u64>,
+}
+
+impl Impairment {
+    /// The RTT below which `percentile` percent of delivered probes fall
+    pub fn expected_percentile(&self, percentile: f64) -> u64 {
+        let low = self.delay.saturating_sub(self.jitter);
+        let high = self.delay + self.jitter;
+        low + ((high - low) as f64 * percentile / 100.0) as u64
+    }
+}
+
+impl FromStr for Impairment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Impairment, String> {
+        let mut impairment = Impairment {
+            loss: 0.0,
+            delay: 0,
+            jitter: 0,
+            reorder: 0.0,
+            seed: None,
+        };
+        for setting in s.split(',').filter(|s| !s.is_empty()) {
+            let mut kv = setting.splitn(2, '=');
+            let key = kv.next().unwrap_or("");
+            let value = match kv.next() {
+                Some(value) => value,
+                None => return Err(format!("expected <key>=<value>, got {}", setting)),
+            };
+            match key {
+                "loss" => impairment.loss = try!(parse_percentage(value)),
+                "reorder" => impairment.reorder = try!(parse_percentage(value)),
+                "delay" => {
+                    let (delay, jitter) = match value.find('±') {
+                        Some(i) => (&value[..i], Some(&value[i + '±'.len_utf8()..])),
+                        None => {
+                            match value.find("+-") {
+                                Some(i) => (&value[..i], Some(&value[i + 2..])),
+                                None => (value, None),
+                            }
+                        }
+                    };
+                    impairment.delay = try!(parse_duration(delay));
+                    impairment.jitter = match jitter {
+                        Some(jitter) => try!(parse_duration(jitter)),
+                        None => 0,
+                    };
+                }
+                "seed" => {
+                    impairment.seed = match value.parse() {
+                        Ok(seed) => Some(seed),
+                        Err(_) => return Err(format!("invalid seed: {}", value)),
+                    }
+                }
+                _ => {
+                    return Err(format!("unknown impairment: {}. Choose from: loss delay \
+                                        reorder seed",
+                                       key))
+                }
+            }
+        }
+        if impairment.loss + impairment.reorder > 100.0 {
+            return Err("loss and reorder add up to more than 100%".to_owned());
+        }
+        Ok(impairment)
+    }
+}
+
+impl fmt::Display for Impairment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f,
+                    "loss={}%,delay={}ns+-{}ns,reorder={}%",
+                    self.loss,
+                    self.delay,
+                    self.jitter,
+                    self.reorder));
+        if let Some(seed) = self.seed {
+            try!(write!(f, ",seed={}", seed));
+        }
+        Ok(())
+    }
+}
+
+// a percentage, with or without the % sign
+fn parse_percentage(value: &str) -> Result<f64, String> {
+    let number = if value.ends_with('%') { &value[..value.len() - 1] } else { value };
+    match number.parse::<f64>() {
+        Ok(p) if p >= 0.0 && p <= 100.0 => Ok(p),
+        _ => Err(format!("invalid percentage: {}", value)),
+    }
+}
+
+// a duration with a unit, in ns
+fn parse_duration(value: &str) -> Result<u64, String> {
+    match value.parse::<Span>() {
+        Ok(ref span) if span.has_unit() => Ok(span.ns(units::NANOSECOND)),
+        Ok(_) => Err(format!("invalid duration: {}. Give a unit of ns, us, ms or s", value)),
+        Err(e) => Err(e),
+    }
+}
+
+/// What becomes of a simulated probe
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fate {
+    /// Answered after this many ns
+    Delivered(u64),
+    /// Answered after this many ns, but behind the next probe's reply
+    Reordered(u64),
+    Lost,
+}
+
+/// Draws the fate of each probe of one worker
+pub struct Simulator {
+    impairment: Impairment,
+    state: u64,
+}
+
+impl Simulator {
+    pub fn new(impairment: Impairment, seed: u64) -> Simulator {
+        Simulator {
+            impairment: impairment,
+            // xorshift needs a non-zero state
+            state: seed | 1,
+        }
+    }
+
+    pub fn next(&mut self) -> Fate {
+        let draw = self.uniform() * 100.0;
+        if draw < self.impairment.loss {
+            return Fate::Lost;
+        }
+        let rtt = self.rtt();
+        if draw < self.impairment.loss + self.impairment.reorder {
+            Fate::Reordered(rtt)
+        } else {
+            Fate::Delivered(rtt)
+        }
+    }
+
+    fn rtt(&mut self) -> u64 {
+        let percentile = self.uniform() * 100.0;
+        self.impairment.expected_percentile(percentile)
+    }
+
+    // uniform in [0, 1)
+    fn uniform(&mut self) -> f64 {
+        // xorshift
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
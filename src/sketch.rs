@@ -0,0 +1,540 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use tic::Clocksource;
+
+use control::Control;
+use units;
+
+/// Default significant digits of a `Sketch`, giving 1% relative accuracy
+pub const DEFAULT_DIGITS: u32 = 2;
+
+/// Default largest value a `Sketch` distinguishes: 100 seconds in ns
+pub const DEFAULT_MAX: u64 = 100_000_000_000;
+
+/// Precision shared by all sketches, so that any two can be merged
+struct Config {
+    digits: u32,
+    max: u64,
+    gamma: f64,
+    buckets: usize,
+}
+
+impl Config {
+    fn new(digits: u32, max: u64) -> Config {
+        let accuracy = 10f64.powi(-(digits as i32));
+        let gamma = (1.0 + accuracy) / (1.0 - accuracy);
+        Config {
+            digits: digits,
+            max: max,
+            gamma: gamma,
+            buckets: ((max as f64).ln() / gamma.ln()).ceil() as usize + 1,
+        }
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: RwLock<Config> = RwLock::new(Config::new(DEFAULT_DIGITS, DEFAULT_MAX));
+}
+
+/// Sets the precision of sketches created from now on: a relative accuracy
+/// of `10^-digits`, with values above `max` counted as `max`. Memory per
+/// sketch grows by about 10x for each extra digit
+pub fn configure(digits: u32, max: u64) {
+    *CONFIG.write().unwrap() = Config::new(digits, max);
+}
+
+/// Describes the configured precision and the memory each sketch uses
+pub fn describe() -> String {
+    let config = CONFIG.read().unwrap();
+    format!("{} significant digits ({}% relative error), max value {}, {} buckets ({} KiB)",
+            config.digits,
+            100.0 * 10f64.powi(-(config.digits as i32)),
+            config.max,
+            config.buckets,
+            config.buckets * 8 / 1024)
+}
+
+/// A fixed-size histogram with logarithmic buckets, in the style of
+/// DDSketch, which gives percentiles within the configured relative error
+#[derive(Clone)]
+pub struct Sketch {
+    counts: Vec<u64>,
+    total: u64,
+    gamma: f64,
+}
+
+impl Sketch {
+    pub fn new() -> Sketch {
+        let config = CONFIG.read().unwrap();
+        Sketch {
+            counts: vec![0; config.buckets],
+            total: 0,
+            gamma: config.gamma,
+        }
+    }
+
+    pub fn insert(&mut self, value: u64) {
+        let index = if value <= 1 {
+            0
+        } else {
+            ((value as f64).ln() / self.gamma.ln()).ceil() as usize
+        };
+        let index = cmp::min(index, self.counts.len() - 1);
+        self.counts[index] += 1;
+        self.total += 1;
+    }
+
+    pub fn merge(&mut self, other: &Sketch) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += *b;
+        }
+        self.total += other.total;
+    }
+
+    pub fn clear(&mut self) {
+        for c in &mut self.counts {
+            *c = 0;
+        }
+        self.total = 0;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Estimates the value at percentile `p`, given in the range 0-100
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let rank = ((p / 100.0) * (self.total - 1) as f64) as u64;
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen > rank {
+                if index == 0 {
+                    return 0;
+                }
+                // the midpoint of the bucket, in the relative sense
+                let gamma = self.gamma;
+                return (2.0 * gamma.powi(index as i32) / (gamma + 1.0)).round() as u64;
+            }
+        }
+        0
+    }
+
+    /// Writes the sketch as its precision followed by its non-empty buckets
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(write_u64(w, self.gamma.to_bits()));
+        try!(write_u64(w, self.counts.len() as u64));
+        try!(write_u64(w, self.total));
+        let used: Vec<(usize, u64)> =
+            self.counts.iter().cloned().enumerate().filter(|&(_, c)| c > 0).collect();
+        try!(write_u64(w, used.len() as u64));
+        for (index, count) in used {
+            try!(write_u64(w, index as u64));
+            try!(write_u64(w, count));
+        }
+        Ok(())
+    }
+
+    /// Reads a sketch written by `write_to`, which must have the configured
+    /// precision so that it can be merged with new sketches
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Sketch> {
+        let mut sketch = Sketch::new();
+        let gamma = f64::from_bits(try!(read_u64(r)));
+        let buckets = try!(read_u64(r));
+        if gamma != sketch.gamma || buckets != sketch.counts.len() as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "sketch precision differs from the configured precision"));
+        }
+        sketch.total = try!(read_u64(r));
+        for _ in 0..try!(read_u64(r)) {
+            let index = try!(read_u64(r));
+            let count = try!(read_u64(r));
+            match sketch.counts.get_mut(index as usize) {
+                Some(c) => *c = count,
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "bucket out of range"))
+                }
+            }
+        }
+        Ok(sketch)
+    }
+}
+
+/// Writes `value` little-endian
+pub fn write_u64<W: Write>(w: &mut W, value: u64) -> io::Result<()> {
+    let mut bytes = [0; 8];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (value >> (8 * i)) as u8;
+    }
+    w.write_all(&bytes)
+}
+
+/// Reads a little-endian `u64`
+pub fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    try!(r.read_exact(&mut bytes));
+    Ok(bytes.iter().rev().fold(0, |value, &b| value << 8 | b as u64))
+}
+
+/// A probe retained for being among the slowest of a window
+#[derive(Clone, Debug)]
+pub struct Slow {
+    pub worker: usize,
+    pub seq: u64,
+    /// Wall-clock send time, in ns since the unix epoch
+    pub sent: u64,
+    /// Send time on the monotonic clock of tic's traces, in ns
+    pub monotonic: u64,
+    pub rtt: u64,
+}
+
+/// Retains the `n` slowest probes inserted, slowest first
+#[derive(Clone)]
+pub struct Slowest {
+    n: usize,
+    probes: Vec<Slow>,
+}
+
+impl Slowest {
+    pub fn new(n: usize) -> Slowest {
+        Slowest {
+            n: n,
+            probes: Vec::with_capacity(n),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.n
+    }
+
+    /// Whether a probe with this RTT would be retained
+    pub fn accepts(&self, rtt: u64) -> bool {
+        self.n > 0 && (self.probes.len() < self.n || self.probes[self.n - 1].rtt < rtt)
+    }
+
+    pub fn insert(&mut self, probe: Slow) {
+        if !self.accepts(probe.rtt) {
+            return;
+        }
+        let position = self.probes
+            .iter()
+            .position(|p| p.rtt < probe.rtt)
+            .unwrap_or(self.probes.len());
+        self.probes.insert(position, probe);
+        self.probes.truncate(self.n);
+    }
+
+    pub fn merge(&mut self, other: &Slowest) {
+        self.n = cmp::max(self.n, other.n);
+        for probe in &other.probes {
+            self.insert(probe.clone());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.probes.clear();
+    }
+
+    pub fn probes(&self) -> &[Slow] {
+        &self.probes
+    }
+}
+
+/// Distributions other than latency which each worker accumulates over an
+/// integration window
+#[derive(Clone)]
+pub struct Distributions {
+    /// RTT of completed probes, in ns
+    pub latency: Sketch,
+    /// How late paced probes were sent relative to their schedule, in ns
+    pub schedule: Sketch,
+    /// Size of received responses, in bytes
+    pub sizes: Sketch,
+    /// The slowest probes, when enabled
+    pub slowest: Slowest,
+    /// Replies by worker and the NAPI ID of the receive queue which handled
+    /// them, when steering
+    pub rx_queues: BTreeMap<(usize, u32), u64>,
+    /// RTT by the CPU or receive queue which handled the reply, when enabled
+    pub by_rx: BTreeMap<u32, Sketch>,
+    /// RTT by the minute of the local day starting the bin in which probes
+    /// were sent, when enabled
+    pub time_of_day: BTreeMap<u32, Sketch>,
+    /// RTT by the millisecond of the second starting the bucket in which
+    /// probes were sent, when enabled
+    pub subsecond: BTreeMap<u32, Sketch>,
+    /// Probes sent from each source address, by its index, with several
+    pub source_probes: BTreeMap<u32, u64>,
+    /// RTT by the index of the source address probes were sent from
+    pub by_source: BTreeMap<u32, Sketch>,
+    /// RTT by the size of the reply, rounded down to the start of its
+    /// bucket, when enabled
+    pub by_size: BTreeMap<u32, Sketch>,
+    /// Change in the one-way delay to and from the reflector, and in the RTT,
+    /// between consecutive timestamped replies, in ns
+    pub forward_jitter: Sketch,
+    pub return_jitter: Sketch,
+    pub rtt_jitter: Sketch,
+}
+
+impl Distributions {
+    /// Creates empty distributions, retaining the `slowest` slowest probes
+    pub fn new(slowest: usize) -> Distributions {
+        Distributions {
+            latency: Sketch::new(),
+            schedule: Sketch::new(),
+            sizes: Sketch::new(),
+            slowest: Slowest::new(slowest),
+            rx_queues: BTreeMap::new(),
+            by_rx: BTreeMap::new(),
+            time_of_day: BTreeMap::new(),
+            subsecond: BTreeMap::new(),
+            source_probes: BTreeMap::new(),
+            by_source: BTreeMap::new(),
+            by_size: BTreeMap::new(),
+            forward_jitter: Sketch::new(),
+            return_jitter: Sketch::new(),
+            rtt_jitter: Sketch::new(),
+        }
+    }
+
+    pub fn merge(&mut self, other: &Distributions) {
+        self.latency.merge(&other.latency);
+        self.schedule.merge(&other.schedule);
+        self.sizes.merge(&other.sizes);
+        self.slowest.merge(&other.slowest);
+        for (&key, &count) in &other.rx_queues {
+            *self.rx_queues.entry(key).or_insert(0) += count;
+        }
+        for (&key, sketch) in &other.by_rx {
+            self.by_rx.entry(key).or_insert_with(Sketch::new).merge(sketch);
+        }
+        for (&key, sketch) in &other.time_of_day {
+            self.time_of_day.entry(key).or_insert_with(Sketch::new).merge(sketch);
+        }
+        for (&key, sketch) in &other.subsecond {
+            self.subsecond.entry(key).or_insert_with(Sketch::new).merge(sketch);
+        }
+        for (&key, &count) in &other.source_probes {
+            *self.source_probes.entry(key).or_insert(0) += count;
+        }
+        for (&key, sketch) in &other.by_source {
+            self.by_source.entry(key).or_insert_with(Sketch::new).merge(sketch);
+        }
+        for (&key, sketch) in &other.by_size {
+            self.by_size.entry(key).or_insert_with(Sketch::new).merge(sketch);
+        }
+        self.forward_jitter.merge(&other.forward_jitter);
+        self.return_jitter.merge(&other.return_jitter);
+        self.rtt_jitter.merge(&other.rtt_jitter);
+    }
+
+    pub fn clear(&mut self) {
+        self.latency.clear();
+        self.schedule.clear();
+        self.sizes.clear();
+        self.slowest.clear();
+        self.rx_queues.clear();
+        self.by_rx.clear();
+        self.time_of_day.clear();
+        self.subsecond.clear();
+        self.source_probes.clear();
+        self.by_source.clear();
+        self.by_size.clear();
+        self.forward_jitter.clear();
+        self.return_jitter.clear();
+        self.rtt_jitter.clear();
+    }
+}
+
+/// A ring of per-second sketches from which percentiles over the most recent
+/// seconds can be computed
+pub struct Sliding {
+    sketches: Vec<Sketch>,
+    seconds: Vec<u64>,
+}
+
+impl Sliding {
+    pub fn new(window: usize) -> Sliding {
+        Sliding {
+            sketches: vec![Sketch::new(); window],
+            seconds: vec![0; window],
+        }
+    }
+
+    /// Inserts a value observed during the given absolute second
+    pub fn insert(&mut self, second: u64, value: u64) {
+        let slot = (second % self.sketches.len() as u64) as usize;
+        if self.seconds[slot] != second {
+            self.sketches[slot].clear();
+            self.seconds[slot] = second;
+        }
+        self.sketches[slot].insert(value);
+    }
+
+    /// Merges the completed seconds in the window ending before `now` into
+    /// `into`
+    pub fn merge_into(&self, now: u64, into: &mut Sketch) {
+        let window = self.sketches.len() as u64;
+        for (slot, second) in self.seconds.iter().enumerate() {
+            if *second < now && *second + window >= now {
+                into.merge(&self.sketches[slot]);
+            }
+        }
+    }
+}
+
+/// Logs percentiles over the last `window` seconds once per second and
+/// publishes them to the control endpoint, until the run is over
+pub fn report(window: usize,
+              slidings: Vec<Arc<Mutex<Sliding>>>,
+              clocksource: Clocksource,
+              control: Arc<Control>) {
+    thread::spawn(move || {
+        while control.running() {
+            thread::sleep(Duration::from_secs(1));
+            let now = clocksource.time() / 1_000_000_000;
+            let mut sketch = Sketch::new();
+            for sliding in &slidings {
+                sliding.lock().unwrap().merge_into(now, &mut sketch);
+            }
+            if sketch.count() == 0 {
+                continue;
+            }
+            info!("last {}s: count: {} p50: {} p90: {} p99: {} p999: {}",
+                  window,
+                  sketch.count(),
+                  units::latency(sketch.percentile(50.0)),
+                  units::latency(sketch.percentile(90.0)),
+                  units::latency(sketch.percentile(99.0)),
+                  units::latency(sketch.percentile(99.9)));
+            control.set_sliding(window, &sketch);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // whether an estimate is within the default 1% relative error
+    fn close(estimate: u64, exact: u64) -> bool {
+        (estimate as f64 - exact as f64).abs() <= exact as f64 * 0.01 + 1.0
+    }
+
+    fn slow(seq: u64, rtt: u64) -> Slow {
+        Slow {
+            worker: 0,
+            seq: seq,
+            sent: 0,
+            monotonic: 0,
+            rtt: rtt,
+        }
+    }
+
+    #[test]
+    fn percentiles_are_within_the_relative_error() {
+        let mut sketch = Sketch::new();
+        for value in 1..10_001 {
+            sketch.insert(value * 1000);
+        }
+        assert_eq!(sketch.count(), 10_000);
+        for &(p, exact) in &[(50.0, 5_000_000), (90.0, 9_000_000), (99.0, 9_900_000)] {
+            let estimate = sketch.percentile(p);
+            assert!(close(estimate, exact), "p{}: {} vs {}", p, estimate, exact);
+        }
+        assert_eq!(Sketch::new().percentile(50.0), 0);
+    }
+
+    #[test]
+    fn merging_matches_inserting_into_one() {
+        let (mut a, mut b, mut both) = (Sketch::new(), Sketch::new(), Sketch::new());
+        for value in 1..1001 {
+            a.insert(value);
+            b.insert(value * 100);
+            both.insert(value);
+            both.insert(value * 100);
+        }
+        a.merge(&b);
+        assert_eq!(a.count(), both.count());
+        for &p in &[1.0, 50.0, 99.0, 99.9] {
+            assert_eq!(a.percentile(p), both.percentile(p));
+        }
+        a.clear();
+        assert_eq!(a.count(), 0);
+        assert_eq!(a.percentile(99.0), 0);
+    }
+
+    #[test]
+    fn sketches_survive_a_roundtrip() {
+        let mut sketch = Sketch::new();
+        for value in &[0, 1, 250, 40_000, 1_000_000, DEFAULT_MAX * 2] {
+            sketch.insert(*value);
+        }
+        let mut bytes = Vec::new();
+        sketch.write_to(&mut bytes).unwrap();
+        let read = Sketch::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read.count(), sketch.count());
+        for &p in &[0.0, 25.0, 50.0, 75.0, 100.0] {
+            assert_eq!(read.percentile(p), sketch.percentile(p));
+        }
+        assert!(Sketch::read_from(&mut &bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn u64s_are_little_endian() {
+        let mut bytes = Vec::new();
+        write_u64(&mut bytes, 0x0102030405060708).unwrap();
+        assert_eq!(bytes, [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(read_u64(&mut &bytes[..]).unwrap(), 0x0102030405060708);
+    }
+
+    #[test]
+    fn slowest_keeps_the_n_slowest_first() {
+        let mut slowest = Slowest::new(3);
+        for (seq, rtt) in [5, 1, 9, 3, 7].iter().enumerate() {
+            slowest.insert(slow(seq as u64, *rtt));
+        }
+        let rtts: Vec<u64> = slowest.probes().iter().map(|p| p.rtt).collect();
+        assert_eq!(rtts, vec![9, 7, 5]);
+        assert!(!slowest.accepts(5));
+        assert!(slowest.accepts(6));
+
+        let mut other = Slowest::new(4);
+        other.insert(slow(10, 8));
+        slowest.merge(&other);
+        assert_eq!(slowest.capacity(), 4);
+        let rtts: Vec<u64> = slowest.probes().iter().map(|p| p.rtt).collect();
+        assert_eq!(rtts, vec![9, 8, 7, 5]);
+
+        assert!(!Slowest::new(0).accepts(u64::max_value()));
+    }
+
+    #[test]
+    fn sliding_merges_the_completed_seconds_in_its_window() {
+        let mut sliding = Sliding::new(3);
+        for second in 10..14 {
+            sliding.insert(second, second * 1000);
+        }
+        // 10 has been overwritten by 13, and 13 isn't over at 13
+        let mut sketch = Sketch::new();
+        sliding.merge_into(13, &mut sketch);
+        assert_eq!(sketch.count(), 2);
+
+        let mut sketch = Sketch::new();
+        sliding.merge_into(14, &mut sketch);
+        assert_eq!(sketch.count(), 3);
+
+        let mut sketch = Sketch::new();
+        sliding.merge_into(20, &mut sketch);
+        assert_eq!(sketch.count(), 0);
+    }
+}
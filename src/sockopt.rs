@@ -0,0 +1,272 @@
+//! Socket options for kernel sockets which std doesn't expose
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+#[cfg(target_os = "linux")]
+mod imp {
+    extern crate libc;
+
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    const IP_OPTIONS: libc::c_int = 4;
+    const IP_MTU_DISCOVER: libc::c_int = 10;
+    const IP_PMTUDISC_DO: libc::c_int = 2;
+    const TCP_QUICKACK: libc::c_int = 12;
+    const TCP_CONGESTION: libc::c_int = 13;
+    const SO_BUSY_POLL: libc::c_int = 46;
+    const SO_INCOMING_CPU: libc::c_int = 49;
+    const SO_INCOMING_NAPI_ID: libc::c_int = 56;
+
+    fn set<S: AsRawFd>(socket: &S,
+                       level: libc::c_int,
+                       name: libc::c_int,
+                       value: *const libc::c_void,
+                       len: usize)
+                       -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(socket.as_raw_fd(), level, name, value, len as libc::socklen_t)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn get_int<S: AsRawFd>(socket: &S, level: libc::c_int, name: libc::c_int) -> io::Result<u32> {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(socket.as_raw_fd(),
+                             level,
+                             name,
+                             &mut value as *mut _ as *mut libc::c_void,
+                             &mut len)
+        };
+        if ret == 0 {
+            Ok(value as u32)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn set_priority<S: AsRawFd>(socket: &S, priority: u32) -> io::Result<()> {
+        let value = priority as libc::c_int;
+        set(socket,
+            libc::SOL_SOCKET,
+            libc::SO_PRIORITY,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>())
+    }
+
+    pub fn set_router_alert<S: AsRawFd>(socket: &S) -> io::Result<()> {
+        // RFC 2113: type 148 (copied, control, number 20), length 4, value 0
+        let option: [u8; 4] = [148, 4, 0, 0];
+        set(socket,
+            libc::IPPROTO_IP,
+            IP_OPTIONS,
+            option.as_ptr() as *const libc::c_void,
+            option.len())
+    }
+
+    pub fn set_dont_fragment<S: AsRawFd>(socket: &S) -> io::Result<()> {
+        let value = IP_PMTUDISC_DO;
+        set(socket,
+            libc::IPPROTO_IP,
+            IP_MTU_DISCOVER,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>())
+    }
+
+    pub fn set_quickack<S: AsRawFd>(socket: &S) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        set(socket,
+            libc::IPPROTO_TCP,
+            TCP_QUICKACK,
+            &on as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>())
+    }
+
+    pub fn set_congestion<S: AsRawFd>(socket: &S, algorithm: &str) -> io::Result<()> {
+        set(socket,
+            libc::IPPROTO_TCP,
+            TCP_CONGESTION,
+            algorithm.as_ptr() as *const libc::c_void,
+            algorithm.len())
+    }
+
+    pub fn priority<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+        get_int(socket, libc::SOL_SOCKET, libc::SO_PRIORITY)
+    }
+
+    pub fn recv_buffer<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+        get_int(socket, libc::SOL_SOCKET, libc::SO_RCVBUF)
+    }
+
+    pub fn send_buffer<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+        get_int(socket, libc::SOL_SOCKET, libc::SO_SNDBUF)
+    }
+
+    pub fn busy_poll<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+        get_int(socket, libc::SOL_SOCKET, SO_BUSY_POLL)
+    }
+
+    pub fn incoming_cpu<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+        get_int(socket, libc::SOL_SOCKET, SO_INCOMING_CPU)
+    }
+
+    pub fn incoming_napi_id<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+        get_int(socket, libc::SOL_SOCKET, SO_INCOMING_NAPI_ID)
+    }
+
+    pub fn available_congestion() -> Option<Vec<String>> {
+        let mut available = String::new();
+        match File::open("/proc/sys/net/ipv4/tcp_available_congestion_control") {
+            Ok(mut f) => {
+                if f.read_to_string(&mut available).is_err() {
+                    return None;
+                }
+            }
+            Err(_) => return None,
+        }
+        Some(available.split_whitespace().map(|a| a.to_owned()).collect())
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+mod imp {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    fn unsupported(option: &str) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, format!("{} is only supported on Linux", option)))
+    }
+
+    pub fn set_priority<S: AsRawFd>(_: &S, _: u32) -> io::Result<()> {
+        unsupported("SO_PRIORITY")
+    }
+
+    pub fn set_router_alert<S: AsRawFd>(_: &S) -> io::Result<()> {
+        unsupported("IP_OPTIONS")
+    }
+
+    pub fn set_dont_fragment<S: AsRawFd>(_: &S) -> io::Result<()> {
+        unsupported("IP_MTU_DISCOVER")
+    }
+
+    pub fn set_quickack<S: AsRawFd>(_: &S) -> io::Result<()> {
+        unsupported("TCP_QUICKACK")
+    }
+
+    pub fn set_congestion<S: AsRawFd>(_: &S, _: &str) -> io::Result<()> {
+        unsupported("TCP_CONGESTION")
+    }
+
+    fn unsupported_get(option: &str) -> io::Result<u32> {
+        Err(io::Error::new(io::ErrorKind::Other, format!("{} is only supported on Linux", option)))
+    }
+
+    pub fn priority<S: AsRawFd>(_: &S) -> io::Result<u32> {
+        unsupported_get("SO_PRIORITY")
+    }
+
+    pub fn recv_buffer<S: AsRawFd>(_: &S) -> io::Result<u32> {
+        unsupported_get("SO_RCVBUF")
+    }
+
+    pub fn send_buffer<S: AsRawFd>(_: &S) -> io::Result<u32> {
+        unsupported_get("SO_SNDBUF")
+    }
+
+    pub fn busy_poll<S: AsRawFd>(_: &S) -> io::Result<u32> {
+        unsupported_get("SO_BUSY_POLL")
+    }
+
+    pub fn incoming_cpu<S: AsRawFd>(_: &S) -> io::Result<u32> {
+        Err(io::Error::new(io::ErrorKind::Other, "SO_INCOMING_CPU is only supported on Linux"))
+    }
+
+    pub fn incoming_napi_id<S: AsRawFd>(_: &S) -> io::Result<u32> {
+        Err(io::Error::new(io::ErrorKind::Other, "SO_INCOMING_NAPI_ID is only supported on Linux"))
+    }
+
+    pub fn available_congestion() -> Option<Vec<String>> {
+        None
+    }
+}
+
+/// Sets `SO_PRIORITY`, which selects the band of the local qdisc (e.g. for
+/// pfifo_fast, 0-6 map through the priomap and values above 6 need
+/// `CAP_NET_ADMIN`)
+pub fn set_priority<S: AsRawFd>(socket: &S, priority: u32) -> io::Result<()> {
+    imp::set_priority(socket, priority)
+}
+
+/// Adds the IPv4 Router Alert option to every packet sent on the socket.
+/// Routers may punt such packets to their CPU, so their latency can differ
+/// markedly from that of ordinary traffic
+pub fn set_router_alert<S: AsRawFd>(socket: &S) -> io::Result<()> {
+    imp::set_router_alert(socket)
+}
+
+/// Sets the DF bit on every packet sent on the socket, so that packets too
+/// large for the path are dropped rather than fragmented, and sends larger
+/// than the known path MTU fail with `EMSGSIZE`
+pub fn set_dont_fragment<S: AsRawFd>(socket: &S) -> io::Result<()> {
+    imp::set_dont_fragment(socket)
+}
+
+/// Enables `TCP_QUICKACK`. The kernel may leave quickack mode on its own, so
+/// this needs repeating before each read
+pub fn set_quickack<S: AsRawFd>(socket: &S) -> io::Result<()> {
+    imp::set_quickack(socket)
+}
+
+/// Selects the congestion control algorithm with `TCP_CONGESTION`
+pub fn set_congestion<S: AsRawFd>(socket: &S, algorithm: &str) -> io::Result<()> {
+    imp::set_congestion(socket, algorithm)
+}
+
+/// The `SO_PRIORITY` of the socket
+pub fn priority<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+    imp::priority(socket)
+}
+
+/// The receive buffer the kernel granted the socket, in bytes, including its
+/// bookkeeping overhead
+pub fn recv_buffer<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+    imp::recv_buffer(socket)
+}
+
+/// The send buffer the kernel granted the socket, in bytes, including its
+/// bookkeeping overhead
+pub fn send_buffer<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+    imp::send_buffer(socket)
+}
+
+/// How long, in microseconds, a read on the socket busy-polls the device
+/// queue before sleeping, 0 when busy polling is off
+pub fn busy_poll<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+    imp::busy_poll(socket)
+}
+
+/// The CPU which processed the last packet received on the socket
+pub fn incoming_cpu<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+    imp::incoming_cpu(socket)
+}
+
+/// The NAPI ID of the receive queue which handled the last packet received on
+/// the socket. Zero when the kernel doesn't track it, e.g. without
+/// `CONFIG_NET_RX_BUSY_POLL` or for loopback traffic
+pub fn incoming_napi_id<S: AsRawFd>(socket: &S) -> io::Result<u32> {
+    imp::incoming_napi_id(socket)
+}
+
+/// The congestion control algorithms the kernel has available, if known
+pub fn available_congestion() -> Option<Vec<String>> {
+    imp::available_congestion()
+}
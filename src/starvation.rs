@@ -0,0 +1,186 @@
+//! Samples of how much CPU the measuring host withheld from this process,
+//! e.g. for a noisy co-tenant or the hypervisor, so that windows in which it
+//! was starved aren't mistaken for latency on the network
+
+extern crate time;
+
+use std::cmp;
+use std::collections::BTreeMap;
+
+/// How the host treated this process over one window
+#[derive(Clone, Copy, Debug)]
+pub struct Starvation {
+    /// Involuntary context switches, over all threads
+    pub involuntary: u64,
+    /// The largest share of the window, in percent, which any one thread
+    /// spent runnable but waiting for a CPU
+    pub wait: f64,
+    /// Share of the host's CPU time, in percent, stolen by the hypervisor
+    pub steal: f64,
+    /// Whether either share reached the threshold
+    pub starved: bool,
+}
+
+// the counters of one thread
+#[derive(Clone, Copy, Default)]
+struct Task {
+    involuntary: u64,
+    run_delay: u64,
+}
+
+/// Compares the scheduling counters of the process and the host's steal
+/// time from one window to the next
+pub struct Sampler {
+    threshold: f64,
+    tasks: BTreeMap<u64, Task>,
+    // steal and total CPU time of the host, in ticks
+    cpu: (u64, u64),
+    at: u64,
+}
+
+impl Sampler {
+    /// Takes the first reading. A window is starved once a thread waited for
+    /// a CPU, or the hypervisor stole, `threshold` percent of it or more
+    pub fn new(threshold: f64) -> Result<Sampler, String> {
+        let mut sampler = Sampler {
+            threshold: threshold,
+            tasks: BTreeMap::new(),
+            cpu: (0, 0),
+            at: 0,
+        };
+        try!(sampler.read());
+        Ok(sampler)
+    }
+
+    // replaces the last reading, returning it
+    fn read(&mut self) -> Result<(BTreeMap<u64, Task>, (u64, u64), u64), String> {
+        let tasks = try!(imp::tasks().map_err(|e| format!("Unable to read threads. {}", e)));
+        let cpu = try!(imp::cpu().map_err(|e| format!("Unable to read steal time. {}", e)));
+        let mut current = BTreeMap::new();
+        for (tid, involuntary, run_delay) in tasks {
+            current.insert(tid,
+                           Task {
+                               involuntary: involuntary,
+                               run_delay: run_delay,
+                           });
+        }
+        let tasks = ::std::mem::replace(&mut self.tasks, current);
+        let cpu = ::std::mem::replace(&mut self.cpu, cpu);
+        let at = ::std::mem::replace(&mut self.at, time::precise_time_ns());
+        Ok((tasks, cpu, at))
+    }
+
+    /// How the host treated the process since the last sample. Threads which
+    /// started in between are counted from their start
+    pub fn sample(&mut self) -> Result<Starvation, String> {
+        let (previous, cpu, at) = try!(self.read());
+        let elapsed = cmp::max(self.at - at, 1) as f64;
+        let mut involuntary = 0;
+        let mut wait: f64 = 0.0;
+        for (tid, task) in &self.tasks {
+            let before = previous.get(tid).cloned().unwrap_or_default();
+            involuntary += task.involuntary.saturating_sub(before.involuntary);
+            let delay = task.run_delay.saturating_sub(before.run_delay);
+            wait = wait.max(100.0 * delay as f64 / elapsed);
+        }
+        let total = self.cpu.1.saturating_sub(cpu.1);
+        let steal = if total > 0 {
+            100.0 * self.cpu.0.saturating_sub(cpu.0) as f64 / total as f64
+        } else {
+            0.0
+        };
+        Ok(Starvation {
+            involuntary: involuntary,
+            wait: wait,
+            steal: steal,
+            starved: wait >= self.threshold || steal >= self.threshold,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::fs::{self, File};
+    use std::io::{self, Read};
+
+    fn read(path: &str) -> io::Result<String> {
+        let mut s = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut s)));
+        Ok(s)
+    }
+
+    fn invalid(path: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("unexpected format of {}", path))
+    }
+
+    /// The ID, involuntary context switches and run queue wait in ns of each
+    /// thread, from /proc/self/task/*/status and schedstat. A thread which
+    /// exits while being read is skipped
+    pub fn tasks() -> io::Result<Vec<(u64, u64, u64)>> {
+        let mut tasks = Vec::new();
+        for entry in try!(fs::read_dir("/proc/self/task")) {
+            let entry = try!(entry);
+            let tid = match entry.file_name().to_string_lossy().parse() {
+                Ok(tid) => tid,
+                Err(_) => continue,
+            };
+            let dir = entry.path().to_string_lossy().into_owned();
+            let (status, schedstat) = match (read(&format!("{}/status", dir)),
+                                             read(&format!("{}/schedstat", dir))) {
+                (Ok(status), Ok(schedstat)) => (status, schedstat),
+                _ => continue,
+            };
+            let involuntary = status.lines()
+                .find(|line| line.starts_with("nonvoluntary_ctxt_switches:"))
+                .and_then(|line| line.split_whitespace().nth(1))
+                .and_then(|n| n.parse().ok());
+            // on-cpu time, run queue wait and timeslices
+            let run_delay = schedstat.split_whitespace().nth(1).and_then(|n| n.parse().ok());
+            match (involuntary, run_delay) {
+                (Some(involuntary), Some(run_delay)) => {
+                    tasks.push((tid, involuntary, run_delay))
+                }
+                _ => return Err(invalid(&dir)),
+            }
+        }
+        Ok(tasks)
+    }
+
+    /// The host's steal and total CPU time, in ticks, from /proc/stat
+    pub fn cpu() -> io::Result<(u64, u64)> {
+        let stat = try!(read("/proc/stat"));
+        let line = match stat.lines().find(|line| line.starts_with("cpu ")) {
+            Some(line) => line,
+            None => return Err(invalid("/proc/stat")),
+        };
+        // user nice system idle iowait irq softirq steal, then guest time
+        // which is already counted in user
+        let ticks: Vec<u64> = line.split_whitespace()
+            .skip(1)
+            .take(8)
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        if ticks.len() < 8 {
+            return Err(invalid("/proc/stat"));
+        }
+        Ok((ticks[7], ticks.iter().sum()))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other,
+                       "host starvation is read from /proc, and is only supported on Linux")
+    }
+
+    pub fn tasks() -> io::Result<Vec<(u64, u64, u64)>> {
+        Err(unsupported())
+    }
+
+    pub fn cpu() -> io::Result<(u64, u64)> {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,83 @@
+//! Flow steering with ethtool ntuple rules, so that the replies to each
+//! worker's probes arrive on a receive queue of their own
+
+use std::fs;
+use std::process::Command;
+
+/// Number of receive queues of `iface`, as listed in sysfs
+pub fn rx_queues(iface: &str) -> Result<usize, String> {
+    if !cfg!(target_os = "linux") {
+        return Err("flow steering uses ethtool, and is only supported on Linux".to_owned());
+    }
+    let path = format!("/sys/class/net/{}/queues", iface);
+    let entries = match fs::read_dir(&path) {
+        Ok(entries) => entries,
+        Err(e) => return Err(format!("Unable to list queues of {}. {}", iface, e)),
+    };
+    let queues = entries.filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with("rx-"))
+        .count();
+    if queues == 0 {
+        return Err(format!("{} has no receive queues", iface));
+    }
+    Ok(queues)
+}
+
+/// The ntuple rules installed for a run
+pub struct Steering {
+    iface: String,
+    rules: Vec<u32>,
+}
+
+impl Steering {
+    pub fn new(iface: &str) -> Steering {
+        Steering {
+            iface: iface.to_owned(),
+            rules: Vec::new(),
+        }
+    }
+
+    /// Steers UDP packets for local `port` to receive queue `queue`
+    pub fn add(&mut self, port: u16, queue: usize) -> Result<(), String> {
+        let port = format!("{}", port);
+        let queue = format!("{}", queue);
+        let output = match Command::new("ethtool")
+            .args(&["-N", &self.iface, "flow-type", "udp4", "dst-port", &port, "action", &queue])
+            .output() {
+            Ok(output) => output,
+            Err(e) => return Err(format!("Unable to run ethtool. {}", e)),
+        };
+        if !output.status.success() {
+            return Err(format!("Unable to add ntuple rule on {}. {}",
+                               self.iface,
+                               String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        // ethtool reports "Added rule with ID <id>"
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match stdout.split_whitespace().last().and_then(|id| id.parse().ok()) {
+            Some(id) => self.rules.push(id),
+            None => {
+                warn!("steering: rule for port {} added on {} but its ID is unknown, so it \
+                       won't be removed",
+                      port,
+                      self.iface)
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the rules added so far
+    pub fn remove(&mut self) {
+        for id in self.rules.drain(..) {
+            let id = format!("{}", id);
+            let result = Command::new("ethtool")
+                .args(&["-N", &self.iface, "delete", &id])
+                .status();
+            match result {
+                Ok(status) if status.success() => {}
+                Ok(_) => warn!("steering: unable to delete ntuple rule {} on {}", id, self.iface),
+                Err(e) => warn!("steering: unable to run ethtool: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+extern crate time;
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Resolves a `<host>:<port>` target to its IPv4 addresses, in the order given
+/// by the resolver
+pub fn resolve(target: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = try!(target.to_socket_addrs()).filter(|a| a.is_ipv4()).collect();
+    if addrs.is_empty() {
+        Err(io::Error::new(io::ErrorKind::NotFound, "no IPv4 addresses found"))
+    } else {
+        Ok(addrs)
+    }
+}
+
+/// Races `count` probes at each address concurrently over kernel sockets,
+/// returning each address with the fastest RTT it answered in
+pub fn race(addrs: &[SocketAddr], count: usize, timeout: Duration) -> Vec<(SocketAddr, Option<u64>)> {
+    let (tx, rx) = mpsc::channel();
+    for (i, addr) in addrs.iter().enumerate() {
+        let tx = tx.clone();
+        let addr = *addr;
+        thread::spawn(move || {
+            let _ = tx.send((i, probe(addr, count, timeout)));
+        });
+    }
+    drop(tx);
+
+    let mut results: Vec<(SocketAddr, Option<u64>)> = addrs.iter().map(|a| (*a, None)).collect();
+    for (i, rtt) in rx {
+        results[i].1 = rtt;
+    }
+    results
+}
+
+/// Picks the address which answered fastest in a race
+pub fn fastest(results: &[(SocketAddr, Option<u64>)]) -> Option<SocketAddr> {
+    results.iter()
+        .filter_map(|&(addr, rtt)| rtt.map(|rtt| (rtt, addr)))
+        .min_by_key(|&(rtt, _)| rtt)
+        .map(|(_, addr)| addr)
+}
+
+fn probe(addr: SocketAddr, count: usize, timeout: Duration) -> Option<u64> {
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(s) => s,
+        Err(_) => return None,
+    };
+    let _ = socket.set_read_timeout(Some(timeout));
+    let request = "PING\r\n".to_owned().into_bytes();
+    let mut buffer = vec![0; 1024*2];
+    let mut best = None;
+    for _ in 0..count {
+        let t0 = time::precise_time_ns();
+        if socket.send_to(&request, addr).is_err() {
+            continue;
+        }
+        // ignore datagrams from anyone but the address being raced
+        match socket.recv_from(&mut buffer) {
+            Ok((_, peer)) if peer == addr => {
+                let rtt = time::precise_time_ns() - t0;
+                best = Some(best.map_or(rtt, |b: u64| if rtt < b { rtt } else { b }));
+            }
+            _ => {}
+        }
+    }
+    best
+}
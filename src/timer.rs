@@ -0,0 +1,105 @@
+//! Sleeping until an absolute deadline on the monotonic clock with a timerfd,
+//! which wakes more precisely than `nanosleep` for short intervals
+
+use std::io;
+
+#[cfg(target_os = "linux")]
+mod imp {
+    extern crate libc;
+
+    use std::io;
+    use std::mem;
+    use std::ptr;
+
+    pub struct Timer {
+        fd: libc::c_int,
+    }
+
+    impl Timer {
+        pub fn new() -> io::Result<Timer> {
+            let fd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC) };
+            if fd < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(Timer { fd: fd })
+            }
+        }
+
+        pub fn sleep_until(&self, deadline: u64) -> io::Result<()> {
+            let spec = libc::itimerspec {
+                it_interval: libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: 0,
+                },
+                it_value: libc::timespec {
+                    tv_sec: (deadline / 1_000_000_000) as libc::time_t,
+                    tv_nsec: (deadline % 1_000_000_000) as libc::c_long,
+                },
+            };
+            let ret = unsafe {
+                libc::timerfd_settime(self.fd, libc::TFD_TIMER_ABSTIME, &spec, ptr::null_mut())
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut expirations: u64 = 0;
+            loop {
+                let ret = unsafe {
+                    libc::read(self.fd,
+                               &mut expirations as *mut _ as *mut libc::c_void,
+                               mem::size_of::<u64>())
+                };
+                if ret >= 0 {
+                    return Ok(());
+                }
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    impl Drop for Timer {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+
+    pub struct Timer;
+
+    impl Timer {
+        pub fn new() -> io::Result<Timer> {
+            Err(io::Error::new(io::ErrorKind::Other, "timerfd is only supported on Linux"))
+        }
+
+        pub fn sleep_until(&self, _: u64) -> io::Result<()> {
+            unreachable!()
+        }
+    }
+}
+
+/// A timer owned by one thread
+pub struct Timer {
+    inner: imp::Timer,
+}
+
+impl Timer {
+    pub fn new() -> io::Result<Timer> {
+        imp::Timer::new().map(|inner| Timer { inner: inner })
+    }
+
+    /// Blocks until `deadline`, in ns on the same clock as
+    /// `time::precise_time_ns` (`CLOCK_MONOTONIC`), returning at once if it
+    /// has passed
+    pub fn sleep_until(&self, deadline: u64) -> io::Result<()> {
+        self.inner.sleep_until(deadline)
+    }
+}
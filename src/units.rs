@@ -0,0 +1,316 @@
+//! Formatting of reported values, and parsing of the durations, sizes and
+//! counts given on the command line. Numbers are always written with `.` as
+//! the decimal separator and no grouping, whatever the locale, so that output
+//! parses and diffs the same on every machine
+
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// Unit in which latencies are reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Ns,
+    Us,
+    Ms,
+    S,
+}
+
+impl Unit {
+    fn divisor(&self) -> f64 {
+        match *self {
+            Unit::Ns => 1.0,
+            Unit::Us => 1_000.0,
+            Unit::Ms => 1_000_000.0,
+            Unit::S => 1_000_000_000.0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Unit::Ns => "ns",
+            Unit::Us => "us",
+            Unit::Ms => "ms",
+            Unit::S => "s",
+        }
+    }
+}
+
+impl FromStr for Unit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Unit, String> {
+        match s {
+            "ns" => Ok(Unit::Ns),
+            "us" => Ok(Unit::Us),
+            "ms" => Ok(Unit::Ms),
+            "s" => Ok(Unit::S),
+            _ => Err(format!("unknown unit: {}", s)),
+        }
+    }
+}
+
+struct Format {
+    unit: Unit,
+    precision: usize,
+}
+
+lazy_static! {
+    static ref FORMAT: RwLock<Format> = RwLock::new(Format {
+        unit: Unit::Ns,
+        precision: 0,
+    });
+}
+
+/// Sets the unit and number of decimal places used for all reported values
+pub fn configure(unit: Unit, precision: usize) {
+    let mut format = FORMAT.write().unwrap();
+    format.unit = unit;
+    format.precision = precision;
+}
+
+/// The unit latencies are reported in
+pub fn unit() -> Unit {
+    FORMAT.read().unwrap().unit
+}
+
+/// A latency given in ns, scaled to the configured unit, without the unit
+pub fn value(ns: u64) -> String {
+    let format = FORMAT.read().unwrap();
+    format!("{:.*}", format.precision, ns as f64 / format.unit.divisor())
+}
+
+/// A latency given in ns, scaled to the configured unit, with the unit
+pub fn latency(ns: u64) -> String {
+    format!("{} {}", value(ns), unit().name())
+}
+
+/// A signed difference between latencies given in ns, with the unit
+pub fn difference(ns: i64) -> String {
+    let format = FORMAT.read().unwrap();
+    format!("{:+.*} {}",
+            format.precision,
+            ns as f64 / format.unit.divisor(),
+            format.unit.name())
+}
+
+/// Any other measurement, such as a rate, at the configured precision
+pub fn number(value: f64) -> String {
+    format!("{:.*}", FORMAT.read().unwrap().precision, value)
+}
+
+/// ns per unit of a duration
+pub const NANOSECOND: u64 = 1;
+pub const MICROSECOND: u64 = 1_000;
+pub const MILLISECOND: u64 = 1_000_000;
+pub const SECOND: u64 = 1_000_000_000;
+pub const MINUTE: u64 = 60 * SECOND;
+pub const HOUR: u64 = 60 * MINUTE;
+
+// splits a value into its number, which may have an exponent as in `1e3`,
+// and the suffix following it
+fn split(value: &str) -> Result<(f64, &str), ()> {
+    let mut end = value.find(|c: char| !(c.is_digit(10) || c == '.')).unwrap_or(value.len());
+    if value[end..].starts_with('e') || value[end..].starts_with('E') {
+        let exponent = &value[end + 1..];
+        let sign = if exponent.starts_with('-') || exponent.starts_with('+') { 1 } else { 0 };
+        let exponent = &exponent[sign..];
+        let digits = exponent.find(|c: char| !c.is_digit(10)).unwrap_or(exponent.len());
+        if digits > 0 {
+            end += 1 + sign + digits;
+        }
+    }
+    match value[..end].parse::<f64>() {
+        Ok(number) => Ok((number, value[end..].trim())),
+        Err(_) => Err(()),
+    }
+}
+
+// `number` times `scale`, if that is a whole number
+fn whole(number: f64, scale: u64) -> Option<u64> {
+    let value = number * scale as f64;
+    if (value - value.round()).abs() < 1e-6 {
+        Some(value.round() as u64)
+    } else {
+        None
+    }
+}
+
+/// A duration as given on the command line: with a unit of ns, us, ms, s, m
+/// or h, e.g. `250ms` or `1.5s`, or as a bare number in the unit of the flag
+/// it is given to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    number: f64,
+    unit: Option<u64>,
+}
+
+impl Span {
+    /// Whether the duration was given with a unit
+    pub fn has_unit(&self) -> bool {
+        self.unit.is_some()
+    }
+
+    /// The duration in ns, a bare number being in `unit`
+    pub fn ns(&self, unit: u64) -> u64 {
+        (self.number * self.unit.unwrap_or(unit) as f64).round() as u64
+    }
+
+    /// The duration in whole `unit`s, a bare number being in them, or None if
+    /// it isn't a multiple of one
+    pub fn whole(&self, unit: u64) -> Option<u64> {
+        let ns = self.ns(unit);
+        if ns % unit == 0 {
+            Some(ns / unit)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for Span {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Span, String> {
+        let (number, suffix) = try!(split(s).map_err(|_| format!("invalid duration: {}", s)));
+        let unit = match suffix {
+            "" => None,
+            "ns" => Some(NANOSECOND),
+            "us" | "µs" => Some(MICROSECOND),
+            "ms" => Some(MILLISECOND),
+            "s" => Some(SECOND),
+            "m" | "min" => Some(MINUTE),
+            "h" => Some(HOUR),
+            _ => {
+                return Err(format!("invalid duration: {}. Give a unit of ns, us, ms, s, m or h",
+                                   s))
+            }
+        };
+        Ok(Span {
+            number: number,
+            unit: unit,
+        })
+    }
+}
+
+/// A size in bytes as given on the command line: bare, or with a unit of B,
+/// kB, MB (powers of 1000) or KiB, MiB (powers of 1024), e.g. `1400B` or
+/// `1.5kB`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Size(pub usize);
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Size, String> {
+        let (number, suffix) = try!(split(s).map_err(|_| format!("invalid size: {}", s)));
+        let scale = match suffix {
+            "" | "B" => 1,
+            "k" | "kB" | "KB" => 1_000,
+            "KiB" => 1_024,
+            "M" | "MB" => 1_000_000,
+            "MiB" => 1_024 * 1_024,
+            _ => return Err(format!("invalid size: {}. Give a unit of B, kB, KiB, MB or MiB", s)),
+        };
+        match whole(number, scale) {
+            Some(bytes) => Ok(Size(bytes as usize)),
+            None => Err(format!("invalid size: {}. Must be whole bytes", s)),
+        }
+    }
+}
+
+/// A count as given on the command line: bare, or with a suffix of k, M or
+/// G, e.g. `50k`, `2.5M` or `1e6`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Count(pub u64);
+
+impl FromStr for Count {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Count, String> {
+        let (number, suffix) = try!(split(s).map_err(|_| format!("invalid number: {}", s)));
+        let scale = match suffix {
+            "" => 1,
+            "k" | "K" => 1_000,
+            "M" => 1_000_000,
+            "G" => 1_000_000_000,
+            _ => return Err(format!("invalid number: {}. Give a suffix of k, M or G", s)),
+        };
+        match whole(number, scale) {
+            Some(count) => Ok(Count(count)),
+            None => Err(format!("invalid number: {}. Must be whole", s)),
+        }
+    }
+}
+
+/// A multiple as given on the command line: bare or with a suffix of x, e.g.
+/// `10` or `2.5x`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Factor(pub f64);
+
+impl FromStr for Factor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Factor, String> {
+        let (number, suffix) = try!(split(s).map_err(|_| format!("invalid multiple: {}", s)));
+        match suffix {
+            "" | "x" => Ok(Factor(number)),
+            _ => Err(format!("invalid multiple: {}. Give a suffix of x", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_take_a_unit_or_that_of_the_flag() {
+        let span: Span = "250ms".parse().unwrap();
+        assert!(span.has_unit());
+        assert_eq!(span.ns(SECOND), 250 * MILLISECOND);
+        let span: Span = "5".parse().unwrap();
+        assert!(!span.has_unit());
+        assert_eq!(span.ns(MICROSECOND), 5 * MICROSECOND);
+        assert_eq!("250µs".parse::<Span>().unwrap().ns(SECOND), 250 * MICROSECOND);
+        assert_eq!("250us".parse::<Span>().unwrap().ns(SECOND), 250 * MICROSECOND);
+        assert!("5 fortnights".parse::<Span>().is_err());
+    }
+
+    #[test]
+    fn fractions_of_a_whole_unit_are_refused() {
+        let span: Span = "1.5s".parse().unwrap();
+        assert_eq!(span.whole(SECOND), None);
+        assert_eq!(span.whole(MILLISECOND), Some(1_500));
+        assert_eq!("2m".parse::<Span>().unwrap().whole(SECOND), Some(120));
+    }
+
+    #[test]
+    fn sizes_are_powers_of_1000_or_1024() {
+        assert_eq!("1400".parse::<Size>(), Ok(Size(1_400)));
+        assert_eq!("1.4kB".parse::<Size>(), Ok(Size(1_400)));
+        assert_eq!("1KiB".parse::<Size>(), Ok(Size(1_024)));
+        assert_eq!("1.5KiB".parse::<Size>(), Ok(Size(1_536)));
+        assert_eq!("2MiB".parse::<Size>(), Ok(Size(2 * 1_024 * 1_024)));
+        assert!("1.0001KiB".parse::<Size>().is_err());
+        assert!("1KB/s".parse::<Size>().is_err());
+    }
+
+    #[test]
+    fn counts_take_a_suffix_or_an_exponent() {
+        assert_eq!("50k".parse::<Count>(), Ok(Count(50_000)));
+        assert_eq!("2.5M".parse::<Count>(), Ok(Count(2_500_000)));
+        assert_eq!("1e3".parse::<Count>(), Ok(Count(1_000)));
+        assert_eq!("1.5e3".parse::<Count>(), Ok(Count(1_500)));
+        assert_eq!("1e3k".parse::<Count>(), Ok(Count(1_000_000)));
+        assert!("1.5".parse::<Count>().is_err());
+        assert!("1e".parse::<Count>().is_err());
+        assert!("ten".parse::<Count>().is_err());
+    }
+
+    #[test]
+    fn multiples_may_be_fractional() {
+        assert_eq!("10".parse::<Factor>(), Ok(Factor(10.0)));
+        assert_eq!("2.5x".parse::<Factor>(), Ok(Factor(2.5)));
+        assert!("2.5%".parse::<Factor>().is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Upload of a run's output files to object storage once it completes, so a
+//! fleet of agents needs no collection pipeline of its own. Transfers are
+//! left to each store's own client, which already holds the credentials
+
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+/// Where a run's files are uploaded, as given to `--upload`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    /// `s3://<bucket>/<prefix>`, uploaded with the AWS CLI
+    S3(String),
+    /// `gs://<bucket>/<prefix>`, uploaded with gsutil
+    Gcs(String),
+    /// An `http://` or `https://` URL, to which each file is PUT with curl,
+    /// e.g. a bucket's endpoint behind a signing proxy
+    Http(String),
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Destination, String> {
+        let mut url = s;
+        while url.ends_with('/') {
+            url = &url[..url.len() - 1];
+        }
+        let url = url.to_owned();
+        if url.starts_with("s3://") && url.len() > 5 {
+            Ok(Destination::S3(url))
+        } else if url.starts_with("gs://") && url.len() > 5 {
+            Ok(Destination::Gcs(url))
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            Ok(Destination::Http(url))
+        } else {
+            Err(format!("unsupported upload URL: {}. Use s3://, gs://, http:// or https://", s))
+        }
+    }
+}
+
+impl Destination {
+    /// Uploads those of `files` which exist under `<url>/<host>/<run id>/`,
+    /// so runs of many agents don't overwrite each other. Every file is
+    /// attempted, and those which failed are returned in the error
+    pub fn upload(&self, host: &str, run_id: u64, files: &[String]) -> Result<usize, String> {
+        let mut uploaded = 0;
+        let mut failed = Vec::new();
+        for file in files.iter().filter(|f| Path::new(f).exists()) {
+            let name = Path::new(file).file_name().map_or(file.clone(), |n| {
+                n.to_string_lossy().into_owned()
+            });
+            let key = format!("{}/{:016x}/{}", host, run_id, name);
+            let (program, args) = self.command(file, &key);
+            match Command::new(program).args(&args).output() {
+                Ok(ref output) if output.status.success() => {
+                    debug!("upload: {} uploaded", file);
+                    uploaded += 1;
+                }
+                Ok(output) => {
+                    warn!("upload: {} failed: {}",
+                          file,
+                          String::from_utf8_lossy(&output.stderr).trim());
+                    failed.push(file.clone());
+                }
+                Err(e) => {
+                    warn!("upload: unable to run {}: {}", program, e);
+                    failed.push(file.clone());
+                }
+            }
+        }
+        if failed.is_empty() {
+            Ok(uploaded)
+        } else {
+            Err(format!("Unable to upload {}", failed.join(", ")))
+        }
+    }
+
+    // the client and its arguments which copy `file` to `key` under the URL
+    fn command(&self, file: &str, key: &str) -> (&'static str, Vec<String>) {
+        match *self {
+            Destination::S3(ref url) => {
+                let args = vec!["s3".to_owned(),
+                                "cp".to_owned(),
+                                file.to_owned(),
+                                format!("{}/{}", url, key)];
+                ("aws", args)
+            }
+            Destination::Gcs(ref url) => {
+                ("gsutil", vec!["cp".to_owned(), file.to_owned(), format!("{}/{}", url, key)])
+            }
+            Destination::Http(ref url) => {
+                ("curl",
+                 vec!["--fail".to_owned(),
+                      "--silent".to_owned(),
+                      "--show-error".to_owned(),
+                      "--upload-file".to_owned(),
+                      file.to_owned(),
+                      format!("{}/{}", url, key)])
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! A plain-language verdict on a run, drawn from the metrics and events it
+//! collected, so that the result can be read without the percentile tables
+
+use units;
+
+/// Window medians may vary by this factor before a run is called unstable
+const STABLE_SPREAD: f64 = 1.25;
+
+/// What a run measured and came across. Events which weren't watched for
+/// are None, and left out of the verdict
+#[derive(Clone, Debug, Default)]
+pub struct Run {
+    /// Probes sent and answered, over all workers
+    pub sent: u64,
+    pub completed: u64,
+    /// p99 latency over the whole run, in ns, if any probe completed
+    pub p99: Option<u64>,
+    /// The median latency of each window with completed probes, in ns
+    pub medians: Vec<u64>,
+    pub route_changes: Option<usize>,
+    /// Windows in which a percentile rose by more than `--highlight`
+    pub regressions: Option<usize>,
+    /// The `--warn-p99` threshold in ns, and windows whose p99 exceeded it
+    pub exceeded: Option<(u64, usize)>,
+    pub starved: Option<usize>,
+    /// How long the link was down, in ns
+    pub downtime: Option<u64>,
+    /// Periods of latency spikes, in seconds
+    pub periods: Vec<usize>,
+    pub worker_errors: u64,
+}
+
+fn plural(count: usize, one: &str, many: &str) -> String {
+    format!("{} {}", count, if count == 1 { one } else { many })
+}
+
+/// The verdict, as clauses separated by `; `, e.g. `loss 0.02%, p99 1.9 ms,
+/// stable; no route changes detected`
+pub fn verdict(run: &Run) -> String {
+    let mut summary = Vec::new();
+    if run.sent > 0 {
+        let lost = run.sent.saturating_sub(run.completed);
+        summary.push(format!("loss {:.2}%", 100.0 * lost as f64 / run.sent as f64));
+    }
+    match run.p99 {
+        Some(p99) => summary.push(format!("p99 {}", units::latency(p99))),
+        None => summary.push("no probes completed".to_owned()),
+    }
+    let lowest = run.medians.iter().min();
+    let highest = run.medians.iter().max();
+    if let (Some(&lowest), Some(&highest)) = (lowest, highest) {
+        if run.medians.len() > 1 {
+            if highest as f64 <= lowest as f64 * STABLE_SPREAD {
+                summary.push("stable".to_owned());
+            } else {
+                summary.push(format!("unstable, window medians ranged {} to {}",
+                                     units::latency(lowest),
+                                     units::latency(highest)));
+            }
+        }
+    }
+    let mut clauses = vec![summary.join(", ")];
+    match run.route_changes {
+        Some(0) => clauses.push("no route changes detected".to_owned()),
+        Some(n) => clauses.push(format!("{} detected", plural(n, "route change", "route changes"))),
+        None => (),
+    }
+    if let Some((threshold, windows)) = run.exceeded {
+        clauses.push(format!("{} exceeded the p99 warn threshold of {}",
+                             plural(windows, "window", "windows"),
+                             units::latency(threshold)));
+    }
+    match run.regressions {
+        Some(0) | None => (),
+        Some(n) => clauses.push(format!("latency rose in {}", plural(n, "window", "windows"))),
+    }
+    if !run.periods.is_empty() {
+        let periods: Vec<String> = run.periods.iter().map(|p| format!("{}s", p)).collect();
+        clauses.push(format!("latency spikes every {}", periods.join(", ")));
+    }
+    match run.starved {
+        Some(0) | None => (),
+        Some(n) => {
+            clauses.push(format!("host starved in {}, so latency there may be its own",
+                                 plural(n, "window", "windows")))
+        }
+    }
+    match run.downtime {
+        Some(0) | None => (),
+        Some(ns) => clauses.push(format!("link down for {}", units::latency(ns))),
+    }
+    if run.worker_errors > 0 {
+        clauses.push(plural(run.worker_errors as usize, "worker error", "worker errors"));
+    }
+    clauses.join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_run_without_probes_says_so() {
+        assert_eq!(verdict(&Run::default()), "no probes completed");
+    }
+
+    #[test]
+    fn a_quiet_run_is_stable() {
+        let run = Run {
+            sent: 10_000,
+            completed: 9_998,
+            p99: Some(1_900_000),
+            medians: vec![1_000_000, 1_100_000],
+            route_changes: Some(0),
+            regressions: Some(0),
+            ..Run::default()
+        };
+        assert_eq!(verdict(&run),
+                   format!("loss 0.02%, p99 {}, stable; no route changes detected",
+                           units::latency(1_900_000)));
+    }
+
+    #[test]
+    fn events_are_listed_as_clauses() {
+        let run = Run {
+            sent: 100,
+            completed: 100,
+            p99: Some(5_000_000),
+            medians: vec![1_000_000, 2_000_000],
+            route_changes: Some(1),
+            exceeded: Some((4_000_000, 2)),
+            periods: vec![10, 30],
+            worker_errors: 1,
+            ..Run::default()
+        };
+        assert_eq!(verdict(&run),
+                   format!("loss 0.00%, p99 {}, unstable, window medians ranged {} to {}; 1 route \
+                            change detected; 2 windows exceeded the p99 warn threshold of {}; \
+                            latency spikes every 10s, 30s; 1 worker error",
+                           units::latency(5_000_000),
+                           units::latency(1_000_000),
+                           units::latency(2_000_000),
+                           units::latency(4_000_000)));
+    }
+}
@@ -0,0 +1,458 @@
+//! End-to-end tests over loopback against an in-process reflector: the
+//! protocols directly through the library, and the kernel socket backends
+//! by running the client binary. The userspace stack needs a raw channel on
+//! a real interface and root, so it isn't covered here
+
+extern crate ipnetwork;
+extern crate ping_rs;
+
+use std::env;
+use std::io;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ipnetwork::Ipv4Network;
+
+use ping_rs::EchoServer;
+use ping_rs::auth::{Direction, Key, Signed};
+use ping_rs::capability;
+use ping_rs::ledger;
+use ping_rs::probe::{self, Probes};
+use ping_rs::protocol::{self, Outcome};
+use ping_rs::server::Policy;
+
+fn udp_exchange(server: &EchoServer, request: &[u8]) -> Vec<u8> {
+    udp_try_exchange(server, request, Duration::from_secs(5)).unwrap()
+}
+
+fn udp_try_exchange(server: &EchoServer,
+                    request: &[u8],
+                    timeout: Duration)
+                    -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(timeout)).unwrap();
+    socket.send_to(request, server.addr()).unwrap();
+    let mut buffer = [0; 2048];
+    let (len, _) = try!(socket.recv_from(&mut buffer));
+    Ok(buffer[..len].to_vec())
+}
+
+// reads one newline-terminated response
+fn tcp_exchange(server: &EchoServer, request: &[u8]) -> Vec<u8> {
+    tcp_exchange_framed(server, request, &protocol::Echo)
+}
+
+// as `tcp_exchange`, reading until `probe` finds a whole response
+fn tcp_exchange_framed(server: &EchoServer, request: &[u8], probe: &protocol::Probe) -> Vec<u8> {
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(request).unwrap();
+    let mut response = Vec::new();
+    let mut byte = [0; 1];
+    while !probe.framed(&response) {
+        stream.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    response
+}
+
+#[test]
+fn ping_is_answered_with_pong() {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    assert_eq!(udp_exchange(&server, b"PING\r\n"), b"PONG\r\n");
+    assert_eq!(tcp_exchange(&server, b"PING\r\n"), b"PONG\r\n");
+}
+
+#[test]
+fn other_datagrams_are_echoed() {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    assert_eq!(udp_exchange(&server, b"hello\n"), b"hello\n");
+}
+
+#[test]
+fn replies_are_padded_to_the_requested_size() {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    let mut probes = Probes::new(1, 0, true, None);
+    probes.set_sizes(768, Some(512));
+    let request = probes.next_request();
+    assert_eq!(request.len(), 768);
+    let reply = udp_exchange(&server, &request);
+    assert_eq!(reply.len(), 512);
+    assert!(reply.starts_with(format!("PONG {}", probes.id()).as_bytes()));
+    let reply = tcp_exchange(&server, &request);
+    assert_eq!(reply.len(), 512);
+}
+
+#[test]
+fn unsigned_replies_are_no_larger_than_requests() {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    let mut probes = Probes::new(1, 0, true, None);
+    probes.set_sizes(64, Some(probe::MAX_REPLY));
+    let request = probes.next_request();
+    let reply = udp_exchange(&server, &request);
+    assert!(reply.len() <= request.len(), "{} byte reply", reply.len());
+    assert!(reply.starts_with(format!("PONG {}", probes.id()).as_bytes()));
+    probes.set_stamped(true);
+    let request = probes.next_request();
+    let reply = udp_exchange(&server, &request);
+    assert!(reply.len() <= request.len(), "{} byte reply", reply.len());
+    assert!(probe::parse_stamps(&reply).is_some());
+}
+
+#[test]
+fn every_protocol_is_answered() {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    for name in protocol::names() {
+        let mut probe = protocol::create(&name).unwrap();
+        let mut probes = Probes::new(1, 0, false, None);
+        let request = probe.request(&mut probes);
+        if probe.udp() {
+            let response = udp_exchange(&server, &request);
+            assert_eq!(probe.classify(&request, &response), Outcome::Ok, "{} over udp", name);
+        }
+        if probe.tcp() {
+            let mut probe = protocol::create(&name).unwrap();
+            probe.use_tcp();
+            let request = probe.request(&mut probes);
+            let response = tcp_exchange_framed(&server, &request, &*probe);
+            assert_eq!(probe.classify(&request, &response), Outcome::Ok, "{} over tcp", name);
+        }
+    }
+}
+
+#[test]
+fn only_signed_requests_are_answered_with_a_key() {
+    let key = Key::new(b"secret").unwrap();
+    let mut policy = Policy::default();
+    policy.key = Some(key.clone());
+    let server = EchoServer::spawn_on_localhost_with_policy(policy).unwrap();
+    let timeout = Duration::from_millis(200);
+    assert!(udp_try_exchange(&server, b"PING\r\n", timeout).is_err());
+    let forged = Key::new(b"guess").unwrap().sign(b"PING\r\n", Direction::Request);
+    assert!(udp_try_exchange(&server, &forged, timeout).is_err());
+
+    let mut probes = Probes::new(1, 0, true, None);
+    probes.set_sizes(128, Some(512));
+    let request = key.sign(&probes.next_request(), Direction::Request);
+    assert_eq!(request.len(), 128);
+    let reply = udp_exchange(&server, &request);
+    assert_eq!(reply.len(), 512);
+    let reply = key.verify(&reply, Direction::Reply).expect("reply isn't signed");
+    assert!(reply.starts_with(format!("PONG {}", probes.id()).as_bytes()));
+}
+
+#[test]
+fn stale_signed_requests_are_not_answered() {
+    let key = Key::new(b"secret").unwrap();
+    let mut policy = Policy::default();
+    policy.key = Some(key.clone());
+    let server = EchoServer::spawn_on_localhost_with_policy(policy).unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut probes = Probes::new(1, 0, true, None);
+    let request = probes.next_request();
+    // as a request captured an hour ago and replayed would be
+    let replayed = key.sign_at(&request, Direction::Request, now - 3600);
+    assert!(udp_try_exchange(&server, &replayed, Duration::from_millis(200)).is_err());
+    let fresh = key.sign_at(&request, Direction::Request, now);
+    assert!(key.verify(&udp_exchange(&server, &fresh), Direction::Reply).is_some());
+}
+
+#[test]
+fn replies_are_authenticated() {
+    let key = Key::new(b"secret").unwrap();
+    let mut policy = Policy::default();
+    policy.key = Some(key.clone());
+    let signing = EchoServer::spawn_on_localhost_with_policy(policy).unwrap();
+    let open = EchoServer::spawn_on_localhost().unwrap();
+    let mut probe = Signed::new(protocol::create("echo").unwrap(), key);
+    let mut probes = Probes::new(1, 0, true, None);
+    let request = probe.request(&mut probes);
+    let response = udp_exchange(&signing, &request);
+    assert_eq!(probe.classify(&request, &response), Outcome::Ok);
+    // a plain echo returns the request's signature, which isn't one of a reply
+    let response = udp_exchange(&open, &request);
+    assert_eq!(probe.classify(&request, &response), Outcome::Unauthenticated);
+}
+
+#[test]
+fn reflected_requests_are_not_replies() {
+    let key = Key::new(b"secret").unwrap();
+    let mut probe = Signed::new(protocol::create("echo").unwrap(), key.clone());
+    let mut probes = Probes::new(1, 0, true, None);
+    let request = probe.request(&mut probes);
+    // as a spoofer without the key would send the client's own request back
+    assert_eq!(probe.classify(&request, &request), Outcome::Unauthenticated);
+    assert!(key.verify(&request, Direction::Reply).is_none());
+    assert!(key.verify(&request, Direction::Request).is_some());
+}
+
+#[test]
+fn tcp_requests_without_an_end_are_cut_off() {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    // once the request outgrows any reply the connection is closed, so the
+    // rest of it may not be written at all
+    let _ = stream.write_all(&vec![b'.'; probe::MAX_REPLY + 1]);
+    let mut byte = [0; 1];
+    match stream.read(&mut byte) {
+        Ok(n) => assert_eq!(n, 0),
+        Err(e) => {
+            assert!(e.kind() != io::ErrorKind::WouldBlock && e.kind() != io::ErrorKind::TimedOut)
+        }
+    }
+}
+
+#[test]
+fn sources_outside_the_allow_list_are_ignored() {
+    let mut policy = Policy::default();
+    policy.allow.push(Ipv4Network::from_cidr("10.0.0.0/8").unwrap());
+    let server = EchoServer::spawn_on_localhost_with_policy(policy).unwrap();
+    assert!(udp_try_exchange(&server, b"PING\r\n", Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn the_reflector_advertises_its_features() {
+    let open = EchoServer::spawn_on_localhost().unwrap();
+    let reflector = capability::query(open.addr(), Duration::from_secs(5), 1).unwrap().unwrap();
+    assert!(reflector.supports(capability::IDS));
+    assert!(reflector.supports(capability::PADDING));
+    assert!(!reflector.supports(capability::AUTH));
+
+    let mut policy = Policy::default();
+    policy.key = Some(Key::new(b"secret").unwrap());
+    let signing = EchoServer::spawn_on_localhost_with_policy(policy).unwrap();
+    let reflector = capability::query(signing.addr(), Duration::from_secs(5), 1).unwrap().unwrap();
+    assert!(reflector.supports(capability::AUTH));
+    // a hello shorter than the welcome isn't answered
+    assert!(udp_try_exchange(&open, b"HELLO ping-rs\r\n", Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn replies_to_other_probes_are_mismatches() {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    let probe = protocol::create("echo").unwrap();
+    let mut probes = Probes::new(1, 0, true, None);
+    let stale = probes.next_request();
+    let request = probes.next_request();
+    let response = udp_exchange(&server, &stale);
+    assert_eq!(probe.classify(&request, &response), Outcome::Mismatch);
+    let response = udp_exchange(&server, &request);
+    assert_eq!(probe.classify(&request, &response), Outcome::Ok);
+}
+
+#[test]
+fn the_reflector_tallies_probes_by_thread() {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    let mut probes = Probes::new(2, 0, true, None);
+    let first = probes.next_request();
+    udp_exchange(&server, &first);
+    // the second probe is lost on the way
+    probes.next_request();
+    let third = probes.next_request();
+    udp_exchange(&server, &third);
+    udp_exchange(&server, &third);
+    let stream = probe::stream(2, 0);
+    let tally = ledger::query(server.addr(), &stream, Duration::from_secs(5), 1).unwrap().unwrap();
+    assert_eq!(tally.received, 2);
+    assert_eq!(tally.highest, 3);
+    assert_eq!(tally.duplicates, 1);
+    // a thread the reflector heard nothing from received nothing
+    let other = probe::stream(2, 1);
+    let tally = ledger::query(server.addr(), &other, Duration::from_secs(5), 1).unwrap().unwrap();
+    assert_eq!(tally, ledger::Tally::default());
+}
+
+#[test]
+fn replies_carry_the_reflectors_timestamps() {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    let probe = protocol::create("echo").unwrap();
+    let mut probes = Probes::new(3, 0, true, None);
+    probes.set_stamped(true);
+    let request = probes.next_request();
+    let response = udp_exchange(&server, &request);
+    assert_eq!(probe.classify(&request, &response), Outcome::Ok);
+    assert!(response.len() >= request.len());
+    let (rx, tx) = probe::parse_stamps(&response).unwrap();
+    assert!(rx > 0 && rx <= tx);
+    // only probes asking for them are timestamped
+    let response = udp_exchange(&server, b"PING\r\n");
+    assert_eq!(probe::parse_stamps(&response), None);
+}
+
+// the client binary, built alongside the tests
+fn binary() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join(format!("ping-rs{}", env::consts::EXE_SUFFIX))
+}
+
+// runs a one second window against a fresh reflector from a directory of its
+// own, returning the number of completed probes
+fn run_client(name: &str, args: &[&str]) -> u64 {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    let dir = env::temp_dir().join(format!("ping-rs-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let output = Command::new(binary())
+        .current_dir(&dir)
+        .args(&["lo", &format!("{}", server.addr()), "--ip", "127.0.0.1/8"])
+        .args(&["--windows", "1", "--duration", "1", "--rate", "100"])
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(output.status.success(),
+            "{} failed:\n{}{}",
+            name,
+            stdout,
+            String::from_utf8_lossy(&output.stderr));
+    let _ = fs::remove_dir_all(&dir);
+    let line = stdout.lines()
+        .find(|l| l.contains("probes: completed:"))
+        .expect("no probe count in output");
+    let count = line.split("completed:").nth(1).unwrap().split_whitespace().next().unwrap();
+    count.parse().unwrap()
+}
+
+#[test]
+fn stdnet_echo() {
+    assert!(run_client("stdnet-echo", &["--stdnet"]) > 0);
+}
+
+#[test]
+fn stdnet_padded() {
+    let args = ["--stdnet", "--probe-ids", "--request-size", "1000", "--reply-size", "100"];
+    assert!(run_client("stdnet-padded", &args) > 0);
+}
+
+#[test]
+fn stdnet_localized_loss() {
+    assert!(run_client("stdnet-localized-loss", &["--stdnet", "--localize-loss"]) > 0);
+}
+
+#[test]
+fn stdnet_direction_jitter() {
+    assert!(run_client("stdnet-direction-jitter", &["--stdnet", "--direction-jitter"]) > 0);
+}
+
+#[test]
+fn stdnet_dns() {
+    assert!(run_client("stdnet-dns", &["--stdnet", "--protocol", "dns"]) > 0);
+}
+
+#[test]
+fn tcp_echo() {
+    assert!(run_client("tcp-echo", &["--tcp"]) > 0);
+}
+
+#[test]
+fn tcp_redis() {
+    assert!(run_client("tcp-redis", &["--tcp", "--protocol", "redis"]) > 0);
+}
+
+#[test]
+fn tcp_dns() {
+    assert!(run_client("tcp-dns", &["--tcp", "--protocol", "dns"]) > 0);
+}
+
+#[test]
+fn dns_over_tcp_is_length_prefixed() {
+    let server = EchoServer::spawn_on_localhost().unwrap();
+    let mut probe = protocol::create("dns").unwrap();
+    probe.use_tcp();
+    let mut probes = Probes::new(1, 0, false, None);
+    let request = probe.request(&mut probes);
+    assert_eq!(((request[0] as usize) << 8 | request[1] as usize) + 2, request.len());
+    let response = tcp_exchange_framed(&server, &request, &*probe);
+    assert_eq!(((response[0] as usize) << 8 | response[1] as usize) + 2, response.len());
+    assert_eq!(probe.classify(&request, &response), Outcome::Ok);
+    // the same query without its length is a malformed response on a stream
+    assert_eq!(probe.classify(&request, &response[2..]), Outcome::Malformed);
+}
+
+#[test]
+fn unsigned_burst_replies_are_not_completed() {
+    let key = env::temp_dir().join(format!("ping-rs-{}-burst-key", process::id()));
+    fs::File::create(&key).unwrap().write_all(b"secret").unwrap();
+    // without the handshake, which would refuse a reflector that doesn't sign
+    let args = ["--burst", "4", "--no-handshake", "--key", key.to_str().unwrap()];
+    assert_eq!(run_client("burst-key", &args), 0);
+    let _ = fs::remove_file(&key);
+}
+
+#[test]
+fn a_probe_log_replays() {
+    let dir = env::temp_dir().join(format!("ping-rs-{}-replay", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    {
+        let mut log = fs::File::create(dir.join("probes")).unwrap();
+        for seq in 1..101u64 {
+            let sent = seq * 5_000_000;
+            writeln!(log, "1-0-{} {} {} {}", seq, 1_000_000_000 + sent, sent, 200_000 + seq)
+                .unwrap();
+        }
+    }
+    let output = Command::new(binary())
+        .current_dir(&dir)
+        .args(&["--duration", "1", "replay", "probes"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(output.status.success(),
+            "replay failed:\n{}{}",
+            stdout,
+            String::from_utf8_lossy(&output.stderr));
+    let _ = fs::remove_dir_all(&dir);
+    assert!(stdout.contains("probes: replayed: 100"), "{}", stdout);
+}
+
+#[test]
+fn an_empty_probe_log_is_refused() {
+    let dir = env::temp_dir().join(format!("ping-rs-{}-replay-empty", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    {
+        // only the metadata header a probe log starts with
+        let mut log = fs::File::create(dir.join("probes")).unwrap();
+        writeln!(log, "# ping-rs version: 0.1.0").unwrap();
+    }
+    let output = Command::new(binary())
+        .current_dir(&dir)
+        .args(&["replay", "probes"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    let _ = fs::remove_dir_all(&dir);
+    assert!(!output.status.success());
+    assert!(stderr.contains("No probes in probes"), "{}", stderr);
+}
+
+#[test]
+fn process_wide_flags_are_refused_on_a_job_line() {
+    let dir = env::temp_dir().join(format!("ping-rs-{}-jobs-precision", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    {
+        let mut jobs = fs::File::create(dir.join("jobs.txt")).unwrap();
+        writeln!(jobs, "fine lo 127.0.0.1:12221 --stdnet --windows 1").unwrap();
+        writeln!(jobs, "precise lo 127.0.0.1:12221 --stdnet --windows 1 --precision 3").unwrap();
+    }
+    let output = Command::new(binary())
+        .current_dir(&dir)
+        .args(&["jobs", "jobs.txt"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    let _ = fs::remove_dir_all(&dir);
+    assert!(!output.status.success());
+    assert!(stderr.contains("Job precise gives --precision"), "{}", stderr);
+}